@@ -0,0 +1,68 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Exposes build information to `gtctl version --detail`.
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set");
+    let dir = Path::new(&dir);
+
+    let commit = Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .current_dir(dir)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|s| s.trim().to_owned())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=GTCTL_GIT_COMMIT={}", commit);
+
+    // Honors reproducible builds.
+    let date = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=GTCTL_BUILD_EPOCH={}", date);
+
+    let drib = fs::read_to_string(dir.join("Cargo.lock"))
+        .ok()
+        .and_then(|lock| locked_version(&lock, "drib"))
+        .unwrap_or_default();
+    println!("cargo:rustc-env=GTCTL_DRIB_VERSION={}", drib);
+
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+// Finds a package's version in a lock file, along with the commit it was
+// built from when it's a git dependency.
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let package = lock.split("[[package]]").find(|package| {
+        package
+            .lines()
+            .any(|line| line.trim() == format!("name = \"{}\"", name))
+    })?;
+    let field = |key: &str| {
+        package.lines().find_map(|line| {
+            let value = line.trim().strip_prefix(key)?.trim().strip_prefix('=')?;
+            Some(value.trim().trim_matches('"').to_owned())
+        })
+    };
+    let version = field("version")?;
+    match field("source").and_then(|s| s.rsplit('#').next().map(str::to_owned)) {
+        Some(commit) if commit.len() >= 7 && !commit.contains('+') => {
+            Some(format!("{} ({})", version, &commit[..7]))
+        }
+        _ => Some(version),
+    }
+}
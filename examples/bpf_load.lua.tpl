@@ -0,0 +1,13 @@
+local dyc = staticlib.c.get_dy_conf()
+
+{% if replace -%}
+local ret = dylib.c.gk_unload_bpf_flow_handler(dyc.gk, {{index}})
+if ret < 0 then
+	error("failed to unload the BPF program at index {{index}}")
+end
+{% endif -%}
+local ret = dylib.c.gk_load_bpf_flow_handler(dyc.gk, {{index}}, "{{path}}", true)
+if ret < 0 then
+	error("failed to load BPF program {{program}}")
+end
+return "loaded BPF program {{program}}"
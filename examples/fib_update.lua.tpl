@@ -0,0 +1,21 @@
+local dyc = staticlib.c.get_dy_conf()
+
+{%- for entry in add %}
+dylib.add_grantor_entry_lb("{{entry.prefix}}", {
+{%- for grantor in entry.grantors %}
+	{ gt_ip = "{{grantor.address}}", gw_ip = "{{grantor.gateway}}" },
+{%- endfor %}
+}, dyc.gk)
+{%- endfor %}
+
+{%- for entry in update %}
+dylib.update_grantor_entry_lb("{{entry.prefix}}", {
+{%- for grantor in entry.grantors %}
+	{ gt_ip = "{{grantor.address}}", gw_ip = "{{grantor.gateway}}" },
+{%- endfor %}
+}, dyc.gk)
+{%- endfor %}
+
+{%- for prefix in remove %}
+dylib.c.del_fib_entry("{{prefix}}", dyc.gk)
+{%- endfor %}
@@ -10,7 +10,12 @@ end
 local function get_lpm_params()
 	local lcore = policylib.c.gt_lcore_id()
 	local num_rules, num_tbl8s = {{lpm_params_function}}({{lpm_table}})
+{%- if reply_format == "json" %}
+	return string.format('{"id": %d, "num_rules": %d, "num_tbl8s": %d}\n',
+		lcore, num_rules, num_tbl8s)
+{%- else %}
 	return lcore .. ":" .. num_rules .. "," .. num_tbl8s .. "\n"
+{%- endif %}
 end
 
 dylib.update_gt_lua_states_incrementally(dyc.gt, get_lpm_params, false)
@@ -0,0 +1,21 @@
+require "gatekeeper/staticlib"
+require "gatekeeper/policylib"
+
+local dyc = staticlib.c.get_dy_conf()
+
+if dyc.gt == nil then
+	return "Gatekeeper: failed to run as Grantor server\n"
+end
+
+local function get_lpm_usage()
+	local lcore = policylib.c.gt_lcore_id()
+	local used_rules, used_tbl8s = {{lpm_usage_function}}({{lpm_table}})
+{%- if reply_format == "json" %}
+	return string.format('{"id": %d, "used_rules": %d, "used_tbl8s": %d}\n',
+		lcore, used_rules, used_tbl8s)
+{%- else %}
+	return lcore .. ":" .. used_rules .. "," .. used_tbl8s .. "\n"
+{%- endif %}
+end
+
+dylib.update_gt_lua_states_incrementally(dyc.gt, get_lpm_usage, false)
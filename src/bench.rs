@@ -0,0 +1,101 @@
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Stats {
+    pub min: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Stats {
+    /// Computes nearest-rank percentiles over the given samples, which must
+    /// not be empty.
+    pub fn new(mut samples: Vec<Duration>) -> Stats {
+        assert!(!samples.is_empty(), "BUG: no samples");
+        samples.sort();
+        Stats {
+            min: samples[0],
+            p50: percentile(&samples, 50),
+            p90: percentile(&samples, 90),
+            p99: percentile(&samples, 99),
+            max: samples[samples.len() - 1],
+        }
+    }
+
+    /// Returns how many items per second were processed at the median.
+    pub fn throughput(&self, items: usize) -> f64 {
+        let secs = self.p50.as_secs_f64();
+        if secs == 0.0 {
+            return f64::INFINITY;
+        }
+        items as f64 / secs
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min={:?}, p50={:?}, p90={:?}, p99={:?}, max={:?}",
+            self.min, self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (p * sorted.len() + 99) / 100;
+    sorted[rank.saturating_sub(1)]
+}
+
+/// Runs `f` `warmup` times without measuring it, then `iterations` times
+/// collecting timings. The output of the last run is returned along with
+/// the statistics.
+pub async fn measure<F, Fut, T, E>(
+    warmup: usize,
+    iterations: usize,
+    mut f: F,
+) -> Result<(Stats, T), E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    for _ in 0..warmup {
+        f().await?;
+    }
+
+    let mut samples = Vec::with_capacity(iterations);
+    let mut output = None;
+    for _ in 0..iterations.max(1) {
+        let start = Instant::now();
+        let res = f().await?;
+        samples.push(start.elapsed());
+        output = Some(res);
+    }
+
+    // unwrap is safe because at least one iteration is run.
+    Ok((Stats::new(samples), output.unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let samples = (1..=100).rev().map(Duration::from_millis).collect();
+        let stats = Stats::new(samples);
+        assert_eq!(Duration::from_millis(1), stats.min);
+        assert_eq!(Duration::from_millis(50), stats.p50);
+        assert_eq!(Duration::from_millis(90), stats.p90);
+        assert_eq!(Duration::from_millis(99), stats.p99);
+        assert_eq!(Duration::from_millis(100), stats.max);
+
+        let stats = Stats::new(vec![Duration::from_millis(7)]);
+        assert_eq!(Duration::from_millis(7), stats.p50);
+        assert_eq!(Duration::from_millis(7), stats.p99);
+    }
+}
@@ -0,0 +1,251 @@
+//! Blocking variants of the library's entry points, for applications without
+//! a tokio runtime of their own. Each asynchronous call runs on a private
+//! single-threaded runtime, so these functions must not be called from
+//! within an asynchronous context.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use ipnet::{Ipv4Net, Ipv6Net};
+use tokio::{io, runtime};
+
+use crate::config::{Config, DyncfgConfig, EstimateConfig, LpmConfig};
+use crate::dyncfg;
+use crate::error;
+use crate::params::{self, CurrentParams, Params, Usage};
+use crate::queue::RetryQueue;
+use crate::run::{self, RunOptions, TargetResult};
+
+fn block_on<F: Future>(f: F) -> Result<F::Output, error::Error> {
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(error::Error::Runtime)?;
+    Ok(rt.block_on(f))
+}
+
+/// Sends a script file, returning the decoded reply.
+pub fn send_config_script(
+    socket: impl AsRef<Path>,
+    script: impl AsRef<Path>,
+    config: &DyncfgConfig,
+) -> Result<String, error::Error> {
+    block_on(dyncfg::send_config_script(socket, script, config))?
+}
+
+/// Sends a script rendered in memory, returning the decoded reply.
+pub fn send_config_bytes(
+    socket: impl AsRef<Path>,
+    script: &[u8],
+    config: &DyncfgConfig,
+) -> Result<String, error::Error> {
+    block_on(dyncfg::send_config_bytes(socket, script, config))?
+}
+
+/// Sends a script rendered in memory, returning the reply undecoded.
+pub fn send_config_raw(
+    socket: impl AsRef<Path>,
+    script: &[u8],
+    config: &DyncfgConfig,
+) -> Result<Vec<u8>, error::Error> {
+    block_on(dyncfg::send_config_raw(socket, script, config))?
+}
+
+/// Reads the current parameters of a table with a rendered parameters
+/// script.
+pub fn read_params<T>(
+    socket: impl AsRef<Path>,
+    script: &[u8],
+    config: &LpmConfig,
+    dyncfg_config: &DyncfgConfig,
+) -> Result<CurrentParams<T>, error::Error> {
+    Ok(block_on(params::read(
+        socket,
+        script,
+        config,
+        dyncfg_config,
+    ))??)
+}
+
+/// Reads the usage of a table with a rendered usage script.
+pub fn read_usage(
+    socket: impl AsRef<Path>,
+    script: &[u8],
+    config: &LpmConfig,
+    dyncfg_config: &DyncfgConfig,
+) -> Result<Vec<Usage>, error::Error> {
+    Ok(block_on(params::read_usage(
+        socket,
+        script,
+        config,
+        dyncfg_config,
+    ))??)
+}
+
+/// Sends the scripts in a retry queue, in order, returning how many were
+/// sent.
+pub fn drain_retry_queue(
+    dir: impl AsRef<Path>,
+    config: &DyncfgConfig,
+) -> Result<usize, error::Error> {
+    Ok(block_on(async {
+        let queue = RetryQueue::open(dir).await?;
+        queue.drain(config).await
+    })??)
+}
+
+/// Estimates the parameters of an IPv4 table from a file of prefixes, one
+/// per line.
+pub fn estimate_ipv4(
+    prefixes: impl AsRef<Path>,
+    config: &EstimateConfig,
+) -> Result<Params<Ipv4Net>, Error> {
+    let prefixes = load_prefixes(prefixes)?;
+    Ok(params::estimate_ipv4(&prefixes, config))
+}
+
+/// Estimates the parameters of an IPv6 table from a file of prefixes, one
+/// per line.
+pub fn estimate_ipv6(
+    prefixes: impl AsRef<Path>,
+    config: &EstimateConfig,
+) -> Result<Params<Ipv6Net>, Error> {
+    let prefixes = load_prefixes(prefixes)?;
+    Ok(params::estimate_ipv6(&prefixes, config))
+}
+
+fn load_prefixes<T>(path: impl AsRef<Path>) -> Result<BTreeSet<T>, Error>
+where
+    T: FromStr + Ord,
+{
+    let reader = BufReader::new(File::open(path)?);
+    let mut prefixes = BTreeSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let prefix = line.parse().map_err(|_| Error::Prefix(line))?;
+        prefixes.insert(prefix);
+    }
+    Ok(prefixes)
+}
+
+/// Applies an aggregate as `gtctl dyncfg` does, to the given targets or to
+/// every configured one, returning the outcome of each target.
+pub fn apply(
+    config: &Config,
+    aggregate: impl AsRef<Path>,
+    targets: &[String],
+    opts: RunOptions<'_>,
+) -> Result<Vec<TargetResult>, error::Error> {
+    block_on(run::apply(config, aggregate, targets, opts))?
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Prefix(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Prefix(s) => write!(f, "invalid prefix '{}'", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::mock::MockServer;
+
+    #[test]
+    fn test_send_config_bytes() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        // The server runs on its own threads, outside the blocking calls.
+        let rt = runtime::Runtime::new().expect("runtime failed");
+        let server = rt
+            .block_on(MockServer::start(
+                tmp.path().join("gatekeeper"),
+                "ok\n".to_owned(),
+            ))
+            .expect("start failed");
+
+        let config = DyncfgConfig::default();
+        let reply = send_config_bytes(server.path(), b"script", &config).expect("send failed");
+        assert_eq!("ok\n", reply);
+        assert_eq!(vec!["script"], server.received());
+
+        let missing = tmp.path().join("missing");
+        assert!(send_config_bytes(&missing, b"script", &config).is_err());
+    }
+
+    #[test]
+    fn test_estimate() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let path = tmp.path().join("prefixes");
+        std::fs::write(&path, "192.0.2.0/24\n198.51.100.0/25\n").expect("write failed");
+        let config = EstimateConfig::default();
+        let params = estimate_ipv4(&path, &config).expect("estimate failed");
+        let nets = load_prefixes(&path).expect("load failed");
+        assert_eq!(params::estimate_ipv4(&nets, &config), params);
+
+        std::fs::write(&path, "192.0.2.0/24\nbogus\n").expect("write failed");
+        assert!(matches!(
+            estimate_ipv4(&path, &config),
+            Err(Error::Prefix(s)) if s == "bogus"
+        ));
+        assert!(matches!(
+            estimate_ipv6(tmp.path().join("missing"), &config),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let aggregate = tmp.path().join("aggregate");
+        let config: Config = serde_yaml::from_str(&format!(
+            r#"
+state_dir: {}
+replace: {{ input: replace.tpl, output: replace.lua }}
+update: {{ input: update.tpl, output: update.lua }}
+lpm: {{
+  table_format: "{{kind}}_lpm_{{proto}}",
+  parameters_script: {{ input: params.tpl, output: params.lua }},
+  ipv4: {{ lpm_table_constructor: new_lpm, lpm_get_params_function: get_paras }},
+  ipv6: {{ lpm_table_constructor: new_lpm6, lpm_get_params_function: get6_paras }},
+}}
+targets: {{ gt1: {{}} }}
+"#,
+            tmp.path().display()
+        ))
+        .expect("invalid config");
+
+        let targets = vec!["gt2".to_owned()];
+        let e = apply(&config, &aggregate, &targets, RunOptions::default()).unwrap_err();
+        assert_eq!("unknown target 'gt2'", e.to_string());
+    }
+}
@@ -0,0 +1,210 @@
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::{debug, warn};
+use tokio::{
+    fs, io,
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::config::DyncfgConfig;
+use crate::dyncfg;
+use crate::metrics;
+use crate::util;
+
+const QUEUE_SIZE: usize = 1024;
+
+/// A long-lived owner of Gatekeeper's dynamic configuration socket. Clients
+/// submit scripts to the broker's own socket using Gatekeeper's protocol, and
+/// the broker forwards them upstream one at a time, in the order they were
+/// received, relaying each reply back to its client.
+#[derive(Debug)]
+pub struct Broker {
+    path: PathBuf,
+    acceptor: JoinHandle<()>,
+    worker: JoinHandle<()>,
+    stop: oneshot::Sender<()>,
+}
+
+struct Request {
+    script: Vec<u8>,
+    queued_at: Instant,
+    reply: oneshot::Sender<Vec<u8>>,
+}
+
+impl Broker {
+    pub async fn start(
+        path: impl AsRef<Path>,
+        upstream: impl AsRef<Path>,
+        mode: u32,
+        config: DyncfgConfig,
+    ) -> Result<Broker, io::Error> {
+        let path = path.as_ref().to_owned();
+        let upstream = upstream.as_ref().to_owned();
+        let listener = util::bind_unix(&path, mode).await?;
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let (stop, stopped) = oneshot::channel();
+        let acceptor = tokio::spawn(accept(listener, tx));
+        let worker = tokio::spawn(forward(upstream, config, rx, stopped));
+        Ok(Broker {
+            path,
+            acceptor,
+            worker,
+            stop,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stops accepting scripts and waits for the queued ones to be
+    /// forwarded.
+    pub async fn shutdown(self) {
+        self.acceptor.abort();
+        let _ = self.stop.send(());
+        let _ = self.worker.await;
+        let _ = fs::remove_file(&self.path).await;
+    }
+}
+
+async fn accept(listener: UnixListener, tx: mpsc::Sender<Request>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _addr)) => stream,
+            Err(e) => {
+                warn!("broker failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, tx).await {
+                warn!("broker failed to handle request: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve(mut stream: UnixStream, tx: mpsc::Sender<Request>) -> Result<(), io::Error> {
+    let script = dyncfg::read_message(&mut stream).await?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let req = Request {
+        script,
+        queued_at: Instant::now(),
+        reply: reply_tx,
+    };
+    if tx.send(req).await.is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "broker is shutting down",
+        ));
+    }
+    // The reply sender is dropped without a reply when forwarding fails, in
+    // which case the connection is closed, as there's no way to report the
+    // error in Gatekeeper's protocol.
+    match reply_rx.await {
+        Ok(reply) => dyncfg::write_message(&mut stream, &reply).await,
+        Err(_) => Ok(()),
+    }
+}
+
+async fn forward(
+    upstream: PathBuf,
+    config: DyncfgConfig,
+    mut rx: mpsc::Receiver<Request>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let mut stopping = false;
+    loop {
+        let req = tokio::select! {
+            req = rx.recv() => match req {
+                Some(req) => req,
+                None => break,
+            },
+            _ = &mut stopped, if !stopping => {
+                // Scripts already queued are still forwarded.
+                stopping = true;
+                rx.close();
+                continue;
+            }
+        };
+        metrics::timing("broker_wait_time", req.queued_at.elapsed(), &[]);
+        match dyncfg::exchange_with(&upstream, &req.script, &config).await {
+            Ok(reply) => {
+                debug!("broker forwarded {} bytes", req.script.len());
+                metrics::count("broker_scripts_forwarded", 1, &[]);
+                let _ = req.reply.send(reply);
+            }
+            Err(e) => {
+                warn!(
+                    "broker failed to forward script to '{}': {}",
+                    upstream.display(),
+                    e
+                );
+                metrics::count("broker_errors", 1, &[]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::mock::MockServer;
+
+    #[tokio::test]
+    async fn test_broker() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let server = MockServer::start(tmp.path().join("gatekeeper"), "ok\n".to_owned())
+            .await
+            .expect("start failed");
+        let config = DyncfgConfig::default();
+        let broker = Broker::start(
+            tmp.path().join("broker"),
+            server.path(),
+            0o600,
+            config.clone(),
+        )
+        .await
+        .expect("start failed");
+
+        // A second broker doesn't steal the socket.
+        assert!(Broker::start(
+            tmp.path().join("broker"),
+            server.path(),
+            0o600,
+            config.clone()
+        )
+        .await
+        .is_err());
+
+        let mut scripts = Vec::new();
+        for i in 0..4 {
+            let script = tmp.path().join(format!("script{}", i));
+            fs::write(&script, format!("script {}", i))
+                .await
+                .expect("write failed");
+            scripts.push(script);
+        }
+        let sends = scripts
+            .iter()
+            .map(|script| dyncfg::send_config_script(broker.path(), script, &config));
+        for reply in futures::future::join_all(sends).await {
+            assert_eq!("ok\n", reply.expect("send failed"));
+        }
+        let mut received = server.received();
+        received.sort();
+        assert_eq!(
+            vec!["script 0", "script 1", "script 2", "script 3"],
+            received
+        );
+
+        broker.shutdown().await;
+        assert!(!tmp.path().join("broker").exists());
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{self, AsyncWriteExt},
+};
+
+/// A message sent to or received from the dynamic configuration socket.
+/// Captures are written as one JSON frame per line, appended as messages go
+/// through, so concurrent gtctl processes can share a capture file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    pub time: DateTime<Utc>,
+    // Identifies the connection the frame belongs to.
+    pub exchange: String,
+    pub direction: Direction,
+    pub socket: PathBuf,
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Request,
+    Reply,
+}
+
+/// A message's bytes, kept readable when they are valid UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "encoding", content = "data", rename_all = "lowercase")]
+pub enum Payload {
+    Text(String),
+    Hex(String),
+}
+
+impl Payload {
+    pub fn new(data: &[u8]) -> Payload {
+        match std::str::from_utf8(data) {
+            Ok(text) => Payload::Text(text.to_owned()),
+            Err(_) => Payload::Hex(hex::encode(data)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            Payload::Text(text) => Ok(text.as_bytes().to_vec()),
+            Payload::Hex(data) => hex::decode(data).map_err(Error::Hex),
+        }
+    }
+}
+
+/// Records the frames of a single connection.
+#[derive(Debug)]
+pub struct Capture {
+    path: PathBuf,
+    socket: PathBuf,
+    exchange: String,
+}
+
+impl Capture {
+    pub fn new(path: impl AsRef<Path>, socket: impl AsRef<Path>) -> Capture {
+        static SERIAL: AtomicUsize = AtomicUsize::new(0);
+        Capture {
+            path: path.as_ref().to_owned(),
+            socket: socket.as_ref().to_owned(),
+            exchange: format!(
+                "{}.{}",
+                std::process::id(),
+                SERIAL.fetch_add(1, Ordering::Relaxed)
+            ),
+        }
+    }
+
+    /// Appends a frame to the capture file. Failures are only logged, so
+    /// that capturing never gets in the way of sending scripts.
+    pub async fn record(&self, direction: Direction, data: &[u8]) {
+        let frame = Frame {
+            time: Utc::now(),
+            exchange: self.exchange.clone(),
+            direction,
+            socket: self.socket.clone(),
+            payload: Payload::new(data),
+        };
+        if let Err(e) = append(&self.path, &frame).await {
+            warn!("failed to capture to '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+async fn append(path: &Path, frame: &Frame) -> io::Result<()> {
+    let mut line = serde_json::to_vec(frame)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    // A single write keeps lines from concurrent processes whole.
+    file.write_all(&line).await
+}
+
+/// A request and the replies to it, in a capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub time: DateTime<Utc>,
+    pub request: Vec<u8>,
+    pub replies: Vec<Vec<u8>>,
+}
+
+/// Loads the exchanges in a capture file, in the order they started.
+pub async fn load(path: impl AsRef<Path>) -> Result<Vec<Exchange>, Error> {
+    let data = fs::read_to_string(path).await?;
+    let mut frames = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let frame: Frame = serde_json::from_str(line).map_err(|e| Error::Parse(i + 1, e))?;
+        frames.push(frame);
+    }
+    exchanges(&frames)
+}
+
+/// Groups frames into exchanges.
+pub fn exchanges(frames: &[Frame]) -> Result<Vec<Exchange>, Error> {
+    let mut ids: Vec<&str> = Vec::new();
+    let mut exchanges: Vec<Exchange> = Vec::new();
+    for frame in frames {
+        let data = frame.payload.to_bytes()?;
+        let pos = ids.iter().position(|id| *id == frame.exchange);
+        match (frame.direction, pos) {
+            (Direction::Request, None) => {
+                ids.push(&frame.exchange);
+                exchanges.push(Exchange {
+                    time: frame.time,
+                    request: data,
+                    replies: Vec::new(),
+                });
+            }
+            (Direction::Reply, Some(pos)) => exchanges[pos].replies.push(data),
+            (Direction::Request, Some(_)) | (Direction::Reply, None) => {
+                return Err(Error::Unmatched(frame.exchange.clone()))
+            }
+        }
+    }
+    Ok(exchanges)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(usize, serde_json::Error),
+    Hex(hex::FromHexError),
+    Unmatched(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Parse(line, e) => write!(f, "invalid frame at line {}: {}", line, e),
+            Error::Hex(e) => write!(f, "invalid hex data: {}", e),
+            Error::Unmatched(id) => write!(f, "unmatched frame in exchange {}", id),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Parse(_, e) => Some(e),
+            Error::Hex(e) => Some(e),
+            Error::Unmatched(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_capture() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let path = tmp.path().join("capture.jsonl");
+        let first = Capture::new(&path, "/run/gatekeeper/dyn_cfg.socket");
+        let second = Capture::new(&path, "/run/gatekeeper/dyn_cfg.socket");
+        first.record(Direction::Request, b"return 1").await;
+        second.record(Direction::Request, b"\xff\x00").await;
+        first.record(Direction::Reply, b"1\n").await;
+        second.record(Direction::Reply, b"").await;
+        first.record(Direction::Reply, b"done\n").await;
+
+        let exchanges = load(&path).await.expect("load failed");
+        assert_eq!(2, exchanges.len());
+        assert_eq!(b"return 1".to_vec(), exchanges[0].request);
+        assert_eq!(
+            vec![b"1\n".to_vec(), b"done\n".to_vec()],
+            exchanges[0].replies
+        );
+        assert_eq!(b"\xff\x00".to_vec(), exchanges[1].request);
+        assert_eq!(vec![Vec::<u8>::new()], exchanges[1].replies);
+    }
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(Payload::Text("ok".to_owned()), Payload::new(b"ok"));
+        assert_eq!(Payload::Hex("ff00".to_owned()), Payload::new(b"\xff\x00"));
+        assert_eq!(
+            b"\xff\x00".to_vec(),
+            Payload::Hex("ff00".to_owned()).to_bytes().unwrap()
+        );
+        assert!(Payload::Hex("f".to_owned()).to_bytes().is_err());
+    }
+}
@@ -1,14 +1,32 @@
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 
-use drib::config::{ChunkedTemplates, Templates};
-use log::Level;
-use serde::Deserialize;
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use drib::config::Templates;
+use ipnet::IpNet;
+use log::{Level, LevelFilter};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize)]
+use crate::dyncfg::NoReply;
+use crate::error::ConfigError;
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     #[serde(default = "default_socket_path")]
     pub socket: PathBuf,
+    // Socket of a broker serializing access to `socket`.
+    pub broker_socket: Option<PathBuf>,
+    #[serde(
+        default = "default_socket_mode",
+        deserialize_with = "parse_socket_mode"
+    )]
+    pub broker_socket_mode: u32,
     pub state_dir: PathBuf,
+    #[serde(default, deserialize_with = "parse_state_namespace")]
+    pub state_namespace: Option<String>,
+    pub scripts_dir: Option<PathBuf>,
     pub replace: ChunkedTemplates,
     pub update: ChunkedTemplates,
 
@@ -19,12 +37,904 @@ pub struct Config {
 
     #[serde(deserialize_with = "parse_log_level", default = "default_log_level")]
     pub log_level: Level,
+    // Levels of specific modules, including those of other crates.
+    #[serde(default, deserialize_with = "parse_log_filters")]
+    pub log_filters: BTreeMap<String, LevelFilter>,
+
+    pub log_target: Option<LogTarget>,
+    pub log_file: Option<LogFileConfig>,
+
+    #[serde(default, deserialize_with = "parse_remove_policy")]
+    pub remove_rendered_scripts: RemovePolicy,
+    #[serde(default)]
+    pub per_run_scripts: bool,
+    // Scripts are sent from memory, so they are only written for
+    // inspection.
+    #[serde(default = "default_true")]
+    pub write_gtctl_scripts: bool,
+    // Skip runs whose aggregate is identical to the last one applied.
+    #[serde(default)]
+    pub skip_unchanged: bool,
+
+    pub max_script_size: Option<usize>,
+
+    #[serde(default)]
+    pub durable: bool,
+
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+
+    #[serde(default = "default_parallelism")]
+    pub parallelism: usize,
+
+    #[serde(default, deserialize_with = "parse_targets")]
+    pub targets: BTreeMap<String, TargetConfig>,
+
+    pub statsd: Option<StatsdConfig>,
+
+    pub health: Option<HealthConfig>,
+    pub control: Option<ControlConfig>,
+    pub proxy: Option<ProxyConfig>,
+
+    pub retry_queue: Option<RetryQueueConfig>,
+
+    #[serde(default)]
+    pub dyncfg: DyncfgConfig,
+
+    pub prune: Option<PruneConfig>,
+    pub script_quota: Option<ScriptQuotaConfig>,
+
+    pub lease: Option<LeaseConfig>,
+
+    pub fib: Option<FibConfig>,
+
+    pub bpf: Option<BpfConfig>,
+
+    pub plan: Option<PlanConfig>,
+
+    pub validation: Option<ValidationConfig>,
+
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    #[serde(default)]
+    pub kind_map: BTreeMap<String, String>,
+
+    #[serde(default = "default_group_by")]
+    pub group_by: Vec<GroupKey>,
+
+    #[serde(default = "default_true")]
+    pub allow_replace: bool,
+
+    #[serde(
+        default = "default_replace_threshold_percent",
+        deserialize_with = "parse_percent"
+    )]
+    pub replace_threshold_percent: usize,
+
+    // Minimum number of seconds between replaces of a table.
+    pub replace_cooldown: Option<u64>,
+
+    // Time windows in which replaces are permitted. Empty means always.
+    #[serde(default)]
+    pub replace_windows: Vec<ReplaceWindow>,
+
+    pub shrink: Option<ShrinkConfig>,
+
+    #[serde(default)]
+    pub change_order: ChangeOrder,
+
+    #[serde(default)]
+    pub table_mode: TableMode,
+
+    #[serde(default)]
+    pub policies: Vec<PolicyRule>,
+
+    #[serde(default)]
+    pub kinds: BTreeMap<String, KindConfig>,
+
+    #[serde(default)]
+    pub extra_vars: ExtraVars,
+
+    // Set from the command line. These apply to every template and take
+    // precedence over any other variable.
+    #[serde(skip)]
+    pub var_overrides: BTreeMap<String, serde_yaml::Value>,
+    // Set for the duration of a run when `per_run_scripts` is enabled.
+    #[serde(skip)]
+    pub run_id: Option<String>,
+    // Set from the `route_kinds` of a target. Tables of other kinds aren't applied
+    // to it.
+    #[serde(skip)]
+    pub target_kinds: Option<BTreeSet<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    Stdout,
+    File,
+    Syslog,
+    Journald,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    pub max_size: Option<u64>,
+    pub rotate_interval: Option<u64>,
+    #[serde(default = "default_log_file_keep")]
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsdConfig {
+    pub address: String,
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub dogstatsd: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthConfig {
+    pub listen: String,
+    pub max_staleness: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ControlConfig {
+    pub socket: PathBuf,
+    #[serde(
+        default = "default_socket_mode",
+        deserialize_with = "parse_socket_mode"
+    )]
+    pub mode: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProxyConfig {
+    pub listen: String,
+    // Networks callers may connect from; others are refused.
+    pub allow: Vec<IpNet>,
+    // A file holding the token callers must send.
+    pub token_file: PathBuf,
+    // Required unless listening on a loopback address.
+    pub tls: Option<TlsConfig>,
+    // Seconds a caller may take to send each message.
+    #[serde(
+        default = "default_proxy_read_timeout",
+        deserialize_with = "parse_proxy_read_timeout"
+    )]
+    pub read_timeout: u64,
+    #[serde(
+        default = "default_proxy_max_connections",
+        deserialize_with = "parse_proxy_max_connections"
+    )]
+    pub max_connections: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    // PEM-encoded certificate chain and private key.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryQueueConfig {
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+}
+
+/// How gtctl talks to the dynamic configuration socket.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DyncfgConfig {
+    // Ends replies sent in multiple messages.
+    pub completion_marker: Option<String>,
+    #[serde(default)]
+    pub reply_decoding: ReplyDecoding,
+    #[serde(default)]
+    pub socket_type: SocketType,
+    // Reach the socket on a remote host through SSH.
+    pub ssh: Option<SshConfig>,
+    // Record every message exchanged to this file.
+    pub capture: Option<PathBuf>,
+    #[serde(default = "default_on_closed")]
+    pub on_closed: NoReplyAction,
+    #[serde(default = "default_on_empty")]
+    pub on_empty: NoReplyAction,
+    #[serde(default = "default_on_reset")]
+    pub on_reset: NoReplyAction,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: usize,
+}
+
+impl DyncfgConfig {
+    /// Returns the action for a script that went unanswered.
+    pub fn no_reply_action(&self, no_reply: NoReply) -> NoReplyAction {
+        match no_reply {
+            NoReply::Closed => self.on_closed,
+            NoReply::Empty => self.on_empty,
+            NoReply::Reset => self.on_reset,
+        }
+    }
+}
+
+impl Default for DyncfgConfig {
+    fn default() -> DyncfgConfig {
+        DyncfgConfig {
+            completion_marker: None,
+            reply_decoding: ReplyDecoding::default(),
+            socket_type: SocketType::default(),
+            ssh: None,
+            capture: None,
+            on_closed: default_on_closed(),
+            on_empty: default_on_empty(),
+            on_reset: default_on_reset(),
+            max_retries: default_max_retries(),
+        }
+    }
+}
+
+/// The type of the dynamic configuration socket.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketType {
+    // SOCK_STREAM, with length-prefixed messages, as in stock Gatekeeper.
+    Stream,
+    // SOCK_SEQPACKET, one message per packet.
+    Seqpacket,
+    // SOCK_DGRAM, one message per datagram.
+    Datagram,
+}
+
+impl Default for SocketType {
+    fn default() -> SocketType {
+        SocketType::Stream
+    }
+}
+
+/// How to reach a remote Gatekeeper host through SSH. The helper command is
+/// run remotely, relaying its standard input and output to the socket, whose
+/// path replaces the `{socket}` placeholder.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SshConfig {
+    // The host to connect to, as `[user@]host`.
+    pub destination: String,
+    pub port: Option<u16>,
+    pub identity_file: Option<PathBuf>,
+    // Options passed with `-o`, e.g. `BatchMode=yes`.
+    #[serde(default)]
+    pub options: Vec<String>,
+    #[serde(default = "default_ssh_helper")]
+    pub helper: String,
+    #[serde(default = "default_ssh_command")]
+    pub command: PathBuf,
+}
+
+/// What to do when a script goes unanswered.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoReplyAction {
+    // Fail the run.
+    Fail,
+    // Send the script again, up to `max_retries` times.
+    Retry,
+    // Take it as an empty successful reply.
+    Success,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyDecoding {
+    // Replace invalid UTF-8 sequences.
+    Lossy,
+    // Fail on invalid UTF-8.
+    Strict,
+    // Keep replies as bytes where they aren't parsed, failing on invalid
+    // UTF-8 elsewhere.
+    Raw,
+}
+
+impl Default for ReplyDecoding {
+    fn default() -> ReplyDecoding {
+        ReplyDecoding::Lossy
+    }
+}
+
+/// When rendered scripts are removed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RemovePolicy {
+    // As soon as each script is sent.
+    Always,
+    Never,
+    // Once the run succeeds, keeping every script of a failed run.
+    OnSuccess,
+}
+
+impl Default for RemovePolicy {
+    fn default() -> RemovePolicy {
+        RemovePolicy::Never
+    }
+}
+
+/// Default retention for `gtctl prune`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PruneConfig {
+    pub max_age: Option<u64>,
+    pub keep: Option<usize>,
+}
+
+/// A cap on the disk space taken by rendered scripts, checked before each
+/// run.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScriptQuotaConfig {
+    pub max_bytes: u64,
+    #[serde(default)]
+    pub action: QuotaAction,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaAction {
+    // Remove the oldest scripts until the quota is met.
+    Prune,
+    // Fail the run.
+    Fail,
+}
+
+impl Default for QuotaAction {
+    fn default() -> QuotaAction {
+        QuotaAction::Prune
+    }
+}
+
+/// A lease on shared storage electing which of several redundant gtctl
+/// instances applies aggregates in watch mode.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LeaseConfig {
+    pub path: PathBuf,
+    pub holder: Option<String>,
+    #[serde(default = "default_lease_ttl", deserialize_with = "parse_lease_ttl")]
+    pub ttl: u64,
+}
+
+/// Signing of plans written by `gtctl plan`. When configured, `gtctl apply`
+/// refuses plans that aren't signed with the same key.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlanConfig {
+    pub key_file: PathBuf,
+}
+
+/// Templates for scripts rendered in chunks of at most `max_ranges_per_file`
+/// ranges each.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChunkedTemplates {
+    #[serde(flatten)]
+    pub templates: Templates,
+    #[serde(default, deserialize_with = "parse_max_ranges")]
+    pub max_ranges_per_file: Option<MaxRanges>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MaxRanges {
+    Count(usize),
+    // Derived from the template so that scripts fit in a dynamic
+    // configuration message.
+    Auto,
+}
+
+/// Checks applied to the ranges of the new aggregate before they reach
+/// the LPM tables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidationConfig {
+    #[serde(default)]
+    pub action: ValidationAction,
+    #[serde(default = "default_true")]
+    pub bogons: bool,
+    #[serde(default = "default_true")]
+    pub default_route: bool,
+    #[serde(default)]
+    pub exempt_kinds: BTreeSet<String>,
+    #[serde(default)]
+    pub max_prefix_len: MaxPrefixLen,
+    #[serde(default)]
+    pub normalize_ipv4_embedded: bool,
+}
+
+/// Limits on how specific the ranges of a table may be. Up to `allowed`
+/// ranges per table may exceed the limit; beyond that, they are handled
+/// according to the validation action, or replaced by their covering
+/// prefixes if `aggregate` is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MaxPrefixLen {
+    #[serde(default, deserialize_with = "parse_ipv4_prefix_len")]
+    pub ipv4: Option<u8>,
+    #[serde(default, deserialize_with = "parse_ipv6_prefix_len")]
+    pub ipv6: Option<u8>,
+    #[serde(default)]
+    pub allowed: usize,
+    #[serde(default)]
+    pub aggregate: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationAction {
+    // Fail the run.
+    Reject,
+    // Leave the offending ranges out of the tables.
+    Strip,
+}
+
+impl Default for ValidationAction {
+    fn default() -> ValidationAction {
+        ValidationAction::Reject
+    }
+}
+
+/// Warnings for estimated parameters approaching the limits of a table.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitsConfig {
+    #[serde(default = "default_warn_percent", deserialize_with = "parse_percent")]
+    pub warn_percent: usize,
+    #[serde(default)]
+    pub fail_on_warning: bool,
+    #[serde(default)]
+    pub ipv4: Caps,
+    #[serde(default)]
+    pub ipv6: Caps,
+    #[serde(default)]
+    pub action: CapAction,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            warn_percent: default_warn_percent(),
+            fail_on_warning: false,
+            ipv4: Caps::default(),
+            ipv6: Caps::default(),
+            action: CapAction::default(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn caps(&self, proto: &str) -> &Caps {
+        match proto {
+            "ipv4" => &self.ipv4,
+            _ => &self.ipv6,
+        }
+    }
+}
+
+/// Ceilings on the parameters of the tables of a protocol.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Caps {
+    pub max_rules: Option<usize>,
+    pub max_tbl8s: Option<usize>,
+}
+
+impl Caps {
+    pub fn is_empty(&self) -> bool {
+        self.max_rules.is_none() && self.max_tbl8s.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CapAction {
+    // Fail the run.
+    Fail,
+    // Size the table at the caps.
+    Truncate,
+}
+
+impl Default for CapAction {
+    fn default() -> CapAction {
+        CapAction::Fail
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOrder {
+    // Removals and insertions are rendered together, and may share scripts.
+    Interleaved,
+    // All removals are sent before any insertion.
+    RemoveFirst,
+    // All insertions are sent before any removal.
+    InsertFirst,
+}
+
+impl Default for ChangeOrder {
+    fn default() -> ChangeOrder {
+        ChangeOrder::Interleaved
+    }
+}
+
+/// How the ranges of a table are stored in Gatekeeper.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableMode {
+    // An LPM table, whose parameters are estimated and which is replaced
+    // when they don't fit.
+    Lpm,
+    // A Lua table or other hash-based set, only ever added to and removed
+    // from.
+    Hash,
+}
+
+impl Default for TableMode {
+    fn default() -> TableMode {
+        TableMode::Lpm
+    }
+}
+
+/// Maps the kind and class of ranges to the policy identifiers, e.g. a
+/// policy ID or BPF index, that templates apply to them. A rule without a
+/// kind or class matches any.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PolicyRule {
+    pub kind: Option<String>,
+    pub class: Option<String>,
+    pub vars: BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KindCaps {
+    pub ipv4: Option<Caps>,
+    pub ipv6: Option<Caps>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FibConfig {
+    pub input: PathBuf,
+    pub output: String,
+    #[serde(default = "default_max_entries_per_file")]
+    pub max_entries_per_file: usize,
+}
+
+/// BPF programs referenced by policies, uploaded to Gatekeeper whenever their
+/// files change.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BpfConfig {
+    pub input: PathBuf,
+    pub output: String,
+    // The start of the reply to a script that loaded its program.
+    #[serde(default = "default_bpf_success_reply")]
+    pub success_reply: String,
+    #[serde(default)]
+    pub programs: BTreeMap<String, BpfProgram>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BpfProgram {
+    pub path: PathBuf,
+    // The index policies refer to the program by.
+    pub index: u8,
+}
+
+/// Site-specific variables merged into the context of the respective
+/// templates. These can't shadow the variables provided by gtctl.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExtraVars {
+    #[serde(default)]
+    pub parameters: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    pub replace: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    pub update: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    pub fib: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    pub bpf: BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TargetConfig {
+    pub socket: Option<PathBuf>,
+    pub broker_socket: Option<PathBuf>,
+    pub table_format: Option<String>,
+    pub parameters_script: Option<Templates>,
+    pub usage_script: Option<Templates>,
+    pub reply_format: Option<ReplyFormat>,
+    pub reply_pattern: Option<ReplyPattern>,
+    pub replace: Option<ChunkedTemplates>,
+    pub update: Option<ChunkedTemplates>,
+    #[serde(default, deserialize_with = "parse_state_namespace")]
+    pub state_namespace: Option<String>,
+    pub extra_vars: Option<ExtraVars>,
+    pub route_kinds: Option<BTreeSet<String>>,
+}
+
+/// Replacing tables with smaller ones once they stay overprovisioned for a
+/// number of consecutive runs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShrinkConfig {
+    #[serde(deserialize_with = "parse_percent")]
+    pub below_percent: usize,
+    #[serde(
+        default = "default_shrink_runs",
+        deserialize_with = "parse_shrink_runs"
+    )]
+    pub runs: usize,
+}
 
+/// A daily UTC time window, optionally restricted to some days of the week,
+/// in which tables may be replaced. Windows ending before they start span
+/// midnight, and their days refer to the day they start.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplaceWindow {
     #[serde(default)]
-    pub remove_rendered_scripts: bool,
+    pub days: Vec<Weekday>,
+    #[serde(deserialize_with = "parse_time")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "parse_time")]
+    pub end: NaiveTime,
 }
 
-#[derive(Debug, Deserialize)]
+impl ReplaceWindow {
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        let t = time.time();
+        let day = if self.start <= self.end {
+            if t < self.start || t >= self.end {
+                return false;
+            }
+            time.weekday()
+        } else if t >= self.start {
+            time.weekday()
+        } else if t < self.end {
+            (time - Duration::days(1)).weekday()
+        } else {
+            return false;
+        };
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+/// Returns whether replaces are permitted at the given time.
+pub fn in_replace_window(windows: &[ReplaceWindow], time: DateTime<Utc>) -> bool {
+    windows.is_empty() || windows.iter().any(|w| w.contains(time))
+}
+
+/// Loads the configuration file, applying overrides of keys given as dotted
+/// paths.
+pub fn load(
+    path: impl AsRef<Path>,
+    overrides: &[(String, serde_yaml::Value)],
+) -> Result<Config, ConfigError> {
+    let path = path.as_ref();
+    let data = std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+    let mut value: serde_yaml::Value = serde_yaml::from_str(&data).map_err(ConfigError::Parse)?;
+    for (key, v) in overrides {
+        set_value(&mut value, key, v.clone()).map_err(|e| ConfigError::Override(key.clone(), e))?;
+    }
+    serde_yaml::from_value(value).map_err(ConfigError::Deserialize)
+}
+
+/// Sets the value at a dotted key path in a parsed configuration, creating
+/// any missing sections along the way. Numeric path components index into
+/// lists.
+pub fn set_value(
+    root: &mut serde_yaml::Value,
+    key: &str,
+    value: serde_yaml::Value,
+) -> Result<(), String> {
+    use serde_yaml::{Mapping, Value};
+
+    let mut node = root;
+    for name in key.split('.') {
+        if name.is_empty() {
+            return Err(format!("invalid key '{}'", key));
+        }
+        if node.is_null() {
+            *node = Value::Mapping(Mapping::new());
+        }
+        node = match node {
+            Value::Mapping(map) => {
+                let name = Value::from(name);
+                if !map.contains_key(&name) {
+                    map.insert(name.clone(), Value::Null);
+                }
+                map.get_mut(&name).expect("missing inserted key")
+            }
+            Value::Sequence(seq) => {
+                let len = seq.len();
+                match name.parse::<usize>() {
+                    Ok(i) if i < len => &mut seq[i],
+                    _ => {
+                        return Err(format!(
+                            "invalid index '{}' in '{}' ({} items)",
+                            name, key, len
+                        ))
+                    }
+                }
+            }
+            _ => return Err(format!("'{}' in '{}' is not a section", name, key)),
+        };
+    }
+    *node = value;
+    Ok(())
+}
+
+/// Entry metadata by which ranges are grouped into tables.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupKey {
+    Kind,
+    Class,
+}
+
+/// Per-kind overrides of the table update policy.
+#[derive(Debug, Clone, Deserialize)]
+pub struct KindConfig {
+    pub allow_replace: Option<bool>,
+    #[serde(default, deserialize_with = "parse_optional_percent")]
+    pub replace_threshold_percent: Option<usize>,
+    pub replace_cooldown: Option<u64>,
+    pub replace_windows: Option<Vec<ReplaceWindow>>,
+    pub headroom_percent: Option<usize>,
+    pub replace_headroom_percent: Option<usize>,
+    pub caps: Option<KindCaps>,
+    pub replace: Option<ChunkedTemplates>,
+    pub update: Option<ChunkedTemplates>,
+    // Tables of kinds with a higher priority are applied first.
+    pub priority: Option<i64>,
+    pub change_order: Option<ChangeOrder>,
+    pub table_mode: Option<TableMode>,
+}
+
+impl Config {
+    /// Returns the effective configuration for the named target, i.e. the
+    /// top-level settings with the target's overrides applied. Each target
+    /// keeps its state in a subdirectory of `state_dir`, named after its
+    /// `state_namespace` setting or, if that is not given, the target name.
+    pub fn target(&self, name: &str) -> Option<Config> {
+        let target = self.targets.get(name)?;
+        let mut config = self.clone();
+        config.targets = BTreeMap::new();
+
+        if let Some(socket) = &target.socket {
+            config.socket = socket.clone();
+        }
+        if let Some(broker_socket) = &target.broker_socket {
+            config.broker_socket = Some(broker_socket.clone());
+        }
+        if let Some(table_format) = &target.table_format {
+            config.lpm.table_format = table_format.clone();
+        }
+        if let Some(parameters_script) = &target.parameters_script {
+            config.lpm.parameters_script = parameters_script.clone();
+        }
+        if let Some(usage_script) = &target.usage_script {
+            config.lpm.usage_script = Some(usage_script.clone());
+        }
+        if let Some(reply_format) = target.reply_format {
+            config.lpm.reply_format = reply_format;
+        }
+        if let Some(reply_pattern) = &target.reply_pattern {
+            config.lpm.reply_pattern = Some(reply_pattern.clone());
+        }
+        if let Some(replace) = &target.replace {
+            config.replace = replace.clone();
+        }
+        if let Some(update) = &target.update {
+            config.update = update.clone();
+        }
+        if let Some(extra_vars) = &target.extra_vars {
+            config.extra_vars = extra_vars.clone();
+        }
+        if let Some(kinds) = &target.route_kinds {
+            config.target_kinds = Some(kinds.clone());
+        }
+        let namespace = target.state_namespace.as_deref().unwrap_or(name);
+        config.state_dir = self.state_dir.join(namespace);
+
+        Some(config)
+    }
+
+    /// Returns the output paths of every rendered script, as configured,
+    /// i.e. with their placeholders and before resolving them against
+    /// `scripts_dir`.
+    pub fn script_outputs(&self) -> Vec<&str> {
+        let mut outputs = vec![
+            self.lpm.parameters_script.output.as_str(),
+            self.replace.templates.output.as_str(),
+            self.update.templates.output.as_str(),
+        ];
+        outputs.extend(self.lpm.usage_script.iter().map(|t| t.output.as_str()));
+        outputs.extend(self.fib.iter().map(|fib| fib.output.as_str()));
+        outputs.extend(self.bpf.iter().map(|bpf| bpf.output.as_str()));
+        outputs.extend(
+            self.kinds
+                .values()
+                .flat_map(|kind| kind.replace.iter().chain(&kind.update))
+                .map(|templates| templates.templates.output.as_str()),
+        );
+        outputs
+    }
+
+    /// Returns the socket scripts are sent to, i.e. the broker's, if one is
+    /// configured, or Gatekeeper's.
+    pub fn dyncfg_socket(&self) -> &Path {
+        self.broker_socket.as_deref().unwrap_or(&self.socket)
+    }
+
+    /// Returns the settings for talking to the socket scripts are sent to.
+    /// The broker's socket is always a stream socket.
+    pub fn dyncfg_config(&self) -> Cow<'_, DyncfgConfig> {
+        if self.broker_socket.is_none() {
+            return Cow::Borrowed(&self.dyncfg);
+        }
+        Cow::Owned(DyncfgConfig {
+            socket_type: SocketType::Stream,
+            ssh: None,
+            ..self.dyncfg.clone()
+        })
+    }
+
+    /// Returns the effective configuration for the tables of a kind, i.e.
+    /// these settings with the kind's overrides applied.
+    pub fn kind(&self, kind: &Option<String>) -> Cow<'_, Config> {
+        let overrides = match kind.as_ref().and_then(|kind| self.kinds.get(kind)) {
+            Some(overrides) => overrides,
+            None => return Cow::Borrowed(self),
+        };
+        let mut config = self.clone();
+        if let Some(allow_replace) = overrides.allow_replace {
+            config.allow_replace = allow_replace;
+        }
+        if let Some(percent) = overrides.replace_threshold_percent {
+            config.replace_threshold_percent = percent;
+        }
+        if let Some(cooldown) = overrides.replace_cooldown {
+            config.replace_cooldown = Some(cooldown);
+        }
+        if let Some(windows) = &overrides.replace_windows {
+            config.replace_windows = windows.clone();
+        }
+        if let Some(headroom_percent) = overrides.headroom_percent {
+            config.estimate.headroom_percent = headroom_percent;
+        }
+        if let Some(percent) = overrides.replace_headroom_percent {
+            config.estimate.replace_headroom_percent = percent;
+        }
+        if let Some(caps) = &overrides.caps {
+            if let Some(ipv4) = &caps.ipv4 {
+                config.limits.ipv4 = ipv4.clone();
+            }
+            if let Some(ipv6) = &caps.ipv6 {
+                config.limits.ipv6 = ipv6.clone();
+            }
+        }
+        if let Some(replace) = &overrides.replace {
+            config.replace = replace.clone();
+        }
+        if let Some(update) = &overrides.update {
+            config.update = update.clone();
+        }
+        if let Some(order) = overrides.change_order {
+            config.change_order = order;
+        }
+        if let Some(mode) = overrides.table_mode {
+            config.table_mode = mode;
+        }
+        Cow::Owned(config)
+    }
+
+    /// Returns the priority of the given kind's tables, which defaults to 0.
+    pub fn kind_priority(&self, kind: &Option<String>) -> i64 {
+        kind.as_ref()
+            .and_then(|kind| self.kinds.get(kind))
+            .and_then(|overrides| overrides.priority)
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct EstimateConfig {
     #[serde(default = "default_scaling_factor")]
     #[serde(deserialize_with = "parse_scaling_factor")]
@@ -32,20 +942,177 @@ pub struct EstimateConfig {
     #[serde(default = "default_scaling_factor")]
     #[serde(deserialize_with = "parse_scaling_factor")]
     pub tbl8s_scaling_factor: usize,
+    #[serde(default)]
+    pub headroom_percent: usize,
+    #[serde(default)]
+    pub replace_headroom_percent: usize,
+    #[serde(default, deserialize_with = "parse_rounding")]
+    pub rounding: Rounding,
+    #[serde(default)]
+    pub ipv4_lpm: Ipv4Lpm,
+    #[serde(default)]
+    pub ipv6_lpm: Ipv6Lpm,
+    #[serde(default)]
+    pub lpm6: Lpm6Geometry,
+}
+
+impl Default for EstimateConfig {
+    fn default() -> EstimateConfig {
+        default_estimate_config()
+    }
+}
+
+/// The LPM implementation IPv4 estimates are made for.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+pub enum Ipv4Lpm {
+    // DPDK's DIR-24-8 `rte_lpm`.
+    #[serde(rename = "dir24_8")]
+    Dir24_8,
+}
+
+impl Default for Ipv4Lpm {
+    fn default() -> Ipv4Lpm {
+        Ipv4Lpm::Dir24_8
+    }
+}
+
+/// The LPM implementation IPv6 estimates are made for.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ipv6Lpm {
+    // DPDK's `rte_lpm6`.
+    Lpm6,
+}
+
+impl Default for Ipv6Lpm {
+    fn default() -> Ipv6Lpm {
+        Ipv6Lpm::Lpm6
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// The shape of `rte_lpm6` tables, for DPDK builds that change its defaults.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+pub struct Lpm6Geometry {
+    #[serde(default = "default_first_stage_depth")]
+    #[serde(deserialize_with = "parse_lpm6_depth")]
+    pub first_stage_depth: u8,
+    #[serde(default = "default_tbl8_stride")]
+    #[serde(deserialize_with = "parse_lpm6_depth")]
+    pub tbl8_stride: u8,
+    #[serde(default = "default_tbl8s_per_expansion")]
+    #[serde(deserialize_with = "parse_tbl8s_per_expansion")]
+    pub tbl8s_per_expansion: usize,
+}
+
+impl Default for Lpm6Geometry {
+    fn default() -> Lpm6Geometry {
+        Lpm6Geometry {
+            first_stage_depth: default_first_stage_depth(),
+            tbl8_stride: default_tbl8_stride(),
+            tbl8s_per_expansion: default_tbl8s_per_expansion(),
+        }
+    }
+}
+
+/// How the parameters of replacement tables are rounded up.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Rounding {
+    None,
+    PowerOfTwo,
+    Multiple(usize),
+}
+
+impl Default for Rounding {
+    fn default() -> Rounding {
+        Rounding::None
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct LpmConfig {
     pub table_format: String,
     pub parameters_script: Templates,
+    pub usage_script: Option<Templates>,
     pub ipv4: LuaFunctions,
     pub ipv6: LuaFunctions,
+    #[serde(default)]
+    pub kinds: BTreeMap<String, KindLuaFunctions>,
+    #[serde(default)]
+    pub reply_format: ReplyFormat,
+    pub reply_pattern: Option<ReplyPattern>,
+}
+
+/// The format in which the parameters script reports the LPM parameters.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyFormat {
+    // Lines of numbers printed by Lua, parsed leniently.
+    Text,
+    // One JSON object per Gatekeeper instance.
+    Json,
+}
+
+impl Default for ReplyFormat {
+    fn default() -> ReplyFormat {
+        ReplyFormat::Text
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// A regular expression replacing the default parser of text replies. It
+/// must have the `id`, `rules` and `tbl8s` named capture groups, and may
+/// have the `used_rules` group along with `used_tbl8s` or `free_tbl8s`.
+#[derive(Debug, Clone)]
+pub struct ReplyPattern(pub Regex);
+
+impl<'de> Deserialize<'de> for ReplyPattern {
+    fn deserialize<D>(deserializer: D) -> Result<ReplyPattern, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let re = Regex::new(&s).map_err(serde::de::Error::custom)?;
+        for name in &["id", "rules", "tbl8s"] {
+            if !re.capture_names().any(|n| n == Some(name)) {
+                return Err(serde::de::Error::custom(format!(
+                    "reply_pattern is missing the '{}' capture group",
+                    name
+                )));
+            }
+        }
+        // Usage is only reported when both of its numbers are known.
+        let has = |name| re.capture_names().any(|n| n == Some(name));
+        if has("used_rules") != (has("used_tbl8s") || has("free_tbl8s")) {
+            return Err(serde::de::Error::custom(
+                "reply_pattern must have both the 'used_rules' and the 'used_tbl8s' or 'free_tbl8s' capture groups, or neither",
+            ));
+        }
+        Ok(ReplyPattern(re))
+    }
+}
+
+impl LpmConfig {
+    /// Returns the Lua functions for the given table, preferring the ones
+    /// configured for its kind over the protocol defaults.
+    pub fn lua_functions(&self, proto: &str, kind: &Option<String>) -> &LuaFunctions {
+        let by_kind = kind.as_ref().and_then(|k| self.kinds.get(k));
+        match proto {
+            "ipv4" => by_kind.and_then(|f| f.ipv4.as_ref()).unwrap_or(&self.ipv4),
+            _ => by_kind.and_then(|f| f.ipv6.as_ref()).unwrap_or(&self.ipv6),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct LuaFunctions {
     pub lpm_table_constructor: String,
     pub lpm_get_params_function: String,
+    pub lpm_get_usage_function: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KindLuaFunctions {
+    pub ipv4: Option<LuaFunctions>,
+    pub ipv6: Option<LuaFunctions>,
 }
 
 fn parse_log_level<'de, D>(deserializer: D) -> Result<Level, D::Error>
@@ -56,6 +1123,98 @@ where
     s.parse().map_err(serde::de::Error::custom)
 }
 
+fn parse_log_filters<'de, D>(deserializer: D) -> Result<BTreeMap<String, LevelFilter>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    let filters: BTreeMap<String, String> = serde::de::Deserialize::deserialize(deserializer)?;
+    filters
+        .into_iter()
+        .map(|(module, level)| {
+            let level = level.parse().map_err(serde::de::Error::custom)?;
+            Ok((module, level))
+        })
+        .collect()
+}
+
+fn parse_max_ranges<'de, D>(deserializer: D) -> Result<Option<MaxRanges>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Count(usize),
+        Name(String),
+    }
+    match Raw::deserialize(deserializer)? {
+        Raw::Count(0) => Err(serde::de::Error::custom(
+            "max_ranges_per_file must be positive",
+        )),
+        Raw::Count(n) => Ok(Some(MaxRanges::Count(n))),
+        Raw::Name(s) if s == "auto" => Ok(Some(MaxRanges::Auto)),
+        Raw::Name(s) => Err(serde::de::Error::custom(format!(
+            "invalid max_ranges_per_file '{}'",
+            s
+        ))),
+    }
+}
+
+// Booleans are accepted for compatibility with earlier versions.
+fn parse_remove_policy<'de, D>(deserializer: D) -> Result<RemovePolicy, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Bool(bool),
+        Name(String),
+    }
+    match Raw::deserialize(deserializer)? {
+        Raw::Bool(true) => Ok(RemovePolicy::Always),
+        Raw::Bool(false) => Ok(RemovePolicy::Never),
+        Raw::Name(s) if s == "always" => Ok(RemovePolicy::Always),
+        Raw::Name(s) if s == "never" => Ok(RemovePolicy::Never),
+        Raw::Name(s) if s == "on-success" => Ok(RemovePolicy::OnSuccess),
+        Raw::Name(s) => Err(serde::de::Error::custom(format!(
+            "invalid remove_rendered_scripts policy '{}'",
+            s
+        ))),
+    }
+}
+
+fn parse_rounding<'de, D>(deserializer: D) -> Result<Rounding, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Multiple(usize),
+        Name(String),
+    }
+    match Raw::deserialize(deserializer)? {
+        Raw::Multiple(0) => Err(serde::de::Error::custom("rounding must be positive")),
+        Raw::Multiple(n) => Ok(Rounding::Multiple(n)),
+        Raw::Name(s) if s == "none" => Ok(Rounding::None),
+        Raw::Name(s) if s == "power_of_two" => Ok(Rounding::PowerOfTwo),
+        Raw::Name(s) => Err(serde::de::Error::custom(format!(
+            "invalid rounding '{}'",
+            s
+        ))),
+    }
+}
+
+fn parse_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M")
+        .map_err(|_| serde::de::Error::custom(format!("invalid time '{}', expected HH:MM", s)))
+}
+
 fn default_socket_path() -> PathBuf {
     PathBuf::from("/var/run/gatekeeper/dyn_cfg.socket")
 }
@@ -64,10 +1223,244 @@ fn default_log_level() -> Level {
     Level::Info
 }
 
+fn default_log_file_keep() -> usize {
+    5
+}
+
+fn default_statsd_prefix() -> String {
+    "gtctl".to_owned()
+}
+
+fn parse_ipv4_prefix_len<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    parse_prefix_len(deserializer, 32)
+}
+
+fn parse_ipv6_prefix_len<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    parse_prefix_len(deserializer, 128)
+}
+
+fn parse_prefix_len<'de, D>(deserializer: D, max: u8) -> Result<Option<u8>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match u8::deserialize(deserializer)? {
+        len if len > max => Err(serde::de::Error::custom(format!(
+            "invalid prefix length {}",
+            len
+        ))),
+        len => Ok(Some(len)),
+    }
+}
+
+fn default_group_by() -> Vec<GroupKey> {
+    vec![GroupKey::Kind]
+}
+
+fn default_ssh_helper() -> String {
+    "socat - UNIX-CONNECT:{socket}".to_owned()
+}
+
+fn default_ssh_command() -> PathBuf {
+    PathBuf::from("ssh")
+}
+
+fn default_on_closed() -> NoReplyAction {
+    NoReplyAction::Fail
+}
+
+fn default_on_empty() -> NoReplyAction {
+    NoReplyAction::Success
+}
+
+fn default_on_reset() -> NoReplyAction {
+    NoReplyAction::Fail
+}
+
+fn default_max_retries() -> usize {
+    3
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_proxy_read_timeout() -> u64 {
+    10
+}
+
+fn parse_proxy_read_timeout<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "proxy read_timeout must be positive",
+        )),
+        n => Ok(n),
+    }
+}
+
+fn default_proxy_max_connections() -> usize {
+    64
+}
+
+fn parse_proxy_max_connections<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match usize::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "proxy max_connections must be positive",
+        )),
+        n => Ok(n),
+    }
+}
+
+fn default_socket_mode() -> u32 {
+    0o600
+}
+
+fn parse_socket_mode<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    let s: String = serde::de::Deserialize::deserialize(deserializer)?;
+    match u32::from_str_radix(&s, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(serde::de::Error::custom(format!(
+            "invalid socket mode '{}'",
+            s
+        ))),
+    }
+}
+
+/// Returns whether the name can be used as a subdirectory of `state_dir`.
+pub fn is_valid_namespace(name: &str) -> bool {
+    !(name.is_empty() || name == "." || name == ".." || name.contains('/'))
+}
+
+fn parse_state_namespace<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(name) if !is_valid_namespace(&name) => Err(serde::de::Error::custom(format!(
+            "invalid state namespace '{}'",
+            name
+        ))),
+        name => Ok(name),
+    }
+}
+
+// Targets without a `state_namespace` keep their state in a directory named
+// after them.
+fn parse_targets<'de, D>(deserializer: D) -> Result<BTreeMap<String, TargetConfig>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    let targets = BTreeMap::<String, TargetConfig>::deserialize(deserializer)?;
+    for (name, target) in &targets {
+        if target.state_namespace.is_none() && !is_valid_namespace(name) {
+            return Err(serde::de::Error::custom(format!(
+                "target '{}' needs a state_namespace, as its name can't be used as one",
+                name
+            )));
+        }
+    }
+    Ok(targets)
+}
+
+fn default_lease_ttl() -> u64 {
+    30
+}
+
+fn parse_lease_ttl<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("lease ttl must be positive")),
+        n => Ok(n),
+    }
+}
+
+fn default_warn_percent() -> usize {
+    90
+}
+
+fn default_replace_threshold_percent() -> usize {
+    100
+}
+
+fn parse_percent<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match usize::deserialize(deserializer)? {
+        n if n == 0 || n > 100 => Err(serde::de::Error::custom(
+            "percentage must be between 1 and 100",
+        )),
+        n => Ok(n),
+    }
+}
+
+fn parse_optional_percent<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    parse_percent(deserializer).map(Some)
+}
+
+fn default_shrink_runs() -> usize {
+    3
+}
+
+fn parse_shrink_runs<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match usize::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("shrink runs must be positive")),
+        n => Ok(n),
+    }
+}
+
+fn default_parallelism() -> usize {
+    1
+}
+
+fn default_history_size() -> usize {
+    100
+}
+
+fn default_max_backoff() -> u64 {
+    300
+}
+
+fn default_max_entries_per_file() -> usize {
+    500
+}
+
+fn default_bpf_success_reply() -> String {
+    "loaded BPF program".to_owned()
+}
+
 fn default_estimate_config() -> EstimateConfig {
     EstimateConfig {
         rules_scaling_factor: default_scaling_factor(),
         tbl8s_scaling_factor: default_scaling_factor(),
+        headroom_percent: 0,
+        replace_headroom_percent: 0,
+        rounding: Rounding::None,
+        ipv4_lpm: Ipv4Lpm::default(),
+        ipv6_lpm: Ipv6Lpm::default(),
+        lpm6: Lpm6Geometry::default(),
     }
 }
 
@@ -75,6 +1468,41 @@ fn default_scaling_factor() -> usize {
     1
 }
 
+// The defaults of DPDK's RTE_LPM6 implementation.
+fn default_first_stage_depth() -> u8 {
+    24
+}
+
+fn default_tbl8_stride() -> u8 {
+    8
+}
+
+fn default_tbl8s_per_expansion() -> usize {
+    1
+}
+
+fn parse_lpm6_depth<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match u8::deserialize(deserializer)? {
+        n if n == 0 || n > 128 => Err(serde::de::Error::custom("depth must be between 1 and 128")),
+        n => Ok(n),
+    }
+}
+
+fn parse_tbl8s_per_expansion<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match usize::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "tbl8s per expansion must be positive",
+        )),
+        n => Ok(n),
+    }
+}
+
 fn parse_scaling_factor<'de, D>(deserializer: D) -> Result<usize, D::Error>
 where
     D: serde::de::Deserializer<'de>,
@@ -86,3 +1514,380 @@ where
         Err(e) => Err(serde::de::Error::custom(e)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+state_dir: /var/lib/gtctl
+broker_socket_mode: "0660"
+log_filters: {
+  gtctl::dyncfg: debug,
+  drib: warn,
+}
+replace: {
+  input: /etc/gtctl/policy_replace.lua.tpl,
+  output: "/var/lib/gtctl/policy_replace_{proto}_{kind}.{2i}.lua",
+}
+update: {
+  input: /etc/gtctl/policy_update.lua.tpl,
+  output: "/var/lib/gtctl/policy_update_{proto}_{kind}.{2i}.lua",
+  max_ranges_per_file: auto,
+}
+lpm: {
+  table_format: "{kind}_lpm_{proto}",
+  parameters_script: {
+    input: /etc/gtctl/lpm_params.lua.tpl,
+    output: "/var/lib/gtctl/lpm_params_{proto}_{kind}.lua",
+  },
+  ipv4: {
+    lpm_table_constructor: lpmlib.new_lpm,
+    lpm_get_params_function: lpmlib.lpm_get_paras,
+  },
+  ipv6: {
+    lpm_table_constructor: lpmlib.new_lpm6,
+    lpm_get_params_function: lpmlib.lpm6_get_paras,
+  },
+  kinds: {
+    whitelist: {
+      ipv4: {
+        lpm_table_constructor: wl.new_lpm,
+        lpm_get_params_function: wl.lpm_get_paras,
+      },
+    },
+  },
+}
+targets: {
+  gt1: {
+    socket: /run/gt1/dyn_cfg.socket,
+    broker_socket: /run/gt1/broker.socket,
+  },
+  gt2: {
+    table_format: "{kind}_{proto}",
+    reply_format: json,
+    state_namespace: second,
+    route_kinds: [blocklist],
+    extra_vars: {
+      replace: {
+        policy_id: 2,
+      },
+    },
+  },
+}
+extra_vars: {
+  replace: {
+    policy_id: 1,
+  },
+}
+limits: {
+  ipv4: { max_rules: 100000, max_tbl8s: 4096 },
+}
+kinds: {
+  whitelist: {
+    priority: 10,
+    change_order: insert_first,
+    allow_replace: false,
+    replace_threshold_percent: 80,
+    replace_cooldown: 3600,
+    headroom_percent: 50,
+    replace_headroom_percent: 25,
+    caps: {
+      ipv4: { max_rules: 1000 },
+    },
+    update: {
+      input: /etc/gtctl/whitelist_update.lua.tpl,
+      output: "/var/lib/gtctl/whitelist_update_{proto}.{2i}.lua",
+    },
+  },
+  scanners: {
+    table_mode: hash,
+  },
+}
+"#;
+
+    #[test]
+    fn test_target() {
+        let config: Config = serde_yaml::from_str(CONFIG).expect("deserialize failed");
+
+        let gt1 = config.target("gt1").expect("gt1 not found");
+        assert_eq!(PathBuf::from("/run/gt1/dyn_cfg.socket"), gt1.socket);
+        assert_eq!(Path::new("/run/gt1/broker.socket"), gt1.dyncfg_socket());
+        assert_eq!("{kind}_lpm_{proto}", gt1.lpm.table_format);
+        assert_eq!(ReplyFormat::Text, gt1.lpm.reply_format);
+        assert_eq!(PathBuf::from("/var/lib/gtctl/gt1"), gt1.state_dir);
+        assert!(gt1.targets.is_empty());
+        assert_eq!(
+            Some(&serde_yaml::Value::from(1)),
+            gt1.extra_vars.replace.get("policy_id")
+        );
+
+        let gt2 = config.target("gt2").expect("gt2 not found");
+        assert_eq!(config.socket, gt2.socket);
+        assert_eq!(config.socket, gt2.dyncfg_socket());
+        assert_eq!("{kind}_{proto}", gt2.lpm.table_format);
+        assert_eq!(ReplyFormat::Json, gt2.lpm.reply_format);
+        assert_eq!(PathBuf::from("/var/lib/gtctl/second"), gt2.state_dir);
+        assert_eq!(
+            Some(&serde_yaml::Value::from(2)),
+            gt2.extra_vars.replace.get("policy_id")
+        );
+        assert!(gt2.extra_vars.update.is_empty());
+        let kinds: BTreeSet<String> = vec!["blocklist".to_owned()].into_iter().collect();
+        assert_eq!(Some(kinds), gt2.target_kinds);
+        assert_eq!(None, gt1.target_kinds);
+
+        assert!(config.target("gt3").is_none());
+        assert_eq!(0o660, gt1.broker_socket_mode);
+    }
+
+    #[test]
+    fn test_state_namespace() {
+        let invalid = CONFIG.replace("state_namespace: second", "state_namespace: ../second");
+        assert!(serde_yaml::from_str::<Config>(&invalid).is_err());
+        let invalid = CONFIG.replace("gt1: {", "\"../gt1\": {");
+        assert!(serde_yaml::from_str::<Config>(&invalid).is_err());
+        let named = CONFIG.replace("gt2: {", "\"gt/2\": {");
+        assert!(serde_yaml::from_str::<Config>(&named).is_ok());
+
+        assert!(is_valid_namespace("feed1"));
+        assert!(!is_valid_namespace(""));
+        assert!(!is_valid_namespace(".."));
+        assert!(!is_valid_namespace("a/b"));
+    }
+
+    #[test]
+    fn test_socket_mode() {
+        let config: Config =
+            serde_yaml::from_str(&CONFIG.replace("broker_socket_mode: \"0660\"\n", ""))
+                .expect("deserialize failed");
+        assert_eq!(0o600, config.broker_socket_mode);
+        for mode in &["\"0999\"", "\"01777\"", "\"rw\""] {
+            let invalid = CONFIG.replace("\"0660\"", mode);
+            assert!(serde_yaml::from_str::<Config>(&invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_kind() {
+        let config: Config = serde_yaml::from_str(CONFIG).expect("deserialize failed");
+
+        let whitelist = config.kind(&Some("whitelist".to_owned()));
+        assert!(!whitelist.allow_replace);
+        assert_eq!(80, whitelist.replace_threshold_percent);
+        assert_eq!(Some(3600), whitelist.replace_cooldown);
+        assert_eq!(50, whitelist.estimate.headroom_percent);
+        assert_eq!(25, whitelist.estimate.replace_headroom_percent);
+        assert_eq!(Some(1000), whitelist.limits.ipv4.max_rules);
+        assert_eq!(None, whitelist.limits.ipv4.max_tbl8s);
+        assert!(whitelist.limits.ipv6.is_empty());
+        assert_eq!(
+            PathBuf::from("/etc/gtctl/whitelist_update.lua.tpl"),
+            whitelist.update.templates.input
+        );
+        assert_eq!(
+            config.replace.templates.input,
+            whitelist.replace.templates.input
+        );
+
+        let blocklist = config.kind(&Some("blocklist".to_owned()));
+        assert!(blocklist.allow_replace);
+        assert_eq!(100, blocklist.replace_threshold_percent);
+        assert_eq!(None, blocklist.replace_cooldown);
+        assert_eq!(0, blocklist.estimate.headroom_percent);
+        assert_eq!(Some(4096), blocklist.limits.caps("ipv4").max_tbl8s);
+        assert_eq!(CapAction::Fail, blocklist.limits.action);
+        assert!(config.kind(&None).allow_replace);
+
+        assert_eq!(ChangeOrder::InsertFirst, whitelist.change_order);
+        assert_eq!(ChangeOrder::Interleaved, blocklist.change_order);
+
+        let scanners = config.kind(&Some("scanners".to_owned()));
+        assert_eq!(TableMode::Hash, scanners.table_mode);
+        assert_eq!(TableMode::Lpm, whitelist.table_mode);
+
+        assert_eq!(10, config.kind_priority(&Some("whitelist".to_owned())));
+        assert_eq!(0, config.kind_priority(&Some("blocklist".to_owned())));
+        assert_eq!(0, config.kind_priority(&None));
+    }
+
+    #[test]
+    fn test_log_filters() {
+        let config: Config = serde_yaml::from_str(CONFIG).expect("deserialize failed");
+        assert_eq!(
+            Some(&LevelFilter::Debug),
+            config.log_filters.get("gtctl::dyncfg")
+        );
+        assert_eq!(Some(&LevelFilter::Warn), config.log_filters.get("drib"));
+
+        let invalid = CONFIG.replace("drib: warn", "drib: loud");
+        assert!(serde_yaml::from_str::<Config>(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_max_ranges() {
+        let config: Config = serde_yaml::from_str(CONFIG).expect("deserialize failed");
+        assert_eq!(None, config.replace.max_ranges_per_file);
+        assert_eq!(Some(MaxRanges::Auto), config.update.max_ranges_per_file);
+
+        let parse = |s| -> Result<ChunkedTemplates, _> {
+            serde_yaml::from_str(&format!(
+                "{{input: in, output: out, max_ranges_per_file: {}}}",
+                s
+            ))
+        };
+        let templates = parse("1500").expect("deserialize failed");
+        assert_eq!(Some(MaxRanges::Count(1500)), templates.max_ranges_per_file);
+        assert!(parse("0").is_err());
+        assert!(parse("many").is_err());
+    }
+
+    #[test]
+    fn test_rounding() {
+        let parse = |s| -> Result<EstimateConfig, _> {
+            serde_yaml::from_str(&format!("{{rounding: {}}}", s))
+        };
+        assert_eq!(
+            Rounding::None,
+            parse("none").expect("deserialize failed").rounding
+        );
+        assert_eq!(
+            Rounding::PowerOfTwo,
+            parse("power_of_two").expect("deserialize failed").rounding
+        );
+        assert_eq!(
+            Rounding::Multiple(256),
+            parse("256").expect("deserialize failed").rounding
+        );
+        assert!(parse("0").is_err());
+        assert!(parse("pow2").is_err());
+        assert_eq!(
+            Rounding::None,
+            serde_yaml::from_str::<EstimateConfig>("{}")
+                .expect("deserialize failed")
+                .rounding
+        );
+    }
+
+    #[test]
+    fn test_lpm_backends() {
+        let config: EstimateConfig = serde_yaml::from_str("{ipv4_lpm: dir24_8, ipv6_lpm: lpm6}")
+            .expect("deserialize failed");
+        assert_eq!(Ipv4Lpm::Dir24_8, config.ipv4_lpm);
+        assert_eq!(Ipv6Lpm::Lpm6, config.ipv6_lpm);
+        assert!(serde_yaml::from_str::<EstimateConfig>("{ipv4_lpm: poptrie}").is_err());
+    }
+
+    #[test]
+    fn test_lpm6_geometry() {
+        let config: EstimateConfig =
+            serde_yaml::from_str("{lpm6: {tbl8_stride: 16}}").expect("deserialize failed");
+        assert_eq!(
+            Lpm6Geometry {
+                first_stage_depth: 24,
+                tbl8_stride: 16,
+                tbl8s_per_expansion: 1,
+            },
+            config.lpm6
+        );
+        let parse = |s| serde_yaml::from_str::<Lpm6Geometry>(s);
+        assert!(parse("{tbl8_stride: 0}").is_err());
+        assert!(parse("{first_stage_depth: 129}").is_err());
+        assert!(parse("{tbl8s_per_expansion: 0}").is_err());
+    }
+
+    #[test]
+    fn test_shrink() {
+        let shrink: ShrinkConfig =
+            serde_yaml::from_str("{below_percent: 25}").expect("deserialize failed");
+        assert_eq!(25, shrink.below_percent);
+        assert_eq!(3, shrink.runs);
+        assert!(serde_yaml::from_str::<ShrinkConfig>("{below_percent: 0}").is_err());
+        assert!(serde_yaml::from_str::<ShrinkConfig>("{below_percent: 25, runs: 0}").is_err());
+    }
+
+    #[test]
+    fn test_remove_policy() {
+        let policy = |value: &str| {
+            let config = format!("{}\nremove_rendered_scripts: {}\n", CONFIG, value);
+            serde_yaml::from_str::<Config>(&config).map(|c| c.remove_rendered_scripts)
+        };
+        assert_eq!(RemovePolicy::Always, policy("true").expect("parse failed"));
+        assert_eq!(RemovePolicy::Never, policy("false").expect("parse failed"));
+        assert_eq!(
+            RemovePolicy::OnSuccess,
+            policy("on-success").expect("parse failed")
+        );
+        assert!(policy("sometimes").is_err());
+    }
+
+    #[test]
+    fn test_set_value() {
+        let mut root: serde_yaml::Value = serde_yaml::from_str(CONFIG).expect("parse failed");
+        set_value(&mut root, "lpm.table_format", "{kind}_{proto}".into()).expect("set failed");
+        set_value(&mut root, "health.listen", "127.0.0.1:9000".into()).expect("set failed");
+        assert!(set_value(&mut root, "state_dir.x", "x".into()).is_err());
+        assert!(set_value(&mut root, "lpm..table_format", "x".into()).is_err());
+
+        let config: Config = serde_yaml::from_value(root).expect("deserialize failed");
+        assert_eq!("{kind}_{proto}", config.lpm.table_format);
+        assert_eq!("127.0.0.1:9000", config.health.expect("no health").listen);
+    }
+
+    #[test]
+    fn test_replace_windows() {
+        let at = |s: &str| -> DateTime<Utc> { s.parse().expect("invalid time") };
+        let window: ReplaceWindow =
+            serde_yaml::from_str("{start: '02:00', end: '04:00'}").expect("deserialize failed");
+        assert!(window.contains(at("2021-03-01T02:00:00Z")));
+        assert!(window.contains(at("2021-03-01T03:59:59Z")));
+        assert!(!window.contains(at("2021-03-01T04:00:00Z")));
+        assert!(!window.contains(at("2021-03-01T01:59:59Z")));
+
+        // 2021-03-06 is a Saturday.
+        let window: ReplaceWindow =
+            serde_yaml::from_str("{days: [Sat], start: '22:00', end: '02:00'}")
+                .expect("deserialize failed");
+        assert!(window.contains(at("2021-03-06T23:00:00Z")));
+        assert!(window.contains(at("2021-03-07T01:00:00Z")));
+        assert!(!window.contains(at("2021-03-07T23:00:00Z")));
+        assert!(!window.contains(at("2021-03-06T01:00:00Z")));
+        assert!(!window.contains(at("2021-03-06T12:00:00Z")));
+
+        assert!(in_replace_window(&[], at("2021-03-06T12:00:00Z")));
+        assert!(!in_replace_window(&[window], at("2021-03-06T12:00:00Z")));
+        assert!(serde_yaml::from_str::<ReplaceWindow>("{start: '2am', end: '04:00'}").is_err());
+    }
+
+    #[test]
+    fn test_lua_functions() {
+        let config: Config = serde_yaml::from_str(CONFIG).expect("deserialize failed");
+        let whitelist = Some("whitelist".to_owned());
+        let blocklist = Some("blocklist".to_owned());
+
+        let f = config.lpm.lua_functions("ipv4", &whitelist);
+        assert_eq!("wl.new_lpm", f.lpm_table_constructor);
+        let f = config.lpm.lua_functions("ipv6", &whitelist);
+        assert_eq!("lpmlib.new_lpm6", f.lpm_table_constructor);
+        let f = config.lpm.lua_functions("ipv4", &blocklist);
+        assert_eq!("lpmlib.new_lpm", f.lpm_table_constructor);
+        let f = config.lpm.lua_functions("ipv4", &None);
+        assert_eq!("lpmlib.lpm_get_paras", f.lpm_get_params_function);
+    }
+
+    #[test]
+    fn test_reply_pattern() {
+        let parse = |s: &str| serde_yaml::from_str::<ReplyPattern>(s);
+        let pattern =
+            parse(r#"'^(?P<id>\d+) (?P<rules>\d+) (?P<tbl8s>\d+)$'"#).expect("deserialize failed");
+        assert!(pattern.0.is_match("0 1 2"));
+        assert!(parse(r#"'^(?P<id>\d+) (?P<rules>\d+)$'"#).is_err());
+        assert!(parse(r#"'(?P<id>'"#).is_err());
+
+        let usage = r#"'^(?P<id>\d+) (?P<rules>\d+) (?P<tbl8s>\d+) (?P<used_rules>\d+) (?P<used_tbl8s>\d+)$'"#;
+        assert!(parse(usage).is_ok());
+        assert!(parse(&usage.replace("used_tbl8s", "free_tbl8s")).is_ok());
+        assert!(parse(&usage.replace("(?P<used_rules>\\d+)", "\\d+")).is_err());
+        assert!(parse(&usage.replace("(?P<used_tbl8s>\\d+)", "\\d+")).is_err());
+    }
+}
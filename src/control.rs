@@ -0,0 +1,308 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::Notify,
+};
+
+use crate::health::{self, Health};
+
+/// Lets operators inspect and steer the applies performed in watch mode
+/// through commands sent to a Unix socket.
+#[derive(Debug)]
+pub struct Control {
+    health: Arc<Health>,
+    state: Mutex<State>,
+    wake: Notify,
+}
+
+#[derive(Debug)]
+struct State {
+    paused: bool,
+    apply_now: bool,
+    applying: bool,
+    leader: bool,
+    pending: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Command {
+    Status,
+    Pause,
+    Resume,
+    ApplyNow,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Command, String> {
+        match s {
+            "status" => Ok(Command::Status),
+            "pause" => Ok(Command::Pause),
+            "resume" => Ok(Command::Resume),
+            "apply-now" => Ok(Command::ApplyNow),
+            _ => Err(format!("unknown command '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Command::Status => "status",
+            Command::Pause => "pause",
+            Command::Resume => "resume",
+            Command::ApplyNow => "apply-now",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub paused: bool,
+    pub apply_requested: bool,
+    pub applying: bool,
+    pub leader: bool,
+    /// The targets whose apply is held off while paused, empty when no
+    /// targets are configured.
+    pub pending: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub health: health::Status,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Reply {
+    Status(Status),
+    Error(String),
+}
+
+impl Control {
+    pub fn new(health: Arc<Health>) -> Control {
+        Control {
+            health,
+            state: Mutex::new(State {
+                paused: false,
+                apply_now: false,
+                applying: false,
+                leader: true,
+                pending: None,
+            }),
+            wake: Notify::new(),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+
+    /// Returns whether an immediate apply was requested, clearing the
+    /// request.
+    pub fn take_apply_now(&self) -> bool {
+        let mut state = self.lock();
+        let apply_now = state.apply_now;
+        state.apply_now = false;
+        apply_now
+    }
+
+    pub fn set_applying(&self, applying: bool) {
+        self.lock().applying = applying;
+    }
+
+    pub fn set_leader(&self, leader: bool) {
+        self.lock().leader = leader;
+    }
+
+    pub fn set_pending(&self, pending: Option<Vec<String>>) {
+        self.lock().pending = pending;
+    }
+
+    /// Waits until a command requires the watch loop to run.
+    pub async fn notified(&self) {
+        self.wake.notified().await
+    }
+
+    pub async fn execute(&self, command: Command) -> Status {
+        match command {
+            Command::Status => {}
+            Command::Pause => self.lock().paused = true,
+            Command::Resume => {
+                self.lock().paused = false;
+                self.wake.notify_one();
+            }
+            Command::ApplyNow => {
+                self.lock().apply_now = true;
+                self.wake.notify_one();
+            }
+        }
+        self.status().await
+    }
+
+    async fn status(&self) -> Status {
+        let health = self.health.status().await;
+        let state = self.lock();
+        Status {
+            paused: state.paused,
+            apply_requested: state.apply_now,
+            applying: state.applying,
+            leader: state.leader,
+            pending: state.pending.clone(),
+            health,
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _addr)) => stream,
+                Err(e) => {
+                    warn!("failed to accept control connection: {}", e);
+                    continue;
+                }
+            };
+            let control = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = control.handle(stream).await {
+                    debug!("control connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, stream: UnixStream) -> Result<(), io::Error> {
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        let reply = match line.trim().parse() {
+            Ok(command) => {
+                debug!("got control command {}", command);
+                Reply::Status(self.execute(command).await)
+            }
+            Err(e) => Reply::Error(e),
+        };
+        let mut body = serde_json::to_vec(&reply).expect("BUG: reply serialization");
+        body.push(b'\n');
+        let stream = stream.get_mut();
+        stream.write_all(&body).await?;
+        stream.shutdown().await
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("BUG: control lock poisoned")
+    }
+}
+
+/// Sends a command to the control socket of a gtctl process in watch mode,
+/// returning its status after the command.
+pub async fn send(socket: impl AsRef<Path>, command: Command) -> Result<Status, Error> {
+    let mut stream = UnixStream::connect(&socket).await?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    match serde_json::from_str(&line)? {
+        Reply::Status(status) => Ok(status),
+        Reply::Error(e) => Err(Error::Remote(e)),
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Remote(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Json(e) => write!(f, "invalid reply: {}", e),
+            Error::Remote(e) => write!(f, "command failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Remote(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::DyncfgConfig;
+
+    #[tokio::test]
+    async fn test_control() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let health = Arc::new(Health::new(
+            tmp.path().join("gatekeeper"),
+            DyncfgConfig::default(),
+            None,
+        ));
+        let control = Arc::new(Control::new(health));
+        let socket = tmp.path().join("control");
+        let listener = UnixListener::bind(&socket).expect("bind failed");
+        tokio::spawn(control.clone().serve(listener));
+
+        let status = send(&socket, Command::Pause).await.expect("send failed");
+        assert!(status.paused);
+        assert!(control.is_paused());
+
+        control.set_pending(Some(vec!["gt1".to_owned()]));
+        let status = send(&socket, Command::Status).await.expect("send failed");
+        assert_eq!(Some(vec!["gt1".to_owned()]), status.pending);
+        assert_eq!(Some(false), status.health.socket_reachable);
+
+        let status = send(&socket, Command::ApplyNow).await.expect("send failed");
+        assert!(status.apply_requested);
+        control.notified().await;
+        assert!(control.take_apply_now());
+        assert!(!control.take_apply_now());
+
+        let status = send(&socket, Command::Resume).await.expect("send failed");
+        assert!(!status.paused);
+    }
+
+    #[test]
+    fn test_command() {
+        for command in &[
+            Command::Status,
+            Command::Pause,
+            Command::Resume,
+            Command::ApplyNow,
+        ] {
+            assert_eq!(Ok(*command), command.to_string().parse());
+        }
+        assert!("stop".parse::<Command>().is_err());
+    }
+}
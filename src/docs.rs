@@ -0,0 +1,541 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write};
+
+use clap::{App, Arg, ArgSettings};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+};
+
+// Guards against recursive types.
+const MAX_DEPTH: usize = 16;
+
+/// Renders a man page for the application and its subcommands.
+pub fn man_page(app: &App, version: &str) -> String {
+    let name = app.get_name();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        ".TH {} 1 \"\" \"{} {}\" \"User Commands\"",
+        name.to_uppercase(),
+        name,
+        version
+    );
+    let _ = writeln!(out, ".SH NAME");
+    let _ = writeln!(out, "{} \\- {}", name, roff(app.get_about().unwrap_or("")));
+    let _ = writeln!(out, ".SH SYNOPSIS");
+    let _ = writeln!(out, ".B {}", name);
+    let _ = writeln!(out, "[\\fIOPTIONS\\fR] \\fICOMMAND\\fR");
+    let _ = writeln!(out, ".SH OPTIONS");
+    man_args(&mut out, app);
+    let _ = writeln!(out, ".SH COMMANDS");
+    for sub in app.get_subcommands() {
+        let _ = writeln!(out, ".SS {}", sub.get_name());
+        if let Some(about) = sub.get_about() {
+            let _ = writeln!(out, "{}", roff(about));
+        }
+        man_args(&mut out, sub);
+        for nested in sub.get_subcommands() {
+            let _ = writeln!(out, ".SS {} {}", sub.get_name(), nested.get_name());
+            if let Some(about) = nested.get_about() {
+                let _ = writeln!(out, "{}", roff(about));
+            }
+            man_args(&mut out, nested);
+        }
+    }
+    out
+}
+
+fn man_args(out: &mut String, app: &App) {
+    for arg in visible_args(app) {
+        let _ = writeln!(out, ".TP");
+        let _ = writeln!(out, "{}", roff(&arg_usage(arg)));
+        let _ = writeln!(out, "{}", roff(&arg_description(arg)));
+    }
+}
+
+/// Renders a Markdown reference of the application's subcommands and their
+/// flags.
+pub fn cli_reference(app: &App) -> String {
+    let name = app.get_name();
+    let mut out = String::new();
+    let _ = writeln!(out, "# {} command line reference", name);
+    let _ = writeln!(out);
+    md_args(&mut out, app);
+    for sub in app.get_subcommands() {
+        md_command(&mut out, &format!("{} {}", name, sub.get_name()), sub);
+        for nested in sub.get_subcommands() {
+            let path = format!("{} {} {}", name, sub.get_name(), nested.get_name());
+            md_command(&mut out, &path, nested);
+        }
+    }
+    out
+}
+
+fn md_command(out: &mut String, path: &str, app: &App) {
+    let _ = writeln!(out, "## `{}`", path);
+    let _ = writeln!(out);
+    if let Some(about) = app.get_about() {
+        let _ = writeln!(out, "{}", about);
+        let _ = writeln!(out);
+    }
+    md_args(out, app);
+}
+
+fn md_args(out: &mut String, app: &App) {
+    let mut any = false;
+    for arg in visible_args(app) {
+        let _ = writeln!(out, "* `{}`: {}", arg_usage(arg), arg_description(arg));
+        any = true;
+    }
+    if any {
+        let _ = writeln!(out);
+    }
+}
+
+fn visible_args<'a, 'b>(app: &'a App<'b>) -> impl Iterator<Item = &'a Arg<'b>> + 'a {
+    app.get_arguments()
+        .filter(|arg| !arg.is_set(ArgSettings::Hidden))
+}
+
+fn arg_usage(arg: &Arg) -> String {
+    let mut flags = Vec::new();
+    if let Some(short) = arg.get_short() {
+        flags.push(format!("-{}", short));
+    }
+    if let Some(long) = arg.get_long() {
+        flags.push(format!("--{}", long));
+    }
+    let value = format!("<{}>", arg.get_name());
+    if flags.is_empty() {
+        return value;
+    }
+    let mut usage = flags.join(", ");
+    if arg.is_set(ArgSettings::TakesValue) {
+        usage.push(' ');
+        usage.push_str(&value);
+    }
+    usage
+}
+
+fn arg_description(arg: &Arg) -> String {
+    let mut desc = arg.get_about().unwrap_or("").to_owned();
+    if let Some(values) = arg.get_possible_values() {
+        desc.push_str(&format!(" (one of {})", values.join(", ")));
+    }
+    if arg.is_set(ArgSettings::MultipleOccurrences) {
+        desc.push_str(" (may be repeated)");
+    }
+    desc.trim().to_owned()
+}
+
+fn roff(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('-', "\\-")
+}
+
+/// Renders a Markdown reference of the configuration keys accepted by a
+/// type, discovered by deserializing it (see `Schema`).
+pub fn config_reference<T: DeserializeOwned>(title: &str) -> String {
+    let schema = Schema::of::<T>();
+    let mut out = String::new();
+    let _ = writeln!(out, "# {}", title);
+    let _ = writeln!(out);
+    schema.render(&mut out, &[]);
+    out
+}
+
+/// The keys of a type's serialized form, along with their types. Serde has
+/// no notion of a schema, so the keys are discovered by deserializing the
+/// type with a deserializer that records what it's asked for, taking one
+/// key of a section at a time, until every section has been visited.
+/// Default values are decided by the type itself, so they aren't known.
+#[derive(Debug, Default)]
+pub struct Schema {
+    nodes: HashMap<Vec<String>, Node>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    optional: bool,
+    ty: Option<Ty>,
+}
+
+#[derive(Debug)]
+enum Ty {
+    Section(Vec<&'static str>),
+    OneOf(Vec<&'static str>),
+    Map,
+    List,
+    Scalar(&'static str),
+}
+
+impl Schema {
+    pub fn of<T: DeserializeOwned>() -> Schema {
+        let schema = RefCell::new(Schema::default());
+        let mut queue = VecDeque::new();
+        queue.push_back(Vec::new());
+        while let Some(choices) = queue.pop_front() {
+            let reached = Cell::new(None);
+            let probe = Probe {
+                schema: &schema,
+                path: &[],
+                choices: &choices,
+                reached: &reached,
+            };
+            // Probing always fails, as no actual values are produced.
+            let _ = T::deserialize(probe);
+            if let Some(fields) = reached.get() {
+                if choices.len() < MAX_DEPTH {
+                    for i in 0..fields {
+                        let mut next = choices.clone();
+                        next.push(i);
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        schema.into_inner()
+    }
+
+    fn render(&self, out: &mut String, path: &[String]) {
+        let node = match self.nodes.get(path) {
+            Some(node) => node,
+            None => return,
+        };
+        let fields = match &node.ty {
+            Some(Ty::Section(fields)) => fields,
+            Some(Ty::Map) => return self.render(out, &child(path, "<name>")),
+            Some(Ty::List) => return self.render(out, &child(path, "[]")),
+            _ => return,
+        };
+        for field in fields {
+            let path = child(path, field);
+            if let Some(node) = self.nodes.get(&path) {
+                let _ = writeln!(out, "* `{}`: {}", key(&path), node);
+            }
+            self.render(out, &path);
+        }
+    }
+
+    fn record(&mut self, path: &[String], f: impl FnOnce(&mut Node)) {
+        f(self.nodes.entry(path.to_vec()).or_default())
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.ty {
+            Some(Ty::Section(_)) => write!(f, "section")?,
+            Some(Ty::OneOf(variants)) => write!(f, "one of `{}`", variants.join("`, `"))?,
+            Some(Ty::Map) => write!(f, "map")?,
+            Some(Ty::List) => write!(f, "list")?,
+            Some(Ty::Scalar(name)) => write!(f, "{}", name)?,
+            None => write!(f, "any")?,
+        }
+        if self.optional {
+            write!(f, ", optional")?;
+        }
+        Ok(())
+    }
+}
+
+fn child(path: &[String], name: &str) -> Vec<String> {
+    let mut path = path.to_vec();
+    path.push(name.to_owned());
+    path
+}
+
+fn key(path: &[String]) -> String {
+    let mut key = String::new();
+    for (i, name) in path.iter().enumerate() {
+        if i > 0 && name != "[]" {
+            key.push('.');
+        }
+        key.push_str(name);
+    }
+    key
+}
+
+#[derive(Clone, Copy)]
+struct Probe<'a> {
+    schema: &'a RefCell<Schema>,
+    path: &'a [String],
+    choices: &'a [usize],
+    // The number of fields of the section reached once the choices are
+    // exhausted.
+    reached: &'a Cell<Option<usize>>,
+}
+
+impl<'a> Probe<'a> {
+    fn record(&self, ty: Ty) -> ProbeError {
+        self.schema
+            .borrow_mut()
+            .record(self.path, |node| node.ty = Some(ty));
+        ProbeError("probe".to_owned())
+    }
+}
+
+#[derive(Debug)]
+struct ProbeError(String);
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl de::Error for ProbeError {
+    fn custom<T: fmt::Display>(msg: T) -> ProbeError {
+        ProbeError(msg.to_string())
+    }
+}
+
+macro_rules! scalar {
+    ($($method:ident => $name:expr),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeError> {
+                Err(self.record(Ty::Scalar($name)))
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Probe<'a> {
+    type Error = ProbeError;
+
+    scalar! {
+        deserialize_any => "any",
+        deserialize_bool => "boolean",
+        deserialize_i8 => "integer",
+        deserialize_i16 => "integer",
+        deserialize_i32 => "integer",
+        deserialize_i64 => "integer",
+        deserialize_u8 => "integer",
+        deserialize_u16 => "integer",
+        deserialize_u32 => "integer",
+        deserialize_u64 => "integer",
+        deserialize_f32 => "number",
+        deserialize_f64 => "number",
+        deserialize_char => "string",
+        deserialize_str => "string",
+        deserialize_string => "string",
+        deserialize_bytes => "bytes",
+        deserialize_byte_buf => "bytes",
+        deserialize_unit => "null",
+        deserialize_identifier => "string",
+        deserialize_ignored_any => "any",
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.schema
+            .borrow_mut()
+            .record(self.path, |node| node.optional = true);
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _visitor: V,
+    ) -> Result<V::Value, ProbeError> {
+        Err(self.record(Ty::Scalar("null")))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ProbeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.record(Ty::List);
+        let path = child(self.path, "[]");
+        visitor.visit_seq(Single {
+            probe: Probe {
+                path: &path,
+                ..self
+            },
+            done: false,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, ProbeError> {
+        Err(self.record(Ty::List))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, ProbeError> {
+        Err(self.record(Ty::List))
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.record(Ty::Map);
+        let path = child(self.path, "<name>");
+        visitor.visit_map(Entries {
+            key: None,
+            probe: Probe {
+                path: &path,
+                ..self
+            },
+            done: false,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ProbeError> {
+        self.record(Ty::Section(fields.to_vec()));
+        let (choice, rest) = match self.choices.split_first() {
+            Some(split) => split,
+            None => {
+                self.reached.set(Some(fields.len()));
+                return Err(ProbeError("probe".to_owned()));
+            }
+        };
+        let field = match fields.get(*choice) {
+            Some(field) => *field,
+            None => return Err(ProbeError("probe".to_owned())),
+        };
+        let path = child(self.path, field);
+        visitor.visit_map(Entries {
+            key: Some(field),
+            probe: Probe {
+                path: &path,
+                choices: rest,
+                ..self
+            },
+            done: false,
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, ProbeError> {
+        Err(self.record(Ty::OneOf(variants.to_vec())))
+    }
+}
+
+// A sequence with a single probed element.
+struct Single<'a> {
+    probe: Probe<'a>,
+    done: bool,
+}
+
+impl<'de, 'a> SeqAccess<'de> for Single<'a> {
+    type Error = ProbeError;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, ProbeError> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        seed.deserialize(self.probe).map(Some)
+    }
+}
+
+// A map with a single probed value, under the given key or, for maps with
+// arbitrary keys, under a placeholder.
+struct Entries<'a> {
+    key: Option<&'static str>,
+    probe: Probe<'a>,
+    done: bool,
+}
+
+impl<'de, 'a> MapAccess<'de> for Entries<'a> {
+    type Error = ProbeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, ProbeError> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        let key = self.key.unwrap_or("name");
+        seed.deserialize(key.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<S::Value, ProbeError> {
+        seed.deserialize(self.probe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct Outer {
+        name: String,
+        #[serde(default)]
+        count: usize,
+        inner: Option<Inner>,
+        by_name: BTreeMap<String, Inner>,
+        list: Vec<Inner>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct Inner {
+        enabled: bool,
+        mode: Mode,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Mode {
+        Fast,
+        Slow,
+    }
+
+    #[test]
+    fn test_config_reference() {
+        let reference = config_reference::<Outer>("Reference");
+        let expected = "\
+# Reference
+
+* `name`: string
+* `count`: integer
+* `inner`: section, optional
+* `inner.enabled`: boolean
+* `inner.mode`: one of `fast`, `slow`
+* `by_name`: map
+* `by_name.<name>.enabled`: boolean
+* `by_name.<name>.mode`: one of `fast`, `slow`
+* `list`: list
+* `list[].enabled`: boolean
+* `list[].mode`: one of `fast`, `slow`
+";
+        assert_eq!(expected, reference);
+    }
+}
@@ -1,20 +1,49 @@
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use byteorder::{ByteOrder, NetworkEndian};
-use log::debug;
+use log::{debug, warn};
 use tokio::{
     fs::File,
     io::{self, AsyncReadExt, AsyncWriteExt},
-    net::UnixStream,
+    net::{UnixDatagram, UnixStream},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    time,
 };
+use tokio_seqpacket::UnixSeqpacket;
+
+use crate::capture::{Capture, Direction};
+use crate::config::{DyncfgConfig, NoReplyAction, ReplyDecoding, SocketType, SshConfig};
+use crate::error;
 
 const HEADER_LEN: usize = std::mem::size_of::<u16>();
-const MAX_MSG_LEN: u16 = u16::MAX - 1;
+// The delay before the first retry of a script that went unanswered,
+// growing linearly with each retry.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// The largest script accepted by Gatekeeper's dynamic configuration socket.
+pub const MAX_MSG_LEN: u16 = u16::MAX - 1;
+/// The version of the dynamic configuration protocol spoken. Gatekeeper's
+/// protocol isn't versioned itself, so this identifies the length-prefixed
+/// framing gtctl implements, and changes only if that does.
+pub const PROTOCOL_VERSION: u32 = 1;
 
+/// Sends a script file, returning the decoded reply.
 pub async fn send_config_script(
     socket: impl AsRef<Path>,
     script: impl AsRef<Path>,
+    config: &DyncfgConfig,
+) -> Result<String, error::Error> {
+    Ok(send_script(socket, script, config).await?)
+}
+
+async fn send_script(
+    socket: impl AsRef<Path>,
+    script: impl AsRef<Path>,
+    config: &DyncfgConfig,
 ) -> Result<String, Error> {
     debug!("sending '{}'", script.as_ref().display());
 
@@ -24,53 +53,334 @@ pub async fn send_config_script(
     let size = meta.len();
     if size > u64::from(MAX_MSG_LEN) {
         return Err(Error::Size(SizeError {
-            script: script.as_ref().to_owned(),
+            script: Some(script.as_ref().to_owned()),
             size,
         }));
     }
 
-    let packet = create_packet(&mut file, size as u16).await?;
-    let mut stream = UnixStream::connect(&socket).await?;
-    stream.write_all(&packet).await?;
+    let mut msg = Vec::with_capacity(size as usize);
+    file.read_to_end(&mut msg).await?;
+    let reply = exchange_with(&socket, &msg, config).await?;
+    decode(reply, config)
+}
 
-    // read_u16 assumes big-endian
-    let resp_size = stream.read_u16().await?;
-    let mut recv_buf = vec![0u8; MAX_MSG_LEN as usize];
-    stream
-        .read_exact(&mut recv_buf[0..resp_size as usize])
-        .await?;
+/// Sends a script rendered in memory, without going through a file.
+pub async fn send_config_bytes(
+    socket: impl AsRef<Path>,
+    script: &[u8],
+    config: &DyncfgConfig,
+) -> Result<String, error::Error> {
+    let reply = send_config_raw(socket, script, config).await?;
+    Ok(decode(reply, config)?)
+}
+
+/// Sends a script rendered in memory, returning the reply undecoded.
+pub async fn send_config_raw(
+    socket: impl AsRef<Path>,
+    script: &[u8],
+    config: &DyncfgConfig,
+) -> Result<Vec<u8>, error::Error> {
+    debug!("sending {} bytes", script.len());
+
+    if script.len() > MAX_MSG_LEN as usize {
+        return Err(Error::Size(SizeError {
+            script: None,
+            size: script.len() as u64,
+        })
+        .into());
+    }
+    Ok(exchange_with(&socket, script, config).await?)
+}
+
+/// Decodes a reply as configured. Replies kept raw must still be valid
+/// UTF-8 to be decoded.
+pub fn decode(reply: Vec<u8>, config: &DyncfgConfig) -> Result<String, Error> {
+    match config.reply_decoding {
+        ReplyDecoding::Lossy => Ok(String::from_utf8_lossy(&reply).into_owned()),
+        ReplyDecoding::Strict | ReplyDecoding::Raw => Ok(String::from_utf8(reply)?),
+    }
+}
+
+/// Sends a message to the socket, returning the raw reply. A missing reply
+/// is handled according to the action configured for its kind.
+pub(crate) async fn exchange_with(
+    socket: impl AsRef<Path>,
+    msg: &[u8],
+    config: &DyncfgConfig,
+) -> Result<Vec<u8>, Error> {
+    let mut retries = 0;
+    loop {
+        let no_reply = match exchange_once(&socket, msg, config).await {
+            Err(Error::NoReply(no_reply)) => no_reply,
+            res => return res,
+        };
+        match config.no_reply_action(no_reply) {
+            NoReplyAction::Fail => return Err(Error::NoReply(no_reply)),
+            NoReplyAction::Success => {
+                debug!("{}; assuming success", no_reply);
+                return Ok(Vec::new());
+            }
+            NoReplyAction::Retry if retries < config.max_retries => {
+                retries += 1;
+                warn!(
+                    "{}; retrying ({} of {})",
+                    no_reply, retries, config.max_retries
+                );
+                time::sleep(RETRY_DELAY * retries as u32).await;
+            }
+            NoReplyAction::Retry => return Err(Error::NoReply(no_reply)),
+        }
+    }
+}
 
-    Ok(String::from_utf8_lossy(&recv_buf[0..resp_size as usize]).into_owned())
+// With a completion marker configured, replies sent in multiple messages are
+// read up to the marker, or until the socket is closed, and concatenated,
+// without the marker.
+async fn exchange_once(
+    socket: impl AsRef<Path>,
+    msg: &[u8],
+    config: &DyncfgConfig,
+) -> Result<Vec<u8>, Error> {
+    let capture = config
+        .capture
+        .as_ref()
+        .map(|path| Capture::new(path, &socket));
+    let mut conn = connect(&socket, config).await?;
+    // Until the script is fully written, it can't have been applied, so
+    // failures to send it are told apart from those reading its reply.
+    conn.send(msg).await?;
+    record(&capture, Direction::Request, msg).await;
+    let mut reply = conn.recv().await.map_err(classify)?;
+    record(&capture, Direction::Reply, &reply).await;
+    let marker = match &config.completion_marker {
+        Some(marker) if !marker.is_empty() => marker.as_bytes(),
+        _ if reply.is_empty() => return Err(Error::NoReply(NoReply::Empty)),
+        _ => return Ok(reply),
+    };
+    loop {
+        if reply.ends_with(marker) {
+            reply.truncate(reply.len() - marker.len());
+            return Ok(reply);
+        }
+        match conn.recv().await {
+            Ok(msg) => {
+                record(&capture, Direction::Reply, &msg).await;
+                reply.extend_from_slice(&msg);
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                debug!("socket closed before the completion marker");
+                return Ok(reply);
+            }
+            Err(e) => return Err(classify(e)),
+        }
+    }
 }
 
-async fn create_packet<R: Unpin + AsyncReadExt>(r: &mut R, len: u16) -> Result<Vec<u8>, io::Error> {
-    let mut packet = vec![0u8; HEADER_LEN + MAX_MSG_LEN as usize];
-    let end = HEADER_LEN + len as usize;
+async fn record(capture: &Option<Capture>, direction: Direction, data: &[u8]) {
+    if let Some(capture) = capture {
+        capture.record(direction, data).await;
+    }
+}
 
-    NetworkEndian::write_u16(&mut packet[0..HEADER_LEN], len);
-    r.read_exact(&mut packet[HEADER_LEN..end]).await?;
+/// A connection to the dynamic configuration socket. Stream sockets and SSH
+/// channels carry length-prefixed messages, while on the other socket types
+/// each message is a packet of its own.
+pub enum Connection {
+    Stream(UnixStream),
+    Seqpacket(UnixSeqpacket),
+    Datagram(UnixDatagram, LocalSocket),
+    Ssh(SshChannel),
+}
 
-    packet.truncate(end);
-    Ok(packet)
+/// Connects to the socket, through SSH if configured.
+pub async fn connect(socket: impl AsRef<Path>, config: &DyncfgConfig) -> io::Result<Connection> {
+    if let Some(ssh) = &config.ssh {
+        return Ok(Connection::Ssh(SshChannel::open(socket.as_ref(), ssh)?));
+    }
+    match config.socket_type {
+        SocketType::Stream => Ok(Connection::Stream(UnixStream::connect(socket).await?)),
+        SocketType::Seqpacket => Ok(Connection::Seqpacket(UnixSeqpacket::connect(socket).await?)),
+        SocketType::Datagram => {
+            // Datagram sockets must be bound to receive replies.
+            let local = LocalSocket::new();
+            let sock = UnixDatagram::bind(&local.0)?;
+            sock.connect(socket)?;
+            Ok(Connection::Datagram(sock, local))
+        }
+    }
+}
+
+impl Connection {
+    /// Sends a message.
+    pub async fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        match self {
+            Connection::Stream(stream) => write_message(stream, msg).await,
+            Connection::Seqpacket(sock) => sock.send(msg).await.map(drop),
+            Connection::Datagram(sock, _) => sock.send(msg).await.map(drop),
+            Connection::Ssh(channel) => write_message(&mut channel.stdin, msg).await,
+        }
+    }
+
+    /// Receives a message. A seqpacket socket being closed can't be told
+    /// apart from an empty packet, so both are taken as the end of the input.
+    pub async fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; usize::from(u16::MAX)];
+        let n = match self {
+            Connection::Stream(stream) => return read_message(stream).await,
+            Connection::Ssh(channel) => return read_message(&mut channel.stdout).await,
+            Connection::Seqpacket(sock) => match sock.recv(&mut buf).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => n,
+            },
+            Connection::Datagram(sock, _) => sock.recv(&mut buf).await?,
+        };
+        buf.truncate(n);
+        Ok(buf)
+    }
+}
+
+/// An SSH process running the remote helper, killed when dropped.
+pub struct SshChannel {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl SshChannel {
+    fn open(socket: &Path, config: &SshConfig) -> io::Result<SshChannel> {
+        let mut cmd = Command::new(&config.command);
+        if let Some(port) = config.port {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity_file) = &config.identity_file {
+            cmd.arg("-i").arg(identity_file);
+        }
+        for option in &config.options {
+            cmd.arg("-o").arg(option);
+        }
+        let helper = config
+            .helper
+            .replace("{socket}", &shell_quote(&socket.to_string_lossy()));
+        debug!("running '{}' on '{}'", helper, config.destination);
+        let mut child = cmd
+            .arg("--")
+            .arg(&config.destination)
+            .arg(helper)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("BUG: ssh stdin not piped");
+        let stdout = child.stdout.take().expect("BUG: ssh stdout not piped");
+        Ok(SshChannel {
+            _child: child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+// Quotes a string for the remote shell ssh runs commands with.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// The path a datagram connection is bound to, removed with it.
+pub struct LocalSocket(PathBuf);
+
+impl LocalSocket {
+    fn new() -> LocalSocket {
+        static SERIAL: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "gtctl.{}.{}.socket",
+            std::process::id(),
+            SERIAL.fetch_add(1, Ordering::Relaxed)
+        );
+        LocalSocket(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for LocalSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn classify(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::NoReply(NoReply::Closed),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => {
+            Error::NoReply(NoReply::Reset)
+        }
+        _ => Error::Read(e),
+    }
+}
+
+/// Reads a length-prefixed message.
+pub(crate) async fn read_message<R: Unpin + AsyncReadExt>(r: &mut R) -> Result<Vec<u8>, io::Error> {
+    // read_u16 assumes big-endian
+    let len = r.read_u16().await?;
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Writes a length-prefixed message.
+pub(crate) async fn write_message<W: Unpin + AsyncWriteExt>(
+    w: &mut W,
+    msg: &[u8],
+) -> Result<(), io::Error> {
+    if msg.len() > MAX_MSG_LEN as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message too large: {} > {}", msg.len(), MAX_MSG_LEN),
+        ));
+    }
+    let mut packet = vec![0u8; HEADER_LEN + msg.len()];
+    NetworkEndian::write_u16(&mut packet[0..HEADER_LEN], msg.len() as u16);
+    packet[HEADER_LEN..].copy_from_slice(msg);
+    w.write_all(&packet).await
 }
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
+    // A failure reading the reply to a script that was sent.
+    Read(io::Error),
     Size(SizeError),
+    Utf8(FromUtf8Error),
+    NoReply(NoReply),
+}
+
+/// The ways a script can go unanswered.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NoReply {
+    // The socket was closed before a reply.
+    Closed,
+    // The reply was empty.
+    Empty,
+    // The connection was reset.
+    Reset,
+}
+
+impl fmt::Display for NoReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoReply::Closed => write!(f, "socket closed without a reply"),
+            NoReply::Empty => write!(f, "empty reply"),
+            NoReply::Reset => write!(f, "connection reset"),
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::Io(e) => write!(f, "i/o error: {}", e),
-            Error::Size(e) => write!(
-                f,
-                "script '{}' too large: {} > {}",
-                e.script.display(),
-                e.size,
-                MAX_MSG_LEN
-            ),
+            Error::Read(e) => write!(f, "failed to read reply: {}", e),
+            Error::Size(e) => write!(f, "{}", e),
+            Error::Utf8(e) => write!(f, "invalid reply: {}", e),
+            Error::NoReply(e) => write!(f, "{}", e),
         }
     }
 }
@@ -78,8 +388,10 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::Io(e) => Some(e),
+            Error::Io(e) | Error::Read(e) => Some(e),
             Error::Size(e) => Some(e),
+            Error::Utf8(e) => Some(e),
+            Error::NoReply(_) => None,
         }
     }
 }
@@ -90,6 +402,12 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Error {
+        Error::Utf8(e)
+    }
+}
+
 impl From<SizeError> for Error {
     fn from(e: SizeError) -> Error {
         Error::Size(e)
@@ -98,19 +416,23 @@ impl From<SizeError> for Error {
 
 #[derive(Debug)]
 pub struct SizeError {
-    script: PathBuf,
+    // Unknown for scripts sent from memory.
+    script: Option<PathBuf>,
     size: u64,
 }
 
 impl fmt::Display for SizeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "script '{}' too large: {} > {}",
-            self.script.display(),
-            self.size,
-            MAX_MSG_LEN
-        )
+        match &self.script {
+            Some(script) => write!(
+                f,
+                "script '{}' too large: {} > {}",
+                script.display(),
+                self.size,
+                MAX_MSG_LEN
+            ),
+            None => write!(f, "script too large: {} > {}", self.size, MAX_MSG_LEN),
+        }
     }
 }
 
@@ -122,15 +444,23 @@ impl std::error::Error for SizeError {
 
 #[cfg(test)]
 mod tests {
-    use tempdir::TempDir;
+    use tempfile::TempDir;
     use tokio::net::UnixListener;
     use tokio::sync::oneshot;
 
     use super::*;
+    use crate::error::TemplateError;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!("'/run/gk.socket'", shell_quote("/run/gk.socket"));
+        assert_eq!("'/tmp/a b; rm'", shell_quote("/tmp/a b; rm"));
+        assert_eq!("'it'\\''s'", shell_quote("it's"));
+    }
 
     #[tokio::test]
     async fn test_send_config_script() {
-        let tmp = TempDir::new("gtctl").expect("tempdir failed");
+        let tmp = TempDir::new().expect("tempdir failed");
         let data = b"test";
 
         let script_path = tmp.path().join("input");
@@ -149,7 +479,7 @@ mod tests {
 
         rx.await.expect("error waiting for server");
 
-        let resp = send_config_script(&socket, &script_path)
+        let resp = send_config_script(&socket, &script_path, &DyncfgConfig::default())
             .await
             .expect("send script failed");
         assert_eq!(data, resp.as_bytes());
@@ -157,6 +487,204 @@ mod tests {
         stop_server(&socket).await;
     }
 
+    #[tokio::test]
+    async fn test_send_config_bytes() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let socket = tmp.path().join("socket");
+        let (tx, rx) = oneshot::channel();
+        {
+            let socket = socket.clone();
+            tokio::spawn(async move { echo_server(&socket, tx).await });
+        }
+
+        rx.await.expect("error waiting for server");
+
+        let config = DyncfgConfig::default();
+        let resp = send_config_bytes(&socket, b"test", &config)
+            .await
+            .expect("send script failed");
+        assert_eq!(b"test", resp.as_bytes());
+
+        let large = vec![b'-'; MAX_MSG_LEN as usize + 1];
+        match send_config_bytes(&socket, &large, &config).await {
+            Err(error::Error::Template(TemplateError::Size(e))) => assert_eq!(None, e.script),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        stop_server(&socket).await;
+    }
+
+    #[tokio::test]
+    async fn test_completion_marker() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let socket = tmp.path().join("socket");
+        let listener = UnixListener::bind(&socket).expect("bind failed");
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _addr) = listener.accept().await.expect("accept failed");
+                let msg = read_message(&mut stream).await.expect("read failed");
+                for part in msg.split(|&b| b == b'|') {
+                    // The client may stop reading at the marker.
+                    let _ = write_message(&mut stream, part).await;
+                }
+            }
+        });
+
+        let config = DyncfgConfig {
+            completion_marker: Some("done\n".to_owned()),
+            ..Default::default()
+        };
+        let reply = exchange_with(&socket, b"10%\n|50%\n|ok\ndone\n", &config)
+            .await
+            .expect("exchange failed");
+        assert_eq!(b"10%\n50%\nok\n".to_vec(), reply);
+
+        // The reply ends when the socket is closed.
+        let reply = exchange_with(&socket, b"10%\n|50%\n", &config)
+            .await
+            .expect("exchange failed");
+        assert_eq!(b"10%\n50%\n".to_vec(), reply);
+
+        let reply = exchange_with(&socket, b"10%\n|50%\n", &Default::default())
+            .await
+            .expect("exchange failed");
+        assert_eq!(b"10%\n".to_vec(), reply);
+    }
+
+    #[tokio::test]
+    async fn test_no_reply() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let socket = tmp.path().join("socket");
+        let listener = UnixListener::bind(&socket).expect("bind failed");
+        tokio::spawn(async move {
+            // Every other connection is closed without a reply.
+            for i in 0.. {
+                let (mut stream, _addr) = listener.accept().await.expect("accept failed");
+                let msg = read_message(&mut stream).await.expect("read failed");
+                if i % 2 == 1 {
+                    let _ = write_message(&mut stream, &msg).await;
+                }
+            }
+        });
+
+        let config = DyncfgConfig::default();
+        match exchange_with(&socket, b"ok", &config).await {
+            Err(Error::NoReply(NoReply::Closed)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        // An empty reply is taken as success by default.
+        let reply = exchange_with(&socket, b"", &config)
+            .await
+            .expect("exchange failed");
+        assert!(reply.is_empty());
+
+        let config = DyncfgConfig {
+            on_closed: NoReplyAction::Retry,
+            on_empty: NoReplyAction::Fail,
+            ..Default::default()
+        };
+        let reply = exchange_with(&socket, b"ok", &config)
+            .await
+            .expect("exchange failed");
+        assert_eq!(b"ok".to_vec(), reply);
+        match exchange_with(&socket, b"", &config).await {
+            Err(Error::NoReply(NoReply::Empty)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_packet_sockets() {
+        let tmp = TempDir::new().expect("tempdir failed");
+
+        let socket = tmp.path().join("seqpacket");
+        let mut listener =
+            tokio_seqpacket::UnixSeqpacketListener::bind(&socket).expect("bind failed");
+        tokio::spawn(async move {
+            let conn = listener.accept().await.expect("accept failed");
+            let mut buf = vec![0u8; 1024];
+            let n = conn.recv(&mut buf).await.expect("recv failed");
+            conn.send(&buf[..n]).await.expect("send failed");
+        });
+        let config = DyncfgConfig {
+            socket_type: SocketType::Seqpacket,
+            ..Default::default()
+        };
+        let reply = exchange_with(&socket, b"ok", &config)
+            .await
+            .expect("exchange failed");
+        assert_eq!(b"ok".to_vec(), reply);
+
+        let socket = tmp.path().join("datagram");
+        let server = UnixDatagram::bind(&socket).expect("bind failed");
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            let (n, addr) = server.recv_from(&mut buf).await.expect("recv failed");
+            let peer = addr.as_pathname().expect("unnamed peer");
+            server.send_to(&buf[..n], peer).await.expect("send failed");
+        });
+        let config = DyncfgConfig {
+            socket_type: SocketType::Datagram,
+            ..Default::default()
+        };
+        let reply = exchange_with(&socket, b"ok", &config)
+            .await
+            .expect("exchange failed");
+        assert_eq!(b"ok".to_vec(), reply);
+    }
+
+    #[tokio::test]
+    async fn test_ssh() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for ssh, running the helper locally.
+        let tmp = TempDir::new().expect("tempdir failed");
+        let command = tmp.path().join("ssh");
+        std::fs::write(
+            &command,
+            "#!/bin/sh\nshift $(($# - 1))\nexec sh -c \"$1\"\n",
+        )
+        .expect("write failed");
+        std::fs::set_permissions(&command, std::fs::Permissions::from_mode(0o755))
+            .expect("chmod failed");
+
+        let config = DyncfgConfig {
+            ssh: Some(SshConfig {
+                destination: "gatekeeper".to_owned(),
+                port: Some(2222),
+                identity_file: None,
+                options: vec!["BatchMode=yes".to_owned()],
+                // Echoes the framed message back.
+                helper: "test -n {socket} && cat".to_owned(),
+                command,
+            }),
+            ..Default::default()
+        };
+        let reply = exchange_with("/run/gatekeeper/dyn_cfg.socket", b"ok", &config)
+            .await
+            .expect("exchange failed");
+        assert_eq!(b"ok".to_vec(), reply);
+    }
+
+    #[test]
+    fn test_decode() {
+        let invalid = b"ok \xff".to_vec();
+        let config = |reply_decoding| DyncfgConfig {
+            reply_decoding,
+            ..Default::default()
+        };
+        assert_eq!(
+            "ok \u{fffd}",
+            decode(invalid.clone(), &config(ReplyDecoding::Lossy)).expect("decode failed")
+        );
+        assert!(decode(invalid.clone(), &config(ReplyDecoding::Strict)).is_err());
+        assert!(decode(invalid, &config(ReplyDecoding::Raw)).is_err());
+        assert_eq!(
+            "ok",
+            decode(b"ok".to_vec(), &config(ReplyDecoding::Strict)).expect("decode failed")
+        );
+    }
+
     async fn echo_server(path: impl AsRef<Path>, ready: oneshot::Sender<()>) {
         let lis = UnixListener::bind(&path).expect("bind failed");
         ready.send(()).expect("send ready failed");
@@ -175,6 +703,20 @@ mod tests {
         }
     }
 
+    async fn create_packet<R: Unpin + AsyncReadExt>(
+        r: &mut R,
+        len: u16,
+    ) -> Result<Vec<u8>, io::Error> {
+        let mut packet = vec![0u8; HEADER_LEN + MAX_MSG_LEN as usize];
+        let end = HEADER_LEN + len as usize;
+
+        NetworkEndian::write_u16(&mut packet[0..HEADER_LEN], len);
+        r.read_exact(&mut packet[HEADER_LEN..end]).await?;
+
+        packet.truncate(end);
+        Ok(packet)
+    }
+
     async fn stop_server(socket: impl AsRef<Path>) {
         let mut stream = UnixStream::connect(&socket).await.expect("connect failed");
         stream
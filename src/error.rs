@@ -0,0 +1,665 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::string::FromUtf8Error;
+
+use crate::dyncfg::{self, NoReply, SizeError};
+use crate::params;
+use crate::plan;
+use crate::queue;
+use crate::reply::ParseError;
+use crate::state;
+
+// These types implement their traits by hand, as every other error type in
+// the crate does, rather than deriving them with thiserror, so that the
+// crate's errors stay uniform and its dependencies few.
+
+/// The categories of failures in applying a configuration, so that callers
+/// can tell, for instance, a broken template from an unreachable Gatekeeper
+/// without matching on messages. Failures in applying an aggregate are
+/// wrapped with the target, table and script they happened in, if any.
+#[derive(Debug)]
+pub enum Error {
+    Config(ConfigError),
+    Template(TemplateError),
+    Transport(TransportError),
+    Gatekeeper(GatekeeperError),
+    Aggregate(AggregateError),
+    State(StateError),
+    Limit(LimitError),
+    Plan(plan::Error),
+    // A failure to start the runtime running a blocking call.
+    Runtime(io::Error),
+    Target(String, Box<Error>),
+    Table(String, Box<Error>),
+    // The script's path and index among the table's scripts.
+    Script(PathBuf, usize, Box<Error>),
+}
+
+impl Error {
+    /// Whether the failure is likely transient, e.g. Gatekeeper being
+    /// restarted, and happened before the script was sent, so that sending
+    /// it again later may succeed without applying it twice.
+    pub fn is_retryable(&self) -> bool {
+        match self.category() {
+            Error::Transport(e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Returns the failure without the target, table and script it happened
+    /// in.
+    pub fn category(&self) -> &Error {
+        match self {
+            Error::Target(_, e) | Error::Table(_, e) | Error::Script(_, _, e) => e.category(),
+            e => e,
+        }
+    }
+
+    /// Returns the target the failure happened in, if known.
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            Error::Target(target, _) => Some(target),
+            Error::Table(_, e) | Error::Script(_, _, e) => e.target(),
+            _ => None,
+        }
+    }
+
+    /// Returns the table the failure happened in, if any.
+    pub fn table(&self) -> Option<&str> {
+        match self {
+            Error::Table(table, _) => Some(table),
+            Error::Target(_, e) | Error::Script(_, _, e) => e.table(),
+            _ => None,
+        }
+    }
+
+    /// Returns the script the failure happened in, if any, along with its
+    /// index among the table's scripts.
+    pub fn script(&self) -> Option<(&Path, usize)> {
+        match self {
+            Error::Script(script, chunk, _) => Some((script, *chunk)),
+            Error::Target(_, e) | Error::Table(_, e) => e.script(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn in_target(self, target: &str) -> Error {
+        Error::Target(target.to_owned(), Box::new(self))
+    }
+
+    pub(crate) fn in_table(self, table: &str) -> Error {
+        Error::Table(table.to_owned(), Box::new(self))
+    }
+
+    pub(crate) fn in_script(self, script: &Path, chunk: usize) -> Error {
+        Error::Script(script.to_owned(), chunk, Box::new(self))
+    }
+}
+
+// The alternate form appends the causes, as anyhow's does.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "{}", e)?,
+            Error::Template(e) => write!(f, "{}", e)?,
+            Error::Transport(e) => write!(f, "{}", e)?,
+            Error::Gatekeeper(e) => write!(f, "{}", e)?,
+            Error::Aggregate(e) => write!(f, "{}", e)?,
+            Error::State(e) => write!(f, "{}", e)?,
+            Error::Limit(e) => write!(f, "{}", e)?,
+            Error::Plan(e) if e.is_stale() => write!(f, "{}; make a new plan", e)?,
+            Error::Plan(e) => write!(f, "{}", e)?,
+            Error::Runtime(e) => write!(f, "failed to start runtime: {}", e)?,
+            Error::Target(target, _) => write!(f, "target {}", target)?,
+            Error::Table(table, _) => write!(f, "failed to apply table {}", table)?,
+            Error::Script(script, _, _) => {
+                write!(f, "failed to send script '{}'", script.display())?
+            }
+        }
+        if f.alternate() {
+            let mut source = std::error::Error::source(self);
+            while let Some(e) = source {
+                write!(f, ": {}", e)?;
+                source = e.source();
+            }
+        }
+        Ok(())
+    }
+}
+
+// The categories display as the errors they hold, so their sources are
+// those errors' sources, which would otherwise be reported twice.
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(e) => std::error::Error::source(e),
+            Error::Template(e) => std::error::Error::source(e),
+            Error::Transport(e) => std::error::Error::source(e),
+            Error::Gatekeeper(e) => std::error::Error::source(e),
+            Error::Aggregate(e) => std::error::Error::source(e),
+            Error::State(e) => std::error::Error::source(e),
+            Error::Limit(e) => std::error::Error::source(e),
+            Error::Plan(e) => std::error::Error::source(e),
+            Error::Runtime(e) => std::error::Error::source(e),
+            Error::Target(_, e) | Error::Table(_, e) | Error::Script(_, _, e) => Some(&**e),
+        }
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Error {
+        Error::Config(e)
+    }
+}
+
+impl From<TemplateError> for Error {
+    fn from(e: TemplateError) -> Error {
+        Error::Template(e)
+    }
+}
+
+impl From<TransportError> for Error {
+    fn from(e: TransportError) -> Error {
+        Error::Transport(e)
+    }
+}
+
+impl From<GatekeeperError> for Error {
+    fn from(e: GatekeeperError) -> Error {
+        Error::Gatekeeper(e)
+    }
+}
+
+impl From<AggregateError> for Error {
+    fn from(e: AggregateError) -> Error {
+        Error::Aggregate(e)
+    }
+}
+
+impl From<StateError> for Error {
+    fn from(e: StateError) -> Error {
+        Error::State(e)
+    }
+}
+
+impl From<LimitError> for Error {
+    fn from(e: LimitError) -> Error {
+        Error::Limit(e)
+    }
+}
+
+impl From<plan::Error> for Error {
+    fn from(e: plan::Error) -> Error {
+        Error::Plan(e)
+    }
+}
+
+// Scripts too large to send are a problem with what was rendered, while
+// missing or undecodable replies are Gatekeeper's.
+impl From<dyncfg::Error> for Error {
+    fn from(e: dyncfg::Error) -> Error {
+        match e {
+            dyncfg::Error::Io(e) => Error::Transport(TransportError::Io(e)),
+            dyncfg::Error::Read(e) => Error::Transport(TransportError::Read(e)),
+            dyncfg::Error::Size(e) => Error::Template(TemplateError::Size(e)),
+            dyncfg::Error::Utf8(e) => Error::Gatekeeper(GatekeeperError::Utf8(e)),
+            dyncfg::Error::NoReply(NoReply::Empty) => {
+                Error::Gatekeeper(GatekeeperError::NoReply(NoReply::Empty))
+            }
+            dyncfg::Error::NoReply(reason) => Error::Transport(TransportError::NoReply(reason)),
+        }
+    }
+}
+
+impl From<params::Error> for Error {
+    fn from(e: params::Error) -> Error {
+        match e {
+            params::Error::Dyncfg(e) => e,
+            params::Error::Parse(e) => Error::Gatekeeper(GatekeeperError::Reply(e)),
+        }
+    }
+}
+
+// Scripts that fail to be sent from the queue keep their category.
+impl From<queue::Error> for Error {
+    fn from(e: queue::Error) -> Error {
+        match e {
+            queue::Error::Dyncfg(e) => e,
+            e => Error::State(StateError::Queue(Box::new(e))),
+        }
+    }
+}
+
+/// A failure to load the configuration file, or a configuration that can't
+/// be applied.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(serde_yaml::Error),
+    // An invalid `--set` override, with its key.
+    Override(String, String),
+    Deserialize(serde_yaml::Error),
+    Target(String),
+    // An output path shared by tables applied concurrently.
+    Parallel(String),
+    // An output path shared by removals and insertions rendered apart.
+    Phase(String),
+    // Two BPF programs and the index they share.
+    BpfIndex(String, String, u8),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "failed to read '{}': {}", path.display(), e),
+            ConfigError::Parse(e) => write!(f, "configuration parse failed: {}", e),
+            ConfigError::Override(key, e) => write!(f, "invalid override of '{}': {}", key, e),
+            ConfigError::Deserialize(e) => write!(f, "configuration deserialize failed: {}", e),
+            ConfigError::Target(name) => write!(f, "unknown target '{}'", name),
+            ConfigError::Parallel(output) => write!(
+                f,
+                "output '{}' must distinguish the tables it is rendered for when parallelism is greater than 1",
+                output
+            ),
+            ConfigError::Phase(output) => write!(
+                f,
+                "output '{}' must contain the {{phase}} variable when removals and insertions are not interleaved",
+                output
+            ),
+            ConfigError::BpfIndex(a, b, index) => write!(
+                f,
+                "bpf programs {} and {} have the same index {}",
+                a, b, index
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read(_, e) => Some(e),
+            ConfigError::Parse(e) | ConfigError::Deserialize(e) => Some(e),
+            ConfigError::Override(..)
+            | ConfigError::Target(_)
+            | ConfigError::Parallel(_)
+            | ConfigError::Phase(_)
+            | ConfigError::BpfIndex(..) => None,
+        }
+    }
+}
+
+/// A failure to render a script.
+#[derive(Debug)]
+pub enum TemplateError {
+    Read(PathBuf, io::Error),
+    Render(tera::Error),
+    Write(PathBuf, io::Error),
+    Size(SizeError),
+    Oversized(Oversized),
+    Sync(io::Error),
+    Remove(PathBuf, io::Error),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Read(path, e) => {
+                write!(f, "failed to read template '{}': {}", path.display(), e)
+            }
+            TemplateError::Render(e) => write!(f, "failed to render template: {}", e),
+            TemplateError::Write(path, e) => {
+                write!(f, "failed to write script '{}': {}", path.display(), e)
+            }
+            TemplateError::Size(e) => write!(f, "{}", e),
+            TemplateError::Oversized(e) => write!(f, "{}", e),
+            TemplateError::Sync(e) => write!(f, "failed to sync rendered scripts: {}", e),
+            TemplateError::Remove(path, e) => {
+                write!(f, "failed to remove script '{}': {}", path.display(), e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TemplateError::Read(_, e)
+            | TemplateError::Write(_, e)
+            | TemplateError::Sync(e)
+            | TemplateError::Remove(_, e) => Some(e),
+            TemplateError::Render(e) => Some(e),
+            TemplateError::Size(e) => Some(e),
+            TemplateError::Oversized(_) => None,
+        }
+    }
+}
+
+/// A script rendered larger than a dynamic configuration message, detected
+/// before anything is sent.
+#[derive(Debug)]
+pub struct Oversized {
+    pub script: String,
+    pub input: PathBuf,
+    // The index of the script and the number of scripts rendered.
+    pub chunk: usize,
+    pub chunks: usize,
+    pub size: usize,
+    pub limit: usize,
+    // The number of ranges per script that would fit.
+    pub suggested: usize,
+}
+
+impl fmt::Display for Oversized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "script '{}' (chunk {} of {}) rendered from '{}' has {} bytes, {} over the {} bytes limit; \
+             set max_ranges_per_file to {} or less, or to auto to split scripts automatically",
+            self.script,
+            self.chunk + 1,
+            self.chunks,
+            self.input.display(),
+            self.size,
+            self.size - self.limit,
+            self.limit,
+            self.suggested,
+        )
+    }
+}
+
+impl From<tera::Error> for TemplateError {
+    fn from(e: tera::Error) -> TemplateError {
+        TemplateError::Render(e)
+    }
+}
+
+/// A failure to reach Gatekeeper or a lost connection to it.
+#[derive(Debug)]
+pub enum TransportError {
+    Io(io::Error),
+    // Failures after the script was sent, which may have been applied.
+    Read(io::Error),
+    NoReply(NoReply),
+}
+
+impl TransportError {
+    /// Whether the failure happened before the script was sent.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TransportError::Io(_) => true,
+            TransportError::Read(_) | TransportError::NoReply(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(e) => write!(f, "i/o error: {}", e),
+            TransportError::Read(e) => write!(f, "failed to read reply: {}", e),
+            TransportError::NoReply(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransportError::Io(e) | TransportError::Read(e) => Some(e),
+            TransportError::NoReply(_) => None,
+        }
+    }
+}
+
+/// A reply from Gatekeeper that is missing or can't be understood.
+#[derive(Debug)]
+pub enum GatekeeperError {
+    NoReply(NoReply),
+    Utf8(FromUtf8Error),
+    Reply(ParseError),
+    // A BPF program whose upload was refused, with the reply.
+    Bpf(String, String),
+}
+
+impl fmt::Display for GatekeeperError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GatekeeperError::NoReply(reason) => write!(f, "{}", reason),
+            GatekeeperError::Utf8(e) => write!(f, "invalid reply: {}", e),
+            GatekeeperError::Reply(e) => write!(f, "unexpected reply: {}", e),
+            GatekeeperError::Bpf(name, reply) => {
+                write!(f, "failed to upload bpf program {}: {}", name, reply)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GatekeeperError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GatekeeperError::NoReply(_) | GatekeeperError::Bpf(..) => None,
+            GatekeeperError::Utf8(e) => Some(e),
+            GatekeeperError::Reply(e) => Some(e),
+        }
+    }
+}
+
+/// A failure to read an aggregate, or an aggregate that can't be applied.
+#[derive(Debug)]
+pub enum AggregateError {
+    Read(PathBuf, io::Error),
+    // drib's errors are opaque, so they are only kept as the source.
+    Deserialize(PathBuf, Box<dyn std::error::Error + Send + Sync>),
+    // An old aggregate that can't be read, so that every range would be
+    // inserted.
+    Old(PathBuf, Box<dyn std::error::Error + Send + Sync>),
+    // The number of invalid ranges and a description of some of them.
+    Rejected(usize, String),
+}
+
+impl fmt::Display for AggregateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AggregateError::Read(path, e) => {
+                write!(f, "failed to read aggregate '{}': {}", path.display(), e)
+            }
+            AggregateError::Deserialize(path, e) => write!(
+                f,
+                "failed to deserialize aggregate from '{}': {}",
+                path.display(),
+                e
+            ),
+            AggregateError::Old(path, e) => write!(
+                f,
+                "failed to deserialize old aggregate from '{}'; use --bootstrap for an initial full load: {}",
+                path.display(),
+                e
+            ),
+            AggregateError::Rejected(n, shown) => {
+                write!(f, "aggregate rejected with {} invalid ranges: {}", n, shown)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AggregateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AggregateError::Read(_, e) => Some(e),
+            AggregateError::Deserialize(_, e) | AggregateError::Old(_, e) => Some(&**e),
+            AggregateError::Rejected(..) => None,
+        }
+    }
+}
+
+/// A failure to read or record the state kept between runs, or state left by
+/// an earlier run that must be dealt with first.
+#[derive(Debug)]
+pub enum StateError {
+    Dir(PathBuf, io::Error),
+    // What was being read or recorded, e.g. `progress`.
+    Read(&'static str, state::Error),
+    Write(&'static str, state::Error),
+    Copy(PathBuf, PathBuf, io::Error),
+    Rename(PathBuf, PathBuf, io::Error),
+    // Boxed, as queue errors may hold the failure to send a queued script.
+    Queue(Box<queue::Error>),
+    Scratch(io::Error),
+    Prune(io::Error),
+    // A current aggregate left by an interrupted run with no recorded
+    // progress.
+    Interrupted(PathBuf),
+    // A current aggregate or queued scripts, which a plan can't account for.
+    Pending(PathBuf),
+    Queued(PathBuf),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Dir(path, e) => {
+                write!(f, "failed to create directory '{}': {}", path.display(), e)
+            }
+            StateError::Read(what, e) => write!(f, "failed to read {}: {}", what, e),
+            StateError::Write(what, e) => write!(f, "failed to record {}: {}", what, e),
+            StateError::Copy(from, to, e) => write!(
+                f,
+                "failed to copy '{}' to '{}': {}",
+                from.display(),
+                to.display(),
+                e
+            ),
+            StateError::Rename(from, to, e) => write!(
+                f,
+                "failed to rename '{}' to '{}': {}",
+                from.display(),
+                to.display(),
+                e
+            ),
+            StateError::Queue(e) => write!(f, "retry queue failed: {}", e),
+            StateError::Scratch(e) => write!(f, "failed to create scratch directory: {}", e),
+            StateError::Prune(e) => write!(f, "failed to prune rendered scripts: {}", e),
+            StateError::Interrupted(path) => write!(
+                f,
+                "found current aggregate '{}' left by an interrupted run with no recorded progress; use --recover to reprocess it",
+                path.display()
+            ),
+            StateError::Pending(path) => write!(
+                f,
+                "found current aggregate '{}' left by an interrupted run; apply it first",
+                path.display()
+            ),
+            StateError::Queued(path) => write!(
+                f,
+                "found queued scripts in '{}'; send them first",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StateError::Dir(_, e)
+            | StateError::Copy(_, _, e)
+            | StateError::Rename(_, _, e)
+            | StateError::Scratch(e)
+            | StateError::Prune(e) => Some(e),
+            StateError::Read(_, e) | StateError::Write(_, e) => Some(e),
+            StateError::Queue(e) => Some(&**e),
+            StateError::Interrupted(_) | StateError::Pending(_) | StateError::Queued(_) => None,
+        }
+    }
+}
+
+/// A change refused by the configured limits on tables and scripts.
+#[derive(Debug)]
+pub enum LimitError {
+    // The table, its estimated parameters and its caps.
+    Caps(String, String, String),
+    // A table that must be replaced, with its estimated parameters.
+    Replace(String, String),
+    Window(String),
+    // A table replaced too recently, with the seconds elapsed since and the
+    // ones remaining until it can be replaced again.
+    Cooldown(String, u64, u64),
+    // The bytes taken by rendered scripts and the quota.
+    Quota(u64, u64),
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::Caps(table, params, caps) => write!(
+                f,
+                "estimated parameters {} for table {} exceed its caps {}",
+                params, table, caps
+            ),
+            LimitError::Replace(table, params) => write!(
+                f,
+                "table {} must be replaced to fit parameters {}, but replaces are not allowed for it",
+                table, params
+            ),
+            LimitError::Window(table) => write!(
+                f,
+                "table {} must be replaced, but replaces are only permitted in the configured windows",
+                table
+            ),
+            LimitError::Cooldown(table, elapsed, remaining) => write!(
+                f,
+                "table {} must be replaced, but it was replaced {}s ago; deferring the replace for {}s",
+                table, elapsed, remaining
+            ),
+            LimitError::Quota(used, max) => write!(
+                f,
+                "rendered scripts take {} bytes, exceeding the quota of {} bytes",
+                used, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_dyncfg() {
+        let e = Error::from(dyncfg::Error::NoReply(NoReply::Reset));
+        assert!(matches!(
+            e,
+            Error::Transport(TransportError::NoReply(NoReply::Reset))
+        ));
+        // The script may have been applied before the connection was lost.
+        assert!(!e.is_retryable());
+
+        let refused = || io::Error::from(io::ErrorKind::ConnectionRefused);
+        let e = Error::from(dyncfg::Error::Io(refused()));
+        assert!(matches!(e, Error::Transport(TransportError::Io(_))));
+        assert!(e.is_retryable());
+        assert!(!Error::from(dyncfg::Error::Read(refused())).is_retryable());
+
+        let e = Error::from(dyncfg::Error::NoReply(NoReply::Empty));
+        assert!(matches!(e, Error::Gatekeeper(GatekeeperError::NoReply(_))));
+        assert!(!e.is_retryable());
+
+        let e = Error::from(params::Error::Parse(ParseError::Empty));
+        assert!(matches!(e, Error::Gatekeeper(GatekeeperError::Reply(_))));
+    }
+
+    #[test]
+    fn test_local_failures() {
+        // Local failures aren't Gatekeeper being unreachable.
+        let e = Error::Runtime(io::Error::from(io::ErrorKind::Other));
+        assert!(!e.is_retryable());
+
+        let e = Error::from(queue::Error::Io(io::Error::from(io::ErrorKind::Other)));
+        assert!(matches!(e, Error::State(StateError::Queue(_))));
+        assert!(!e.is_retryable());
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+use ipnet::IpNet;
+use serde::Serialize;
+use tokio::fs;
+use tokio::io;
+
+/// A Grantor server and the gateway through which it is reached.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Grantor {
+    pub address: IpAddr,
+    pub gateway: IpAddr,
+}
+
+/// The desired FIB state, mapping each prefix to the Grantor servers its
+/// traffic is load balanced to.
+pub type FibState = BTreeMap<IpNet, Vec<Grantor>>;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FibEntry<'a> {
+    pub prefix: IpNet,
+    pub grantors: &'a [Grantor],
+}
+
+/// The changes needed to go from one FIB state to another. Prefixes whose
+/// Grantor servers changed are updated in place rather than removed and
+/// added again, so their traffic is never left unrouted.
+#[derive(Debug, Default, Serialize)]
+pub struct FibDiff<'a> {
+    pub add: Vec<FibEntry<'a>>,
+    pub update: Vec<FibEntry<'a>>,
+    pub remove: Vec<IpNet>,
+}
+
+impl<'a> FibDiff<'a> {
+    pub fn len(&self) -> usize {
+        self.add.len() + self.update.len() + self.remove.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Splits the diff into chunks of at most `max` entries each. Additions
+    /// come first and removals last, so that more specific prefixes are
+    /// in place before the ones covering them are removed.
+    pub fn chunks(&self, max: usize) -> Vec<FibDiff<'a>> {
+        let max = max.max(1);
+        let mut chunks = Vec::new();
+        for entry in &self.add {
+            last_chunk(&mut chunks, max).add.push(*entry);
+        }
+        for entry in &self.update {
+            last_chunk(&mut chunks, max).update.push(*entry);
+        }
+        for prefix in &self.remove {
+            last_chunk(&mut chunks, max).remove.push(*prefix);
+        }
+        chunks
+    }
+}
+
+// Returns the chunk being filled, starting a new one if it is full.
+fn last_chunk<'a, 'b>(chunks: &'b mut Vec<FibDiff<'a>>, max: usize) -> &'b mut FibDiff<'a> {
+    if chunks.last().map_or(true, |chunk| chunk.len() >= max) {
+        chunks.push(FibDiff::default());
+    }
+    chunks.last_mut().expect("BUG: no chunk")
+}
+
+pub fn diff<'a>(old: &FibState, new: &'a FibState) -> FibDiff<'a> {
+    let mut diff = FibDiff::default();
+    for (prefix, grantors) in new {
+        let entry = FibEntry {
+            prefix: *prefix,
+            grantors,
+        };
+        match old.get(prefix) {
+            None => diff.add.push(entry),
+            Some(old_grantors) if old_grantors != grantors => diff.update.push(entry),
+            Some(_) => {}
+        }
+    }
+    diff.remove = old
+        .keys()
+        .filter(|prefix| !new.contains_key(prefix))
+        .copied()
+        .collect();
+    diff
+}
+
+/// Reads a FIB state file. Each non-empty line holds a prefix followed by
+/// one or more whitespace-separated `grantor,gateway` address pairs; text
+/// following a `#` is ignored.
+pub async fn load(path: impl AsRef<Path>) -> Result<FibState, Error> {
+    let data = fs::read_to_string(path).await?;
+    parse(&data)
+}
+
+pub fn parse(data: &str) -> Result<FibState, Error> {
+    let mut state = FibState::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let prefix = match fields.next() {
+            Some(prefix) => prefix,
+            None => continue,
+        };
+        let err = |msg: String| Error::Parse { line: i + 1, msg };
+        let prefix: IpNet = prefix
+            .parse()
+            .map_err(|e| err(format!("invalid prefix '{}': {}", prefix, e)))?;
+        let grantors = fields
+            .map(|pair| {
+                parse_grantor(pair).ok_or_else(|| err(format!("invalid grantor '{}'", pair)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if grantors.is_empty() {
+            return Err(err(format!("no grantors for prefix {}", prefix)));
+        }
+        if state.insert(prefix, grantors).is_some() {
+            return Err(err(format!("duplicate prefix {}", prefix)));
+        }
+    }
+    Ok(state)
+}
+
+fn parse_grantor(pair: &str) -> Option<Grantor> {
+    let mut addrs = pair.split(',');
+    let grantor = Grantor {
+        address: addrs.next()?.parse().ok()?,
+        gateway: addrs.next()?.parse().ok()?,
+    };
+    match addrs.next() {
+        Some(_) => None,
+        None => Some(grantor),
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse { line: usize, msg: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Parse { line, msg } => write!(f, "line {}: {}", line, msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Parse { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let old = parse(
+            "
+            # comment
+            10.0.0.0/8     192.0.2.1,198.51.100.1
+            10.1.0.0/16    192.0.2.1,198.51.100.1
+            2001:db8::/32  2001:db8:1::1,2001:db8:2::1
+            ",
+        )
+        .expect("parse failed");
+        let new = parse(
+            "
+            10.0.0.0/8     192.0.2.1,198.51.100.1 192.0.2.2,198.51.100.1
+            172.16.0.0/12  192.0.2.1,198.51.100.1  # added
+            2001:db8::/32  2001:db8:1::1,2001:db8:2::1
+            ",
+        )
+        .expect("parse failed");
+
+        let diff = diff(&old, &new);
+        let prefixes = |entries: &[FibEntry]| -> Vec<String> {
+            entries.iter().map(|e| e.prefix.to_string()).collect()
+        };
+        assert_eq!(vec!["172.16.0.0/12"], prefixes(&diff.add));
+        assert_eq!(vec!["10.0.0.0/8"], prefixes(&diff.update));
+        assert_eq!(2, diff.update[0].grantors.len());
+        assert_eq!(vec!["10.1.0.0/16".parse::<IpNet>().unwrap()], diff.remove);
+
+        let chunks = diff.chunks(2);
+        let sizes: Vec<_> = chunks
+            .iter()
+            .map(|c| (c.add.len(), c.update.len(), c.remove.len()))
+            .collect();
+        assert_eq!(vec![(1, 1, 0), (0, 0, 1)], sizes);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("10.0.0.0/8").is_err());
+        assert!(parse("10.0.0.0/33 192.0.2.1,198.51.100.1").is_err());
+        assert!(parse("10.0.0.0/8 192.0.2.1").is_err());
+        assert!(parse("10.0.0.0/8 192.0.2.1,198.51.100.1,192.0.2.2").is_err());
+        match parse("10.0.0.0/8 192.0.2.1,198.51.100.1\n10.0.0.0/8 192.0.2.2,198.51.100.1") {
+            Err(Error::Parse { line, .. }) => assert_eq!(2, line),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::config::DyncfgConfig;
+use crate::dyncfg;
+
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// Tracks the outcome of the applies performed in watch mode and serves it
+/// over HTTP on the `/healthz` and `/readyz` endpoints.
+#[derive(Debug)]
+pub struct Health {
+    socket: PathBuf,
+    dyncfg_config: DyncfgConfig,
+    max_staleness: Option<Duration>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    last_attempt: Option<SystemTime>,
+    last_success: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    pub staleness_secs: Option<u64>,
+    // Unknown over SSH, where reaching the socket takes sending a script.
+    pub socket_reachable: Option<bool>,
+}
+
+impl Status {
+    pub fn is_healthy(&self) -> bool {
+        self.last_error.is_none()
+    }
+
+    pub fn is_ready(&self, max_staleness: Option<Duration>) -> bool {
+        let fresh = match (self.staleness_secs, max_staleness) {
+            (Some(staleness), Some(max)) => staleness <= max.as_secs(),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        self.is_healthy() && fresh && self.socket_reachable != Some(false)
+    }
+}
+
+impl Health {
+    pub fn new(
+        socket: PathBuf,
+        dyncfg_config: DyncfgConfig,
+        max_staleness: Option<Duration>,
+    ) -> Health {
+        Health {
+            socket,
+            dyncfg_config,
+            max_staleness,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let now = SystemTime::now();
+        let mut state = self.state.lock().expect("BUG: health lock poisoned");
+        state.last_attempt = Some(now);
+        state.last_success = Some(now);
+        state.last_error = None;
+    }
+
+    pub fn record_failure(&self, error: String) {
+        let mut state = self.state.lock().expect("BUG: health lock poisoned");
+        state.last_attempt = Some(SystemTime::now());
+        state.last_error = Some(error);
+    }
+
+    pub async fn status(&self) -> Status {
+        let socket_reachable = match self.dyncfg_config.ssh {
+            Some(_) => None,
+            None => Some(
+                dyncfg::connect(&self.socket, &self.dyncfg_config)
+                    .await
+                    .is_ok(),
+            ),
+        };
+        let state = self.state.lock().expect("BUG: health lock poisoned");
+        Status {
+            last_attempt: state.last_attempt.map(unix_secs),
+            last_success: state.last_success.map(unix_secs),
+            last_error: state.last_error.clone(),
+            staleness_secs: state
+                .last_success
+                .and_then(|t| t.elapsed().ok())
+                .map(|d| d.as_secs()),
+            socket_reachable,
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _addr)) => stream,
+                Err(e) => {
+                    warn!("failed to accept health check connection: {}", e);
+                    continue;
+                }
+            };
+            let health = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = health.handle(stream).await {
+                    debug!("health check connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<(), io::Error> {
+        let mut buf = vec![0u8; MAX_REQUEST_LEN];
+        let mut len = 0;
+        while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") && len < buf.len() {
+            let n = stream.read(&mut buf[len..]).await?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+
+        let request = String::from_utf8_lossy(&buf[..len]);
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+        let (code, body) = match path {
+            "/healthz" | "/readyz" => {
+                let status = self.status().await;
+                let ok = if path == "/healthz" {
+                    status.is_healthy()
+                } else {
+                    status.is_ready(self.max_staleness)
+                };
+                let body = serde_json::to_string(&status).expect("BUG: status serialization");
+                (
+                    if ok {
+                        "200 OK"
+                    } else {
+                        "503 Service Unavailable"
+                    },
+                    body,
+                )
+            }
+            _ => ("404 Not Found", "{}".to_owned()),
+        };
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            code,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let mut status = Status {
+            last_attempt: Some(100),
+            last_success: Some(100),
+            last_error: None,
+            staleness_secs: Some(30),
+            socket_reachable: Some(true),
+        };
+        assert!(status.is_healthy());
+        assert!(status.is_ready(None));
+        assert!(status.is_ready(Some(Duration::from_secs(60))));
+        assert!(!status.is_ready(Some(Duration::from_secs(10))));
+
+        status.socket_reachable = Some(false);
+        assert!(status.is_healthy());
+        assert!(!status.is_ready(None));
+
+        status.socket_reachable = None;
+        assert!(status.is_ready(None));
+
+        status.socket_reachable = Some(true);
+        status.last_error = Some("failed".to_owned());
+        assert!(!status.is_healthy());
+        assert!(!status.is_ready(None));
+
+        status.last_error = None;
+        status.staleness_secs = None;
+        assert!(!status.is_ready(None));
+    }
+}
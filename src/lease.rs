@@ -0,0 +1,274 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::time;
+
+/// The contents of a lease file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Holder {
+    pub holder: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A lease kept as a file on storage shared by redundant gtctl instances.
+/// The instance holding an unexpired lease is the leader; the others wait
+/// for it to expire before taking over.
+///
+/// Instances check and update the lease while holding an exclusive `flock(2)`
+/// on a `.lock` file next to it, so when two instances race for an expired
+/// lease, only the first one takes it. The lock is released by the kernel if
+/// an instance dies while holding it.
+#[derive(Debug)]
+pub struct Lease {
+    path: PathBuf,
+    holder: String,
+    ttl: Duration,
+    held: AtomicBool,
+}
+
+impl Lease {
+    pub fn new(path: impl AsRef<Path>, holder: String, ttl: Duration) -> Lease {
+        Lease {
+            path: path.as_ref().to_owned(),
+            holder,
+            ttl,
+            held: AtomicBool::new(false),
+        }
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    /// Acquires the lease if it is free or expired, or extends it if it is
+    /// already held. Returns whether the lease is held afterwards.
+    pub async fn renew(&self) -> Result<bool, Error> {
+        let res = self.try_renew().await;
+        // If the lease file can't be accessed, another instance may take
+        // over, so leadership must be given up.
+        let held = *res.as_ref().unwrap_or(&false);
+        self.held.store(held, Ordering::Relaxed);
+        res
+    }
+
+    async fn try_renew(&self) -> Result<bool, Error> {
+        let _lock = self.lock().await?;
+        let now = Utc::now();
+        if let Some(cur) = self.read().await? {
+            if cur.holder != self.holder && cur.expires_at > now {
+                return Ok(false);
+            }
+        }
+        let ttl = chrono::Duration::from_std(self.ttl).map_err(|_| Error::InvalidTtl)?;
+        let lease = Holder {
+            holder: self.holder.clone(),
+            expires_at: now + ttl,
+        };
+        self.write(&lease).await?;
+        Ok(true)
+    }
+
+    /// Gives up the lease, if held, so that a standby can take over without
+    /// waiting for it to expire.
+    pub async fn release(&self) -> Result<(), Error> {
+        if !self.held.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let _lock = self.lock().await?;
+        match self.read().await? {
+            Some(cur) if cur.holder == self.holder => match fs::remove_file(&self.path).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Renews the lease at a third of its duration, logging leadership
+    /// changes. Never returns.
+    pub async fn maintain(self: Arc<Self>) {
+        let mut interval = time::interval(self.ttl / 3);
+        loop {
+            interval.tick().await;
+            let was_held = self.is_held();
+            let held = match self.renew().await {
+                Ok(held) => held,
+                Err(e) => {
+                    warn!("failed to renew lease '{}': {}", self.path.display(), e);
+                    false
+                }
+            };
+            match (was_held, held) {
+                (false, true) => info!("acquired lease as {}", self.holder),
+                (true, false) => warn!("lost lease held as {}", self.holder),
+                _ => {}
+            }
+        }
+    }
+
+    // Waits for the exclusive lock on the lease, held until the returned file
+    // is dropped.
+    async fn lock(&self) -> Result<std::fs::File, Error> {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(".lock");
+        let lock = tokio::task::spawn_blocking(move || {
+            let file = OpenOptions::new().create(true).write(true).open(&path)?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(file)
+        });
+        match lock.await {
+            Ok(res) => Ok(res?),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e).into()),
+        }
+    }
+
+    async fn read(&self) -> Result<Option<Holder>, Error> {
+        let data = match fs::read(&self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    // Each instance writes through its own temporary file, so a lease is
+    // never seen half written.
+    async fn write(&self, lease: &Holder) -> Result<(), Error> {
+        let tmp = PathBuf::from(format!(
+            "{}.{}.tmp",
+            self.path.display(),
+            sanitize(&self.holder)
+        ));
+        let mut file = File::create(&tmp).await?;
+        file.write_all(&serde_json::to_vec_pretty(lease)?).await?;
+        file.sync_all().await?;
+        drop(file);
+        fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+/// The default holder name, made of the host name and the process ID.
+pub fn default_holder() -> String {
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_owned())
+        .unwrap_or_else(|_| "localhost".to_owned());
+    format!("{}:{}", host, process::id())
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    InvalidTtl,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Json(e) => write!(f, "json error: {}", e),
+            Error::InvalidTtl => write!(f, "invalid lease ttl"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::InvalidTtl => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lease() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let path = tmp.path().join("lease");
+        let ttl = Duration::from_secs(60);
+        let a = Lease::new(&path, "a".to_owned(), ttl);
+        let b = Lease::new(&path, "b".to_owned(), ttl);
+
+        assert!(a.renew().await.expect("renew failed"));
+        assert!(a.renew().await.expect("renew failed"));
+        assert!(!b.renew().await.expect("renew failed"));
+        assert!(a.is_held());
+        assert!(!b.is_held());
+
+        a.release().await.expect("release failed");
+        assert!(!a.is_held());
+        assert!(b.renew().await.expect("renew failed"));
+        assert!(!a.renew().await.expect("renew failed"));
+
+        // An expired lease is taken over.
+        b.release().await.expect("release failed");
+        let c = Lease::new(&path, "c".to_owned(), Duration::from_secs(0));
+        assert!(c.renew().await.expect("renew failed"));
+        assert!(a.renew().await.expect("renew failed"));
+        assert!(!c.renew().await.expect("renew failed"));
+    }
+
+    #[tokio::test]
+    async fn test_lease_race() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let path = tmp.path().join("lease");
+        let expired = Lease::new(&path, "expired".to_owned(), Duration::from_secs(0));
+        assert!(expired.renew().await.expect("renew failed"));
+
+        let ttl = Duration::from_secs(60);
+        let renewals = (0..8).map(|i| {
+            let lease = Lease::new(&path, format!("l{}", i), ttl);
+            tokio::spawn(async move { lease.renew().await.expect("renew failed") })
+        });
+        let held = futures::future::join_all(renewals)
+            .await
+            .into_iter()
+            .filter(|res| *res.as_ref().expect("join failed"))
+            .count();
+        assert_eq!(1, held);
+    }
+}
@@ -1,4 +1,29 @@
+pub mod bench;
+pub mod blocking;
+pub mod broker;
+pub mod capture;
 pub mod config;
+pub mod control;
+pub mod docs;
 pub mod dyncfg;
+pub mod error;
+pub mod fib;
+pub mod health;
+pub mod lease;
+pub mod logging;
+pub mod metrics;
+pub mod mock;
 pub mod params;
+pub mod plan;
+pub mod proxy;
+pub mod prune;
+pub mod queue;
+pub mod reply;
+pub mod run;
+pub mod shell;
+pub mod state;
+pub mod template;
+pub mod term;
 pub mod util;
+pub mod validate;
+pub mod version;
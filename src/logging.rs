@@ -0,0 +1,341 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::config::LogFileConfig;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "gtctl";
+
+// The "daemon" syslog facility.
+const SYSLOG_FACILITY: u8 = 3;
+
+/// Log levels by module. Records get the level of the most specific filter
+/// naming their module or one of its parents, or, for gtctl's own records,
+/// the default level. Records from other crates are otherwise dropped.
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    level: LevelFilter,
+    modules: BTreeMap<String, LevelFilter>,
+}
+
+impl LogFilter {
+    pub fn new(level: LevelFilter, modules: BTreeMap<String, LevelFilter>) -> LogFilter {
+        LogFilter { level, modules }
+    }
+
+    pub fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    /// The most verbose level any record may be logged at.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules.values().copied().fold(self.level, Ord::max)
+    }
+
+    /// Returns the default level along with the level of each module.
+    pub fn directives(&self) -> impl Iterator<Item = (&str, LevelFilter)> {
+        let modules = self.modules.iter().map(|(m, l)| (m.as_str(), *l));
+        std::iter::once((IDENTIFIER, self.level)).chain(modules)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let module = self
+            .modules
+            .iter()
+            .filter(|(module, _)| is_within(target, module))
+            .max_by_key(|(module, _)| module.len());
+        match module {
+            Some((_, level)) => *level,
+            None if is_within(target, IDENTIFIER) => self.level,
+            None => LevelFilter::Off,
+        }
+    }
+}
+
+fn is_within(target: &str, module: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+/// A log file that is rotated once it grows past a maximum size or once it
+/// is older than a given interval, whichever comes first. Rotated files are
+/// renamed to `<path>.1`, `<path>.2` and so on, up to `keep` files.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    interval: Option<Duration>,
+    keep: usize,
+    file: File,
+    size: u64,
+    created: SystemTime,
+}
+
+impl RotatingFile {
+    pub fn open(config: &LogFileConfig) -> Result<RotatingFile, io::Error> {
+        let (file, size, created) = open_append(&config.path)?;
+        Ok(RotatingFile {
+            path: config.path.clone(),
+            max_size: config.max_size,
+            interval: config.rotate_interval.map(Duration::from_secs),
+            keep: config.keep,
+            file,
+            size,
+            created,
+        })
+    }
+
+    fn should_rotate(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        if let Some(max_size) = self.max_size {
+            if self.size + len as u64 > max_size {
+                return true;
+            }
+        }
+        if let Some(interval) = self.interval {
+            // A clock going backwards yields an error here, in which
+            // case rotation is postponed.
+            if let Ok(age) = self.created.elapsed() {
+                return age >= interval;
+            }
+        }
+        false
+    }
+
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, i);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, i + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        let (file, size, created) = open_append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.created = created;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A logger that sends RFC 3164 messages to the local syslog daemon.
+#[derive(Debug)]
+pub struct SyslogLogger {
+    socket: UnixDatagram,
+    filter: LogFilter,
+}
+
+impl SyslogLogger {
+    pub fn new(filter: LogFilter) -> Result<SyslogLogger, io::Error> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SYSLOG_SOCKET)?;
+        Ok(SyslogLogger { socket, filter })
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let pri = SYSLOG_FACILITY * 8 + severity(record.level());
+        let msg = format!(
+            "<{}>{}[{}]: {}",
+            pri,
+            IDENTIFIER,
+            process::id(),
+            record.args()
+        );
+        // There is nowhere left to report logging failures.
+        let _ = self.socket.send(msg.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+/// A logger that sends structured entries to the systemd journal using its
+/// native protocol.
+#[derive(Debug)]
+pub struct JournaldLogger {
+    socket: UnixDatagram,
+    filter: LogFilter,
+}
+
+impl JournaldLogger {
+    pub fn new(filter: LogFilter) -> Result<JournaldLogger, io::Error> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+        Ok(JournaldLogger { socket, filter })
+    }
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut buf = Vec::new();
+        journald_field(&mut buf, "MESSAGE", &record.args().to_string());
+        journald_field(&mut buf, "PRIORITY", &severity(record.level()).to_string());
+        journald_field(&mut buf, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        journald_field(&mut buf, "SYSLOG_PID", &process::id().to_string());
+        journald_field(&mut buf, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            journald_field(&mut buf, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            journald_field(&mut buf, "CODE_LINE", &line.to_string());
+        }
+        // There is nowhere left to report logging failures.
+        let _ = self.socket.send(&buf);
+    }
+
+    fn flush(&self) {}
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// Values containing newlines must use the binary encoding of the journal
+// native protocol, where the value is preceded by its little-endian length.
+fn journald_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        let mut len = [0u8; 8];
+        LittleEndian::write_u64(&mut len, value.len() as u64);
+        buf.push(b'\n');
+        buf.extend_from_slice(&len);
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+fn open_append(path: &Path) -> Result<(File, u64, SystemTime), io::Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let meta = file.metadata()?;
+    // Not every filesystem records creation times, and gtctl is usually
+    // short-lived, so fall back to the modification time of the file.
+    let created = meta
+        .created()
+        .or_else(|_| meta.modified())
+        .unwrap_or_else(|_| SystemTime::now());
+    Ok((file, meta.len(), created))
+}
+
+fn rotated_path(path: &Path, i: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.display(), i))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_log_filter() {
+        let modules = vec![
+            ("gtctl::dyncfg".to_owned(), LevelFilter::Debug),
+            ("drib".to_owned(), LevelFilter::Warn),
+        ];
+        let filter = LogFilter::new(LevelFilter::Info, modules.into_iter().collect());
+        let enabled = |level, target| {
+            filter.enabled(&Metadata::builder().level(level).target(target).build())
+        };
+        assert!(enabled(Level::Info, "gtctl"));
+        assert!(!enabled(Level::Debug, "gtctl::params"));
+        assert!(enabled(Level::Debug, "gtctl::dyncfg"));
+        assert!(!enabled(Level::Debug, "gtctl::dyncfgx"));
+        assert!(enabled(Level::Warn, "drib::output"));
+        assert!(!enabled(Level::Info, "drib::output"));
+        assert!(!enabled(Level::Error, "tokio"));
+        assert_eq!(LevelFilter::Debug, filter.max_level());
+    }
+
+    #[test]
+    fn test_journald_field() {
+        let mut buf = Vec::new();
+        journald_field(&mut buf, "MESSAGE", "hello");
+        assert_eq!(b"MESSAGE=hello\n".to_vec(), buf);
+
+        let mut buf = Vec::new();
+        journald_field(&mut buf, "MESSAGE", "a\nb");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(expected, buf);
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let path = tmp.path().join("gtctl.log");
+        let config = LogFileConfig {
+            path: path.clone(),
+            max_size: Some(10),
+            rotate_interval: None,
+            keep: 2,
+        };
+
+        let mut file = RotatingFile::open(&config).expect("open failed");
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).expect("write failed");
+        }
+        file.flush().expect("flush failed");
+
+        let read = |p: PathBuf| fs::read_to_string(p).expect("read failed");
+        assert_eq!("fourth\n", read(path.clone()));
+        assert_eq!("third\n", read(rotated_path(&path, 1)));
+        assert_eq!("second\n", read(rotated_path(&path, 2)));
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
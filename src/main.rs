@@ -1,49 +1,176 @@
-use std::cmp::Ord;
-use std::collections::BTreeSet;
-use std::fmt::Debug;
+use std::borrow::Cow;
+use std::cmp::{max, min, Ord};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::{self, Debug, Display};
+use std::iter;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Context;
-use clap::{crate_name, crate_version, ArgGroup, Clap};
+use anyhow::{anyhow, Context};
+use clap::{crate_name, crate_version, ArgGroup, Clap, IntoApp};
+use clap_generate::generators::{Bash, Fish, Zsh};
 use drib::aggregate::{self, Entry};
-use drib::config::Templates;
-use drib::net::Net;
-use drib::output::{self, Bootstrap, Changes, Diff};
-use ipnet::{Ipv4Net, Ipv6Net};
-use log::{debug, info, warn, Level};
-use serde::Serialize;
+use drib::output::{Bootstrap, Changes};
+use futures::future;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use log::{debug, error, info, warn, Level, LevelFilter};
+use serde::{Deserialize, Serialize};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::{
     fs::{self, File},
     io::{self, AsyncBufReadExt, BufReader},
+    net::TcpListener,
+    time,
 };
 
 use gtctl::{
-    config::{Config, EstimateConfig, LuaFunctions},
-    dyncfg,
-    params::{self, CurrentParams, Params},
-    util::safe_write,
+    bench::{measure, Stats},
+    broker::Broker,
+    capture,
+    config::{
+        self, Config, DyncfgConfig, EstimateConfig, LogTarget, RemovePolicy, ReplyDecoding,
+        Rounding, TableMode,
+    },
+    control::{self, Control},
+    docs, dyncfg,
+    error::{self, GatekeeperError},
+    fib::{self, FibDiff, FibState},
+    health::Health,
+    lease::{self, Lease},
+    logging::{JournaldLogger, LogFilter, RotatingFile, SyslogLogger},
+    metrics,
+    mock::{params_reply, MockServer},
+    params::{self, CurrentParams, LimitWarning, Params, TableParams, Tbl8Breakdown, Update},
+    plan,
+    proxy::{self, Proxy},
+    prune,
+    queue::RetryQueue,
+    run::{
+        self, applied_routing, create_dirs, display_value, dyn_cfg, embedded_entries,
+        fixture_tables, limit_warnings, policies, prepare_tables, render_chunked, render_template,
+        replace_vars, run_ipv4, run_ipv6, run_mode, scratch_config, script_path, script_patterns,
+        select_targets, valid_ranges, validate_tables, KindSummary, ReplaceModeVariables,
+        RunOptions, TargetResult, OLD_AGGREGATE, RETRY_QUEUE,
+    },
+    shell::Shell,
+    state::{self, LastRun, Mode, TableRun},
+    term::{self, Style, TableDiff},
+    util::{self, safe_copy, safe_write},
+    validate::Prefix,
+    version::BuildInfo,
 };
 
-const CUR_AGGREGATE: &'static str = "aggregate.cur";
-const OLD_AGGREGATE: &'static str = "aggregate.old";
+const OLD_FIB: &str = "fib.old";
+const HISTORY_FILE: &str = ".gtctl_history";
+// The exit status of `dyncfg --check` when there are changes to apply.
+const CHANGES_PENDING: i32 = 2;
 
+/// Applies aggregate changes to Gatekeeper's LPM tables.
 #[derive(Debug, Clap)]
 #[clap(name = crate_name!(), version = crate_version!())]
 struct Opts {
+    /// Reports failures as text or as JSON objects on standard error. The
+    /// estimate command also accepts lua, printing the parameters as Lua
+    /// variables for Gatekeeper's static configuration.
+    #[clap(
+        long,
+        name = "OUTPUT-FORMAT",
+        value_name = "FORMAT",
+        default_value = "text",
+        parse(try_from_str = parse_output_format),
+        global = true
+    )]
+    format: OutputFormat,
+    /// Increases the log level.
+    #[clap(short, long, parse(from_occurrences), global = true)]
+    verbose: i64,
+    /// Decreases the log level.
+    #[clap(short, long, parse(from_occurrences), global = true)]
+    quiet: i64,
+    /// Sets the log level, overriding the configured one. The -v and -q
+    /// flags adjust this level.
+    #[clap(
+        long,
+        value_name = "LEVEL",
+        possible_values = &["error", "warn", "info", "debug", "trace"],
+        global = true
+    )]
+    log_level: Option<String>,
+    /// Overrides a configuration key, given as a dotted path, for this
+    /// invocation.
+    #[clap(
+        long,
+        name = "SET",
+        value_name = "KEY=VALUE",
+        parse(try_from_str = parse_var),
+        global = true
+    )]
+    set: Vec<(String, serde_yaml::Value)>,
     #[clap(subcommand)]
     command: Cmd,
 }
 
 #[derive(Debug, Clone, Clap)]
 enum Cmd {
+    /// Applies an aggregate to Gatekeeper.
     Dyncfg(Dyncfg),
+    /// Shows the changes an aggregate would apply.
+    Diff(DiffCmd),
+    /// Writes a plan of the changes an aggregate would apply, for review.
+    Plan(PlanCmd),
+    /// Applies a reviewed plan, unless the targets changed since it was made.
+    Apply(ApplyCmd),
+    /// Estimates the LPM table parameters an aggregate needs.
     Estimate(Estimate),
+    /// Suggests LPM table sizes for Gatekeeper's static configuration.
+    SuggestConfig(SuggestConfigCmd),
+    /// Benchmarks diffing and rendering an aggregate.
+    Bench(Bench),
+    /// Applies an aggregate whenever it changes.
+    Watch(Watch),
+    /// Renders the scripts that load an aggregate from scratch.
+    Bootstrap(BootstrapCmd),
+    /// Applies FIB updates.
+    Fib(FibCmd),
+    /// Shows the outcome of the last run.
+    LastRun(LastRunCmd),
+    /// Shows the history of past runs.
+    History(HistoryCmd),
+    /// Removes old rendered scripts.
+    Prune(PruneCmd),
+    /// Applies an aggregate against a mock Gatekeeper.
+    TestRun(TestRunCmd),
+    /// Serializes scripts sent to Gatekeeper by multiple clients.
+    Broker(BrokerCmd),
+    /// Relays scripts from remote callers to the dynamic configuration socket.
+    Proxy(ProxyCmd),
+    /// Sends a command to a gtctl process in watch mode.
+    Ctl(CtlCmd),
+    /// Runs an interactive shell over the dynamic configuration socket.
+    Shell(ShellCmd),
+    /// Sends a script to the dynamic configuration socket.
+    Send(SendCmd),
+    /// Resends the scripts in a capture file.
+    Replay(ReplayCmd),
+    /// Works with templates.
+    Template(TemplateCmd),
+    /// Prints shell completions.
+    Completions(CompletionsCmd),
+    /// Generates the man page and the CLI and configuration references.
+    GenDocs(GenDocsCmd),
+    /// Prints version information.
+    Version(VersionCmd),
 }
 
+// The flags selecting the configuration, targets and state of the commands
+// that work with an aggregate.
 #[derive(Debug, Clone, Clap)]
-struct Dyncfg {
+struct ConfigFlags {
     #[clap(
         short,
         long,
@@ -52,8 +179,64 @@ struct Dyncfg {
         parse(from_os_str)
     )]
     config: PathBuf,
+    #[clap(short, long, name = "TARGET")]
+    target: Vec<String>,
+    #[clap(long, name = "NAME")]
+    state_name: Option<String>,
+    #[clap(long = "var", name = "KEY=VALUE", parse(try_from_str = parse_var))]
+    vars: Vec<(String, serde_yaml::Value)>,
+}
+
+#[derive(Debug, Clone, Clap)]
+struct Dyncfg {
+    #[clap(flatten)]
+    common: ConfigFlags,
+    #[clap(short, long, name = "AGGREGATE", parse(from_os_str))]
+    aggregate: PathBuf,
+    #[clap(long)]
+    recover: bool,
+    #[clap(long)]
+    bootstrap: bool,
+    #[clap(long)]
+    dry_run: bool,
+    /// Shows a summary of the pending changes without applying them, and
+    /// exits with status 2 if there are any.
+    #[clap(long, conflicts_with = "dry-run")]
+    check: bool,
+}
+
+#[derive(Debug, Clone, Clap)]
+struct DiffCmd {
+    #[clap(flatten)]
+    common: ConfigFlags,
+    #[clap(short, long, name = "AGGREGATE", parse(from_os_str))]
+    aggregate: PathBuf,
+    #[clap(short, long, name = "OLD-AGGREGATE", parse(from_os_str))]
+    old: Option<PathBuf>,
+    #[clap(long)]
+    summary: bool,
+}
+
+#[derive(Debug, Clone, Clap)]
+struct PlanCmd {
+    #[clap(flatten)]
+    common: ConfigFlags,
     #[clap(short, long, name = "AGGREGATE", parse(from_os_str))]
     aggregate: PathBuf,
+    #[clap(short, long, name = "PLAN", parse(from_os_str))]
+    out: PathBuf,
+}
+
+#[derive(Debug, Clone, Clap)]
+struct ApplyCmd {
+    #[clap(flatten)]
+    common: ConfigFlags,
+    #[clap(short, long, name = "PLAN", parse(from_os_str))]
+    plan: PathBuf,
+    /// Applies the plan without verifying its signature when no plan key is
+    /// configured.
+    #[clap(long)]
+    allow_unsigned: bool,
 }
 
 #[derive(Debug, Clone, Clap)]
@@ -75,6 +258,27 @@ struct Estimate {
         group = "estimate"
     )]
     ipv6_prefixes: Option<PathBuf>,
+    /// Compares the parameters of the prefixes in this file or directory to
+    /// those of the new ones.
+    #[clap(
+        long,
+        name = "OLD-PREFIXES",
+        parse(from_os_str),
+        group = "estimate",
+        requires = "NEW-PREFIXES"
+    )]
+    old: Option<PathBuf>,
+    /// The new prefixes compared to the old ones.
+    #[clap(
+        long,
+        name = "NEW-PREFIXES",
+        parse(from_os_str),
+        requires = "OLD-PREFIXES"
+    )]
+    new: Option<PathBuf>,
+    /// Only loads the files matching this pattern from prefix directories.
+    #[clap(long, name = "PATTERN", default_value = "*")]
+    glob: glob::Pattern,
     #[clap(
         short,
         long,
@@ -83,366 +287,2904 @@ struct Estimate {
         parse(from_os_str)
     )]
     config: PathBuf,
+    #[clap(long, name = "RULES-FACTOR", parse(try_from_str = parse_scaling_factor))]
+    rules_scale: Option<usize>,
+    #[clap(long, name = "TBL8S-FACTOR", parse(try_from_str = parse_scaling_factor))]
+    tbl8s_scale: Option<usize>,
+    #[clap(long, name = "PERCENT")]
+    headroom_percent: Option<usize>,
+    #[clap(long)]
+    detail: bool,
+    #[clap(long, name = "NUM-BUCKETS", default_value = "10")]
+    top: usize,
 }
 
-#[derive(Debug, Eq, PartialEq)]
-enum Mode {
-    Replace,
-    Update,
+#[derive(Debug, Clone, Clap)]
+struct Watch {
+    #[clap(flatten)]
+    common: ConfigFlags,
+    #[clap(short, long, name = "AGGREGATE", parse(from_os_str))]
+    aggregate: PathBuf,
+    #[clap(short, long, name = "SECONDS", default_value = "60")]
+    interval: u64,
+    #[clap(long)]
+    recover: bool,
+    #[clap(long)]
+    bootstrap: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
-    let opts = Opts::parse();
+#[derive(Debug, Clone, Clap)]
+struct BootstrapCmd {
+    #[clap(flatten)]
+    common: ConfigFlags,
+    #[clap(short, long, name = "AGGREGATE", parse(from_os_str))]
+    aggregate: PathBuf,
+    #[clap(long)]
+    force: bool,
+}
 
-    match opts.command {
-        Cmd::Dyncfg(flags) => {
-            let config = load_config(&flags.config)?;
-            setup_logger(&config.log_level);
-            ignore_signals().await?;
-            // Current path already exists: must be
-            // a remain from an interrupted execution.
-            // Run the diff to the previous version.
-            let cur_path = config.state_dir.join(CUR_AGGREGATE);
-            if Path::new(&cur_path).exists() {
-                warn!("found preexisting current aggregate file; processing");
-                dyn_cfg(&cur_path, &config).await?;
-            }
-            dyn_cfg(&flags.aggregate, &config).await?;
-        }
-        Cmd::Estimate(flags) => {
-            let config = load_config(&flags.config)?;
-            if let Some(path) = flags.ipv4_prefixes {
-                let prefixes: BTreeSet<Ipv4Net> = load_prefixes(&path).await?;
-                let params = params::estimate_ipv4(&prefixes, &config.estimate);
-                println!("ipv4: {}", params);
-            }
-            if let Some(path) = flags.ipv6_prefixes {
-                let prefixes: BTreeSet<Ipv6Net> = load_prefixes(&path).await?;
-                let params = params::estimate_ipv6(&prefixes, &config.estimate);
-                println!("ipv6: {}", params);
-            }
-        }
-    }
+#[derive(Debug, Clone, Clap)]
+struct FibCmd {
+    #[clap(flatten)]
+    common: ConfigFlags,
+    #[clap(short, long, name = "FIB-FILE", parse(from_os_str))]
+    fib: PathBuf,
+    #[clap(long)]
+    bootstrap: bool,
+}
 
-    Ok(())
+#[derive(Debug, Clone, Clap)]
+struct LastRunCmd {
+    #[clap(flatten)]
+    common: ConfigFlags,
 }
 
-async fn ignore_signals() -> Result<(), io::Error> {
-    let mut alarm = signal(SignalKind::alarm())?;
-    let mut child = signal(SignalKind::child())?;
-    let mut hup = signal(SignalKind::hangup())?;
-    let mut int = signal(SignalKind::interrupt())?;
-    let mut io = signal(SignalKind::io())?;
-    let mut pipe = signal(SignalKind::pipe())?;
-    let mut quit = signal(SignalKind::quit())?;
-    let mut term = signal(SignalKind::terminate())?;
-    let mut usr1 = signal(SignalKind::user_defined1())?;
-    let mut usr2 = signal(SignalKind::user_defined2())?;
-    let mut winch = signal(SignalKind::window_change())?;
+#[derive(Debug, Clone, Clap)]
+struct HistoryCmd {
+    #[clap(flatten)]
+    common: ConfigFlags,
+    #[clap(long, name = "ID")]
+    show: Option<u64>,
+    #[clap(short = 'n', long, name = "NUM-RUNS")]
+    limit: Option<usize>,
+}
 
-    tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                Some(()) = alarm.recv() => info!("got sigalrm"),
-                Some(()) = child.recv() => info!("got sigchld"),
-                Some(()) = hup.recv() => info!("got sighup"),
-                Some(()) = int.recv() => info!("got sigint"),
-                Some(()) = io.recv() => info!("got sigio"),
-                Some(()) = pipe.recv() => info!("got sigpipe"),
-                Some(()) = quit.recv() => info!("got sigquit"),
-                Some(()) = term.recv() => info!("got sigterm"),
-                Some(()) = usr1.recv() => info!("got sigusr1"),
-                Some(()) = usr2.recv() => info!("got sigusr2"),
-                Some(()) = winch.recv() => info!("got sigwinch"),
-            };
-        }
-    });
+#[derive(Debug, Clone, Clap)]
+struct PruneCmd {
+    #[clap(flatten)]
+    common: ConfigFlags,
+    /// Removes scripts older than this number of seconds.
+    #[clap(long, name = "SECONDS")]
+    max_age: Option<u64>,
+    /// Keeps at most this number of scripts, newest first.
+    #[clap(long, name = "NUM-SCRIPTS")]
+    keep: Option<usize>,
+    /// Lists the scripts that would be removed without removing them.
+    #[clap(long)]
+    dry_run: bool,
+}
 
-    Ok(())
+#[derive(Debug, Clone, Clap)]
+struct TestRunCmd {
+    #[clap(flatten)]
+    common: ConfigFlags,
+    #[clap(short, long, name = "AGGREGATE", parse(from_os_str))]
+    aggregate: PathBuf,
+    #[clap(short, long, name = "OLD-AGGREGATE", parse(from_os_str))]
+    old: Option<PathBuf>,
+    #[clap(long, name = "RULES,TBL8S", parse(try_from_str = parse_params_reply))]
+    params: Option<(usize, usize)>,
+    #[clap(long, name = "DIR", parse(from_os_str))]
+    output: Option<PathBuf>,
 }
 
-fn load_config(path: impl AsRef<Path>) -> Result<Config, anyhow::Error> {
-    let path = path.as_ref();
-    let data = std::fs::read_to_string(path)
-        .with_context(|| format!("failed to read '{}'", path.display()))?;
-    let config: Config = serde_yaml::from_str(&data).context("configuration deserialize failed")?;
-    Ok(config)
+#[derive(Debug, Clone, Clap)]
+struct SuggestConfigCmd {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    /// The prefixes of a kind, in a file or directory.
+    #[clap(short, long = "feed", name = "KIND=PATH", required = true, parse(try_from_str = parse_feed))]
+    feeds: Vec<(String, PathBuf)>,
+    #[clap(long, name = "PERCENT")]
+    headroom_percent: Option<usize>,
+    /// Only loads the files matching this pattern from feed directories.
+    #[clap(long, name = "PATTERN", default_value = "*")]
+    glob: glob::Pattern,
+    /// Prints the gtctl settings the sizes are estimated with, as YAML,
+    /// instead of the Lua table.
+    #[clap(long)]
+    settings: bool,
 }
 
-async fn dyn_cfg(new_path: impl AsRef<Path>, config: &Config) -> Result<(), anyhow::Error> {
-    let cur_path = config.state_dir.join(CUR_AGGREGATE);
+#[derive(Debug, Clone, Clap)]
+struct Bench {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    #[clap(short, long, name = "AGGREGATE", parse(from_os_str))]
+    aggregate: PathBuf,
+    #[clap(short, long, name = "OLD-AGGREGATE", parse(from_os_str))]
+    old: Option<PathBuf>,
+    #[clap(short, long, name = "ITERATIONS", default_value = "10")]
+    iterations: usize,
+    #[clap(short, long, name = "WARMUP", default_value = "2")]
+    warmup: usize,
+}
 
-    fs::copy(&new_path, &cur_path).await.with_context(|| {
-        format!(
-            "failed to copy new aggregate '{}' to '{}'",
-            new_path.as_ref().display(),
-            cur_path.display()
-        )
-    })?;
+#[derive(Debug, Clone, Clap)]
+struct BrokerCmd {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    #[clap(short, long, name = "TARGET")]
+    target: Vec<String>,
+}
 
-    let (ipv4_aggregate, ipv6_aggregate) =
-        aggregate::deserialize(&cur_path).await.with_context(|| {
-            format!(
-                "failed to deserialize current aggregate from '{}'",
-                cur_path.display()
-            )
-        })?;
-    let new_bootstrap = Bootstrap::new(&ipv4_aggregate, &ipv6_aggregate);
+#[derive(Debug, Clone, Clap)]
+struct ProxyCmd {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    #[clap(short, long, name = "TARGET")]
+    target: Option<String>,
+}
 
-    let old_path = config.state_dir.join(OLD_AGGREGATE);
-    let (ipv4_aggregate, ipv6_aggregate) =
-        aggregate::deserialize(&old_path).await.with_context(|| {
-            format!(
-                "failed to deserialize old aggregate from '{}'",
-                old_path.display()
-            )
-        })?;
-    let old_bootstrap = Bootstrap::new(&ipv4_aggregate, &ipv6_aggregate);
+#[derive(Debug, Clone, Clap)]
+struct CtlCmd {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    #[clap(name = "COMMAND", possible_values = &["status", "pause", "resume", "apply-now"])]
+    command: control::Command,
+}
 
-    for (kind, new_ranges) in &new_bootstrap.ipv4 {
-        let empty = BTreeSet::new();
-        let old_ranges = old_bootstrap.ipv4.get(kind).unwrap_or(&empty);
-        run_ipv4(config, kind, &new_ranges, &old_ranges).await?;
-    }
+#[derive(Debug, Clone, Clap)]
+struct ShellCmd {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    #[clap(short, long, name = "TARGET")]
+    target: Option<String>,
+    #[clap(short, long, name = "SOCKET", parse(from_os_str))]
+    socket: Option<PathBuf>,
+    #[clap(long, name = "HISTORY-FILE", parse(from_os_str))]
+    history: Option<PathBuf>,
+}
 
-    for (kind, new_ranges) in &new_bootstrap.ipv6 {
-        let empty = BTreeSet::new();
-        let old_ranges = old_bootstrap.ipv6.get(kind).unwrap_or(&empty);
-        run_ipv6(config, kind, &new_ranges, &old_ranges).await?;
-    }
+#[derive(Debug, Clone, Clap)]
+#[clap(group = ArgGroup::new("input").required(true))]
+struct SendCmd {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    #[clap(short, long, name = "TARGET")]
+    target: Option<String>,
+    #[clap(short, long, name = "SOCKET", parse(from_os_str))]
+    socket: Option<PathBuf>,
+    /// The script to send, or `-` to read it from the standard input.
+    #[clap(name = "SCRIPT", group = "input", parse(from_os_str))]
+    script: Option<PathBuf>,
+    /// Sends the given Lua code.
+    #[clap(short, long, name = "LUA", group = "input")]
+    eval: Option<String>,
+    /// Writes the reply, exactly as received, to this file.
+    #[clap(short, long, name = "OUTPUT", parse(from_os_str))]
+    output: Option<PathBuf>,
+}
 
-    fs::rename(&cur_path, &old_path).await.with_context(|| {
-        format!(
-            "failed to rename '{}' to '{}'",
-            cur_path.display(),
-            old_path.display()
-        )
-    })?;
+#[derive(Debug, Clone, Clap)]
+struct ReplayCmd {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    /// Sends the scripts to this socket instead of a mock server.
+    #[clap(short, long, name = "SOCKET", parse(from_os_str))]
+    socket: Option<PathBuf>,
+    /// The reply of the mock server.
+    #[clap(short, long, name = "REPLY", default_value = "")]
+    reply: String,
+    /// The capture file to replay.
+    #[clap(name = "CAPTURE", parse(from_os_str))]
+    capture: PathBuf,
+}
 
-    Ok(())
+#[derive(Debug, Clone, Clap)]
+struct TemplateCmd {
+    #[clap(subcommand)]
+    command: TemplateSubCmd,
 }
 
-async fn run_ipv4(
-    config: &Config,
-    kind: &Option<String>,
-    new: &BTreeSet<&Entry<Ipv4Net>>,
-    old: &BTreeSet<&Entry<Ipv4Net>>,
-) -> Result<(), anyhow::Error> {
-    run(
-        config,
-        &config.lpm.ipv4,
-        "ipv4",
-        kind,
-        &new,
-        &old,
-        params::estimate_ipv4,
-        Diff::ipv4,
-    )
-    .await
+#[derive(Debug, Clone, Clap)]
+enum TemplateSubCmd {
+    Test(TemplateTestCmd),
 }
 
-async fn run_ipv6(
-    config: &Config,
-    kind: &Option<String>,
-    new: &BTreeSet<&Entry<Ipv6Net>>,
-    old: &BTreeSet<&Entry<Ipv6Net>>,
-) -> Result<(), anyhow::Error> {
-    run(
-        config,
-        &config.lpm.ipv6,
-        "ipv6",
-        kind,
-        &new,
-        &old,
-        params::estimate_ipv6,
-        Diff::ipv6,
-    )
-    .await
+#[derive(Debug, Clone, Clap)]
+struct TemplateTestCmd {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    #[clap(short, long, name = "FIXTURE", parse(from_os_str))]
+    fixture: Option<PathBuf>,
+    #[clap(short, long, name = "TARGET")]
+    target: Option<String>,
+    #[clap(long, name = "LUAC", default_value = "luac")]
+    luac: String,
+    #[clap(long = "var", name = "KEY=VALUE", parse(try_from_str = parse_var))]
+    vars: Vec<(String, serde_yaml::Value)>,
 }
 
-#[derive(Debug, Serialize)]
-struct ParametersScriptVariables<'a> {
-    lpm_table: &'a str,
-    lpm_params_function: &'a str,
+#[derive(Debug, Clone, Clap)]
+#[clap(group = ArgGroup::new("output").required(true))]
+struct CompletionsCmd {
+    #[clap(
+        short,
+        long,
+        name = "FILE",
+        default_value = "/etc/gtctl/gtctl.conf",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+    #[clap(name = "SHELL", possible_values = &["bash", "zsh", "fish"], group = "output")]
+    shell: Option<String>,
+    // Used by the bash completions.
+    #[clap(long, name = "NAMES", possible_values = &["targets", "kinds"], group = "output", hidden = true)]
+    list: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-struct ReplaceModeVariables<'a, T> {
-    params: &'a Params<T>,
-    lpm_table: &'a str,
-    lpm_table_constructor: &'a str,
-    proto: &'a str,
+#[derive(Debug, Clone, Clap)]
+struct GenDocsCmd {
+    /// The directory the documentation is written to.
+    #[clap(short, long, name = "DIR", default_value = ".", parse(from_os_str))]
+    output: PathBuf,
 }
 
-#[derive(Debug, Serialize)]
-struct UpdateModeVariables<'a> {
-    lpm_table: &'a str,
-    proto: &'a str,
+#[derive(Debug, Clone, Clap)]
+struct VersionCmd {
+    /// Prints build details as JSON.
+    #[clap(long)]
+    detail: bool,
 }
 
-async fn run<'changes, 'ranges: 'changes, T>(
-    config: &Config,
-    lua_functions: &LuaFunctions,
-    proto: &str,
-    kind: &Option<String>,
-    new_ranges: &'ranges BTreeSet<&Entry<T>>,
-    old_ranges: &'ranges BTreeSet<&Entry<T>>,
-    estimate: impl Fn(&BTreeSet<T>, &EstimateConfig) -> Params<T>,
-    make_diff: impl Fn(Changes<'changes, T>) -> Diff<'changes>,
-) -> Result<(), anyhow::Error>
-where
-    T: Ord + Serialize + Copy + Debug,
-{
-    let table = replace_vars(&config.lpm.table_format, proto, kind);
-    let vars = ParametersScriptVariables {
-        lpm_table: &table,
-        lpm_params_function: &lua_functions.lpm_get_params_function,
-    };
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let opts = Opts::parse();
+    let format = opts.format;
+    let res = execute(opts).await;
+    // Everything is dropped by now, e.g. leases are released, so exiting
+    // skips nothing.
+    if let Some(Exit(status)) = res.as_ref().err().and_then(|e| e.downcast_ref()) {
+        process::exit(*status);
+    }
+    match res {
+        Err(e) if format == OutputFormat::Json => {
+            eprintln!(
+                "{}",
+                serde_json::to_string(&ErrorReport::new(None, e.as_ref()))?
+            );
+            process::exit(1);
+        }
+        res => res,
+    }
+}
 
-    let script =
-        render_parameters_script(&config.lpm.parameters_script, proto, kind, &vars)
-            .await
+async fn execute(mut opts: Opts) -> Result<(), anyhow::Error> {
+    term::init();
+    let verbosity = opts.verbose - opts.quiet;
+    let format = opts.format;
+    if format == OutputFormat::Lua && !matches!(opts.command, Cmd::Estimate(_)) {
+        return Err(anyhow!("the lua format is only supported by estimate"));
+    }
+    if let Some(level) = opts.log_level.take() {
+        opts.set
+            .push(("log_level".to_owned(), serde_yaml::Value::from(level)));
+    }
+
+    match opts.command {
+        Cmd::Dyncfg(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            if flags.dry_run {
+                show_diff(&config, &flags.aggregate, None, &flags.common.target, false).await?;
+                return Ok(());
+            }
+            if flags.check {
+                if show_diff(&config, &flags.aggregate, None, &flags.common.target, true).await? {
+                    return Err(Exit(CHANGES_PENDING).into());
+                }
+                return Ok(());
+            }
+            if let Some(statsd) = &config.statsd {
+                metrics::init(statsd).context("failed to set up statsd metrics")?;
+            }
+            util::set_durable(config.durable);
+            ignore_signals().await?;
+            let opts = RunOptions {
+                recover: flags.recover,
+                bootstrap: flags.bootstrap,
+                ..Default::default()
+            };
+            let results = run::apply(&config, &flags.aggregate, &flags.common.target, opts).await?;
+            report_results(&config, format, &results)?;
+        }
+        Cmd::Plan(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            let key = signing_key(&config).await?;
+            let (plan, runs) =
+                run::make_plan(&config, &flags.aggregate, &flags.common.target).await?;
+            plan::write(&flags.out, &plan, key.as_deref())
+                .await
+                .with_context(|| format!("failed to write plan to '{}'", flags.out.display()))?;
+            for (target, tables) in plan.targets.iter().zip(&runs) {
+                if let Some(name) = &target.target {
+                    println!("{}:", name);
+                }
+                for upload in &target.bpf {
+                    println!("bpf program {} at index {}", upload.program, upload.index);
+                }
+                for line in summary_lines(tables) {
+                    println!("{}", line);
+                }
+            }
+        }
+        Cmd::Apply(flags) => {
+            // The plan records the targets and variables it was made with.
+            if !flags.common.target.is_empty() || !flags.common.vars.is_empty() {
+                return Err(anyhow!(
+                    "--target and --var can't be given when applying a plan"
+                ));
+            }
+            let mut config = effective_config(&flags.common, &opts.set)?;
+            let key = signing_key(&config).await?;
+            if key.is_none() && !flags.allow_unsigned {
+                return Err(anyhow!(
+                    "no plan key is configured to verify the plan with; use --allow-unsigned to apply it anyway"
+                ));
+            }
+            let plan = plan::read(&flags.plan, key.as_deref())
+                .await
+                .with_context(|| format!("failed to read plan from '{}'", flags.plan.display()))?;
+            config.var_overrides = plan.vars.clone();
+            setup_logger(&config, verbosity)?;
+            if let Some(statsd) = &config.statsd {
+                metrics::init(statsd).context("failed to set up statsd metrics")?;
+            }
+            util::set_durable(config.durable);
+            ignore_signals().await?;
+            let results = run::apply_plan(&config, &plan).await?;
+            report_results(&config, format, &results)?;
+        }
+        Cmd::Diff(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            show_diff(
+                &config,
+                &flags.aggregate,
+                flags.old.as_deref(),
+                &flags.common.target,
+                flags.summary,
+            )
+            .await?;
+        }
+        Cmd::Watch(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            if let Some(statsd) = &config.statsd {
+                metrics::init(statsd).context("failed to set up statsd metrics")?;
+            }
+            util::set_durable(config.durable);
+            watch(&flags, &config).await?;
+        }
+        Cmd::Bootstrap(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            if let Some(statsd) = &config.statsd {
+                metrics::init(statsd).context("failed to set up statsd metrics")?;
+            }
+            util::set_durable(config.durable);
+            ignore_signals().await?;
+            bootstrap(&config, &flags).await?;
+        }
+        Cmd::Fib(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            if let Some(statsd) = &config.statsd {
+                metrics::init(statsd).context("failed to set up statsd metrics")?;
+            }
+            util::set_durable(config.durable);
+            ignore_signals().await?;
+            // A failed target doesn't keep the others from being updated.
+            let mut results = Vec::new();
+            for (name, config) in select_targets(&config, &flags.common.target)? {
+                if let Some(name) = &name {
+                    info!("processing target {}", name);
+                }
+                let res = update_fib(&config, &flags).await;
+                if let (Some(name), Err(e)) = (&name, &res) {
+                    error!("failed to update the FIB of target {}: {:#}", name, e);
+                }
+                results.push((name, res));
+            }
+            // Without named targets, the single error is returned as is.
+            if results.len() == 1 && results[0].0.is_none() {
+                return results.remove(0).1;
+            }
+            check_results(&results)?;
+        }
+        Cmd::SuggestConfig(flags) => {
+            let config = load_config(&flags.config, &opts.set)?;
+            suggest_config(&config, &flags).await?;
+        }
+        Cmd::Estimate(flags) => {
+            let mut config = load_config(&flags.config, &opts.set)?;
+            if let Some(factor) = flags.rules_scale {
+                config.estimate.rules_scaling_factor = factor;
+            }
+            if let Some(factor) = flags.tbl8s_scale {
+                config.estimate.tbl8s_scaling_factor = factor;
+            }
+            if let Some(percent) = flags.headroom_percent {
+                config.estimate.headroom_percent = percent;
+            }
+            if let Some(path) = flags.ipv4_prefixes {
+                let prefixes: BTreeSet<Ipv4Net> = load_prefix_input(&path, &flags.glob).await?;
+                let params = params::estimate_ipv4(&prefixes, &config.estimate);
+                if format == OutputFormat::Lua {
+                    print!("{}", lua_params(&params, "ipv4", &config));
+                } else {
+                    println!("ipv4: {}", params);
+                    if config.estimate.rounding != Rounding::None {
+                        let rounded = params.rounded_for("ipv4", &config.estimate);
+                        println!("  rounded: {}", rounded);
+                    }
+                    print_limit_warnings(&params, "ipv4", &config);
+                    if flags.detail {
+                        print_breakdown(
+                            &params::breakdown_ipv4(&prefixes, &config.estimate),
+                            flags.top,
+                        );
+                    }
+                }
+            }
+            if let Some(path) = flags.ipv6_prefixes {
+                let prefixes: BTreeSet<Ipv6Net> = load_prefix_input(&path, &flags.glob).await?;
+                let params = params::estimate_ipv6(&prefixes, &config.estimate);
+                if format == OutputFormat::Lua {
+                    print!("{}", lua_params(&params, "ipv6", &config));
+                } else {
+                    println!("ipv6: {}", params);
+                    if config.estimate.rounding != Rounding::None {
+                        let rounded = params.rounded_for("ipv6", &config.estimate);
+                        println!("  rounded: {}", rounded);
+                    }
+                    print_limit_warnings(&params, "ipv6", &config);
+                    if flags.detail {
+                        print_breakdown(
+                            &params::breakdown_ipv6(&prefixes, &config.estimate),
+                            flags.top,
+                        );
+                    }
+                }
+            }
+            if let (Some(old), Some(new)) = (&flags.old, &flags.new) {
+                let old: BTreeSet<IpNet> = load_prefix_input(old, &flags.glob).await?;
+                let new: BTreeSet<IpNet> = load_prefix_input(new, &flags.glob).await?;
+                let (old4, old6) = split_prefixes(&old);
+                let (new4, new6) = split_prefixes(&new);
+                if !old4.is_empty() || !new4.is_empty() {
+                    print_estimate_delta(
+                        "ipv4",
+                        &old4,
+                        &new4,
+                        &config,
+                        params::estimate_ipv4,
+                        params::tbl8s_ipv4,
+                    );
+                }
+                if !old6.is_empty() || !new6.is_empty() {
+                    print_estimate_delta(
+                        "ipv6",
+                        &old6,
+                        &new6,
+                        &config,
+                        params::estimate_ipv6,
+                        params::tbl8s_ipv6,
+                    );
+                }
+            }
+        }
+        Cmd::LastRun(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            print_last_run(&config, &flags.common.target).await?;
+        }
+        Cmd::History(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            print_history(&config, &flags).await?;
+        }
+        Cmd::Prune(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            prune_scripts(&config, &flags).await?;
+        }
+        Cmd::TestRun(flags) => {
+            let config = effective_config(&flags.common, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            test_run(&config, &flags).await?;
+        }
+        Cmd::Bench(flags) => {
+            let config = load_config(&flags.config, &opts.set)?;
+            bench(&flags, &config).await?;
+        }
+        Cmd::Broker(flags) => {
+            let config = load_config(&flags.config, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            if let Some(statsd) = &config.statsd {
+                metrics::init(statsd).context("failed to set up statsd metrics")?;
+            }
+            broker(&config, &flags.target).await?;
+        }
+        Cmd::Proxy(flags) => {
+            let config = load_config(&flags.config, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            if let Some(statsd) = &config.statsd {
+                metrics::init(statsd).context("failed to set up statsd metrics")?;
+            }
+            let config = match &flags.target {
+                Some(name) => config
+                    .target(name)
+                    .ok_or_else(|| anyhow!("target '{}' not found", name))?,
+                None => config,
+            };
+            run_proxy(&config).await?;
+        }
+        Cmd::Ctl(flags) => {
+            let config = load_config(&flags.config, &opts.set)?;
+            let socket = match &config.control {
+                Some(control) => &control.socket,
+                None => return Err(anyhow!("no control socket configured")),
+            };
+            let status = control::send(socket, flags.command)
+                .await
+                .with_context(|| format!("failed to send command to '{}'", socket.display()))?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+        Cmd::Send(flags) => {
+            let config = load_config(&flags.config, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            let config = match &flags.target {
+                Some(name) => config
+                    .target(name)
+                    .ok_or_else(|| anyhow!("target '{}' not found", name))?,
+                None => config,
+            };
+            let (socket, dyncfg_config) = match &flags.socket {
+                Some(socket) => (socket.as_path(), Cow::Borrowed(&config.dyncfg)),
+                None => (config.dyncfg_socket(), config.dyncfg_config()),
+            };
+            let script = read_send_script(&flags).await?;
+            let reply = dyncfg::send_config_raw(socket, &script, &dyncfg_config)
+                .await
+                .with_context(|| format!("failed to send script to '{}'", socket.display()))?;
+            if let Some(output) = &flags.output {
+                safe_write(output, &reply)
+                    .await
+                    .with_context(|| format!("failed to write reply to '{}'", output.display()))?;
+            } else if dyncfg_config.reply_decoding == ReplyDecoding::Raw {
+                use std::io::Write;
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&reply)?;
+                stdout.flush()?;
+            } else {
+                let reply = dyncfg::decode(reply, &dyncfg_config)?;
+                println!("{}", reply.trim_end());
+            }
+        }
+        Cmd::Replay(flags) => {
+            let config = load_config(&flags.config, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            replay(&config, &flags).await?;
+        }
+        Cmd::Shell(flags) => {
+            let config = load_config(&flags.config, &opts.set)?;
+            setup_logger(&config, verbosity)?;
+            let config = match &flags.target {
+                Some(name) => config
+                    .target(name)
+                    .ok_or_else(|| anyhow!("target '{}' not found", name))?,
+                None => config,
+            };
+            let (socket, dyncfg_config) = match &flags.socket {
+                Some(socket) => (socket.as_path(), Cow::Borrowed(&config.dyncfg)),
+                None => (config.dyncfg_socket(), config.dyncfg_config()),
+            };
+            let history = flags
+                .history
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE)));
+            Shell::new(socket, history, dyncfg_config.into_owned())
+                .run()
+                .await?;
+        }
+        Cmd::Completions(flags) => match (flags.shell.as_deref(), flags.list.as_deref()) {
+            (Some(shell), _) => print_completions(shell),
+            (None, Some(names)) => {
+                let config = load_config(&flags.config, &opts.set)?;
+                for name in completion_names(&config, names) {
+                    println!("{}", name);
+                }
+            }
+            (None, None) => unreachable!("BUG: missing required argument group"),
+        },
+        Cmd::Template(TemplateCmd {
+            command: TemplateSubCmd::Test(flags),
+        }) => {
+            let mut config = load_config(&flags.config, &opts.set)?;
+            config.var_overrides.extend(flags.vars.clone());
+            setup_logger(&config, verbosity)?;
+            template_test(&config, &flags).await?;
+        }
+        Cmd::GenDocs(flags) => gen_docs(&flags.output).await?,
+        Cmd::Version(flags) => {
+            if flags.detail {
+                let info = BuildInfo::current();
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                println!("{} {}", crate_name!(), crate_version!());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Prints the summary of every target applied, failing if any target failed
+// or exceeded its limits.
+fn report_results(
+    config: &Config,
+    format: OutputFormat,
+    results: &[TargetResult],
+) -> Result<(), anyhow::Error> {
+    // Without named targets, the single error is returned as is.
+    if let [(None, Err(e))] = results {
+        if format == OutputFormat::Json {
+            report_failed_targets(results)?;
+            return Err(Exit(1).into());
+        }
+        return Err(anyhow!("{:#}", e));
+    }
+    for (name, res) in results {
+        if let Ok(tables) = res {
+            if let Some(name) = name {
+                println!("{}:", name);
+            }
+            for line in summary_lines(tables) {
+                println!("{}", line);
+            }
+        }
+    }
+    // The failures are already reported, so they aren't reported again as an
+    // error.
+    if format == OutputFormat::Json && results.iter().any(|(_, res)| res.is_err()) {
+        report_failed_targets(results)?;
+        return Err(Exit(1).into());
+    }
+    check_results(results)?;
+    let tables: Vec<&TableRun> = results
+        .iter()
+        .filter_map(|(_, res)| res.as_ref().ok())
+        .flatten()
+        .collect();
+    check_limit_warnings(config, &tables)
+}
+
+async fn signing_key(config: &Config) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    match &config.plan {
+        Some(plan) => {
+            let key = fs::read(&plan.key_file).await.with_context(|| {
+                format!("failed to read plan key from '{}'", plan.key_file.display())
+            })?;
+            Ok(Some(key))
+        }
+        None => Ok(None),
+    }
+}
+
+// Fails if any target failed, after every target was processed.
+fn check_results<T, E>(results: &[(Option<String>, Result<T, E>)]) -> Result<(), anyhow::Error> {
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|(_, res)| res.is_err())
+        .map(|(name, _)| name.as_deref().unwrap_or("default"))
+        .collect();
+    match failed.len() {
+        0 => Ok(()),
+        n => Err(anyhow!(
+            "failed to apply {} of {} targets: {}",
+            n,
+            results.len(),
+            failed.join(", ")
+        )),
+    }
+}
+
+// Formats the parameters as the variables of Gatekeeper's GK configuration,
+// rounded if so configured, with limit warnings as comments.
+fn lua_params<T>(params: &Params<T>, proto: &str, config: &Config) -> String {
+    let params = params.rounded_for(proto, &config.estimate);
+    let mut lua = format!("-- {} LPM parameters estimated by gtctl.\n", proto);
+    for w in limit_warnings(&params, proto, config) {
+        lua.push_str(&format!("-- warning: {}\n", w));
+    }
+    lua.push_str(&format!(
+        "local max_num_{}_rules = {}\nlocal num_{}_tbl8s = {}\n",
+        proto, params.num_rules, proto, params.num_tbl8s
+    ));
+    lua
+}
+
+fn print_limit_warnings<T>(params: &Params<T>, proto: &str, config: &Config) {
+    for w in limit_warnings(params, proto, config) {
+        println!("  warning: {}", w);
+    }
+}
+
+// Fails if any table approached its limits and warnings are fatal. The run
+// itself is not affected, so this is checked once it is complete.
+fn check_limit_warnings(config: &Config, tables: &[&TableRun]) -> Result<(), anyhow::Error> {
+    let warned: Vec<&str> = tables
+        .iter()
+        .filter(|t| !t.limit_warnings.is_empty())
+        .map(|t| t.table.as_str())
+        .collect();
+    if config.limits.fail_on_warning && !warned.is_empty() {
+        return Err(anyhow!(
+            "tables approaching their limits: {}",
+            warned.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+// Performs the first full load of each target, creating its tables from
+// the aggregate regardless of their current parameters. Targets that were
+// already loaded are refused unless forced, since the steady-state path
+// should be used for them instead.
+async fn bootstrap(config: &Config, flags: &BootstrapCmd) -> Result<(), anyhow::Error> {
+    let targets = select_targets(config, &flags.common.target)?;
+    // Every target is checked before any is loaded, so that refusing one
+    // doesn't leave the others loaded.
+    if !flags.force {
+        let mut loaded = Vec::new();
+        for (name, config) in &targets {
+            let old_path = config.state_dir.join(OLD_AGGREGATE);
+            if aggregate::deserialize(&old_path).await.is_ok() {
+                loaded.push((name, old_path));
+            }
+        }
+        match loaded.as_slice() {
+            [] => {}
+            [(None, old_path)] => {
+                return Err(anyhow!(
+                    "found old aggregate '{}'; target was already bootstrapped, use --force to load it again",
+                    old_path.display()
+                ));
+            }
+            _ => {
+                let loaded: Vec<String> = loaded
+                    .iter()
+                    .map(|(name, old_path)| {
+                        let name = name.as_deref().unwrap_or("default");
+                        format!("{} ('{}')", name, old_path.display())
+                    })
+                    .collect();
+                return Err(anyhow!(
+                    "found old aggregates of targets {}; they were already bootstrapped, use --force to load them again",
+                    loaded.join(", ")
+                ));
+            }
+        }
+    }
+
+    let mut results = Vec::new();
+    for (name, config) in targets {
+        if let Some(name) = &name {
+            info!("bootstrapping target {}", name);
+        }
+        let res = bootstrap_target(&config, &flags.aggregate).await;
+        if let (Some(name), Err(e)) = (&name, &res) {
+            error!("failed to bootstrap target {}: {:#}", name, e);
+        }
+        results.push((name, res));
+    }
+    // Without named targets, the single error is returned as is.
+    if results.len() == 1 && results[0].0.is_none() {
+        results.remove(0).1?;
+    }
+    for (name, res) in &results {
+        if let Ok(tables) = res {
+            if let Some(name) = name {
+                println!("{}:", name);
+            }
+            for t in tables {
+                println!(
+                    "{} ({}): {} ranges, rules={}, tbl8s={}, {} scripts",
+                    t.table, t.proto, t.inserted, t.num_rules, t.num_tbl8s, t.scripts
+                );
+            }
+        }
+    }
+    check_results(&results)?;
+    let warned: Vec<&TableRun> = results
+        .iter()
+        .filter_map(|(_, res)| res.as_ref().ok())
+        .flatten()
+        .collect();
+    check_limit_warnings(config, &warned)
+}
+
+async fn bootstrap_target(
+    config: &Config,
+    aggregate: &Path,
+) -> Result<Vec<TableRun>, anyhow::Error> {
+    let opts = RunOptions {
+        initial_load: true,
+        ..Default::default()
+    };
+    create_dirs(config).await?;
+    // Queued scripts target the tables about to be recreated.
+    if config.retry_queue.is_some() {
+        let queue = RetryQueue::open(config.state_dir.join(RETRY_QUEUE)).await?;
+        let dropped = queue.clear().await.context("failed to clear retry queue")?;
+        if dropped > 0 {
+            warn!("dropped {} queued scripts", dropped);
+        }
+    }
+    Ok(dyn_cfg(aggregate, config, opts).await?)
+}
+
+// Brings the FIB to the state described in the given file, sending only the
+// changes relative to the state applied by the previous run.
+async fn update_fib(config: &Config, flags: &FibCmd) -> Result<(), anyhow::Error> {
+    let fib_config = config
+        .fib
+        .as_ref()
+        .ok_or_else(|| anyhow!("no fib section in configuration"))?;
+    create_dirs(config).await?;
+
+    let new = fib::load(&flags.fib)
+        .await
+        .with_context(|| format!("failed to load FIB from '{}'", flags.fib.display()))?;
+    let old_path = config.state_dir.join(OLD_FIB);
+    let old = match fib::load(&old_path).await {
+        Ok(old) => old,
+        Err(e) if flags.bootstrap => {
+            let e = anyhow::Error::from(e);
+            warn!(
+                "no valid old FIB in '{}' ({:#}); bootstrapping",
+                old_path.display(),
+                e
+            );
+            FibState::new()
+        }
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!(
+                    "failed to load old FIB from '{}'; use --bootstrap for an initial full load",
+                    old_path.display()
+                )
+            });
+        }
+    };
+
+    let diff = fib::diff(&old, &new);
+    if diff.is_empty() {
+        info!("no FIB changes");
+        return Ok(());
+    }
+    info!(
+        "updating FIB: {} added, {} updated, {} removed",
+        diff.add.len(),
+        diff.update.len(),
+        diff.remove.len()
+    );
+
+    let chunks = diff.chunks(fib_config.max_entries_per_file);
+    let template = fs::read_to_string(&fib_config.input)
+        .await
+        .with_context(|| format!("failed to read '{}'", fib_config.input.display()))?;
+    let mut scripts = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let vars = FibVariables {
+            extra: &config.extra_vars.fib,
+            diff: chunk,
+            script_index: i,
+            is_first_script: i == 0,
+            is_last_script: i == chunks.len() - 1,
+            overrides: &config.var_overrides,
+        };
+        let output = script_path(config, &fib_config.output.replace("{i}", &i.to_string()));
+        let output = PathBuf::from(output);
+        let script = render_template(config, &template, &vars, &output)
+            .await
+            .with_context(|| format!("failed to render FIB script '{}'", output.display()))?;
+        scripts.push(script);
+    }
+    let paths: Vec<PathBuf> = scripts.iter().filter_map(|s| s.path.clone()).collect();
+    if config.durable {
+        util::sync_files(&paths)
+            .await
+            .context("failed to sync rendered scripts")?;
+    }
+
+    // Scripts sent by an interrupted update of the same changes aren't sent
+    // again.
+    let scripts_hash = state::hash_lines(scripts.iter().map(|s| state::hash_data(&s.data)));
+    let sent = match state::read_fib_progress(&config.state_dir)
+        .await
+        .context("failed to read FIB progress")?
+    {
+        Some(progress) if progress.scripts_hash == scripts_hash => {
+            warn!(
+                "resuming interrupted FIB update with {} of {} scripts already sent",
+                progress.sent,
+                scripts.len()
+            );
+            progress.sent
+        }
+        _ => 0,
+    };
+    for (i, script) in scripts.iter().enumerate().skip(sent) {
+        dyncfg::send_config_bytes(
+            config.dyncfg_socket(),
+            &script.data,
+            &config.dyncfg_config(),
+        )
+        .await
+        .with_context(|| format!("failed to send FIB script {}", i))?;
+        metrics::count("fib_scripts_sent", 1, &[]);
+        let progress = state::FibProgress {
+            scripts_hash: scripts_hash.clone(),
+            sent: i + 1,
+        };
+        state::write_fib_progress(&config.state_dir, &progress)
+            .await
+            .context("failed to record FIB progress")?;
+        if let (RemovePolicy::Always, Some(path)) = (config.remove_rendered_scripts, &script.path) {
+            remove_fib_script(path).await;
+        }
+    }
+
+    safe_copy(&flags.fib, &old_path).await.with_context(|| {
+        format!(
+            "failed to copy FIB '{}' to '{}'",
+            flags.fib.display(),
+            old_path.display()
+        )
+    })?;
+    state::clear_fib_progress(&config.state_dir)
+        .await
+        .context("failed to clear FIB progress")?;
+    if config.remove_rendered_scripts == RemovePolicy::OnSuccess {
+        for path in &paths {
+            remove_fib_script(path).await;
+        }
+    }
+    Ok(())
+}
+
+// The FIB is already updated when rendered scripts are removed, so failing
+// to remove them doesn't fail the update.
+async fn remove_fib_script(path: &Path) {
+    if let Err(e) = fs::remove_file(path).await {
+        warn!("failed to remove '{}': {}", path.display(), e);
+    }
+}
+
+// Applies the aggregate whenever its modification time changes. Termination
+// signals are only acted upon between applies, so a run is never interrupted
+// halfway through.
+async fn watch(flags: &Watch, config: &Config) -> Result<(), anyhow::Error> {
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut term = signal(SignalKind::terminate())?;
+
+    // Health checks go through the broker, if any, like the scripts do.
+    let health = Arc::new(Health::new(
+        config.dyncfg_socket().to_owned(),
+        config.dyncfg_config().into_owned(),
+        config
+            .health
+            .as_ref()
+            .and_then(|h| h.max_staleness)
+            .map(Duration::from_secs),
+    ));
+    if let Some(health_config) = &config.health {
+        let listener = TcpListener::bind(&health_config.listen)
+            .await
+            .with_context(|| format!("failed to listen on '{}'", health_config.listen))?;
+        tokio::spawn(health.clone().serve(listener));
+    }
+
+    let control = match &config.control {
+        Some(control_config) => {
+            let path = &control_config.socket;
+            let listener = util::bind_unix(path, control_config.mode)
+                .await
+                .with_context(|| format!("failed to listen on '{}'", path.display()))?;
+            let control = Arc::new(Control::new(health.clone()));
+            tokio::spawn(control.clone().serve(listener));
+            Some(control)
+        }
+        None => None,
+    };
+
+    let lease = match &config.lease {
+        Some(lease_config) => {
+            let holder = lease_config
+                .holder
+                .clone()
+                .unwrap_or_else(lease::default_holder);
+            let ttl = Duration::from_secs(lease_config.ttl);
+            let lease = Arc::new(Lease::new(&lease_config.path, holder, ttl));
+            match lease.renew().await {
+                Ok(true) => info!("acquired lease as {}", lease.holder()),
+                Ok(false) => info!("waiting for lease as {}", lease.holder()),
+                Err(e) => warn!("failed to acquire lease: {}", e),
+            }
+            let task = tokio::spawn(lease.clone().maintain());
+            Some((lease, task))
+        }
+        None => None,
+    };
+
+    let interval = Duration::from_secs(flags.interval);
+    let max_backoff = config
+        .retry_queue
+        .as_ref()
+        .map(|q| Duration::from_secs(q.max_backoff))
+        .unwrap_or(interval);
+    let mut delay = interval;
+    let mut last_modified = None;
+    let opts = RunOptions {
+        recover: flags.recover,
+        bootstrap: flags.bootstrap,
+        ..Default::default()
+    };
+    // Targets that failed to apply the current aggregate are retried on
+    // their own until they succeed or the aggregate changes. An empty list
+    // retries the top-level configuration when no targets are configured.
+    let mut retry: Option<Vec<String>> = None;
+    loop {
+        let modified = fs::metadata(&flags.aggregate)
+            .await
+            .and_then(|meta| meta.modified())
+            .ok();
+        let leader = lease
+            .as_ref()
+            .map_or(true, |(lease, _task)| lease.is_held());
+        let targets = if !leader {
+            // On takeover, the aggregate is applied again, picking up
+            // whatever the previous leader left in the shared state.
+            last_modified = None;
+            retry = None;
+            None
+        } else if modified.is_some() && modified != last_modified {
+            Some(flags.common.target.clone())
+        } else {
+            retry.clone()
+        };
+        let targets = match &control {
+            Some(control) => {
+                control.set_leader(leader);
+                if control.take_apply_now() {
+                    if leader {
+                        info!("applying on request");
+                        Some(flags.common.target.clone())
+                    } else {
+                        warn!("not applying on request, the lease is held elsewhere");
+                        targets
+                    }
+                } else if control.is_paused() {
+                    // The apply is held off, but the aggregate is still
+                    // considered changed when resuming.
+                    control.set_pending(targets);
+                    None
+                } else {
+                    control.set_pending(None);
+                    targets
+                }
+            }
+            None => targets,
+        };
+        if let Some(targets) = targets {
+            if let Some(control) = &control {
+                control.set_applying(true);
+            }
+            let res = run::apply(config, &flags.aggregate, &targets, opts)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|results| {
+                    last_modified = modified;
+                    retry = None;
+                    for (name, res) in &results {
+                        match res {
+                            Ok(tables) => {
+                                for line in summary_lines(tables) {
+                                    match name {
+                                        Some(name) => info!("{}: {}", name, line),
+                                        None => info!("{}", line),
+                                    }
+                                }
+                            }
+                            Err(_) => retry
+                                .get_or_insert_with(Vec::new)
+                                .extend(name.iter().cloned()),
+                        }
+                    }
+                    check_results(&results)
+                });
+            match res {
+                Ok(()) => {
+                    health.record_success();
+                    delay = interval;
+                }
+                Err(e) => {
+                    error!("failed to apply '{}': {:#}", flags.aggregate.display(), e);
+                    health.record_failure(format!("{:#}", e));
+                    // Back off exponentially while failures persist.
+                    delay = max(interval, min(delay * 2, max_backoff));
+                }
+            }
+            if let Some(control) = &control {
+                control.set_applying(false);
+            }
+        }
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = control_notified(&control) => {}
+            _ = int.recv() => break,
+            _ = term.recv() => break,
+        }
+    }
+
+    if let Some((lease, task)) = lease {
+        // Otherwise the lease could be renewed again right after its release.
+        task.abort();
+        let _ = task.await;
+        if let Err(e) = lease.release().await {
+            warn!("failed to release lease: {}", e);
+        }
+    }
+
+    if let Some(control_config) = &config.control {
+        let _ = fs::remove_file(&control_config.socket).await;
+    }
+
+    info!("exiting");
+    Ok(())
+}
+
+async fn control_notified(control: &Option<Arc<Control>>) {
+    match control {
+        Some(control) => control.notified().await,
+        None => future::pending().await,
+    }
+}
+
+// Runs a full dyncfg cycle against a mock dynamic configuration socket,
+// keeping state and rendered scripts in a scratch directory, and reports
+// what Gatekeeper would have received.
+async fn test_run(config: &Config, flags: &TestRunCmd) -> Result<(), anyhow::Error> {
+    let config = match flags.common.target.as_slice() {
+        [] => config.clone(),
+        [name] => config
+            .target(name)
+            .ok_or_else(|| anyhow!("target '{}' not found", name))?,
+        _ => return Err(anyhow!("test-run takes at most one target")),
+    };
+    let dir = tempfile::Builder::new()
+        .prefix("gtctl-test-run.")
+        .tempdir()
+        .context("failed to create test directory")?;
+    let path = dir.path().to_owned();
+    let res = test_run_in(config, flags, &path).await;
+    if let Err(e) = dir.close() {
+        warn!("failed to remove '{}': {}", path.display(), e);
+    }
+    res
+}
+
+async fn test_run_in(
+    mut config: Config,
+    flags: &TestRunCmd,
+    dir: &Path,
+) -> Result<(), anyhow::Error> {
+    scratch_config(&mut config, dir);
+    create_dirs(&config).await?;
+    let opts = RunOptions {
+        bootstrap: flags.old.is_none(),
+        ..Default::default()
+    };
+    if let Some(old) = &flags.old {
+        safe_copy(old, config.state_dir.join(OLD_AGGREGATE))
+            .await
+            .with_context(|| format!("failed to copy old aggregate '{}'", old.display()))?;
+    }
+
+    // Without given parameters, tables are reported large enough for any
+    // aggregate, so that they are updated.
+    let (rules, tbl8s) = flags
+        .params
+        .unwrap_or((u32::MAX as usize, u32::MAX as usize));
+    let server = MockServer::start(&config.socket, params_reply(&config, rules, tbl8s)).await?;
+    let tables = dyn_cfg(&flags.aggregate, &config, opts).await?;
+    let received = server.received();
+
+    for line in summary_lines(&tables) {
+        println!("{}", line);
+    }
+    let bytes: usize = received.iter().map(|script| script.len()).sum();
+    println!();
+    println!("received {} scripts, {} bytes", received.len(), bytes);
+    for (i, script) in received.iter().enumerate() {
+        let first = script.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+        println!("{:4}  {:6} bytes  {}", i, script.len(), first.trim());
+    }
+    if let Some(output) = &flags.output {
+        fs::create_dir_all(output).await?;
+        for (i, script) in received.iter().enumerate() {
+            let path = output.join(format!("{:04}.lua", i));
+            fs::write(&path, script)
+                .await
+                .with_context(|| format!("failed to write '{}'", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_params_reply(s: &str) -> Result<(usize, usize), anyhow::Error> {
+    let mut parts = s.splitn(2, ',');
+    let rules: usize = parts.next().unwrap_or("").trim().parse()?;
+    let tbl8s: usize = parts
+        .next()
+        .ok_or_else(|| anyhow!("expected RULES,TBL8S"))?
+        .trim()
+        .parse()?;
+    Ok((rules, tbl8s))
+}
+
+// The ranges of each protocol a template test is run with: the old ones
+// are replaced by the new ones.
+#[derive(Debug, Default, Deserialize)]
+struct Fixture {
+    #[serde(default)]
+    ipv4: FixtureRanges<Ipv4Net>,
+    #[serde(default)]
+    ipv6: FixtureRanges<Ipv6Net>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(bound(deserialize = "Entry<T>: Deserialize<'de>"))]
+struct FixtureRanges<T> {
+    #[serde(default)]
+    old: Vec<Entry<T>>,
+    #[serde(default)]
+    new: Vec<Entry<T>>,
+}
+
+impl<T> Default for FixtureRanges<T> {
+    fn default() -> FixtureRanges<T> {
+        FixtureRanges {
+            old: Vec::new(),
+            new: Vec::new(),
+        }
+    }
+}
+
+// A few documentation ranges for each configured kind, some of them
+// removed, kept and inserted, so that every template has something to
+// render.
+fn synthetic_fixture(config: &Config) -> Result<Fixture, anyhow::Error> {
+    let kinds: Vec<Option<&str>> = if config.kinds.is_empty() {
+        vec![Some("example")]
+    } else {
+        config.kinds.keys().map(|k| Some(k.as_str())).collect()
+    };
+    let entries = |ranges: &[&str]| -> Vec<serde_json::Value> {
+        kinds
+            .iter()
+            .flat_map(|kind| {
+                ranges.iter().map(move |range| {
+                    serde_json::json!({"range": range, "kind": kind, "class": "example"})
+                })
+            })
+            .collect()
+    };
+    let fixture = serde_json::json!({
+        "ipv4": {
+            "old": entries(&["192.0.2.0/24", "198.51.100.0/25", "203.0.113.0/24"]),
+            "new": entries(&["192.0.2.0/24", "198.51.100.128/25", "203.0.113.0/24", "203.0.113.64/26"]),
+        },
+        "ipv6": {
+            "old": entries(&["2001:db8:1::/48", "2001:db8:2::/48"]),
+            "new": entries(&["2001:db8:1::/48", "2001:db8:3::/48", "2001:db8:4::/64"]),
+        },
+    });
+    Ok(serde_json::from_value(fixture)?)
+}
+
+// Renders the configured templates against a fixture, first replacing and
+// then updating every table, and prints the scripts Gatekeeper would
+// receive, checking their syntax with luac.
+async fn template_test(config: &Config, flags: &TemplateTestCmd) -> Result<(), anyhow::Error> {
+    let config = match &flags.target {
+        Some(name) => config
+            .target(name)
+            .ok_or_else(|| anyhow!("target '{}' not found", name))?,
+        None => config.clone(),
+    };
+    let fixture = match &flags.fixture {
+        Some(path) => {
+            let data = fs::read_to_string(path)
+                .await
+                .with_context(|| format!("failed to read fixture '{}'", path.display()))?;
+            serde_yaml::from_str(&data)
+                .with_context(|| format!("failed to parse fixture '{}'", path.display()))?
+        }
+        None => synthetic_fixture(&config)?,
+    };
+    let dir = tempfile::Builder::new()
+        .prefix("gtctl-template-test.")
+        .tempdir()
+        .context("failed to create test directory")?;
+    let path = dir.path().to_owned();
+    let res = template_test_in(config, flags, &fixture, &path).await;
+    if let Err(e) = dir.close() {
+        warn!("failed to remove '{}': {}", path.display(), e);
+    }
+    res
+}
+
+async fn template_test_in(
+    config: Config,
+    flags: &TemplateTestCmd,
+    fixture: &Fixture,
+    dir: &Path,
+) -> Result<(), anyhow::Error> {
+    let mut luac = Some(flags.luac.as_str());
+    let mut errors = 0;
+    for (table, name, script) in template_test_scripts(config, fixture, dir).await? {
+        println!("-- {} {} ({} bytes)", table, name, script.len());
+        println!("{}", script.trim_end());
+        if let Some(path) = luac {
+            match check_lua(path, &script).await {
+                Ok(None) => {}
+                Ok(Some(e)) => {
+                    println!("-- syntax error: {}", e.trim_end());
+                    errors += 1;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    warn!("'{}' not found, skipping syntax checks", path);
+                    luac = None;
+                }
+                Err(e) => return Err(e).context("failed to run luac"),
+            }
+        }
+        println!();
+    }
+    if errors > 0 {
+        return Err(anyhow!("{} scripts with syntax errors", errors));
+    }
+    Ok(())
+}
+
+// Applies the fixture to every table against a mock Gatekeeper, first
+// replacing and then updating them, returning the table, role and contents
+// of each script sent.
+async fn template_test_scripts(
+    mut config: Config,
+    fixture: &Fixture,
+    dir: &Path,
+) -> Result<Vec<(String, String, String)>, anyhow::Error> {
+    scratch_config(&mut config, dir);
+    // Nothing may keep the tables from being replaced.
+    config.allow_replace = true;
+    config.replace_cooldown = None;
+    config.replace_windows.clear();
+    config.shrink = None;
+    for kind in config.kinds.values_mut() {
+        kind.allow_replace = None;
+        kind.replace_cooldown = None;
+        kind.replace_windows = None;
+    }
+    create_dirs(&config).await?;
+
+    let (mut new4, mut old4) = (
+        fixture_tables(&fixture.ipv4.new),
+        fixture_tables(&fixture.ipv4.old),
+    );
+    let (mut new6, mut old6) = (
+        fixture_tables(&fixture.ipv6.new),
+        fixture_tables(&fixture.ipv6.old),
+    );
+    let (new_embedded, old_embedded) = (
+        embedded_entries(&config, &new6),
+        embedded_entries(&config, &old6),
+    );
+    let routed = config.target_kinds.as_ref();
+    prepare_tables(&config, &mut new4, &mut new6, &new_embedded, routed);
+    prepare_tables(&config, &mut old4, &mut old6, &old_embedded, routed);
+    let mut kinds = Vec::new();
+    for (kind, ranges) in &new4 {
+        kinds.push(KindSummary::new(&config, "ipv4", kind, ranges.len()));
+    }
+    for (kind, ranges) in &new6 {
+        kinds.push(KindSummary::new(&config, "ipv6", kind, ranges.len()));
+    }
+
+    let mut scripts = Vec::new();
+    let (empty4, empty6) = (BTreeSet::new(), BTreeSet::new());
+    // Tables reported with no room are replaced, and tables reported large
+    // enough for anything are updated.
+    let large = u32::MAX as usize;
+    for (i, (rules, tbl8s)) in [(0, 0), (large, large)].iter().enumerate() {
+        config.socket = dir.join(format!("socket{}", i));
+        let server =
+            MockServer::start(&config.socket, params_reply(&config, *rules, *tbl8s)).await?;
+        let opts = RunOptions::default();
+        let mut runs = Vec::new();
+        for (kind, new) in &new4 {
+            let old = old4.get(kind).unwrap_or(&empty4);
+            let start = server.received().len();
+            runs.push((
+                run_ipv4(&config, opts, &kinds, kind, new, old).await?,
+                start,
+            ));
+        }
+        for (kind, new) in &new6 {
+            let old = old6.get(kind).unwrap_or(&empty6);
+            let start = server.received().len();
+            runs.push((
+                run_ipv6(&config, opts, &kinds, kind, new, old).await?,
+                start,
+            ));
+        }
+        let received = server.received();
+        for (j, (run, start)) in runs.iter().enumerate() {
+            let end = runs.get(j + 1).map_or(received.len(), |(_, next)| *next);
+            // Only LPM tables are sent a parameters script, which comes
+            // first and is the same in both passes. Hash tables are never
+            // replaced, so both passes send them the same scripts.
+            let lpm = config.kind(&run.kind).table_mode == TableMode::Lpm;
+            if i > 0 && !lpm {
+                continue;
+            }
+            for (k, script) in received[*start..end].iter().enumerate() {
+                let n = if lpm { k } else { k + 1 };
+                let name = match (n, run.mode) {
+                    (0, _) if i > 0 => continue,
+                    (0, _) => "parameters".to_owned(),
+                    (_, Mode::Replace) => format!("replace {}", n),
+                    (_, Mode::Update) => format!("update {}", n),
+                };
+                scripts.push((run.table.clone(), name, script.clone()));
+            }
+        }
+    }
+    Ok(scripts)
+}
+
+// Parses a script with `luac -p`, returning its error message if the script
+// is invalid.
+async fn check_lua(luac: &str, script: &str) -> Result<Option<String>, io::Error> {
+    use std::process::Stdio;
+    use tokio::io::AsyncWriteExt;
+    use tokio::process::Command;
+
+    let mut child = Command::new(luac)
+        .args(&["-p", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(script.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if output.status.success() {
+        Ok(None)
+    } else {
+        Ok(Some(String::from_utf8_lossy(&output.stderr).into_owned()))
+    }
+}
+
+// Completes the values of the -t and --target flags with the targets, and
+// the kinds of suggest-config's -f and --feed flags with the kinds,
+// configured in the file given by -c or --config, if any, falling back to
+// the generated completions otherwise. Only bash gets these, as the zsh and
+// fish completions are used as generated.
+const BASH_DYNAMIC_COMPLETIONS: &str = r#"
+_gtctl_names() {
+    local i config=()
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${COMP_WORDS[i]}" in
+            -c|--config) config=(-c "${COMP_WORDS[i+1]}") ;;
+        esac
+    done
+    gtctl completions --list "$1" "${config[@]}" 2>/dev/null
+}
+
+_gtctl_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}"
+    case "$prev" in
+        -t|--target)
+            COMPREPLY=($(compgen -W "$(_gtctl_names targets)" -- "$cur"))
+            return 0
+            ;;
+        -f|--feed)
+            if [[ " ${COMP_WORDS[*]} " == *" suggest-config "* ]]; then
+                COMPREPLY=($(compgen -S = -W "$(_gtctl_names kinds)" -- "$cur"))
+                compopt -o nospace
+                return 0
+            fi
+            ;;
+    esac
+    _gtctl "$@"
+}
+
+complete -F _gtctl_dynamic -o bashdefault -o default gtctl
+"#;
+
+fn print_completions(shell: &str) {
+    use clap_generate::generate;
+
+    let mut app = Opts::into_app();
+    let mut stdout = std::io::stdout();
+    match shell {
+        "bash" => {
+            generate::<Bash, _>(&mut app, crate_name!(), &mut stdout);
+            print!("{}", BASH_DYNAMIC_COMPLETIONS);
+        }
+        "zsh" => generate::<Zsh, _>(&mut app, crate_name!(), &mut stdout),
+        _ => generate::<Fish, _>(&mut app, crate_name!(), &mut stdout),
+    }
+}
+
+// Kinds are listed as tables are named, i.e. after any renaming by
+// `kind_map`.
+fn completion_names(config: &Config, names: &str) -> BTreeSet<String> {
+    match names {
+        "targets" => config.targets.keys().cloned().collect(),
+        _ => config
+            .kinds
+            .keys()
+            .chain(config.kind_map.values())
+            .cloned()
+            .collect(),
+    }
+}
+
+async fn read_send_script(flags: &SendCmd) -> Result<Vec<u8>, anyhow::Error> {
+    use tokio::io::AsyncReadExt;
+
+    match (&flags.eval, &flags.script) {
+        (Some(lua), _) => Ok(lua.as_bytes().to_vec()),
+        (None, Some(path)) if path.as_os_str() == "-" => {
+            let mut script = Vec::new();
+            io::stdin()
+                .read_to_end(&mut script)
+                .await
+                .context("failed to read script from standard input")?;
+            Ok(script)
+        }
+        (None, Some(path)) => fs::read(path)
+            .await
+            .with_context(|| format!("failed to read script '{}'", path.display())),
+        (None, None) => unreachable!("BUG: missing required argument group"),
+    }
+}
+
+async fn gen_docs(dir: &Path) -> Result<(), anyhow::Error> {
+    let app = Opts::into_app();
+    let docs = [
+        (
+            format!("{}.1", crate_name!()),
+            docs::man_page(&app, crate_version!()),
+        ),
+        ("cli.md".to_owned(), docs::cli_reference(&app)),
+        (
+            "config.md".to_owned(),
+            docs::config_reference::<Config>(&format!("{} configuration reference", crate_name!())),
+        ),
+    ];
+    fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("failed to create '{}'", dir.display()))?;
+    for (name, contents) in &docs {
+        let path = dir.join(name);
+        safe_write(&path, contents.as_bytes())
+            .await
+            .with_context(|| format!("failed to write '{}'", path.display()))?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+// The flags take precedence over the `prune` section, each setting on its
+// own.
+async fn prune_scripts(config: &Config, flags: &PruneCmd) -> Result<(), anyhow::Error> {
+    let defaults = config.prune.as_ref();
+    let retention = prune::Retention {
+        max_age: flags
+            .max_age
+            .or_else(|| defaults.and_then(|d| d.max_age))
+            .map(Duration::from_secs),
+        keep: flags.keep.or_else(|| defaults.and_then(|d| d.keep)),
+    };
+    if retention.max_age.is_none() && retention.keep.is_none() {
+        return Err(anyhow!("no retention given; use --max-age or --keep"));
+    }
+    let mut outputs = BTreeSet::new();
+    for (_, config) in select_targets(config, &flags.common.target)? {
+        outputs.extend(script_patterns(&config));
+    }
+    let outputs: Vec<String> = outputs.into_iter().collect();
+    let pruned = prune::prune(&outputs, retention, flags.dry_run)
+        .await
+        .context("failed to prune rendered scripts")?;
+    for artifact in &pruned {
+        println!("{}", artifact.path.display());
+    }
+    let bytes: u64 = pruned.iter().map(|a| a.size).sum();
+    if flags.dry_run {
+        info!("would remove {} scripts ({} bytes)", pruned.len(), bytes);
+    } else {
+        info!("removed {} scripts ({} bytes)", pruned.len(), bytes);
+        metrics::count("scripts_pruned", pruned.len() as u64, &[]);
+    }
+    Ok(())
+}
+
+async fn ignore_signals() -> Result<(), io::Error> {
+    let mut alarm = signal(SignalKind::alarm())?;
+    let mut child = signal(SignalKind::child())?;
+    let mut hup = signal(SignalKind::hangup())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut io = signal(SignalKind::io())?;
+    let mut pipe = signal(SignalKind::pipe())?;
+    let mut quit = signal(SignalKind::quit())?;
+    let mut term = signal(SignalKind::terminate())?;
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    let mut winch = signal(SignalKind::window_change())?;
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = alarm.recv() => info!("got sigalrm"),
+                Some(()) = child.recv() => info!("got sigchld"),
+                Some(()) = hup.recv() => info!("got sighup"),
+                Some(()) = int.recv() => info!("got sigint"),
+                Some(()) = io.recv() => info!("got sigio"),
+                Some(()) = pipe.recv() => info!("got sigpipe"),
+                Some(()) = quit.recv() => info!("got sigquit"),
+                Some(()) = term.recv() => info!("got sigterm"),
+                Some(()) = usr1.recv() => info!("got sigusr1"),
+                Some(()) = usr2.recv() => info!("got sigusr2"),
+                Some(()) = winch.recv() => info!("got sigwinch"),
+            };
+        }
+    });
+
+    Ok(())
+}
+
+// Overrides are applied to the parsed file so that they go through the
+// same validation as the configuration itself.
+fn load_config(
+    path: impl AsRef<Path>,
+    overrides: &[(String, serde_yaml::Value)],
+) -> Result<Config, anyhow::Error> {
+    // Wrapped in a category, so that error reports classify it.
+    Ok(config::load(path, overrides).map_err(error::Error::from)?)
+}
+
+// The configuration a command runs with, in its selected state namespace
+// and with the template variables given on the command line.
+fn effective_config(
+    flags: &ConfigFlags,
+    overrides: &[(String, serde_yaml::Value)],
+) -> Result<Config, anyhow::Error> {
+    let mut config = load_config(&flags.config, overrides)?;
+    set_state_namespace(&mut config, flags.state_name.as_ref())?;
+    config.var_overrides.extend(flags.vars.iter().cloned());
+    Ok(config)
+}
+
+// The namespace given on the command line takes precedence over the
+// configured one. Targets keep their state in subdirectories of the
+// namespace's directory.
+fn set_state_namespace(config: &mut Config, name: Option<&String>) -> Result<(), anyhow::Error> {
+    let name = match name.or_else(|| config.state_namespace.as_ref()) {
+        Some(name) => name.clone(),
+        None => return Ok(()),
+    };
+    if !config::is_valid_namespace(&name) {
+        return Err(anyhow!("invalid state namespace '{}'", name));
+    }
+    config.state_dir = config.state_dir.join(&name);
+    config.state_namespace = Some(name);
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct FibVariables<'a> {
+    #[serde(flatten)]
+    extra: &'a BTreeMap<String, serde_yaml::Value>,
+    #[serde(flatten)]
+    diff: &'a FibDiff<'a>,
+    script_index: usize,
+    is_first_script: bool,
+    is_last_script: bool,
+    #[serde(flatten)]
+    overrides: &'a BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ErrorClass {
+    Config,
+    Template,
+    Transport,
+    Gatekeeper,
+    Aggregate,
+    State,
+    Limit,
+    Plan,
+    Other,
+}
+
+/// A failure, as reported with `--format json`.
+#[derive(Debug, Serialize)]
+struct ErrorReport {
+    class: ErrorClass,
+    // The module the underlying error comes from.
+    module: Option<&'static str>,
+    target: Option<String>,
+    table: Option<String>,
+    chunk: Option<usize>,
+    script: Option<PathBuf>,
+    retryable: bool,
+    error: String,
+    causes: Vec<String>,
+}
+
+impl ErrorReport {
+    // Failures of the library are reported by their category, whatever
+    // context the command added to them.
+    fn new(target: Option<&str>, e: &(dyn std::error::Error + 'static)) -> ErrorReport {
+        let chain: Vec<_> = iter::successors(Some(e), |e| e.source()).collect();
+        let typed = chain.iter().find_map(|e| e.downcast_ref::<error::Error>());
+        let (class, module) = match typed {
+            Some(e) => classify_error(e),
+            None => (ErrorClass::Other, None),
+        };
+        let script = typed.and_then(error::Error::script);
+        let target = target.or_else(|| typed.and_then(error::Error::target));
+        ErrorReport {
+            class,
+            module,
+            target: target.map(ToOwned::to_owned),
+            table: typed.and_then(error::Error::table).map(ToOwned::to_owned),
+            chunk: script.map(|(_, chunk)| chunk),
+            script: script.map(|(script, _)| script.to_owned()),
+            retryable: typed.map_or(false, error::Error::is_retryable),
+            error: chain
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(": "),
+            causes: chain[1..].iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+fn classify_error(e: &error::Error) -> (ErrorClass, Option<&'static str>) {
+    match e.category() {
+        error::Error::Config(_) => (ErrorClass::Config, Some("config")),
+        error::Error::Template(_) => (ErrorClass::Template, Some("template")),
+        error::Error::Transport(_) => (ErrorClass::Transport, Some("dyncfg")),
+        error::Error::Gatekeeper(GatekeeperError::Reply(_)) => {
+            (ErrorClass::Gatekeeper, Some("params"))
+        }
+        error::Error::Gatekeeper(_) => (ErrorClass::Gatekeeper, Some("dyncfg")),
+        error::Error::Aggregate(_) => (ErrorClass::Aggregate, Some("aggregate")),
+        error::Error::State(_) => (ErrorClass::State, Some("state")),
+        error::Error::Limit(_) => (ErrorClass::Limit, Some("params")),
+        error::Error::Plan(_) => (ErrorClass::Plan, Some("plan")),
+        error::Error::Runtime(_) => (ErrorClass::Other, None),
+        // Contexts are skipped by `category`.
+        error::Error::Target(..) | error::Error::Table(..) | error::Error::Script(..) => {
+            (ErrorClass::Other, None)
+        }
+    }
+}
+
+// Prints a report for each failed target, one per line.
+fn report_failed_targets(results: &[TargetResult]) -> Result<(), anyhow::Error> {
+    for (name, res) in results {
+        if let Err(e) = res {
+            let report = ErrorReport::new(name.as_deref(), e);
+            eprintln!("{}", serde_json::to_string(&report)?);
+        }
+    }
+    Ok(())
+}
+
+fn setup_logger(config: &Config, verbosity: i64) -> Result<(), anyhow::Error> {
+    use env_logger::{Builder, Target, WriteStyle};
+
+    let level = adjust_level(config.log_level, verbosity);
+    let filter = LogFilter::new(level, config.log_filters.clone());
+    let target = config.log_target.unwrap_or(if config.log_file.is_some() {
+        LogTarget::File
+    } else {
+        LogTarget::Stdout
+    });
+
+    let logger: Box<dyn log::Log> = match target {
+        LogTarget::Syslog => {
+            Box::new(SyslogLogger::new(filter.clone()).context("failed to connect to syslog")?)
+        }
+        LogTarget::Journald => {
+            Box::new(JournaldLogger::new(filter.clone()).context("failed to connect to journald")?)
+        }
+        LogTarget::File => {
+            let log_file = config
+                .log_file
+                .as_ref()
+                .ok_or_else(|| anyhow!("log target is 'file' but no log_file is configured"))?;
+            let file = RotatingFile::open(log_file).with_context(|| {
+                format!("failed to open log file '{}'", log_file.path.display())
+            })?;
+            let mut builder = Builder::new();
+            builder.target(Target::Pipe(Box::new(file)));
+            builder.write_style(WriteStyle::Never);
+            for (module, level) in filter.directives() {
+                builder.filter_module(module, level);
+            }
+            Box::new(builder.build())
+        }
+        LogTarget::Stdout => {
+            let mut builder = Builder::new();
+            builder.target(Target::Stdout);
+            builder.write_style(if term::is_color_enabled() {
+                WriteStyle::Always
+            } else {
+                WriteStyle::Never
+            });
+            for (module, level) in filter.directives() {
+                builder.filter_module(module, level);
+            }
+            Box::new(builder.build())
+        }
+    };
+
+    log::set_boxed_logger(logger).context("failed to set logger")?;
+    log::set_max_level(filter.max_level());
+    Ok(())
+}
+
+// Shows the changes between the aggregate and the old one, which defaults
+// to the aggregate applied by the previous run of each target. Nothing is
+// sent or recorded.
+async fn show_diff(
+    config: &Config,
+    aggregate: &Path,
+    old: Option<&Path>,
+    targets: &[String],
+    summary: bool,
+) -> Result<bool, anyhow::Error> {
+    let (ipv4_aggregate, ipv6_aggregate) =
+        aggregate::deserialize(aggregate).await.with_context(|| {
+            format!(
+                "failed to deserialize aggregate from '{}'",
+                aggregate.display()
+            )
+        })?;
+    let (empty4, empty6) = (BTreeSet::new(), BTreeSet::new());
+
+    let mut changed = false;
+    for (name, config) in select_targets(config, targets)? {
+        let mut new = Bootstrap::new(&ipv4_aggregate, &ipv6_aggregate);
+        let new_embedded = embedded_entries(&config, &new.ipv6);
+        let routed = config.target_kinds.as_ref();
+        prepare_tables(&config, &mut new.ipv4, &mut new.ipv6, &new_embedded, routed);
+
+        if let Some(name) = name {
+            println!("{}:", name);
+        }
+        let (old_path, old_routed) = match old {
+            Some(path) => (path.to_owned(), config.target_kinds.clone()),
+            None => (
+                config.state_dir.join(OLD_AGGREGATE),
+                applied_routing(&config).await?,
+            ),
+        };
+        let old_aggregates = match aggregate::deserialize(&old_path).await {
+            Ok(aggregates) => Some(aggregates),
+            Err(e) => {
+                let e = anyhow::Error::from(e);
+                warn!(
+                    "no valid old aggregate in '{}' ({:#}); showing a full load",
+                    old_path.display(),
+                    e
+                );
+                None
+            }
+        };
+        let mut old = old_aggregates
+            .as_ref()
+            .map(|(ipv4, ipv6)| Bootstrap::new(ipv4, ipv6));
+        let old_embedded = match &old {
+            Some(old) => embedded_entries(&config, &old.ipv6),
+            None => Vec::new(),
+        };
+        if let Some(old) = &mut old {
+            prepare_tables(
+                &config,
+                &mut old.ipv4,
+                &mut old.ipv6,
+                &old_embedded,
+                old_routed.as_ref(),
+            );
+        }
+        // The diff is that of the ranges a run would apply.
+        validate_tables(&config, &new.ipv4, &new.ipv6)?;
+
+        let mut diffs = Vec::new();
+        let kinds: BTreeSet<_> = new
+            .ipv4
+            .keys()
+            .chain(old.iter().flat_map(|o| o.ipv4.keys()))
+            .collect();
+        for kind in kinds {
+            let new_ranges = new.ipv4.get(kind).unwrap_or(&empty4);
+            let old_ranges = old
+                .as_ref()
+                .and_then(|o| o.ipv4.get(kind))
+                .unwrap_or(&empty4);
+            diffs.push(valid_table_diff(
+                &config, "ipv4", kind, new_ranges, old_ranges,
+            ));
+        }
+        let kinds: BTreeSet<_> = new
+            .ipv6
+            .keys()
+            .chain(old.iter().flat_map(|o| o.ipv6.keys()))
+            .collect();
+        for kind in kinds {
+            let new_ranges = new.ipv6.get(kind).unwrap_or(&empty6);
+            let old_ranges = old
+                .as_ref()
+                .and_then(|o| o.ipv6.get(kind))
+                .unwrap_or(&empty6);
+            diffs.push(valid_table_diff(
+                &config, "ipv6", kind, new_ranges, old_ranges,
+            ));
+        }
+        print!("{}", term::render_diff(&diffs, summary));
+        changed |= diffs.iter().any(TableDiff::has_changes);
+    }
+    Ok(changed)
+}
+
+// Invalid ranges are left out of both aggregates, as when applying them.
+fn valid_table_diff<T>(
+    config: &Config,
+    proto: &str,
+    kind: &Option<String>,
+    new_ranges: &BTreeSet<&Entry<T>>,
+    old_ranges: &BTreeSet<&Entry<T>>,
+) -> TableDiff
+where
+    T: Prefix + Serialize,
+{
+    let new_valid = valid_ranges(config, kind, new_ranges);
+    let old_valid = valid_ranges(config, kind, old_ranges);
+    table_diff(proto, kind, &new_valid.ranges(), &old_valid.ranges())
+}
+
+fn table_diff<T>(
+    proto: &str,
+    kind: &Option<String>,
+    new_ranges: &BTreeSet<&Entry<T>>,
+    old_ranges: &BTreeSet<&Entry<T>>,
+) -> TableDiff
+where
+    T: Ord + Display + Serialize,
+{
+    let row = |e: &&Entry<T>| (e.range.to_string(), display_value(&e.class));
+    TableDiff {
+        proto: proto.to_owned(),
+        kind: kind.clone(),
+        insert: (new_ranges - old_ranges).iter().map(row).collect(),
+        remove: (old_ranges - new_ranges).iter().map(row).collect(),
+        unchanged: new_ranges.intersection(old_ranges).count(),
+    }
+}
+
+// Formats a per-table summary of a run as aligned columns, preceded by a
+// header.
+fn summary_lines(tables: &[TableRun]) -> Vec<String> {
+    let header = [
+        "proto",
+        "kind",
+        "mode",
+        "inserted",
+        "removed",
+        "unchanged",
+        "scripts",
+        "bytes",
+        "rules%",
+        "tbl8s%",
+    ];
+    let mut rows = vec![header.iter().map(ToString::to_string).collect::<Vec<_>>()];
+    for t in tables {
+        rows.push(vec![
+            t.proto.clone(),
+            t.kind.clone().unwrap_or_else(|| "-".to_owned()),
+            t.mode.to_string(),
+            t.inserted.to_string(),
+            t.removed.to_string(),
+            t.unchanged.to_string(),
+            t.scripts.to_string(),
+            t.bytes_sent.to_string(),
+            percent_column(t.utilization.map(|u| u.rules_percent)),
+            percent_column(t.utilization.map(|u| u.tbl8s_percent)),
+        ]);
+    }
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap_or(0))
+        .collect();
+    rows.iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let cols: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(col, width)| format!("{:<width$}", col, width = width))
+                .collect();
+            let line = cols.join("  ").trim_end().to_owned();
+            // Replacing a table is the costly path, so it stands out.
+            if i > 0 && row[2] == "replace" {
+                term::paint(&line, Style::Yellow)
+            } else {
+                line
+            }
+        })
+        .collect()
+}
+
+fn percent_column(percent: Option<f64>) -> String {
+    percent
+        .map(|p| format!("{:.1}", p))
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+// Each unit of verbosity moves the configured level one step towards
+// `Trace`, or towards `Off` if negative.
+fn adjust_level(level: Level, verbosity: i64) -> LevelFilter {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    let i = level.to_level_filter() as i64 + verbosity;
+    LEVELS[i.max(0).min(LEVELS.len() as i64 - 1) as usize]
+}
+
+// With targets, the last runs are printed as an object keyed by target name.
+async fn print_last_run(config: &Config, targets: &[String]) -> Result<(), anyhow::Error> {
+    let mut last_runs = BTreeMap::new();
+    for (name, config) in select_targets(config, targets)? {
+        let last_run = state::read_last_run(&config.state_dir)
+            .await
             .with_context(|| {
                 format!(
-                "failed to render parameters script from '{}' with proto {}, kind {:?}, vars: {:?}",
-                &config.lpm.parameters_script.input.display(), proto, kind, vars,
+                    "failed to read last run from '{}'",
+                    config.state_dir.display()
+                )
+            })?;
+        match name {
+            Some(name) => {
+                last_runs.insert(name, last_run);
+            }
+            None => {
+                println!("{}", serde_json::to_string_pretty(&last_run)?);
+                return Ok(());
+            }
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&last_runs)?);
+    Ok(())
+}
+
+async fn print_history(config: &Config, flags: &HistoryCmd) -> Result<(), anyhow::Error> {
+    for (name, config) in select_targets(config, &flags.common.target)? {
+        if let Some(name) = name {
+            println!("{}:", name);
+        }
+        let context = || {
+            format!(
+                "failed to read history from '{}'",
+                config.state_dir.display()
             )
-            })?;
+        };
+        match flags.show {
+            Some(id) => match state::read_history_entry(&config.state_dir, id)
+                .await
+                .with_context(context)?
+            {
+                Some(run) => print_run(&run),
+                None => println!("run {} not found", id),
+            },
+            None => {
+                let runs = state::read_history(&config.state_dir)
+                    .await
+                    .with_context(context)?;
+                let skip = match flags.limit {
+                    Some(limit) => runs.len().saturating_sub(limit),
+                    None => 0,
+                };
+                for run in runs.iter().skip(skip).rev() {
+                    println!("{}", history_line(run));
+                }
+            }
+        }
+    }
+    Ok(())
+}
 
-    let current_params = params::read(&config.socket, &script)
-        .await
-        .with_context(|| {
+// One line per run: id, time, aggregate hash prefix, outcome, and the mode
+// and insert/remove counts of each table.
+fn history_line(run: &LastRun) -> String {
+    let tables: Vec<String> = run
+        .tables
+        .iter()
+        .map(|t| {
             format!(
-                "failed to read lpm parameters from '{}'",
-                &config.socket.display()
+                "{}/{} {} +{} -{}",
+                t.proto,
+                t.kind.as_deref().unwrap_or("-"),
+                t.mode,
+                t.inserted,
+                t.removed
             )
-        })?;
+        })
+        .collect();
+    let hash = run.aggregate_hash.get(..12).unwrap_or(&run.aggregate_hash);
+    format!(
+        "{:>6}  {}  {}  {:<7}  {}",
+        run.serial,
+        run.finished_at.format("%Y-%m-%d %H:%M:%S"),
+        hash,
+        run.outcome.to_string(),
+        tables.join(", ")
+    )
+}
 
-    let set = new_ranges.iter().map(|e| e.range).collect();
-    let estimated_params = estimate(&set, &config.estimate);
+fn print_run(run: &LastRun) {
+    println!("id:        {}", run.serial);
+    println!("aggregate: {}", run.aggregate.display());
+    println!("hash:      {}", run.aggregate_hash);
+    println!("started:   {}", run.started_at);
+    println!("finished:  {}", run.finished_at);
+    println!("outcome:   {}", run.outcome);
+    if let Some(error) = &run.error {
+        println!("error:     {}", error);
+    }
+    if !run.tables.is_empty() {
+        println!();
+        for line in summary_lines(&run.tables) {
+            println!("{}", line);
+        }
+    }
+    let current: Vec<_> = run
+        .tables
+        .iter()
+        .filter(|t| !t.current.is_empty())
+        .collect();
+    if !current.is_empty() {
+        println!();
+        println!("current parameters:");
+        for t in current {
+            for params in &t.current {
+                println!("  {} {}", t.table, params);
+            }
+        }
+    }
+}
 
-    debug!("current parameters: {:?}", current_params);
-    debug!("estimated parameters: {:?}", estimated_params);
+// Runs a broker for each target until interrupted, letting queued scripts be
+// forwarded before exiting.
+async fn broker(config: &Config, targets: &[String]) -> Result<(), anyhow::Error> {
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut term = signal(SignalKind::terminate())?;
 
-    let scripts = match run_mode(&current_params, &estimated_params) {
-        Mode::Replace => {
-            info!(
-                "replacing table {} with parameters {}",
-                table, estimated_params,
-            );
-            let changes = Changes {
-                insert: new_ranges.iter().map(Deref::deref).collect(),
-                remove: vec![],
-            };
-            let diff = make_diff(changes);
-            let vars = ReplaceModeVariables {
-                params: &estimated_params,
-                lpm_table: &table,
-                lpm_table_constructor: &lua_functions.lpm_table_constructor,
-                proto: proto,
-            };
-            let mut replace = config.replace.clone();
-            replace.templates.output = replace_vars(&config.replace.templates.output, proto, kind);
-            output::render_diff_with_extra(
-                &diff,
-                &replace.templates.input,
-                &replace.templates.output,
-                replace.max_ranges_per_file,
-                &vars,
-            )
-            .await
-            .context("failed to render replacement script")?
+    let mut brokers = Vec::new();
+    for (name, config) in select_targets(config, targets)? {
+        let path = match &config.broker_socket {
+            Some(path) => path,
+            None => match name {
+                Some(name) => return Err(anyhow!("no broker socket for target {}", name)),
+                None => return Err(anyhow!("no broker socket configured")),
+            },
+        };
+        let broker = Broker::start(
+            path,
+            &config.socket,
+            config.broker_socket_mode,
+            config.dyncfg.clone(),
+        )
+        .await
+        .with_context(|| format!("failed to start broker on '{}'", path.display()))?;
+        info!(
+            "forwarding scripts from '{}' to '{}'",
+            path.display(),
+            config.socket.display()
+        );
+        brokers.push(broker);
+    }
+
+    tokio::select! {
+        Some(()) = int.recv() => info!("got sigint, shutting down"),
+        Some(()) = term.recv() => info!("got sigterm, shutting down"),
+    }
+    for broker in brokers {
+        broker.shutdown().await;
+    }
+    Ok(())
+}
+
+// Resends each captured request, in order, to the given socket or to a mock
+// server, reporting whether the replies match the captured ones.
+async fn replay(config: &Config, flags: &ReplayCmd) -> Result<(), anyhow::Error> {
+    let exchanges = capture::load(&flags.capture)
+        .await
+        .with_context(|| format!("failed to load capture '{}'", flags.capture.display()))?;
+    let dyncfg_config = DyncfgConfig {
+        // Replayed exchanges aren't captured again.
+        capture: None,
+        ..config.dyncfg.clone()
+    };
+    // The mock server's directory is removed when the replay is done.
+    let (socket, _server, _mock_dir) = match &flags.socket {
+        Some(socket) => (socket.clone(), None, None),
+        None => {
+            let mock_dir = tempfile::Builder::new()
+                .prefix("gtctl-replay.")
+                .tempdir()
+                .context("failed to create mock socket directory")?;
+            let socket = mock_dir.path().join("socket");
+            let server = MockServer::start(&socket, flags.reply.clone()).await?;
+            (socket, Some(server), Some(mock_dir))
         }
-        Mode::Update => {
-            info!(
-                "updating table {} with parameters {}",
-                table, estimated_params,
-            );
-            let insert = new_ranges - old_ranges;
-            let remove = old_ranges - new_ranges;
-            let changes = Changes {
-                insert: insert.into_iter().collect(),
-                remove: remove.into_iter().collect(),
-            };
-            let diff = make_diff(changes);
-            let vars = UpdateModeVariables {
-                lpm_table: &table,
-                proto: proto,
-            };
-            let mut update = config.update.clone();
-            update.templates.output = replace_vars(&config.update.templates.output, proto, kind);
-            output::render_diff_with_extra(
-                &diff,
-                &update.templates.input,
-                &update.templates.output,
-                update.max_ranges_per_file,
-                &vars,
-            )
+    };
+    let mut differ = 0;
+    for (i, exchange) in exchanges.iter().enumerate() {
+        let reply = dyncfg::send_config_raw(&socket, &exchange.request, &dyncfg_config)
             .await
-            .context("failed to render update script")?
+            .with_context(|| format!("failed to replay exchange {}", i + 1))?;
+        // Replies are received without the completion marker.
+        let mut captured = exchange.replies.concat();
+        if let Some(marker) = &dyncfg_config.completion_marker {
+            if captured.ends_with(marker.as_bytes()) {
+                captured.truncate(captured.len() - marker.len());
+            }
+        }
+        let status = if reply == captured {
+            "same reply"
+        } else {
+            differ += 1;
+            "different reply"
+        };
+        println!(
+            "{} {}: {} bytes sent, {}",
+            i + 1,
+            exchange.time.to_rfc3339(),
+            exchange.request.len(),
+            status
+        );
+    }
+    println!();
+    println!(
+        "replayed {} exchanges, {} with different replies",
+        exchanges.len(),
+        differ
+    );
+    Ok(())
+}
+
+// Relays scripts from remote callers until interrupted.
+async fn run_proxy(config: &Config) -> Result<(), anyhow::Error> {
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut term = signal(SignalKind::terminate())?;
+
+    let proxy_config = config
+        .proxy
+        .as_ref()
+        .ok_or_else(|| anyhow!("no proxy configured"))?;
+    let path = &proxy_config.token_file;
+    let mut token = fs::read(path)
+        .await
+        .with_context(|| format!("failed to read token from '{}'", path.display()))?;
+    // Tolerate the trailing newline of token files written by hand.
+    while token.last().map_or(false, u8::is_ascii_whitespace) {
+        token.pop();
+    }
+    if token.is_empty() {
+        return Err(anyhow!("empty token in '{}'", path.display()));
+    }
+    let tls = match &proxy_config.tls {
+        Some(tls) => {
+            Some(proxy::tls_acceptor(&tls.cert, &tls.key).context("failed to set up tls")?)
         }
+        None => None,
     };
-    debug!("rendered scripts: {:?}", scripts);
-    for script in scripts {
-        dyncfg::send_config_script(&config.socket, &script)
+    let listener = TcpListener::bind(&proxy_config.listen)
+        .await
+        .with_context(|| format!("failed to listen on '{}'", proxy_config.listen))?;
+    // The token would otherwise cross the network in the clear.
+    if tls.is_none() && !listener.local_addr()?.ip().is_loopback() {
+        return Err(anyhow!(
+            "tls must be configured to listen on non-loopback address '{}'",
+            proxy_config.listen
+        ));
+    }
+    let proxy = Arc::new(Proxy::new(
+        config.dyncfg_socket(),
+        config.dyncfg_config().into_owned(),
+        proxy_config.allow.clone(),
+        token,
+        tls,
+        Duration::from_secs(proxy_config.read_timeout),
+        proxy_config.max_connections,
+    ));
+    info!(
+        "relaying scripts from '{}' to '{}'",
+        proxy_config.listen,
+        config.dyncfg_socket().display()
+    );
+    let server = tokio::spawn(proxy.serve(listener));
+
+    tokio::select! {
+        Some(()) = int.recv() => info!("got sigint, shutting down"),
+        Some(()) = term.recv() => info!("got sigterm, shutting down"),
+    }
+    server.abort();
+    Ok(())
+}
+
+async fn bench(flags: &Bench, config: &Config) -> Result<(), anyhow::Error> {
+    let (warmup, iterations) = (flags.warmup, flags.iterations);
+
+    let (stats, (ipv4_aggregate, ipv6_aggregate)) = measure(warmup, iterations, || async move {
+        aggregate::deserialize(&flags.aggregate)
             .await
-            .with_context(|| format!("failed to send script '{}'", script.display()))?;
-        if config.remove_rendered_scripts {
-            fs::remove_file(script).await?;
+            .with_context(|| {
+                format!(
+                    "failed to deserialize aggregate from '{}'",
+                    flags.aggregate.display()
+                )
+            })
+    })
+    .await?;
+    let new = Bootstrap::new(&ipv4_aggregate, &ipv6_aggregate);
+    let num_ranges = new.ipv4.values().map(BTreeSet::len).sum::<usize>()
+        + new.ipv6.values().map(BTreeSet::len).sum::<usize>();
+    print_bench("deserialize", &stats, num_ranges);
+    let new = &new;
+
+    let old_aggregates = match &flags.old {
+        Some(path) => Some(aggregate::deserialize(path).await.with_context(|| {
+            format!(
+                "failed to deserialize old aggregate from '{}'",
+                path.display()
+            )
+        })?),
+        None => None,
+    };
+    let old = old_aggregates
+        .as_ref()
+        .map(|(ipv4, ipv6)| Bootstrap::new(ipv4, ipv6));
+    let old = &old;
+
+    let (stats, num_changes) = measure(warmup, iterations, || async move {
+        let empty4 = BTreeSet::new();
+        let empty6 = BTreeSet::new();
+        let mut num_changes = 0;
+        for (kind, new_ranges) in &new.ipv4 {
+            let old_ranges = old
+                .as_ref()
+                .and_then(|o| o.ipv4.get(kind))
+                .unwrap_or(&empty4);
+            num_changes += (new_ranges - old_ranges).len() + (old_ranges - new_ranges).len();
         }
+        for (kind, new_ranges) in &new.ipv6 {
+            let old_ranges = old
+                .as_ref()
+                .and_then(|o| o.ipv6.get(kind))
+                .unwrap_or(&empty6);
+            num_changes += (new_ranges - old_ranges).len() + (old_ranges - new_ranges).len();
+        }
+        Ok::<_, anyhow::Error>(num_changes)
+    })
+    .await?;
+    print_bench("diff", &stats, num_ranges);
+    println!("  {} changes", num_changes);
+
+    let (stats, ()) = measure(warmup, iterations, || async move {
+        for (kind, new_ranges) in &new.ipv4 {
+            let set = new_ranges.iter().map(|e| e.range).collect();
+            params::estimate_ipv4(&set, &config.kind(kind).estimate);
+        }
+        for (kind, new_ranges) in &new.ipv6 {
+            let set = new_ranges.iter().map(|e| e.range).collect();
+            params::estimate_ipv6(&set, &config.kind(kind).estimate);
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await?;
+    print_bench("estimate", &stats, num_ranges);
+
+    let dir = tempfile::Builder::new()
+        .prefix("gtctl-bench.")
+        .tempdir()
+        .context("failed to create benchmark directory")?;
+    let output = dir
+        .path()
+        .join("{proto}_{kind}.{i}.lua")
+        .display()
+        .to_string();
+    let output = &output;
+    let res = measure(warmup, iterations, || async move {
+        for (kind, ranges) in &new.ipv4 {
+            let set = ranges.iter().map(|e| e.range).collect();
+            let params = params::estimate_ipv4(&set, &config.kind(kind).estimate);
+            render_bench(config, output, "ipv4", kind, ranges, &params).await?;
+        }
+        for (kind, ranges) in &new.ipv6 {
+            let set = ranges.iter().map(|e| e.range).collect();
+            let params = params::estimate_ipv6(&set, &config.kind(kind).estimate);
+            render_bench(config, output, "ipv6", kind, ranges, &params).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    let path = dir.path().to_owned();
+    if let Err(e) = dir.close() {
+        warn!("failed to remove '{}': {}", path.display(), e);
     }
-    if config.remove_rendered_scripts {
-        fs::remove_file(script).await?;
-    }
+    let (stats, ()) = res?;
+    print_bench("render", &stats, num_ranges);
+
+    Ok(())
+}
 
+async fn render_bench<'a, T>(
+    config: &Config,
+    output: &str,
+    proto: &str,
+    kind: &Option<String>,
+    ranges: &'a BTreeSet<&Entry<T>>,
+    params: &Params<T>,
+) -> Result<(), anyhow::Error>
+where
+    T: Serialize,
+{
+    // Kinds may override the templates and table settings, as when applying.
+    let config = &*config.kind(kind);
+    let lua_functions = config.lpm.lua_functions(proto, kind);
+    let table = replace_vars(&config.lpm.table_format, proto, kind);
+    let vars = ReplaceModeVariables {
+        extra: &config.extra_vars.replace,
+        params,
+        lpm_table: &table,
+        lpm_table_constructor: &lua_functions.lpm_table_constructor,
+        proto,
+        kind,
+        policies: policies(&config.policies, ranges.iter().copied()),
+        overrides: &config.var_overrides,
+    };
+    let changes = Changes {
+        insert: ranges.iter().map(Deref::deref).collect(),
+        remove: vec![],
+    };
+    let mut replace = config.replace.clone();
+    replace.templates.output = replace_vars(output, proto, kind);
+    render_chunked(config, &replace, proto, changes, &vars)
+        .await
+        .context("failed to render replacement script")?;
     Ok(())
 }
 
-fn run_mode<T>(cur: &CurrentParams<T>, est: &Params<T>) -> Mode {
-    for c in &cur.0 {
-        if (est.num_rules, est.num_tbl8s) > (c.num_rules, c.num_tbl8s) {
-            return Mode::Replace;
+fn print_bench(stage: &str, stats: &Stats, num_ranges: usize) {
+    println!(
+        "{}: {} ({:.0} ranges/s)",
+        stage,
+        stats,
+        stats.throughput(num_ranges)
+    );
+}
+
+fn print_breakdown<T: Ord + Display>(breakdown: &Tbl8Breakdown<T>, top: usize) {
+    let total: usize = breakdown.depths.values().sum();
+    println!("  unscaled tbl8s: {}", total);
+    for (depth, tbl8s) in &breakdown.depths {
+        println!("    /{}: {}", depth, tbl8s);
+    }
+    println!("  top buckets:");
+    for (bucket, usage) in breakdown.top_buckets(top) {
+        println!(
+            "    {}: tbl8s={}, prefixes={}",
+            bucket, usage.tbl8s, usage.prefixes
+        );
+    }
+}
+
+fn parse_feed(s: &str) -> Result<(String, PathBuf), anyhow::Error> {
+    match s.find('=') {
+        Some(i) if i > 0 => Ok((s[..i].to_owned(), PathBuf::from(&s[i + 1..]))),
+        _ => Err(anyhow!("expected KIND=PATH")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+    Lua,
+}
+
+fn parse_output_format(s: &str) -> Result<OutputFormat, anyhow::Error> {
+    match s {
+        "text" => Ok(OutputFormat::Text),
+        "json" => Ok(OutputFormat::Json),
+        "lua" => Ok(OutputFormat::Lua),
+        _ => Err(anyhow!("expected text, json or lua")),
+    }
+}
+
+// Ends the program with the given status once everything is dropped, for
+// outcomes that are already reported.
+#[derive(Debug)]
+struct Exit(i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exit status {}", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+fn parse_scaling_factor(s: &str) -> Result<usize, anyhow::Error> {
+    match s.parse()? {
+        0 => Err(anyhow!("scaling factor must be positive")),
+        n => Ok(n),
+    }
+}
+
+// Values are parsed as YAML, like the ones in the configuration file, so
+// that numbers and lists can be given.
+fn parse_var(s: &str) -> Result<(String, serde_yaml::Value), anyhow::Error> {
+    let (key, value) = match s.find('=') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(anyhow!("expected KEY=VALUE")),
+    };
+    if key.is_empty() {
+        return Err(anyhow!("empty variable name"));
+    }
+    let value = match serde_yaml::from_str(value) {
+        Ok(serde_yaml::Value::Null) | Err(_) => serde_yaml::Value::from(value),
+        Ok(value) => value,
+    };
+    Ok((key.to_owned(), value))
+}
+
+// Estimates the tables of each feed and prints their sizes for Gatekeeper's
+// static configuration, or the estimate settings they were sized with.
+async fn suggest_config(config: &Config, flags: &SuggestConfigCmd) -> Result<(), anyhow::Error> {
+    let mut config = config.clone();
+    if let Some(percent) = flags.headroom_percent {
+        config.estimate.headroom_percent = percent;
+    }
+    let mut tables = Vec::new();
+    for (kind, path) in &flags.feeds {
+        let prefixes: BTreeSet<IpNet> = load_prefix_input(path, &flags.glob).await?;
+        let (ipv4, ipv6) = split_prefixes(&prefixes);
+        let kind = Some(kind.clone());
+        let kind_config = config.kind(&kind);
+        let estimate = &kind_config.estimate;
+        if !ipv4.is_empty() {
+            let params = params::estimate_ipv4(&ipv4, estimate).rounded_for("ipv4", estimate);
+            tables.push(suggested_table(&kind_config, "ipv4", &kind, params));
         }
+        if !ipv6.is_empty() {
+            let params = params::estimate_ipv6(&ipv6, estimate).rounded_for("ipv6", estimate);
+            tables.push(suggested_table(&kind_config, "ipv6", &kind, params));
+        }
+    }
+    if flags.settings {
+        let kinds: Vec<String> = flags.feeds.iter().map(|(kind, _)| kind.clone()).collect();
+        print!("{}", suggested_settings(&config, &kinds));
+    } else {
+        print!("{}", suggested_config(&tables));
     }
-    Mode::Update
+    Ok(())
+}
+
+struct SuggestedTable {
+    name: String,
+    num_rules: usize,
+    num_tbl8s: usize,
+    warnings: Vec<LimitWarning>,
 }
 
-async fn render_parameters_script<'a>(
-    config: &Templates,
+// The configuration is the one of the table's kind.
+fn suggested_table<T>(
+    config: &Config,
     proto: &str,
     kind: &Option<String>,
-    vars: &ParametersScriptVariables<'a>,
-) -> Result<PathBuf, anyhow::Error> {
-    use tera::{Context, Tera};
+    params: Params<T>,
+) -> SuggestedTable {
+    SuggestedTable {
+        name: replace_vars(&config.lpm.table_format, proto, kind),
+        num_rules: params.num_rules,
+        num_tbl8s: params.num_tbl8s,
+        warnings: limit_warnings(&params, proto, config),
+    }
+}
+
+fn suggested_config(tables: &[SuggestedTable]) -> String {
+    let mut out = String::from("-- LPM table sizes suggested by gtctl.\nlocal lpm_params = {\n");
+    for t in tables {
+        for w in &t.warnings {
+            out.push_str(&format!("  -- warning: {}\n", w));
+        }
+        out.push_str(&format!(
+            "  {} = {{ max_num_rules = {}, num_tbl8s = {} }},\n",
+            t.name, t.num_rules, t.num_tbl8s
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
 
-    let template = fs::read_to_string(&config.input).await?;
-    let mut tera = Tera::default();
-    let context = Context::from_serialize(&vars)?;
-    let output = PathBuf::from(replace_vars(&config.output, proto, kind));
-    let res = tera.render_str(&template, &context)?;
+// The headroom is the only estimate setting kinds override.
+fn suggested_settings(config: &Config, kinds: &[String]) -> String {
+    let rounding = match config.estimate.rounding {
+        Rounding::None => "none".to_owned(),
+        Rounding::PowerOfTwo => "power_of_two".to_owned(),
+        Rounding::Multiple(n) => n.to_string(),
+    };
+    let mut out = format!(
+        "# gtctl settings the sizes were estimated with.\n\
+         estimate: {{\n  rules_scaling_factor: {},\n  tbl8s_scaling_factor: {},\n  \
+         headroom_percent: {},\n  rounding: {},\n}}\n",
+        config.estimate.rules_scaling_factor,
+        config.estimate.tbl8s_scaling_factor,
+        config.estimate.headroom_percent,
+        rounding
+    );
+    let overrides: Vec<(&String, usize)> = kinds
+        .iter()
+        .map(|kind| {
+            let kind_config = config.kind(&Some(kind.clone()));
+            (kind, kind_config.estimate.headroom_percent)
+        })
+        .filter(|(_, percent)| *percent != config.estimate.headroom_percent)
+        .collect();
+    if !overrides.is_empty() {
+        out.push_str("kinds: {\n");
+        for (kind, percent) in overrides {
+            out.push_str(&format!(
+                "  {}: {{ headroom_percent: {} }},\n",
+                kind, percent
+            ));
+        }
+        out.push_str("}\n");
+    }
+    out
+}
 
-    safe_write(&output, res.as_bytes()).await?;
-    Ok(output)
+fn split_prefixes(prefixes: &BTreeSet<IpNet>) -> (BTreeSet<Ipv4Net>, BTreeSet<Ipv6Net>) {
+    let mut ipv4 = BTreeSet::new();
+    let mut ipv6 = BTreeSet::new();
+    for prefix in prefixes {
+        match prefix {
+            IpNet::V4(net) => ipv4.insert(*net),
+            IpNet::V6(net) => ipv6.insert(*net),
+        };
+    }
+    (ipv4, ipv6)
 }
 
-fn replace_vars(s: &str, proto: &str, kind: &Option<String>) -> String {
-    s.replace("{proto}", proto)
-        .replace("{kind}", kind.as_deref().unwrap_or(""))
+// The parameters of two prefix sets, the update from one to the other, and
+// the mode it would be applied in.
+struct EstimateDelta<T> {
+    old: Params<T>,
+    new: Params<T>,
+    update: Update,
+    mode: Mode,
 }
 
-fn setup_logger(level: &Level) {
-    use env_logger::{Builder, Target, WriteStyle};
+// Estimates the parameters of the old and new prefixes, and whether a table
+// sized for and filled with the old prefixes would be replaced to take the
+// new ones under the configured threshold.
+fn estimate_delta<T: Ord + Clone>(
+    old: &BTreeSet<T>,
+    new: &BTreeSet<T>,
+    config: &Config,
+    estimate: impl Fn(&BTreeSet<T>, &EstimateConfig) -> Params<T>,
+    count_tbl8s: impl Fn(&BTreeSet<T>, &EstimateConfig) -> usize,
+) -> EstimateDelta<T> {
+    let old_params = estimate(old, &config.estimate);
+    let new_params = estimate(new, &config.estimate);
+    let inserted: BTreeSet<T> = new.difference(old).cloned().collect();
+    let update = Update {
+        removed: old.difference(new).count(),
+        inserted: inserted.len(),
+        inserted_tbl8s: count_tbl8s(&inserted, &config.estimate),
+    };
+    let table = TableParams::new(0, old_params.num_rules, old_params.num_tbl8s)
+        .with_usage(old.len(), count_tbl8s(old, &config.estimate));
+    let current = CurrentParams::new(vec![table]);
+    let mode = run_mode(
+        &current,
+        &new_params,
+        &update,
+        config.replace_threshold_percent,
+    );
+    EstimateDelta {
+        old: old_params,
+        new: new_params,
+        update,
+        mode,
+    }
+}
+
+// Reports the parameters of the old and new prefixes and their difference.
+fn print_estimate_delta<T: Ord + Clone>(
+    proto: &str,
+    old: &BTreeSet<T>,
+    new: &BTreeSet<T>,
+    config: &Config,
+    estimate: impl Fn(&BTreeSet<T>, &EstimateConfig) -> Params<T>,
+    count_tbl8s: impl Fn(&BTreeSet<T>, &EstimateConfig) -> usize,
+) {
+    let EstimateDelta {
+        old: old_params,
+        new: new_params,
+        update,
+        mode,
+    } = estimate_delta(old, new, config, estimate, count_tbl8s);
+    let delta = |new: usize, old: usize| new as i64 - old as i64;
 
-    let mut builder = Builder::new();
-    builder.target(Target::Stdout);
-    builder.write_style(WriteStyle::Auto);
-    builder.filter_module("gtctl", level.to_level_filter());
+    println!("{} old: {}", proto, old_params);
+    println!("{} new: {}", proto, new_params);
+    println!(
+        "  delta: rules={:+}, tbl8s={:+}",
+        delta(new_params.num_rules, old_params.num_rules),
+        delta(new_params.num_tbl8s, old_params.num_tbl8s)
+    );
+    println!("  removed={}, inserted={}", update.removed, update.inserted);
+    match mode {
+        Mode::Replace if !config.allow_replace => {
+            println!("  mode: replace (not allowed by the configuration)")
+        }
+        mode => println!("  mode: {}", mode),
+    }
+}
 
-    builder.init();
+// Loads the prefixes in a file, or merges those in the files of a directory
+// whose names match the pattern.
+async fn load_prefix_input<T>(
+    path: &Path,
+    pattern: &glob::Pattern,
+) -> Result<BTreeSet<T>, anyhow::Error>
+where
+    T: FromStr + Ord,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if !fs::metadata(path).await?.is_dir() {
+        return load_prefixes(path)
+            .await
+            .with_context(|| format!("failed to load prefixes from '{}'", path.display()));
+    }
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let matches = entry
+            .file_name()
+            .to_str()
+            .map_or(false, |name| pattern.matches(name));
+        if matches && entry.file_type().await?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    let mut prefixes = BTreeSet::new();
+    for file in &files {
+        let loaded: BTreeSet<T> = load_prefixes(file)
+            .await
+            .with_context(|| format!("failed to load prefixes from '{}'", file.display()))?;
+        debug!("loaded {} prefixes from '{}'", loaded.len(), file.display());
+        prefixes.extend(loaded);
+    }
+    info!(
+        "loaded {} prefixes from {} files in '{}'",
+        prefixes.len(),
+        files.len(),
+        path.display()
+    );
+    Ok(prefixes)
 }
 
-async fn load_prefixes<T: Net>(path: impl AsRef<Path>) -> Result<BTreeSet<T>, anyhow::Error> {
+async fn load_prefixes<T>(path: impl AsRef<Path>) -> Result<BTreeSet<T>, anyhow::Error>
+where
+    T: FromStr + Ord,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
     let file = File::open(path).await?;
     let reader = BufReader::new(file);
     let mut prefixes = BTreeSet::new();
@@ -457,20 +3199,514 @@ async fn load_prefixes<T: Net>(path: impl AsRef<Path>) -> Result<BTreeSet<T>, an
 
 #[cfg(test)]
 mod tests {
+    use gtctl::dyncfg::NoReply;
+    use gtctl::error::{ConfigError, LimitError};
+    use gtctl::params::Utilization;
+    use tempfile::TempDir;
+
     use super::*;
 
+    // A minimal configuration, extended with the given settings.
+    fn test_config(extra: &str) -> Config {
+        let yaml = format!(
+            "{}{}",
+            r#"
+state_dir: /var/lib/gtctl
+replace: { input: replace.tpl, output: replace.lua }
+update: { input: update.tpl, output: update.lua }
+lpm: {
+  table_format: "{kind}_lpm_{proto}",
+  parameters_script: { input: params.tpl, output: params.lua },
+  ipv4: { lpm_table_constructor: new_lpm, lpm_get_params_function: get_paras },
+  ipv6: { lpm_table_constructor: new_lpm6, lpm_get_params_function: get6_paras },
+}
+"#,
+            extra
+        );
+        serde_yaml::from_str(&yaml).expect("invalid config")
+    }
+
+    #[test]
+    fn test_error_report() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let e = error::Error::from(dyncfg::Error::Io(refused));
+        let script = PathBuf::from("/var/lib/gtctl/update.01.lua");
+        let e = error::Error::Script(script.clone(), 1, Box::new(e));
+        let e = error::Error::Table("blocklist_lpm_ipv4".to_owned(), Box::new(e));
+        let report = ErrorReport::new(Some("gt1"), &e);
+        assert_eq!(ErrorClass::Transport, report.class);
+        assert_eq!(Some("dyncfg"), report.module);
+        assert_eq!(Some("gt1"), report.target.as_deref());
+        assert_eq!(Some("blocklist_lpm_ipv4"), report.table.as_deref());
+        assert_eq!(Some(1), report.chunk);
+        assert_eq!(Some(script), report.script);
+        assert!(report.retryable);
+        assert_eq!(3, report.causes.len());
+        assert_eq!(format!("{:#}", e), report.error);
+
+        // A lost reply isn't retryable, since the script may have been
+        // applied.
+        let e = error::Error::from(dyncfg::Error::NoReply(NoReply::Reset));
+        let report = ErrorReport::new(None, &e);
+        assert_eq!(ErrorClass::Transport, report.class);
+        assert!(!report.retryable);
+
+        let e = error::Error::from(dyncfg::Error::NoReply(NoReply::Empty));
+        let report = ErrorReport::new(None, &e);
+        assert_eq!(ErrorClass::Gatekeeper, report.class);
+        assert!(!report.retryable);
+        assert_eq!(None, report.table);
+
+        // Categories are found under the context added by commands.
+        let e = error::Error::from(ConfigError::Override("lpm".to_owned(), "bad".to_owned()));
+        let e = anyhow::Error::from(e).context("failed to load configuration");
+        let report = ErrorReport::new(None, e.as_ref());
+        assert_eq!(ErrorClass::Config, report.class);
+        assert_eq!(1, report.causes.len());
+
+        let e = error::Error::from(LimitError::Window("blocklist_lpm_ipv4".to_owned()));
+        let e = error::Error::Target("gt2".to_owned(), Box::new(e));
+        let report = ErrorReport::new(None, &e);
+        assert_eq!(ErrorClass::Limit, report.class);
+        assert_eq!(Some("gt2"), report.target.as_deref());
+
+        let e = anyhow!("something else");
+        assert_eq!(ErrorClass::Other, ErrorReport::new(None, e.as_ref()).class);
+    }
+
+    #[test]
+    fn test_lua_params() {
+        let config = test_config(
+            r#"
+estimate: { rounding: power_of_two }
+"#,
+        );
+        let params: Params<Ipv4Net> = Params::new(1000, 200);
+        assert_eq!(
+            "-- ipv4 LPM parameters estimated by gtctl.\n\
+             local max_num_ipv4_rules = 1024\n\
+             local num_ipv4_tbl8s = 256\n",
+            lua_params(&params, "ipv4", &config)
+        );
+    }
+
+    #[test]
+    fn test_suggested_config() {
+        let config = test_config(
+            r#"
+estimate: { headroom_percent: 20, rounding: power_of_two }
+"#,
+        );
+        let table = suggested_table(
+            &config,
+            "ipv4",
+            &Some("blocklist".to_owned()),
+            Params::<Ipv4Net>::new(1024, 256),
+        );
+        assert_eq!("blocklist_lpm_ipv4", table.name);
+        let suggested = suggested_config(&[table]);
+        assert!(suggested
+            .contains("  blocklist_lpm_ipv4 = { max_num_rules = 1024, num_tbl8s = 256 },\n"));
+        assert!(!suggested.contains("estimate"));
+
+        let settings = suggested_settings(&config, &["blocklist".to_owned()]);
+        assert!(settings.contains("  headroom_percent: 20,\n  rounding: power_of_two,\n"));
+        assert!(!settings.contains("kinds"));
+    }
+
+    #[test]
+    fn test_suggested_kind_settings() {
+        let config = test_config(
+            r#"
+estimate: { headroom_percent: 20 }
+kinds: {
+  blocklist: { headroom_percent: 50, caps: { ipv4: { max_rules: 100 } } },
+}
+"#,
+        );
+        let kind = Some("blocklist".to_owned());
+        let table = suggested_table(
+            &config.kind(&kind),
+            "ipv4",
+            &kind,
+            Params::<Ipv4Net>::new(1024, 256),
+        );
+        assert!(!table.warnings.is_empty());
+        let settings =
+            suggested_settings(&config, &["blocklist".to_owned(), "allowlist".to_owned()]);
+        assert!(settings.contains("  headroom_percent: 20,\n"));
+        assert!(settings.contains("kinds: {\n  blocklist: { headroom_percent: 50 },\n}\n"));
+        assert!(!settings.contains("allowlist"));
+    }
+
+    #[test]
+    fn test_estimate_delta() {
+        let config = test_config("");
+        let nets = |nets: &[&str]| -> BTreeSet<Ipv4Net> {
+            nets.iter()
+                .map(|n| n.parse().expect("invalid net"))
+                .collect()
+        };
+        let delta = |old: &BTreeSet<Ipv4Net>, new: &BTreeSet<Ipv4Net>| {
+            estimate_delta(old, new, &config, params::estimate_ipv4, params::tbl8s_ipv4)
+        };
+        let old = nets(&["10.0.0.0/24", "10.1.0.0/24"]);
+
+        // Swapping one prefix for another fits the table.
+        let new = nets(&["10.0.0.0/24", "10.2.0.0/24"]);
+        let d = delta(&old, &new);
+        assert_eq!(d.old, d.new);
+        assert_eq!((1, 1), (d.update.removed, d.update.inserted));
+        assert_eq!(Mode::Update, d.mode);
+
+        // Many more prefixes, some needing tbl8s, don't.
+        let many: Vec<String> = (0..64).map(|i| format!("10.{}.0.128/25", i)).collect();
+        let many: Vec<&str> = many.iter().map(String::as_str).collect();
+        let new = nets(&many);
+        let d = delta(&old, &new);
+        assert!(d.new.num_rules > d.old.num_rules);
+        assert!(d.new.num_tbl8s > d.old.num_tbl8s);
+        assert_eq!((2, 64), (d.update.removed, d.update.inserted));
+        assert!(d.update.inserted_tbl8s > 0);
+        assert_eq!(Mode::Replace, d.mode);
+    }
+
+    #[test]
+    fn test_adjust_level() {
+        assert_eq!(LevelFilter::Info, adjust_level(Level::Info, 0));
+        assert_eq!(LevelFilter::Debug, adjust_level(Level::Info, 1));
+        assert_eq!(LevelFilter::Trace, adjust_level(Level::Info, 5));
+        assert_eq!(LevelFilter::Warn, adjust_level(Level::Info, -1));
+        assert_eq!(LevelFilter::Off, adjust_level(Level::Error, -2));
+    }
+
+    #[test]
+    fn test_set_state_namespace() {
+        let mut config = test_config(
+            r#"
+state_namespace: feed1
+"#,
+        );
+
+        let mut configured = config.clone();
+        set_state_namespace(&mut configured, None).expect("namespace failed");
+        assert_eq!(PathBuf::from("/var/lib/gtctl/feed1"), configured.state_dir);
+
+        let name = "feed2".to_owned();
+        let mut overridden = config.clone();
+        set_state_namespace(&mut overridden, Some(&name)).expect("namespace failed");
+        assert_eq!(PathBuf::from("/var/lib/gtctl/feed2"), overridden.state_dir);
+        assert_eq!(Some("feed2"), overridden.state_namespace.as_deref());
+
+        config.state_namespace = Some("../feed".to_owned());
+        assert!(set_state_namespace(&mut config, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_prefix_input() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        for (name, text) in &[
+            ("a.txt", "192.0.2.0/24\n198.51.100.0/24\n"),
+            ("b.txt", "198.51.100.0/24\n203.0.113.0/24\n"),
+            ("c.bak", "10.0.0.0/8\n"),
+        ] {
+            fs::write(tmp.path().join(name), text)
+                .await
+                .expect("write failed");
+        }
+        fs::create_dir(tmp.path().join("d.txt"))
+            .await
+            .expect("create failed");
+        let load = |path: PathBuf, pattern: &str| {
+            let pattern = glob::Pattern::new(pattern).expect("invalid pattern");
+            async move {
+                let prefixes: BTreeSet<Ipv4Net> = load_prefix_input(&path, &pattern)
+                    .await
+                    .expect("load failed");
+                prefixes.iter().map(ToString::to_string).collect::<Vec<_>>()
+            }
+        };
+
+        // Files matching the pattern are merged, skipping directories.
+        assert_eq!(
+            vec!["192.0.2.0/24", "198.51.100.0/24", "203.0.113.0/24"],
+            load(tmp.path().to_owned(), "*.txt").await
+        );
+        assert_eq!(
+            vec![
+                "10.0.0.0/8",
+                "192.0.2.0/24",
+                "198.51.100.0/24",
+                "203.0.113.0/24"
+            ],
+            load(tmp.path().to_owned(), "*").await
+        );
+
+        // A file is loaded whatever its name.
+        assert_eq!(
+            vec!["10.0.0.0/8"],
+            load(tmp.path().join("c.bak"), "*.txt").await
+        );
+    }
+
+    #[test]
+    fn test_summary_lines() {
+        let table = TableRun {
+            table: "blocklist_lpm_ipv4".to_owned(),
+            proto: "ipv4".to_owned(),
+            kind: Some("blocklist".to_owned()),
+            mode: Mode::Update,
+            num_rules: 10,
+            num_tbl8s: 2,
+            current: vec![],
+            utilization: Some(Utilization {
+                rules_percent: 62.5,
+                tbl8s_percent: 100.0,
+            }),
+            limit_warnings: vec![],
+            overprovisioned_runs: 0,
+            inserted: 3,
+            removed: 1,
+            scripts: 1,
+            queued: 0,
+            unchanged: 1500,
+            bytes_sent: 2048,
+            script_hashes: vec![],
+        };
+        assert_eq!(
+            vec![
+                "proto  kind       mode    inserted  removed  unchanged  scripts  bytes  rules%  tbl8s%",
+                "ipv4   blocklist  update  3         1        1500       1        2048   62.5    100.0",
+            ],
+            summary_lines(&[table])
+        );
+    }
+
+    #[test]
+    fn test_valid_table_diff() {
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 0.0.0.0/0, kind: blocklist, class: drop }
+- { range: 10.0.0.0/8, kind: blocklist, class: drop }
+- { range: 1.1.1.0/24, kind: blocklist, class: drop }
+"#,
+        )
+        .expect("deserialize failed");
+        let kind = Some("blocklist".to_owned());
+        let new: BTreeSet<_> = entries.iter().collect();
+        let mut tables = BTreeMap::new();
+        tables.insert(kind.clone(), new.clone());
+
+        // Invalid ranges are left out of the diff, as they are by a run.
+        let config = test_config("validation: { action: strip }\n");
+        let diff = valid_table_diff(&config, "ipv4", &kind, &new, &BTreeSet::new());
+        assert_eq!(
+            vec![("1.1.1.0/24".to_owned(), "drop".to_owned())],
+            diff.insert
+        );
+        assert!(validate_tables(&config, &tables, &BTreeMap::new()).is_ok());
+
+        let config = test_config("validation: { action: reject }\n");
+        assert!(validate_tables(&config, &tables, &BTreeMap::new()).is_err());
+
+        let config = test_config("");
+        let diff = valid_table_diff(&config, "ipv4", &kind, &new, &BTreeSet::new());
+        assert_eq!(3, diff.insert.len());
+    }
+
+    #[test]
+    fn test_check_invalid_ranges() {
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 1.1.1.0/24, kind: blocklist, class: drop }
+- { range: 192.168.0.0/16, kind: blocklist, class: drop }
+"#,
+        )
+        .expect("deserialize failed");
+        let kind = Some("blocklist".to_owned());
+        let old: BTreeSet<_> = entries[..1].iter().collect();
+        let new: BTreeSet<_> = entries.iter().collect();
+
+        // A stripped range leaves nothing for --check to report.
+        let config = test_config("validation: { action: strip }\n");
+        let diff = valid_table_diff(&config, "ipv4", &kind, &new, &old);
+        assert!(!diff.has_changes());
+
+        let config = test_config("");
+        let diff = valid_table_diff(&config, "ipv4", &kind, &new, &old);
+        assert!(diff.has_changes());
+    }
+
+    #[test]
+    fn test_table_diff() {
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 192.0.2.0/24, kind: blocklist, class: scan }
+- { range: 192.0.2.0/24, kind: blocklist, class: drop }
+- { range: 198.51.100.0/24, kind: blocklist, class: drop }
+"#,
+        )
+        .expect("deserialize failed");
+        let set = |indices: &[usize]| -> BTreeSet<&Entry<Ipv4Net>> {
+            indices.iter().map(|&i| &entries[i]).collect()
+        };
+        let kind = Some("blocklist".to_owned());
+
+        let diff = table_diff("ipv4", &kind, &set(&[0, 2]), &set(&[0, 2]));
+        assert!(!diff.has_changes());
+        assert_eq!(2, diff.unchanged);
+
+        // A range whose class changed is removed and inserted again.
+        let diff = table_diff("ipv4", &kind, &set(&[1, 2]), &set(&[0, 2]));
+        assert!(diff.has_changes());
+        assert_eq!(
+            vec![("192.0.2.0/24".to_owned(), "drop".to_owned())],
+            diff.insert
+        );
+        assert_eq!(
+            vec![("192.0.2.0/24".to_owned(), "scan".to_owned())],
+            diff.remove
+        );
+
+        let diff = table_diff("ipv4", &kind, &set(&[]), &set(&[2]));
+        assert!(diff.has_changes());
+        assert_eq!(1, diff.remove.len());
+    }
+
+    #[test]
+    fn test_fixture() {
+        let fixture: Fixture = serde_yaml::from_str(
+            r#"
+ipv4: {
+  old: [{ range: 192.0.2.0/24, kind: blocklist, class: drop }],
+  new: [
+    { range: 192.0.2.0/25, kind: blocklist, class: drop },
+    { range: 198.51.100.0/24, kind: allowlist, class: pass },
+  ],
+}
+"#,
+        )
+        .expect("deserialize failed");
+        assert_eq!(1, fixture.ipv4.old.len());
+        assert!(fixture.ipv6.new.is_empty());
+
+        let tables = fixture_tables(&fixture.ipv4.new);
+        let kinds: Vec<_> = tables.keys().cloned().collect();
+        assert_eq!(
+            vec![Some("allowlist".to_owned()), Some("blocklist".to_owned())],
+            kinds
+        );
+    }
+
+    #[tokio::test]
+    async fn test_template_test_scripts() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let mut config = test_config(
+            r#"
+kinds: { scanners: { table_mode: hash } }
+"#,
+        );
+        for (name, text) in &[
+            ("params.tpl", "params"),
+            ("replace.tpl", "replace"),
+            (
+                "update.tpl",
+                "update{% for e in ipv4.insert %} +{{e.range}}{% endfor %}\
+                 {% for e in ipv4.remove %} -{{e.range}}{% endfor %}",
+            ),
+        ] {
+            fs::write(tmp.path().join(name), text)
+                .await
+                .expect("write failed");
+        }
+        config.lpm.parameters_script.input = tmp.path().join("params.tpl");
+        config.replace.templates.input = tmp.path().join("replace.tpl");
+        config.update.templates.input = tmp.path().join("update.tpl");
+        let fixture: Fixture = serde_yaml::from_str(
+            r#"
+ipv4: {
+  old: [{ range: 192.0.2.0/24, kind: scanners, class: scan }],
+  new: [
+    { range: 198.51.100.0/24, kind: scanners, class: scan },
+    { range: 203.0.113.0/24, kind: blocklist, class: drop },
+    { range: 203.0.113.128/25, kind: blocklist, class: drop },
+  ],
+}
+"#,
+        )
+        .expect("deserialize failed");
+
+        let dir = tmp.path().join("test");
+        fs::create_dir(&dir).await.expect("create failed");
+        let scripts = template_test_scripts(config, &fixture, &dir)
+            .await
+            .expect("test failed");
+        let names: Vec<_> = scripts
+            .iter()
+            .map(|(table, name, script)| (table.as_str(), name.as_str(), script.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("blocklist_lpm_ipv4", "parameters", "params"),
+                ("blocklist_lpm_ipv4", "replace 1", "replace"),
+                (
+                    "scanners_lpm_ipv4",
+                    "update 1",
+                    "update +198.51.100.0/24 -192.0.2.0/24"
+                ),
+                (
+                    "blocklist_lpm_ipv4",
+                    "update 1",
+                    "update +203.0.113.0/24 +203.0.113.128/25"
+                ),
+            ],
+            names
+        );
+    }
+
+    #[test]
+    fn test_completion_names() {
+        let config = test_config(
+            r#"
+kind_map: { bl1: blocklist, bl2: blocklist }
+kinds: { allowlist: { allow_replace: false } }
+targets: { gt1: {}, gt2: {} }
+"#,
+        );
+        let names: Vec<_> = completion_names(&config, "targets").into_iter().collect();
+        assert_eq!(vec!["gt1", "gt2"], names);
+        let names: Vec<_> = completion_names(&config, "kinds").into_iter().collect();
+        assert_eq!(vec!["allowlist", "blocklist"], names);
+    }
+
+    #[test]
+    fn test_output_format() {
+        for args in &[
+            &["gtctl", "--format", "json", "apply", "-p", "plan"][..],
+            &["gtctl", "apply", "-p", "plan", "--format", "json"][..],
+        ] {
+            let opts = Opts::try_parse_from(*args).expect("parse failed");
+            assert_eq!(OutputFormat::Json, opts.format);
+        }
+        let opts =
+            Opts::try_parse_from(&["gtctl", "estimate", "-4", "prefixes", "--format", "lua"])
+                .expect("parse failed");
+        assert_eq!(OutputFormat::Lua, opts.format);
+    }
+
     #[test]
-    fn test_run_mode() {
-        let cur: CurrentParams<()> = CurrentParams(vec![Params::new(10, 10), Params::new(20, 10)]);
-        let est = Params::new(15, 15);
-        assert_eq!(Mode::Replace, run_mode(&cur, &est));
-
-        let cur: CurrentParams<()> = CurrentParams(vec![Params::new(20, 20), Params::new(15, 10)]);
-        let est = Params::new(15, 15);
-        assert_eq!(Mode::Replace, run_mode(&cur, &est));
-
-        let cur: CurrentParams<()> = CurrentParams(vec![Params::new(20, 20), Params::new(15, 15)]);
-        let est = Params::new(15, 15);
-        assert_eq!(Mode::Update, run_mode(&cur, &est));
+    fn test_parse_var() {
+        let (key, value) = parse_var("lpm_table=tmp_lpm_ipv4").expect("parse failed");
+        assert_eq!("lpm_table", key);
+        assert_eq!(serde_yaml::Value::from("tmp_lpm_ipv4"), value);
+
+        let (_, value) = parse_var("policy_id=2").expect("parse failed");
+        assert_eq!(serde_yaml::Value::from(2), value);
+
+        let (_, value) = parse_var("empty=").expect("parse failed");
+        assert_eq!(serde_yaml::Value::from(""), value);
+
+        assert!(parse_var("policy_id").is_err());
+        assert!(parse_var("=2").is_err());
     }
 }
@@ -0,0 +1,119 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use crate::config::StatsdConfig;
+
+lazy_static! {
+    static ref SINK: Mutex<Option<Statsd>> = Mutex::new(None);
+}
+
+/// Enables metric emission to the given StatsD server. Until this is called,
+/// all metric functions are no-ops.
+pub fn init(config: &StatsdConfig) -> Result<(), io::Error> {
+    let statsd = Statsd::new(config)?;
+    *SINK.lock().expect("BUG: metrics lock poisoned") = Some(statsd);
+    Ok(())
+}
+
+pub fn count(name: &str, value: u64, tags: &[(&str, &str)]) {
+    emit(name, &value.to_string(), "c", tags);
+}
+
+pub fn timing(name: &str, duration: Duration, tags: &[(&str, &str)]) {
+    emit(name, &duration.as_millis().to_string(), "ms", tags);
+}
+
+pub fn gauge(name: &str, value: f64, tags: &[(&str, &str)]) {
+    emit(name, &value.to_string(), "g", tags);
+}
+
+fn emit(name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+    if let Some(statsd) = &*SINK.lock().expect("BUG: metrics lock poisoned") {
+        let msg = statsd.format(name, value, kind, tags);
+        // Metrics are best-effort and must never fail a run.
+        let _ = statsd.socket.send(msg.as_bytes());
+    }
+}
+
+#[derive(Debug)]
+struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    tags: bool,
+}
+
+impl Statsd {
+    fn new(config: &StatsdConfig) -> Result<Statsd, io::Error> {
+        let address = config
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no statsd address"))?;
+        let socket = UdpSocket::bind(local_address(&address))?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Statsd {
+            socket,
+            prefix: config.prefix.clone(),
+            tags: config.dogstatsd,
+        })
+    }
+
+    // Tags are only supported by the DogStatsD protocol extension, so they
+    // are dropped when talking to a plain StatsD server.
+    fn format(&self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) -> String {
+        let mut msg = format!("{}.{}:{}|{}", self.prefix, name, value, kind);
+        if self.tags && !tags.is_empty() {
+            let tags: Vec<String> = tags.iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+            msg.push_str("|#");
+            msg.push_str(&tags.join(","));
+        }
+        msg
+    }
+}
+
+// The socket is bound to the unspecified address of the server's family.
+fn local_address(server: &SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let mut statsd = Statsd {
+            socket: UdpSocket::bind("127.0.0.1:0").expect("bind failed"),
+            prefix: "gtctl".to_owned(),
+            tags: false,
+        };
+        let tags = [("proto", "ipv4"), ("kind", "blocklist")];
+        assert_eq!(
+            "gtctl.scripts_sent:1|c",
+            statsd.format("scripts_sent", "1", "c", &tags)
+        );
+
+        statsd.tags = true;
+        assert_eq!(
+            "gtctl.scripts_sent:1|c|#proto:ipv4,kind:blocklist",
+            statsd.format("scripts_sent", "1", "c", &tags)
+        );
+        assert_eq!("gtctl.runs:1|c", statsd.format("runs", "1", "c", &[]));
+    }
+
+    #[test]
+    fn test_local_address() {
+        let v4: SocketAddr = "192.0.2.1:8125".parse().expect("parse failed");
+        assert_eq!("0.0.0.0:0", local_address(&v4).to_string());
+        let v6: SocketAddr = "[2001:db8::1]:8125".parse().expect("parse failed");
+        assert_eq!("[::]:0", local_address(&v6).to_string());
+    }
+}
@@ -0,0 +1,131 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use byteorder::{ByteOrder, NetworkEndian};
+use log::warn;
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+
+use crate::config::{Config, ReplyFormat};
+
+/// A stand-in for Gatekeeper's dynamic configuration socket, recording the
+/// scripts it receives, in order, and answering each of them with the same
+/// reply. The server stops when dropped.
+#[derive(Debug)]
+pub struct MockServer {
+    path: PathBuf,
+    received: Arc<Mutex<Vec<String>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start(path: impl AsRef<Path>, reply: String) -> Result<MockServer, io::Error> {
+        let path = path.as_ref().to_owned();
+        let listener = UnixListener::bind(&path)?;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let task = {
+            let received = received.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _addr) = match listener.accept().await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            warn!("mock server failed to accept connection: {}", e);
+                            continue;
+                        }
+                    };
+                    // Connections are handled one at a time, as Gatekeeper
+                    // does, so scripts are recorded in the order they are
+                    // sent.
+                    if let Err(e) = serve(stream, &received, &reply).await {
+                        warn!("mock server failed to handle request: {}", e);
+                    }
+                }
+            })
+        };
+        Ok(MockServer {
+            path,
+            received,
+            task,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the scripts received so far.
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().expect("BUG: poisoned lock").clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the reply of the parameters script for a single existing table
+/// with the given parameters, in the configured reply format.
+pub fn params_reply(config: &Config, rules: usize, tbl8s: usize) -> String {
+    match config.lpm.reply_format {
+        ReplyFormat::Text => format!("0: {}, {}\n", rules, tbl8s),
+        ReplyFormat::Json => format!(
+            "{{\"id\": 0, \"num_rules\": {}, \"num_tbl8s\": {}}}\n",
+            rules, tbl8s
+        ),
+    }
+}
+
+async fn serve(
+    mut stream: UnixStream,
+    received: &Mutex<Vec<String>>,
+    reply: &str,
+) -> Result<(), io::Error> {
+    let len = stream.read_u16().await?;
+    let mut script = vec![0u8; len as usize];
+    stream.read_exact(&mut script).await?;
+    received
+        .lock()
+        .expect("BUG: poisoned lock")
+        .push(String::from_utf8_lossy(&script).into_owned());
+
+    let mut header = [0u8; 2];
+    NetworkEndian::write_u16(&mut header, reply.len() as u16);
+    stream.write_all(&header).await?;
+    stream.write_all(reply.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::fs;
+
+    use super::*;
+    use crate::dyncfg;
+
+    #[tokio::test]
+    async fn test_mock_server() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let server = MockServer::start(tmp.path().join("socket"), "0: 10, 2\n".to_owned())
+            .await
+            .expect("start failed");
+
+        for i in 0..2 {
+            let script = tmp.path().join(format!("script{}", i));
+            fs::write(&script, format!("script {}", i))
+                .await
+                .expect("write failed");
+            let reply = dyncfg::send_config_script(server.path(), &script, &Default::default())
+                .await
+                .expect("send failed");
+            assert_eq!("0: 10, 2\n", reply);
+        }
+        assert_eq!(vec!["script 0", "script 1"], server.received());
+    }
+}
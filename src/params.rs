@@ -1,19 +1,22 @@
 use std::cmp::max;
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fmt;
+use std::hash::Hash;
 use std::marker::PhantomData;
-use std::num::ParseIntError;
 use std::path::Path;
 
-use lazy_static::lazy_static;
-use regex::Regex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use ipnet::{Ipv4Net, Ipv6Net};
 use serde::de::DeserializeOwned;
 
-use crate::config::EstimateConfig;
+use crate::config::{
+    Caps, DyncfgConfig, EstimateConfig, Ipv4Lpm, Ipv6Lpm, Lpm6Geometry, LpmConfig, ReplyFormat,
+    ReplyPattern, Rounding,
+};
 use crate::dyncfg;
+use crate::error;
+use crate::reply::{self, ParseError};
 
 #[derive(Debug, Eq, PartialEq, Serialize)]
 pub struct Params<T> {
@@ -32,6 +35,61 @@ impl<T> Params<T> {
     }
 }
 
+impl<T> Params<T> {
+    /// Returns whether these parameters exceed the given limits.
+    pub fn exceeds(&self, limits: &Limits) -> bool {
+        self.num_rules > limits.max_rules || self.num_tbl8s > limits.max_tbl8s
+    }
+
+    /// Returns these parameters grown by the given percentage.
+    pub fn grown(&self, percent: usize) -> Params<T> {
+        Params::new(
+            with_headroom(self.num_rules, percent),
+            with_headroom(self.num_tbl8s, percent),
+        )
+    }
+
+    /// Returns the smallest parameters of which these take at most the
+    /// given percentage.
+    pub fn within(&self, percent: usize) -> Params<T> {
+        Params::new(
+            capacity_for(self.num_rules, percent),
+            capacity_for(self.num_tbl8s, percent),
+        )
+    }
+
+    /// Returns the larger of each of these and the given parameters.
+    pub fn at_least(&self, other: &Params<T>) -> Params<T> {
+        Params::new(
+            self.num_rules.max(other.num_rules),
+            self.num_tbl8s.max(other.num_tbl8s),
+        )
+    }
+
+    /// Returns these parameters rounded up as configured.
+    pub fn rounded(&self, rounding: Rounding) -> Params<T> {
+        Params::new(
+            round_up(self.num_rules, rounding),
+            round_up(self.num_tbl8s, rounding),
+        )
+    }
+
+    /// Returns these parameters rounded up as configured, but never beyond
+    /// the limits of DPDK's tables of the protocol.
+    pub fn rounded_for(&self, proto: &str, config: &EstimateConfig) -> Params<T> {
+        self.rounded(config.rounding)
+            .truncated(&lpm_limits(proto, config))
+    }
+
+    /// Returns these parameters capped at the given limits.
+    pub fn truncated(&self, limits: &Limits) -> Params<T> {
+        Params::new(
+            self.num_rules.min(limits.max_rules),
+            self.num_tbl8s.min(limits.max_tbl8s),
+        )
+    }
+}
+
 impl<T> fmt::Display for Params<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "rules={}, tbl8s={}", self.num_rules, self.num_tbl8s)
@@ -39,17 +97,17 @@ impl<T> fmt::Display for Params<T> {
 }
 
 pub fn estimate_ipv4(nets: &BTreeSet<Ipv4Net>, config: &EstimateConfig) -> Params<Ipv4Net> {
-    estimate_params(nets, config, lpm_add_tables)
+    estimate_params(nets, config, &*ipv4_estimator(config))
 }
 
 pub fn estimate_ipv6(nets: &BTreeSet<Ipv6Net>, config: &EstimateConfig) -> Params<Ipv6Net> {
-    estimate_params(nets, config, lpm6_add_tables)
+    estimate_params(nets, config, &*ipv6_estimator(config))
 }
 
-fn estimate_params<T, F>(nets: &BTreeSet<T>, config: &EstimateConfig, f: F) -> Params<T>
+fn estimate_params<T, E>(nets: &BTreeSet<T>, config: &EstimateConfig, estimator: &E) -> Params<T>
 where
-    T: Ord + DeserializeOwned,
-    F: Fn(&T, &mut HashSet<T>) -> usize,
+    T: Ord + Hash + DeserializeOwned,
+    E: LpmEstimator<T> + ?Sized,
 {
     let mut num_rules = 0;
     let mut num_tbl8s = 0;
@@ -57,114 +115,668 @@ where
 
     for net in nets {
         num_rules += 1;
-        num_tbl8s += f(&net, &mut prefixes);
+        num_tbl8s += estimator.add_tables(net, &mut prefixes);
     }
 
     num_rules = max(1, config.rules_scaling_factor * num_rules);
     num_tbl8s = max(1, config.tbl8s_scaling_factor * num_tbl8s);
 
+    num_rules = with_headroom(num_rules, config.headroom_percent);
+    num_tbl8s = with_headroom(num_tbl8s, config.headroom_percent);
+
     Params::new(num_rules, num_tbl8s)
 }
 
-fn lpm_add_tables(net: &Ipv4Net, prefixes: &mut HashSet<Ipv4Net>) -> usize {
-    if net.prefix_len() <= 24 {
-        return 0;
+/// The largest parameters a table can be created with.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Limits {
+    pub max_rules: usize,
+    pub max_tbl8s: usize,
+}
+
+// DPDK stores the number of rules in a 32-bit integer, and caps the number
+// of tbl8 groups at RTE_LPM_MAX_TBL8_NUM_GROUPS and
+// RTE_LPM6_TBL8_MAX_NUM_GROUPS, respectively.
+pub const LPM_LIMITS: Limits = Limits {
+    max_rules: u32::MAX as usize,
+    max_tbl8s: 1 << 24,
+};
+
+pub const LPM6_LIMITS: Limits = Limits {
+    max_rules: u32::MAX as usize,
+    max_tbl8s: 1 << 21,
+};
+
+// Parameters without a configured cap are unlimited.
+impl From<&Caps> for Limits {
+    fn from(caps: &Caps) -> Limits {
+        Limits {
+            max_rules: caps.max_rules.unwrap_or(usize::MAX),
+            max_tbl8s: caps.max_tbl8s.unwrap_or(usize::MAX),
+        }
+    }
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = |n| match n {
+            usize::MAX => "unlimited".to_owned(),
+            n => n.to_string(),
+        };
+        write!(
+            f,
+            "rules={}, tbl8s={}",
+            limit(self.max_rules),
+            limit(self.max_tbl8s)
+        )
+    }
+}
+
+/// The limits of the LPM implementation configured for the given protocol.
+pub fn lpm_limits(proto: &str, config: &EstimateConfig) -> Limits {
+    match proto {
+        "ipv4" => ipv4_estimator(config).limits(),
+        _ => ipv6_estimator(config).limits(),
+    }
+}
+
+/// An estimated parameter approaching one of the limits of a table.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LimitWarning {
+    pub param: &'static str,
+    pub value: usize,
+    pub limit: usize,
+    pub source: &'static str,
+}
+
+impl fmt::Display for LimitWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}={} is at {:.1}% of the {} limit of {}",
+            self.param,
+            self.value,
+            percent(self.value, self.limit),
+            self.source,
+            self.limit
+        )
+    }
+}
+
+/// Returns a warning for each estimated parameter at or above `warn_percent`
+/// of the given limits.
+pub fn check_limits<T>(
+    est: &Params<T>,
+    limits: &Limits,
+    source: &'static str,
+    warn_percent: usize,
+) -> Vec<LimitWarning> {
+    let mut warnings = Vec::new();
+    let params = [
+        ("rules", est.num_rules, limits.max_rules),
+        ("tbl8s", est.num_tbl8s, limits.max_tbl8s),
+    ];
+    for &(param, value, limit) in &params {
+        if percent(value, limit) >= warn_percent as f64 {
+            warnings.push(LimitWarning {
+                param,
+                value,
+                limit,
+                source,
+            });
+        }
+    }
+    warnings
+}
+
+/// The unscaled number of tbl8s needed to hold the given IPv4 prefixes.
+pub fn tbl8s_ipv4(nets: &BTreeSet<Ipv4Net>, config: &EstimateConfig) -> usize {
+    count_tbl8s(nets, &*ipv4_estimator(config))
+}
+
+/// The unscaled number of tbl8s needed to hold the given IPv6 prefixes.
+pub fn tbl8s_ipv6(nets: &BTreeSet<Ipv6Net>, config: &EstimateConfig) -> usize {
+    count_tbl8s(nets, &*ipv6_estimator(config))
+}
+
+fn count_tbl8s<T, E>(nets: &BTreeSet<T>, estimator: &E) -> usize
+where
+    T: Eq + Hash,
+    E: LpmEstimator<T> + ?Sized,
+{
+    let mut prefixes = HashSet::new();
+    nets.iter()
+        .map(|net| estimator.add_tables(net, &mut prefixes))
+        .sum()
+}
+
+fn round_up(n: usize, rounding: Rounding) -> usize {
+    match rounding {
+        Rounding::None => n,
+        Rounding::PowerOfTwo => n.checked_next_power_of_two().unwrap_or(n),
+        Rounding::Multiple(m) => match n % m {
+            0 => n,
+            r => n.checked_add(m - r).unwrap_or(n),
+        },
+    }
+}
+
+fn with_headroom(n: usize, percent: usize) -> usize {
+    // Round up so that any non-zero headroom adds at least one entry.
+    n + (n * percent + 99) / 100
+}
+
+/// How an LPM implementation allocates tbl8s and how large its tables can
+/// be, so that estimates can follow Gatekeeper LPM backends other than the
+/// stock DPDK ones.
+pub trait LpmEstimator<T: Eq + Hash> {
+    /// Returns the prefixes whose tbl8s `net` is stored in, shortest first.
+    /// Each distinct prefix takes `tbl8s_per_prefix` tbl8s.
+    fn tbl8_prefixes(&self, net: &T) -> Vec<T>;
+
+    /// The largest parameters a table can be created with.
+    fn limits(&self) -> Limits;
+
+    /// The number of tbl8s each distinct prefix takes.
+    fn tbl8s_per_prefix(&self) -> usize {
+        1
+    }
+
+    /// Returns the number of tbl8s that adding `net` allocates in a table
+    /// whose tbl8s are already allocated for the prefixes in `allocated`,
+    /// which is updated.
+    fn add_tables(&self, net: &T, allocated: &mut HashSet<T>) -> usize {
+        let mut tables = 0;
+        for prefix in self.tbl8_prefixes(net) {
+            if allocated.insert(prefix) {
+                tables += self.tbl8s_per_prefix();
+            }
+        }
+        tables
+    }
+}
+
+/// DPDK's `rte_lpm`, a DIR-24-8 table where every prefix longer than 24
+/// bits is stored in the tbl8 of its /24.
+#[derive(Debug, Clone, Copy)]
+pub struct Dir24_8;
+
+impl LpmEstimator<Ipv4Net> for Dir24_8 {
+    fn tbl8_prefixes(&self, net: &Ipv4Net) -> Vec<Ipv4Net> {
+        if net.prefix_len() <= 24 {
+            return Vec::new();
+        }
+
+        // For a prefix with length longer than 24, one tbl8
+        // is needed according to the addition description in
+        // DPDK LPM library:
+        // https://doc.dpdk.org/guides/prog_guide/lpm_lib.html#addition
+        //
+        // unwrap is safe because the prefix length is always 24.
+        vec![Ipv4Net::new(net.addr(), 24).unwrap().trunc()]
+    }
+
+    fn limits(&self) -> Limits {
+        LPM_LIMITS
+    }
+}
+
+/// DPDK's `rte_lpm6`, where prefixes longer than the first stage are
+/// expanded into a tbl8 for every stride they extend past it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lpm6 {
+    geometry: Lpm6Geometry,
+}
+
+impl Lpm6 {
+    pub fn new(geometry: Lpm6Geometry) -> Lpm6 {
+        Lpm6 { geometry }
+    }
+}
+
+impl LpmEstimator<Ipv6Net> for Lpm6 {
+    fn tbl8_prefixes(&self, net: &Ipv6Net) -> Vec<Ipv6Net> {
+        let mut depth = self.geometry.first_stage_depth;
+        let mut prefixes = Vec::new();
+
+        let addr = net.addr();
+        let prefix_len = net.prefix_len();
+
+        while depth < prefix_len {
+            // unwrap is safe because `depth` is smaller than `prefix_len`,
+            // which comes from a valid Ipv6Net.
+            prefixes.push(Ipv6Net::new(addr, depth).unwrap().trunc());
+            depth += self.geometry.tbl8_stride;
+        }
+
+        prefixes
+    }
+
+    fn limits(&self) -> Limits {
+        LPM6_LIMITS
+    }
+
+    fn tbl8s_per_prefix(&self) -> usize {
+        self.geometry.tbl8s_per_expansion
+    }
+}
+
+/// The estimator for the configured IPv4 LPM implementation.
+pub fn ipv4_estimator(config: &EstimateConfig) -> Box<dyn LpmEstimator<Ipv4Net>> {
+    match config.ipv4_lpm {
+        Ipv4Lpm::Dir24_8 => Box::new(Dir24_8),
+    }
+}
+
+/// The estimator for the configured IPv6 LPM implementation.
+pub fn ipv6_estimator(config: &EstimateConfig) -> Box<dyn LpmEstimator<Ipv6Net>> {
+    match config.ipv6_lpm {
+        Ipv6Lpm::Lpm6 => Box::new(Lpm6::new(config.lpm6)),
+    }
+}
+
+/// Unscaled tbl8 usage of a prefix set, grouped by the depth at which tbl8s
+/// are allocated and by the first-stage bucket they descend from.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Tbl8Breakdown<T: Ord> {
+    pub depths: BTreeMap<u8, usize>,
+    pub buckets: BTreeMap<T, BucketUsage>,
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct BucketUsage {
+    pub prefixes: usize,
+    pub tbl8s: usize,
+}
+
+impl<T: Ord> Tbl8Breakdown<T> {
+    fn new() -> Tbl8Breakdown<T> {
+        Tbl8Breakdown {
+            depths: BTreeMap::new(),
+            buckets: BTreeMap::new(),
+        }
     }
 
-    // For a prefix with length longer than 24, one tbl8
-    // is needed according to the addition description in
-    // DPDK LPM library:
-    // https://doc.dpdk.org/guides/prog_guide/lpm_lib.html#addition
-    //
-    // unwrap is safe because the prefix length is always 24.
-    let prefix = Ipv4Net::new(net.addr(), 24).unwrap().trunc();
-    if prefixes.contains(&prefix) {
-        return 0;
+    /// Returns the `n` buckets consuming the most tbl8s, using the number of
+    /// prefixes in each bucket to break ties.
+    pub fn top_buckets(&self, n: usize) -> Vec<(&T, &BucketUsage)> {
+        let mut buckets: Vec<_> = self.buckets.iter().collect();
+        buckets.sort_by(|(_, a), (_, b)| (b.tbl8s, b.prefixes).cmp(&(a.tbl8s, a.prefixes)));
+        buckets.truncate(n);
+        buckets
     }
-    prefixes.insert(prefix);
-    1
 }
 
-fn lpm6_add_tables(net: &Ipv6Net, prefixes: &mut HashSet<Ipv6Net>) -> usize {
-    let mut depth = 24;
-    let mut ret = 0;
+pub fn breakdown_ipv4(nets: &BTreeSet<Ipv4Net>, config: &EstimateConfig) -> Tbl8Breakdown<Ipv4Net> {
+    breakdown(nets, &*ipv4_estimator(config), Ipv4Net::prefix_len)
+}
+
+pub fn breakdown_ipv6(nets: &BTreeSet<Ipv6Net>, config: &EstimateConfig) -> Tbl8Breakdown<Ipv6Net> {
+    breakdown(nets, &*ipv6_estimator(config), Ipv6Net::prefix_len)
+}
 
-    let addr = net.addr();
-    let prefix_len = net.prefix_len();
+// Same walk as LpmEstimator::add_tables, keeping track of where each tbl8 is
+// allocated. Prefixes are bucketed by their first tbl8.
+fn breakdown<T, E>(nets: &BTreeSet<T>, estimator: &E, depth: fn(&T) -> u8) -> Tbl8Breakdown<T>
+where
+    T: Ord + Hash + Clone,
+    E: LpmEstimator<T> + ?Sized,
+{
+    let mut breakdown = Tbl8Breakdown::new();
+    let mut allocated = HashSet::new();
 
-    while depth < prefix_len {
-        // unwrap is safe because `depth` is smaller than `prefix_len`,
-        // which comes from a valid Ipv6Net.
-        let prefix = Ipv6Net::new(addr, depth).unwrap().trunc();
-        if !prefixes.contains(&prefix) {
-            prefixes.insert(prefix);
-            ret += 1;
+    for net in nets {
+        let prefixes = estimator.tbl8_prefixes(net);
+        let bucket = match prefixes.first() {
+            Some(bucket) => bucket.clone(),
+            None => continue,
+        };
+        let usage = breakdown
+            .buckets
+            .entry(bucket)
+            .or_insert_with(BucketUsage::default);
+        usage.prefixes += 1;
+
+        let n = estimator.tbl8s_per_prefix();
+        for prefix in prefixes {
+            let d = depth(&prefix);
+            if allocated.insert(prefix) {
+                usage.tbl8s += n;
+                *breakdown.depths.entry(d).or_insert(0) += n;
+            }
         }
-        depth += 8;
     }
 
-    ret
+    breakdown
+}
+
+/// The parameters of a table as reported by one Gatekeeper instance. Usage
+/// counts are only known if the parameters script reports them.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TableParams {
+    pub id: u64,
+    pub max_rules: usize,
+    pub used_rules: Option<usize>,
+    pub max_tbl8s: usize,
+    pub used_tbl8s: Option<usize>,
 }
 
+impl TableParams {
+    pub fn new(id: u64, max_rules: usize, max_tbl8s: usize) -> TableParams {
+        TableParams {
+            id,
+            max_rules,
+            used_rules: None,
+            max_tbl8s,
+            used_tbl8s: None,
+        }
+    }
+
+    pub fn with_usage(mut self, used_rules: usize, used_tbl8s: usize) -> TableParams {
+        self.used_rules = Some(used_rules);
+        self.used_tbl8s = Some(used_tbl8s);
+        self
+    }
+}
+
+impl fmt::Display for TableParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |used: Option<usize>, max: usize| match used {
+            Some(used) => format!("{}/{}", used, max),
+            None => max.to_string(),
+        };
+        write!(
+            f,
+            "{}: rules={}, tbl8s={}",
+            self.id,
+            count(self.used_rules, self.max_rules),
+            count(self.used_tbl8s, self.max_tbl8s)
+        )
+    }
+}
+
+/// The current parameters of a table in each Gatekeeper instance, sorted by
+/// instance ID.
 #[derive(Debug)]
-pub struct CurrentParams<T>(pub Vec<Params<T>>);
+pub struct CurrentParams<T> {
+    pub tables: Vec<TableParams>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> CurrentParams<T> {
+    pub fn new(mut tables: Vec<TableParams>) -> CurrentParams<T> {
+        tables.sort_by_key(|t| t.id);
+        CurrentParams {
+            tables,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns whether the estimated parameters fit the capacity of the
+    /// table in every instance.
+    pub fn fits(&self, est: &Params<T>) -> bool {
+        self.tables
+            .iter()
+            .all(|t| est.num_rules <= t.max_rules && est.num_tbl8s <= t.max_tbl8s)
+    }
+
+    /// Returns whether the given update keeps the table in every instance
+    /// at or below `threshold_percent` of its capacity.
+    ///
+    /// For instances reporting their usage, the projected usage after the
+    /// update is checked, so that tables are only replaced when they
+    /// actually run out of room. The other instances must fit the estimated
+    /// parameters.
+    pub fn fits_update(&self, est: &Params<T>, update: &Update, threshold_percent: usize) -> bool {
+        let fits = |n, max| percent(n, max) <= threshold_percent as f64;
+        self.tables.iter().all(|t| {
+            let rules = match t.used_rules {
+                Some(used) => used.saturating_sub(update.removed) + update.inserted,
+                None => est.num_rules,
+            };
+            // tbl8s freed by removals are not counted, as they are only
+            // released once their last prefix is gone.
+            let tbl8s = match t.used_tbl8s {
+                Some(used) => used + update.inserted_tbl8s,
+                None => est.num_tbl8s,
+            };
+            fits(rules, t.max_rules) && fits(tbl8s, t.max_tbl8s)
+        })
+    }
+}
+
+impl<T> CurrentParams<T> {
+    /// Returns the utilization the estimated parameters would have in the
+    /// most constrained instance, or `None` if no instance reported.
+    pub fn utilization(&self, est: &Params<T>) -> Option<Utilization> {
+        if self.tables.is_empty() {
+            return None;
+        }
+        let max_percent = |f: fn(&TableParams) -> usize, n| {
+            self.tables
+                .iter()
+                .map(|t| percent(n, f(t)))
+                .fold(0.0, f64::max)
+        };
+        Some(Utilization {
+            rules_percent: max_percent(|t| t.max_rules, est.num_rules),
+            tbl8s_percent: max_percent(|t| t.max_tbl8s, est.num_tbl8s),
+        })
+    }
+}
+
+/// Estimated parameters as a percentage of a table's capacity. Values above
+/// 100 mean the table must grow.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Utilization {
+    pub rules_percent: f64,
+    pub tbl8s_percent: f64,
+}
+
+impl fmt::Display for Utilization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rules={:.1}%, tbl8s={:.1}%",
+            self.rules_percent, self.tbl8s_percent
+        )
+    }
+}
+
+fn capacity_for(n: usize, percent: usize) -> usize {
+    (n * 100 + percent - 1) / percent.max(1)
+}
+
+fn percent(n: usize, max: usize) -> f64 {
+    n as f64 * 100.0 / max.max(1) as f64
+}
+
+impl<T> CurrentParams<T> {
+    /// Fills in the usage of the tables of the instances in `usage`,
+    /// replacing any usage reported along with the parameters.
+    pub fn set_usage(&mut self, usage: &[Usage]) {
+        for u in usage {
+            if let Some(t) = self.tables.iter_mut().find(|t| t.id == u.id) {
+                t.used_rules = Some(u.used_rules);
+                t.used_tbl8s = Some(u.used_tbl8s);
+            }
+        }
+    }
+
+    /// Returns the highest usage among the instances reporting it.
+    pub fn max_usage(&self) -> Option<(usize, usize)> {
+        self.tables
+            .iter()
+            .filter_map(|t| Some((t.used_rules?, t.used_tbl8s?)))
+            .fold(None, |max, (r, t)| match max {
+                Some((mr, mt)) => Some((r.max(mr), t.max(mt))),
+                None => Some((r, t)),
+            })
+    }
+}
+
+/// The number of rules and tbl8s in use in a table, as reported by one
+/// Gatekeeper instance in reply to the usage script.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct Usage {
+    pub id: u64,
+    pub used_rules: usize,
+    pub used_tbl8s: usize,
+}
+
+pub async fn read_usage(
+    socket: impl AsRef<Path>,
+    script: &[u8],
+    config: &LpmConfig,
+    dyncfg_config: &DyncfgConfig,
+) -> Result<Vec<Usage>, Error> {
+    let res = dyncfg::send_config_bytes(&socket, script, dyncfg_config).await?;
+    let usage = parse_usage(&res, config.reply_format)?;
+    Ok(usage)
+}
+
+fn parse_usage(s: &str, format: ReplyFormat) -> Result<Vec<Usage>, ParseError> {
+    if format == ReplyFormat::Json {
+        return reply::parse_json(s);
+    }
+    reply::parse_records(s)?
+        .into_iter()
+        .map(|record| match record.values[..] {
+            [used_rules, used_tbl8s] => Ok(Usage {
+                id: record.id,
+                used_rules: used_rules as usize,
+                used_tbl8s: used_tbl8s as usize,
+            }),
+            _ => Err(ParseError::Fields {
+                line: format!("{}: {:?}", record.id, record.values),
+                expected: "2",
+            }),
+        })
+        .collect()
+}
+
+/// The size of an update to a table.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Update {
+    pub removed: usize,
+    pub inserted: usize,
+    // The unscaled number of tbl8s the inserted prefixes need, ignoring
+    // the ones they may share with prefixes already in the table.
+    pub inserted_tbl8s: usize,
+}
 
 pub async fn read<T>(
     socket: impl AsRef<Path>,
-    script: impl AsRef<Path>,
+    script: &[u8],
+    config: &LpmConfig,
+    dyncfg_config: &DyncfgConfig,
 ) -> Result<CurrentParams<T>, Error> {
-    let res = dyncfg::send_config_script(&socket, &script).await?;
-    let params = parse_params(&res)?;
+    let res = dyncfg::send_config_bytes(&socket, script, dyncfg_config).await?;
+    let params = parse_params(&res, config)?;
     Ok(params)
 }
 
-fn parse_params<T>(s: &str) -> Result<CurrentParams<T>, ParseError> {
-    Ok(CurrentParams(parse_lines(s)?))
-}
-
-fn parse_lines<T>(s: &str) -> Result<Vec<Params<T>>, ParseError> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r#"^\s*(\d+):\s*(\d+),\s*(\d+)\s*$"#).unwrap();
-    }
-
-    let mut v = Vec::new();
-
-    for line in s.lines() {
-        if let Some(caps) = RE.captures(line) {
-            let id: usize = caps
-                .get(1)
-                .expect("BUG: nothing captured at 1")
-                .as_str()
-                .parse()?;
-            let p1 = caps
-                .get(2)
-                .expect("BUG: nothing captured at 2")
-                .as_str()
-                .parse()?;
-            let p2 = caps
-                .get(3)
-                .expect("BUG: nothing captured at 3")
-                .as_str()
-                .parse()?;
-            v.push((id, p1, p2));
-            continue;
-        }
-        if line.trim().is_empty() {
-            continue;
+fn parse_params<T>(s: &str, config: &LpmConfig) -> Result<CurrentParams<T>, ParseError> {
+    let tables = match (config.reply_format, &config.reply_pattern) {
+        (ReplyFormat::Text, None) => parse_lines(s)?,
+        (ReplyFormat::Text, Some(pattern)) => parse_pattern(s, pattern)?,
+        (ReplyFormat::Json, _) => parse_json(s)?,
+    };
+    Ok(CurrentParams::new(tables))
+}
+
+// The object returned by each Gatekeeper instance in JSON replies.
+#[derive(Debug, Deserialize)]
+struct JsonParams {
+    id: u64,
+    num_rules: usize,
+    num_tbl8s: usize,
+    used_rules: Option<usize>,
+    used_tbl8s: Option<usize>,
+    free_tbl8s: Option<usize>,
+}
+
+fn parse_json(s: &str) -> Result<Vec<TableParams>, ParseError> {
+    let v: Vec<JsonParams> = reply::parse_json(s)?;
+    Ok(v.into_iter()
+        .map(|p| TableParams {
+            id: p.id,
+            max_rules: p.num_rules,
+            used_rules: p.used_rules,
+            max_tbl8s: p.num_tbl8s,
+            used_tbl8s: p
+                .used_tbl8s
+                .or_else(|| p.free_tbl8s.map(|free| p.num_tbl8s.saturating_sub(free))),
+        })
+        .collect())
+}
+
+fn parse_lines(s: &str) -> Result<Vec<TableParams>, ParseError> {
+    from_records(reply::parse_records(s)?)
+}
+
+// Usage is read from the `used_rules` and `used_tbl8s` or `free_tbl8s`
+// groups if the pattern has them.
+fn parse_pattern(s: &str, pattern: &ReplyPattern) -> Result<Vec<TableParams>, ParseError> {
+    let mut names = vec!["rules", "tbl8s"];
+    for name in &["used_rules", "used_tbl8s", "free_tbl8s"] {
+        if pattern.0.capture_names().any(|n| n == Some(name)) {
+            names.push(name);
         }
-        return Err(ParseError::Line(line.to_owned()));
     }
+    let records = reply::parse_pattern(s, &pattern.0, &names)?;
+    Ok(records
+        .into_iter()
+        .map(|record| {
+            let value = |name| {
+                names
+                    .iter()
+                    .position(|n| *n == name)
+                    .map(|i| record.values[i] as usize)
+            };
+            // Both groups are required, so these are always present.
+            let max_rules = value("rules").unwrap_or(0);
+            let max_tbl8s = value("tbl8s").unwrap_or(0);
+            TableParams {
+                id: record.id,
+                max_rules,
+                used_rules: value("used_rules"),
+                max_tbl8s,
+                used_tbl8s: value("used_tbl8s")
+                    .or_else(|| value("free_tbl8s").map(|free| max_tbl8s.saturating_sub(free))),
+            }
+        })
+        .collect())
+}
 
-    if v.is_empty() {
-        return Err(ParseError::Empty);
-    }
-    v.sort_by_key(|(id, _, _)| *id);
-    Ok(v.iter().map(|(_, nr, nt)| Params::new(*nr, *nt)).collect())
+// Records hold the maximum numbers of rules and tbl8s, optionally followed
+// by the numbers in use.
+fn from_records(records: Vec<reply::Record>) -> Result<Vec<TableParams>, ParseError> {
+    records
+        .into_iter()
+        .map(|record| match record.values[..] {
+            [max_rules, max_tbl8s] => Ok(TableParams::new(
+                record.id,
+                max_rules as usize,
+                max_tbl8s as usize,
+            )),
+            [max_rules, max_tbl8s, used_rules, used_tbl8s] => {
+                Ok(
+                    TableParams::new(record.id, max_rules as usize, max_tbl8s as usize)
+                        .with_usage(used_rules as usize, used_tbl8s as usize),
+                )
+            }
+            _ => Err(ParseError::Fields {
+                line: format!("{}: {:?}", record.id, record.values),
+                expected: "2 or 4",
+            }),
+        })
+        .collect()
 }
 
 #[derive(Debug)]
 pub enum Error {
-    Dyncfg(dyncfg::Error),
+    Dyncfg(error::Error),
     Parse(ParseError),
 }
 
@@ -186,8 +798,8 @@ impl std::error::Error for Error {
     }
 }
 
-impl From<dyncfg::Error> for Error {
-    fn from(e: dyncfg::Error) -> Error {
+impl From<error::Error> for Error {
+    fn from(e: error::Error) -> Error {
         Error::Dyncfg(e)
     }
 }
@@ -198,55 +810,192 @@ impl From<ParseError> for Error {
     }
 }
 
-#[derive(Debug)]
-pub enum ParseError {
-    Empty,
-    Line(String),
-    ParseInt(ParseIntError),
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ParseError::Empty => write!(f, "dyncfg returned an empty response"),
-            ParseError::Line(s) => write!(f, "dyncfg returned an unexpected line: {}", s),
-            ParseError::ParseInt(e) => write!(f, "failed to parse int in dyncfg response: {}", e),
-        }
+    #[test]
+    fn test_with_headroom() {
+        assert_eq!(10, with_headroom(10, 0));
+        assert_eq!(11, with_headroom(10, 10));
+        assert_eq!(12, with_headroom(10, 11));
+        assert_eq!(2, with_headroom(1, 1));
+        assert_eq!(20, with_headroom(10, 100));
     }
-}
 
-impl std::error::Error for ParseError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            ParseError::Empty => None,
-            ParseError::Line(_) => None,
-            ParseError::ParseInt(e) => Some(e),
-        }
+    #[test]
+    fn test_within() {
+        let params = Params::<()>::new(100, 10).within(80);
+        assert_eq!(Params::new(125, 13), params);
+        assert!(percent(10, params.num_tbl8s) <= 80.0);
+        assert_eq!(Params::<()>::new(100, 10), Params::new(100, 10).within(100));
     }
-}
 
-impl From<ParseIntError> for ParseError {
-    fn from(e: ParseIntError) -> ParseError {
-        ParseError::ParseInt(e)
+    #[test]
+    fn test_check_limits() {
+        let limits = Limits {
+            max_rules: 1000,
+            max_tbl8s: 100,
+        };
+        assert!(check_limits::<()>(&Params::new(899, 89), &limits, "dpdk", 90).is_empty());
+
+        let warnings = check_limits::<()>(&Params::new(900, 120), &limits, "dpdk", 90);
+        assert_eq!(2, warnings.len());
+        assert_eq!(
+            "rules=900 is at 90.0% of the dpdk limit of 1000",
+            warnings[0].to_string()
+        );
+        assert_eq!("tbl8s", warnings[1].param);
+
+        let warnings = check_limits::<()>(&Params::new(1, 1 << 23), &LPM6_LIMITS, "dpdk", 90);
+        assert_eq!(1, warnings.len());
+        assert!(check_limits::<()>(&Params::new(1, 1 << 23), &LPM_LIMITS, "dpdk", 90).is_empty());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_round_up() {
+        assert_eq!(1000, round_up(1000, Rounding::None));
+        assert_eq!(1024, round_up(1000, Rounding::PowerOfTwo));
+        assert_eq!(1024, round_up(1024, Rounding::PowerOfTwo));
+        assert_eq!(1, round_up(1, Rounding::PowerOfTwo));
+        assert_eq!(1280, round_up(1025, Rounding::Multiple(256)));
+        assert_eq!(1024, round_up(1024, Rounding::Multiple(256)));
+        assert_eq!(usize::MAX, round_up(usize::MAX, Rounding::PowerOfTwo));
+        assert_eq!(usize::MAX, round_up(usize::MAX, Rounding::Multiple(2)));
+    }
+
+    #[test]
+    fn test_rounded_for() {
+        let config = EstimateConfig {
+            rounding: Rounding::Multiple(1000),
+            ..Default::default()
+        };
+        let params: Params<()> = Params::new(1500, LPM_LIMITS.max_tbl8s - 1);
+        assert_eq!(
+            Params::new(2000, LPM_LIMITS.max_tbl8s),
+            params.rounded_for("ipv4", &config)
+        );
+        let params: Params<()> = Params::new(1500, LPM6_LIMITS.max_tbl8s - 1);
+        assert_eq!(
+            Params::new(2000, LPM6_LIMITS.max_tbl8s),
+            params.rounded_for("ipv6", &config)
+        );
+    }
+
+    #[test]
+    fn test_caps() {
+        let caps = Caps {
+            max_rules: Some(100),
+            max_tbl8s: None,
+        };
+        let limits = Limits::from(&caps);
+        assert_eq!("rules=100, tbl8s=unlimited", limits.to_string());
+
+        let params: Params<()> = Params::new(150, 1 << 30);
+        assert!(params.exceeds(&limits));
+        assert_eq!(Params::new(100, 1 << 30), params.truncated(&limits));
+        assert!(!Params::<()>::new(100, 1 << 30).exceeds(&limits));
+    }
+
+    #[test]
+    fn test_breakdown_ipv4() {
+        let nets: BTreeSet<Ipv4Net> =
+            vec!["10.0.0.0/16", "10.0.0.0/25", "10.0.0.128/25", "10.0.1.1/32"]
+                .into_iter()
+                .map(|s| s.parse().unwrap())
+                .collect();
+
+        let breakdown = breakdown_ipv4(&nets, &EstimateConfig::default());
+        assert_eq!(Some(&2), breakdown.depths.get(&24));
+        let top = breakdown.top_buckets(1);
+        assert_eq!(1, top.len());
+        assert_eq!("10.0.0.0/24".parse::<Ipv4Net>().unwrap(), *top[0].0);
+        assert_eq!(
+            &BucketUsage {
+                prefixes: 2,
+                tbl8s: 1
+            },
+            top[0].1
+        );
+    }
+
+    #[test]
+    fn test_breakdown_ipv6() {
+        let nets: BTreeSet<Ipv6Net> = vec!["2001:db8::/32", "2001:db8::/48", "2001:db8:100::/48"]
+            .into_iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+
+        let config = EstimateConfig::default();
+        let breakdown = breakdown_ipv6(&nets, &config);
+        let tbl8s: usize = breakdown.depths.values().sum();
+        assert_eq!(tbl8s_ipv6(&nets, &config), tbl8s);
+        assert_eq!(Some(&1), breakdown.depths.get(&24));
+        assert_eq!(Some(&1), breakdown.depths.get(&32));
+        assert_eq!(Some(&2), breakdown.depths.get(&40));
+    }
+
+    #[test]
+    fn test_lpm_estimators() {
+        let mut allocated = HashSet::new();
+        let net: Ipv4Net = "10.0.0.0/25".parse().unwrap();
+        assert_eq!(1, Dir24_8.add_tables(&net, &mut allocated));
+        let net: Ipv4Net = "10.0.0.128/25".parse().unwrap();
+        assert_eq!(0, Dir24_8.add_tables(&net, &mut allocated));
+        let net: Ipv4Net = "10.0.0.0/24".parse().unwrap();
+        assert!(Dir24_8.tbl8_prefixes(&net).is_empty());
+
+        let mut allocated = HashSet::new();
+        let net: Ipv6Net = "2001:db8::/48".parse().unwrap();
+        assert_eq!(3, Lpm6::default().add_tables(&net, &mut allocated));
+        let net: Ipv6Net = "2001:db8:100::/48".parse().unwrap();
+        assert_eq!(1, Lpm6::default().add_tables(&net, &mut allocated));
+
+        assert_eq!(LPM_LIMITS, lpm_limits("ipv4", &EstimateConfig::default()));
+        assert_eq!(LPM6_LIMITS, lpm_limits("ipv6", &EstimateConfig::default()));
+    }
+
+    #[test]
+    fn test_lpm6_geometry() {
+        let lpm6 = Lpm6::new(Lpm6Geometry {
+            first_stage_depth: 32,
+            tbl8_stride: 16,
+            tbl8s_per_expansion: 2,
+        });
+        let net: Ipv6Net = "2001:db8::/64".parse().unwrap();
+        let depths: Vec<u8> = lpm6
+            .tbl8_prefixes(&net)
+            .iter()
+            .map(Ipv6Net::prefix_len)
+            .collect();
+        assert_eq!(vec![32, 48], depths);
+        assert_eq!(4, lpm6.add_tables(&net, &mut HashSet::new()));
+
+        let mut config = EstimateConfig::default();
+        config.lpm6.first_stage_depth = 48;
+        let nets: BTreeSet<Ipv6Net> = vec![net].into_iter().collect();
+        assert_eq!(2, tbl8s_ipv6(&nets, &config));
+        let breakdown = breakdown_ipv6(&nets, &config);
+        assert_eq!(Some(&1), breakdown.depths.get(&48));
+        assert_eq!(Some(&1), breakdown.depths.get(&56));
+    }
+
+    fn sorted(tables: Vec<TableParams>) -> Vec<TableParams> {
+        CurrentParams::<()>::new(tables).tables
+    }
 
     #[test]
     fn test_parse_lines() {
         let lines = "";
-        assert!(parse_lines::<()>(lines).is_err());
+        assert!(parse_lines(lines).is_err());
 
         let lines = "foo";
-        assert!(parse_lines::<()>(lines).is_err());
+        assert!(parse_lines(lines).is_err());
 
         let lines = "99:101,102";
         assert_eq!(
-            vec![Params::new(101, 102)],
-            parse_lines::<()>(lines).unwrap()
+            vec![TableParams::new(99, 101, 102)],
+            parse_lines(lines).unwrap()
         );
 
         let lines = r#"
@@ -256,8 +1005,156 @@ mod tests {
         "#;
 
         assert_eq!(
-            vec![Params::new(1, 2), Params::new(3, 4), Params::new(5, 6)],
-            parse_lines::<()>(lines).unwrap()
+            vec![
+                TableParams::new(0, 1, 2),
+                TableParams::new(1, 3, 4),
+                TableParams::new(2, 5, 6)
+            ],
+            sorted(parse_lines(lines).unwrap())
+        );
+
+        assert_eq!(
+            vec![TableParams::new(0, 10, 4).with_usage(7, 1)],
+            parse_lines("0: 10, 4, 7, 1").unwrap()
+        );
+        assert!(parse_lines("0: 10, 4, 7").is_err());
+    }
+
+    #[test]
+    fn test_parse_pattern() {
+        let pattern = ReplyPattern(
+            regex::Regex::new(r"^lpm\[(?P<id>\d+)\] tbl8s=(?P<tbl8s>\d+) rules=(?P<rules>\d+)$")
+                .unwrap(),
+        );
+        let reply = "lpm[1] tbl8s=4 rules=3\nlpm[0] tbl8s=2 rules=1\n";
+        assert_eq!(
+            vec![TableParams::new(0, 1, 2), TableParams::new(1, 3, 4)],
+            sorted(parse_pattern(reply, &pattern).unwrap())
+        );
+        assert!(parse_pattern("0: 1, 2", &pattern).is_err());
+
+        let pattern = ReplyPattern(
+            regex::Regex::new(
+                r"^(?P<id>\d+) (?P<used_rules>\d+)/(?P<rules>\d+) (?P<used_tbl8s>\d+)/(?P<tbl8s>\d+)$",
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            vec![TableParams::new(0, 10, 4).with_usage(7, 1)],
+            parse_pattern("0 7/10 1/4", &pattern).unwrap()
+        );
+
+        let pattern = ReplyPattern(
+            regex::Regex::new(
+                r"^(?P<id>\d+) (?P<rules>\d+) (?P<tbl8s>\d+) free=(?P<free_tbl8s>\d+)$",
+            )
+            .unwrap(),
+        );
+        let expected = TableParams {
+            used_tbl8s: Some(3),
+            ..TableParams::new(0, 10, 4)
+        };
+        assert_eq!(
+            vec![expected],
+            parse_pattern("0 10 4 free=1", &pattern).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        assert!(parse_json("").is_err());
+        assert!(parse_json("{\"id\": 0, \"num_rules\": 1}").is_err());
+
+        let reply = r#"
+            {"id": 2, "num_rules": 5, "num_tbl8s": 6}
+            {"id": 0, "num_rules": 1, "num_tbl8s": 2, "used_rules": 1, "used_tbl8s": 0}
+            {"id": 1, "num_rules": 3, "num_tbl8s": 4, "free_tbl8s": 1}
+        "#;
+        assert_eq!(
+            vec![
+                TableParams::new(0, 1, 2).with_usage(1, 0),
+                TableParams {
+                    used_tbl8s: Some(3),
+                    ..TableParams::new(1, 3, 4)
+                },
+                TableParams::new(2, 5, 6)
+            ],
+            sorted(parse_json(reply).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_usage() {
+        let expected = vec![
+            Usage {
+                id: 0,
+                used_rules: 7,
+                used_tbl8s: 1,
+            },
+            Usage {
+                id: 1,
+                used_rules: 9,
+                used_tbl8s: 0,
+            },
+        ];
+        assert_eq!(
+            expected,
+            parse_usage("0: 7, 1\n1: 9, 0\n", ReplyFormat::Text).unwrap()
         );
+        let reply = r#"{"id": 0, "used_rules": 7, "used_tbl8s": 1}
+            {"id": 1, "used_rules": 9, "used_tbl8s": 0}"#;
+        assert_eq!(expected, parse_usage(reply, ReplyFormat::Json).unwrap());
+        assert!(parse_usage("0: 7, 1, 2", ReplyFormat::Text).is_err());
+
+        let mut cur: CurrentParams<()> = CurrentParams::new(vec![
+            TableParams::new(0, 10, 4).with_usage(1, 1),
+            TableParams::new(1, 10, 4),
+            TableParams::new(2, 10, 4),
+        ]);
+        assert_eq!(Some((1, 1)), cur.max_usage());
+        cur.set_usage(&expected);
+        assert_eq!(TableParams::new(0, 10, 4).with_usage(7, 1), cur.tables[0]);
+        assert_eq!(TableParams::new(1, 10, 4).with_usage(9, 0), cur.tables[1]);
+        assert_eq!(TableParams::new(2, 10, 4), cur.tables[2]);
+        assert_eq!(Some((9, 1)), cur.max_usage());
+    }
+
+    #[test]
+    fn test_current_params() {
+        let cur: CurrentParams<()> = CurrentParams::new(vec![
+            TableParams::new(0, 20, 20).with_usage(18, 5),
+            TableParams::new(1, 20, 10),
+        ]);
+        assert!(cur.fits(&Params::new(20, 10)));
+        assert!(!cur.fits(&Params::new(15, 15)));
+        assert!(!cur.fits(&Params::new(21, 1)));
+
+        let update = |removed, inserted, inserted_tbl8s| Update {
+            removed,
+            inserted,
+            inserted_tbl8s,
+        };
+        let est = Params::new(20, 10);
+        assert!(cur.fits_update(&est, &update(0, 2, 0), 100));
+        assert!(!cur.fits_update(&est, &update(0, 3, 0), 100));
+        assert!(cur.fits_update(&est, &update(5, 7, 15), 100));
+        assert!(!cur.fits_update(&est, &update(5, 7, 16), 100));
+        // Instances without usage must fit the estimate.
+        assert!(!cur.fits_update(&Params::new(20, 11), &update(0, 0, 0), 100));
+
+        // Only reported usage counts, whatever the estimate.
+        let half: CurrentParams<()> =
+            CurrentParams::new(vec![TableParams::new(0, 20, 20).with_usage(10, 10)]);
+        assert!(half.fits_update(&Params::new(40, 40), &update(0, 10, 10), 100));
+        assert_eq!("0: rules=18/20, tbl8s=5/20", cur.tables[0].to_string());
+
+        let utilization = cur.utilization(&Params::new(15, 5)).unwrap();
+        assert_eq!(75.0, utilization.rules_percent);
+        assert_eq!(50.0, utilization.tbl8s_percent);
+        assert_eq!("rules=75.0%, tbl8s=50.0%", utilization.to_string());
+        assert!(CurrentParams::<()>::new(vec![])
+            .utilization(&Params::new(1, 1))
+            .is_none());
+        assert_eq!("1: rules=20, tbl8s=10", cur.tables[1].to_string());
     }
 }
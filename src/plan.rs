@@ -0,0 +1,445 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::fs;
+use tokio::io;
+
+use crate::params::TableParams;
+use crate::state::{Mode, TableRun};
+use crate::util::safe_write;
+
+pub const VERSION: u32 = 1;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The changes applying an aggregate would make to each target, written by
+/// `gtctl plan` for review and executed by `gtctl apply`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    pub aggregate: PathBuf,
+    pub aggregate_hash: String,
+    // Template variable overrides given when planning, reused when applying
+    // so that the same scripts are rendered.
+    #[serde(default)]
+    pub vars: BTreeMap<String, serde_yaml::Value>,
+    pub targets: Vec<TargetPlan>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TargetPlan {
+    pub target: Option<String>,
+    // The hash of the old aggregate the changes were computed against.
+    pub base_hash: Option<String>,
+    pub tables: Vec<TablePlan>,
+    // The BPF programs to be uploaded before any table is changed.
+    #[serde(default)]
+    pub bpf: Vec<BpfPlan>,
+}
+
+/// A BPF program whose file changed since it was last uploaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BpfPlan {
+    pub program: String,
+    pub index: u8,
+    // The SHA-256 digest of the program file.
+    pub hash: String,
+    // Whether a program was uploaded at the index before.
+    pub replace: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TablePlan {
+    pub table: String,
+    pub proto: String,
+    pub kind: Option<String>,
+    pub mode: Mode,
+    pub num_rules: usize,
+    pub num_tbl8s: usize,
+    // The parameters reported by each Gatekeeper instance when planning,
+    // for review only.
+    #[serde(default)]
+    pub current: Vec<TableParams>,
+    pub inserted: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    // The SHA-256 digests of the scripts, in the order they are sent.
+    pub scripts: Vec<String>,
+}
+
+impl From<TableRun> for TablePlan {
+    fn from(run: TableRun) -> TablePlan {
+        TablePlan {
+            table: run.table,
+            proto: run.proto,
+            kind: run.kind,
+            mode: run.mode,
+            num_rules: run.num_rules,
+            num_tbl8s: run.num_tbl8s,
+            current: run.current,
+            inserted: run.inserted,
+            removed: run.removed,
+            unchanged: run.unchanged,
+            scripts: run.script_hashes,
+        }
+    }
+}
+
+impl Plan {
+    pub fn new(
+        aggregate: impl AsRef<Path>,
+        aggregate_hash: String,
+        vars: BTreeMap<String, serde_yaml::Value>,
+        targets: Vec<TargetPlan>,
+    ) -> Plan {
+        Plan {
+            version: VERSION,
+            created_at: Utc::now(),
+            aggregate: aggregate.as_ref().to_owned(),
+            aggregate_hash,
+            vars,
+            targets,
+        }
+    }
+}
+
+impl TargetPlan {
+    /// Checks that the scripts rendered for a table, given by their digests,
+    /// are the ones in the plan.
+    pub fn check_scripts(&self, table: &str, hashes: &[String]) -> Result<(), Error> {
+        match self.tables.iter().find(|t| t.table == table) {
+            Some(planned) if planned.scripts == hashes => Ok(()),
+            Some(_) => Err(Error::Drift(table.to_owned())),
+            None => Err(Error::Unplanned(table.to_owned())),
+        }
+    }
+
+    /// Checks that the target, planned again, would make exactly the planned
+    /// changes.
+    pub fn check(&self, replanned: &TargetPlan) -> Result<(), Error> {
+        if self.base_hash != replanned.base_hash {
+            return Err(Error::Base);
+        }
+        for table in &replanned.tables {
+            self.check_scripts(&table.table, &table.scripts)?;
+        }
+        for planned in &self.tables {
+            if !replanned.tables.iter().any(|t| t.table == planned.table) {
+                return Err(Error::Unchanged(planned.table.clone()));
+            }
+        }
+        self.check_bpf(&replanned.bpf)
+    }
+
+    /// Checks that the BPF programs to be uploaded are the planned ones.
+    pub fn check_bpf(&self, uploads: &[BpfPlan]) -> Result<(), Error> {
+        if self.bpf == uploads {
+            Ok(())
+        } else {
+            Err(Error::BpfDrift)
+        }
+    }
+}
+
+/// Returns the path of the detached signature of the plan at `path`.
+pub fn signature_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+/// Returns the hex-encoded HMAC-SHA256 of the data.
+pub fn sign(data: &[u8], key: &[u8]) -> Result<String, Error> {
+    let mac = hmac(data, key)?;
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+pub fn verify(data: &[u8], signature: &str, key: &[u8]) -> Result<(), Error> {
+    let tag = hex::decode(signature.trim()).map_err(|_| Error::BadSignature)?;
+    hmac(data, key)?
+        .verify(&tag)
+        .map_err(|_| Error::BadSignature)
+}
+
+fn hmac(data: &[u8], key: &[u8]) -> Result<HmacSha256, Error> {
+    let mut mac = HmacSha256::new_from_slice(key).map_err(|_| Error::Key)?;
+    mac.update(data);
+    Ok(mac)
+}
+
+/// Reads a plan. Given a key, the plan must have a detached signature of the
+/// file's exact contents made with it.
+pub async fn read(path: impl AsRef<Path>, key: Option<&[u8]>) -> Result<Plan, Error> {
+    let data = fs::read(&path).await?;
+    if let Some(key) = key {
+        let signature = match fs::read_to_string(signature_path(&path)).await {
+            Ok(signature) => signature,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(Error::Unsigned),
+            Err(e) => return Err(e.into()),
+        };
+        verify(&data, &signature, key)?;
+    }
+    let plan: Plan = serde_json::from_slice(&data)?;
+    if plan.version != VERSION {
+        return Err(Error::Version(plan.version));
+    }
+    Ok(plan)
+}
+
+/// Writes a plan, along with its detached signature if a key is given. A
+/// signature left by a previous plan at the same path is removed otherwise.
+pub async fn write(path: impl AsRef<Path>, plan: &Plan, key: Option<&[u8]>) -> Result<(), Error> {
+    let data = serde_json::to_vec_pretty(plan)?;
+    safe_write(&path, &data).await?;
+    let signature_path = signature_path(&path);
+    match key {
+        Some(key) => safe_write(&signature_path, sign(&data, key)?.as_bytes()).await?,
+        None => match fs::remove_file(&signature_path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        },
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Version(u32),
+    Key,
+    Unsigned,
+    BadSignature,
+    Base,
+    Drift(String),
+    Unplanned(String),
+    Unchanged(String),
+    BpfDrift,
+    // The aggregate the plan was made for.
+    Changed(PathBuf),
+    Targets,
+}
+
+impl Error {
+    /// Whether the plan no longer matches the aggregate or the targets, so
+    /// that a new one must be made.
+    pub fn is_stale(&self) -> bool {
+        match self {
+            Error::Base
+            | Error::Drift(_)
+            | Error::Unplanned(_)
+            | Error::Unchanged(_)
+            | Error::BpfDrift
+            | Error::Changed(_)
+            | Error::Targets => true,
+            Error::Io(_)
+            | Error::Json(_)
+            | Error::Version(_)
+            | Error::Key
+            | Error::Unsigned
+            | Error::BadSignature => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Json(e) => write!(f, "invalid plan: {}", e),
+            Error::Version(v) => write!(f, "unsupported plan version {}", v),
+            Error::Key => write!(f, "invalid signing key"),
+            Error::Unsigned => write!(f, "plan is not signed"),
+            Error::BadSignature => write!(f, "plan signature does not match"),
+            Error::Drift(t) => write!(f, "the scripts of table {} differ from the plan", t),
+            Error::Base => write!(f, "the old aggregate changed since the plan was made"),
+            Error::Unplanned(t) => write!(f, "table {} would be changed, but isn't in the plan", t),
+            Error::Unchanged(t) => write!(f, "table {} of the plan would be left unchanged", t),
+            Error::BpfDrift => write!(f, "the bpf programs to upload differ from the plan"),
+            Error::Changed(path) => write!(
+                f,
+                "aggregate '{}' changed since the plan was made",
+                path.display()
+            ),
+            Error::Targets => write!(f, "the plan's targets don't match the configured ones"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn table_plan(table: &str, mode: Mode, scripts: &[&str]) -> TablePlan {
+        TablePlan {
+            table: table.to_owned(),
+            proto: "ipv4".to_owned(),
+            kind: Some("blocklist".to_owned()),
+            mode,
+            num_rules: 1024,
+            num_tbl8s: 128,
+            current: vec![],
+            inserted: 10,
+            removed: 2,
+            unchanged: 100,
+            scripts: scripts.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    fn target_plan(tables: Vec<TablePlan>) -> TargetPlan {
+        TargetPlan {
+            target: Some("gt1".to_owned()),
+            base_hash: Some("abc".to_owned()),
+            tables,
+            bpf: vec![],
+        }
+    }
+
+    fn plan() -> Plan {
+        Plan::new(
+            "aggregate.json",
+            "def".to_owned(),
+            BTreeMap::new(),
+            vec![target_plan(vec![table_plan("t1", Mode::Update, &["a"])])],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_read_write() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let path = tmp.path().join("plan.json");
+        let plan = plan();
+        write(&path, &plan, Some(b"secret"))
+            .await
+            .expect("write failed");
+        let read_plan = read(&path, Some(b"secret")).await.expect("read failed");
+        assert_eq!(plan, read_plan);
+        assert!(matches!(
+            read(&path, Some(b"other")).await,
+            Err(Error::BadSignature)
+        ));
+
+        // The signature covers the file as written, not its contents.
+        let data = fs::read_to_string(&path).await.expect("read failed");
+        fs::write(&path, data.replace("  ", "   "))
+            .await
+            .expect("write failed");
+        assert!(matches!(
+            read(&path, Some(b"secret")).await,
+            Err(Error::BadSignature)
+        ));
+
+        write(&path, &plan, None).await.expect("write failed");
+        assert!(!signature_path(&path).exists());
+        assert!(matches!(
+            read(&path, Some(b"secret")).await,
+            Err(Error::Unsigned)
+        ));
+        assert_eq!(plan, read(&path, None).await.expect("read failed"));
+    }
+
+    #[test]
+    fn test_signature() {
+        let signature = sign(b"data", b"secret").expect("sign failed");
+        assert!(verify(b"data", &signature, b"secret").is_ok());
+        assert!(verify(b"data", &format!("{}\n", signature), b"secret").is_ok());
+        assert!(matches!(
+            verify(b"data", &signature, b"other"),
+            Err(Error::BadSignature)
+        ));
+        assert!(matches!(
+            verify(b"date", &signature, b"secret"),
+            Err(Error::BadSignature)
+        ));
+        assert!(matches!(
+            verify(b"data", "not hex", b"secret"),
+            Err(Error::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn test_check_scripts() {
+        let plan = target_plan(vec![
+            table_plan("t1", Mode::Update, &["a"]),
+            table_plan("t2", Mode::Replace, &["b", "c"]),
+        ]);
+        let hashes = |h: &[&str]| h.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert!(plan.check_scripts("t1", &hashes(&["a"])).is_ok());
+        assert!(plan.check_scripts("t2", &hashes(&["b", "c"])).is_ok());
+        assert!(matches!(
+            plan.check_scripts("t2", &hashes(&["b"])),
+            Err(Error::Drift(t)) if t == "t2"
+        ));
+        assert!(matches!(
+            plan.check_scripts("t3", &hashes(&["d"])),
+            Err(Error::Unplanned(t)) if t == "t3"
+        ));
+    }
+
+    #[test]
+    fn test_check() {
+        let plan = target_plan(vec![
+            table_plan("t1", Mode::Update, &["a"]),
+            table_plan("t2", Mode::Replace, &["b", "c"]),
+        ]);
+        assert!(plan.check(&plan.clone()).is_ok());
+
+        let mut replanned = plan.clone();
+        replanned.tables[1].scripts.pop();
+        assert!(matches!(plan.check(&replanned), Err(Error::Drift(t)) if t == "t2"));
+
+        let mut replanned = plan.clone();
+        replanned.tables.remove(0);
+        assert!(matches!(plan.check(&replanned), Err(Error::Unchanged(t)) if t == "t1"));
+
+        let mut replanned = plan.clone();
+        replanned
+            .tables
+            .push(table_plan("t3", Mode::Update, &["d"]));
+        assert!(matches!(plan.check(&replanned), Err(Error::Unplanned(t)) if t == "t3"));
+
+        let mut replanned = plan.clone();
+        replanned.base_hash = None;
+        assert!(matches!(plan.check(&replanned), Err(Error::Base)));
+
+        let mut replanned = plan.clone();
+        replanned.bpf.push(BpfPlan {
+            program: "prog".to_owned(),
+            index: 1,
+            hash: "e".to_owned(),
+            replace: false,
+        });
+        assert!(matches!(plan.check(&replanned), Err(Error::BpfDrift)));
+        assert!(replanned.check_bpf(&replanned.bpf).is_ok());
+    }
+}
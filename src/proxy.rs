@@ -0,0 +1,315 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use log::{debug, warn};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::Semaphore,
+    time,
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+        NoClientAuth, ServerConfig, TLSError,
+    },
+    TlsAcceptor,
+};
+
+use crate::config::DyncfgConfig;
+use crate::dyncfg;
+use crate::error;
+use crate::metrics;
+
+/// Relays scripts received over TCP, optionally over TLS, to the local
+/// dynamic configuration socket. Each connection carries a single script,
+/// preceded by the token, framed as in Gatekeeper's protocol, and gets the
+/// reply back. Callers outside the allowed networks, sending the wrong token,
+/// too slow to send their messages or beyond the connection limit are
+/// disconnected.
+pub struct Proxy {
+    upstream: PathBuf,
+    config: DyncfgConfig,
+    allow: Vec<IpNet>,
+    token: Vec<u8>,
+    tls: Option<TlsAcceptor>,
+    read_timeout: Duration,
+    connections: Arc<Semaphore>,
+}
+
+impl Proxy {
+    pub fn new(
+        upstream: impl AsRef<Path>,
+        config: DyncfgConfig,
+        allow: Vec<IpNet>,
+        token: Vec<u8>,
+        tls: Option<TlsAcceptor>,
+        read_timeout: Duration,
+        max_connections: usize,
+    ) -> Proxy {
+        Proxy {
+            upstream: upstream.as_ref().to_owned(),
+            config,
+            allow,
+            token,
+            tls,
+            read_timeout,
+            connections: Arc::new(Semaphore::new(max_connections)),
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("proxy failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            if !self.is_allowed(addr.ip()) {
+                warn!("proxy refused connection from {}", addr);
+                metrics::count("proxy_refused", 1, &[]);
+                continue;
+            }
+            let permit = match self.connections.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    warn!(
+                        "proxy refused connection from {}: too many connections",
+                        addr
+                    );
+                    metrics::count("proxy_refused", 1, &[]);
+                    continue;
+                }
+            };
+            let proxy = self.clone();
+            tokio::spawn(async move {
+                let res = match &proxy.tls {
+                    Some(tls) => {
+                        match time::timeout(proxy.read_timeout, tls.accept(stream)).await {
+                            Ok(Ok(stream)) => proxy.handle(stream).await,
+                            Ok(Err(e)) => Err(Error::Io(e)),
+                            Err(_) => Err(Error::Timeout),
+                        }
+                    }
+                    None => proxy.handle(stream).await,
+                };
+                drop(permit);
+                if let Err(e) = res {
+                    warn!("proxy failed to handle request from {}: {}", addr, e);
+                    metrics::count("proxy_errors", 1, &[]);
+                }
+            });
+        }
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 callers may show up as IPv4-mapped IPv6 addresses. Other
+        // IPv6 addresses, including IPv4-compatible ones, are taken as is.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        self.allow.iter().any(|net| net.contains(&ip))
+    }
+
+    async fn handle<S>(&self, mut stream: S) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let sent = self.read_message(&mut stream).await?;
+        if !constant_time_eq(&self.token, &sent) {
+            metrics::count("proxy_refused", 1, &[]);
+            return Err(Error::Unauthorized);
+        }
+        let script = self.read_message(&mut stream).await?;
+        debug!("proxy forwarding {} bytes", script.len());
+        let reply = dyncfg::send_config_raw(&self.upstream, &script, &self.config).await?;
+        dyncfg::write_message(&mut stream, &reply).await?;
+        metrics::count("proxy_scripts_forwarded", 1, &[]);
+        Ok(())
+    }
+
+    async fn read_message<S: AsyncRead + Unpin>(&self, stream: &mut S) -> Result<Vec<u8>, Error> {
+        match time::timeout(self.read_timeout, dyncfg::read_message(stream)).await {
+            Ok(res) => Ok(res?),
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+}
+
+/// Builds a TLS acceptor from PEM-encoded certificate chain and private key
+/// files.
+pub fn tls_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, Error> {
+    let chain =
+        certs(&mut BufReader::new(File::open(cert)?)).map_err(|()| Error::Pem(cert.to_owned()))?;
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+        .map_err(|()| Error::Pem(key.to_owned()))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|()| Error::Pem(key.to_owned()))?;
+    }
+    let key = keys.pop().ok_or_else(|| Error::Pem(key.to_owned()))?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Compares without short-circuiting, so the time taken doesn't reveal how
+// much of the token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Dyncfg(error::Error),
+    Tls(TLSError),
+    Pem(PathBuf),
+    Unauthorized,
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Dyncfg(e) => write!(f, "failed to forward script: {}", e),
+            Error::Tls(e) => write!(f, "tls error: {}", e),
+            Error::Pem(path) => write!(f, "no valid PEM data in '{}'", path.display()),
+            Error::Unauthorized => write!(f, "invalid token"),
+            Error::Timeout => write!(f, "timed out waiting for the caller"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Dyncfg(e) => Some(e),
+            Error::Tls(e) => Some(e),
+            Error::Pem(_) | Error::Unauthorized | Error::Timeout => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<error::Error> for Error {
+    fn from(e: error::Error) -> Error {
+        Error::Dyncfg(e)
+    }
+}
+
+impl From<TLSError> for Error {
+    fn from(e: TLSError) -> Error {
+        Error::Tls(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::mock::MockServer;
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let server = MockServer::start(tmp.path().join("gatekeeper"), "ok\n".to_owned())
+            .await
+            .expect("start failed");
+        let proxy = Arc::new(Proxy::new(
+            server.path(),
+            DyncfgConfig::default(),
+            vec!["127.0.0.0/8".parse().unwrap()],
+            b"secret".to_vec(),
+            None,
+            Duration::from_secs(10),
+            64,
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+        tokio::spawn(proxy.clone().serve(listener));
+
+        let mut stream = TcpStream::connect(addr).await.expect("connect failed");
+        dyncfg::write_message(&mut stream, b"secret")
+            .await
+            .expect("write failed");
+        dyncfg::write_message(&mut stream, b"script")
+            .await
+            .expect("write failed");
+        let reply = dyncfg::read_message(&mut stream)
+            .await
+            .expect("read failed");
+        assert_eq!(b"ok\n".to_vec(), reply);
+        assert_eq!(vec!["script"], server.received());
+
+        let mut stream = TcpStream::connect(addr).await.expect("connect failed");
+        dyncfg::write_message(&mut stream, b"wrong")
+            .await
+            .expect("write failed");
+        let _ = dyncfg::write_message(&mut stream, b"script").await;
+        assert!(dyncfg::read_message(&mut stream).await.is_err());
+        assert_eq!(1, server.received().len());
+
+        assert!(!proxy.is_allowed("192.0.2.1".parse().unwrap()));
+        assert!(proxy.is_allowed("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!proxy.is_allowed("::127.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_limits() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let server = MockServer::start(tmp.path().join("gatekeeper"), "ok\n".to_owned())
+            .await
+            .expect("start failed");
+        let proxy = Arc::new(Proxy::new(
+            server.path(),
+            DyncfgConfig::default(),
+            vec!["127.0.0.0/8".parse().unwrap()],
+            b"secret".to_vec(),
+            None,
+            Duration::from_millis(200),
+            1,
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+        tokio::spawn(proxy.serve(listener));
+
+        // An idle caller holds the only connection until it times out.
+        let mut idle = TcpStream::connect(addr).await.expect("connect failed");
+        time::sleep(Duration::from_millis(50)).await;
+        let mut refused = TcpStream::connect(addr).await.expect("connect failed");
+        assert!(dyncfg::read_message(&mut refused).await.is_err());
+        assert!(dyncfg::read_message(&mut idle).await.is_err());
+        time::sleep(Duration::from_millis(50)).await;
+
+        let mut stream = TcpStream::connect(addr).await.expect("connect failed");
+        dyncfg::write_message(&mut stream, b"secret")
+            .await
+            .expect("write failed");
+        dyncfg::write_message(&mut stream, b"script")
+            .await
+            .expect("write failed");
+        let reply = dyncfg::read_message(&mut stream)
+            .await
+            .expect("read failed");
+        assert_eq!(b"ok\n".to_vec(), reply);
+    }
+}
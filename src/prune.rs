@@ -0,0 +1,285 @@
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::debug;
+use regex::Regex;
+use tokio::{fs, io};
+
+/// How long rendered scripts are kept. Scripts are removed once they are
+/// older than `max_age`, or once `keep` newer ones exist.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub keep: Option<usize>,
+}
+
+/// A file rendered to one of the configured output paths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Artifact {
+    pub path: PathBuf,
+    pub modified: SystemTime,
+    pub size: u64,
+    /// The directory rendered from a placeholder the artifact is in, e.g. a
+    /// per-run directory, removed along with its last artifact.
+    pub dir: Option<PathBuf>,
+}
+
+/// Finds the files matching any of the given output paths, newest first.
+/// Template placeholders, e.g. `{kind}`, match anything.
+pub async fn find(outputs: &[String]) -> io::Result<Vec<Artifact>> {
+    let mut artifacts = Vec::new();
+    for output in outputs {
+        let (base, pattern) = output_pattern(output);
+        let depth = Path::new(output).components().count() - base.components().count();
+        collect(&base, &pattern, depth, &mut artifacts).await?;
+    }
+    // Outputs may overlap.
+    artifacts.sort_by(|a, b| b.modified.cmp(&a.modified).then(a.path.cmp(&b.path)));
+    artifacts.dedup_by(|a, b| a.path == b.path);
+    Ok(artifacts)
+}
+
+/// Returns the artifacts that fall outside the retention, given the
+/// artifacts sorted newest first.
+pub fn expired(artifacts: &[Artifact], retention: Retention, now: SystemTime) -> Vec<&Artifact> {
+    artifacts
+        .iter()
+        .enumerate()
+        .filter(|(i, artifact)| {
+            let too_many = retention.keep.map_or(false, |keep| *i >= keep);
+            let too_old = retention.max_age.map_or(false, |max_age| {
+                now.duration_since(artifact.modified)
+                    .map_or(false, |age| age > max_age)
+            });
+            too_many || too_old
+        })
+        .map(|(_, artifact)| artifact)
+        .collect()
+}
+
+/// Returns the oldest artifacts that must go for the rest to take at most
+/// `max_bytes`, given the artifacts sorted newest first.
+pub fn over_quota(artifacts: &[Artifact], max_bytes: u64) -> Vec<&Artifact> {
+    let mut total = 0u64;
+    artifacts
+        .iter()
+        .skip_while(|artifact| {
+            total = total.saturating_add(artifact.size);
+            total <= max_bytes
+        })
+        .collect()
+}
+
+/// Removes the oldest rendered scripts until the rest take at most
+/// `max_bytes`, returning the removed ones.
+pub async fn enforce_quota(outputs: &[String], max_bytes: u64) -> io::Result<Vec<Artifact>> {
+    let artifacts = find(outputs).await?;
+    let over: Vec<Artifact> = over_quota(&artifacts, max_bytes)
+        .into_iter()
+        .cloned()
+        .collect();
+    remove(&over).await?;
+    Ok(over)
+}
+
+/// Removes the rendered scripts outside the retention, returning them.
+pub async fn prune(
+    outputs: &[String],
+    retention: Retention,
+    dry_run: bool,
+) -> io::Result<Vec<Artifact>> {
+    let artifacts = find(outputs).await?;
+    let expired: Vec<Artifact> = expired(&artifacts, retention, SystemTime::now())
+        .into_iter()
+        .cloned()
+        .collect();
+    if !dry_run {
+        remove(&expired).await?;
+    }
+    Ok(expired)
+}
+
+/// Removes the given artifacts, along with the directories they leave
+/// empty.
+pub async fn remove(artifacts: &[Artifact]) -> io::Result<()> {
+    for artifact in artifacts {
+        debug!("removing '{}'", artifact.path.display());
+        match fs::remove_file(&artifact.path).await {
+            Ok(()) => {}
+            // Removed concurrently, e.g. by a run sending it.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if let Some(dir) = &artifact.dir {
+            // Fails while other artifacts remain.
+            let _ = fs::remove_dir(dir).await;
+        }
+    }
+    Ok(())
+}
+
+// Splits an output path into its longest directory without placeholders and
+// a pattern matching the whole path.
+fn output_pattern(output: &str) -> (PathBuf, Regex) {
+    let mut base = PathBuf::new();
+    for component in Path::new(output)
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+    {
+        if component.as_os_str().to_string_lossy().contains('{') {
+            break;
+        }
+        base.push(component);
+    }
+    if base.as_os_str().is_empty() {
+        base.push(Component::CurDir);
+    }
+    let placeholder = Regex::new(r"\{[^}]*\}").expect("BUG: invalid placeholder regex");
+    let mut pattern = String::from("^");
+    let mut last = 0;
+    for m in placeholder.find_iter(output) {
+        pattern.push_str(&regex::escape(&output[last..m.start()]));
+        pattern.push_str("[^/]*");
+        last = m.end();
+    }
+    pattern.push_str(&regex::escape(&output[last..]));
+    pattern.push('$');
+    let pattern = Regex::new(&pattern).expect("BUG: invalid output pattern");
+    (base, pattern)
+}
+
+async fn collect(
+    base: &Path,
+    pattern: &Regex,
+    depth: usize,
+    artifacts: &mut Vec<Artifact>,
+) -> io::Result<()> {
+    // Directories are visited iteratively, as async functions can't recurse.
+    let mut dirs = vec![(base.to_owned(), depth)];
+    while let Some((dir, depth)) = dirs.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let meta = entry.metadata().await?;
+            if meta.is_dir() {
+                if depth > 1 {
+                    dirs.push((path, depth - 1));
+                }
+                continue;
+            }
+            let relative = path.strip_prefix(".").unwrap_or(&path);
+            if !pattern.is_match(&relative.to_string_lossy()) {
+                continue;
+            }
+            let parent = path.parent().filter(|parent| *parent != base);
+            artifacts.push(Artifact {
+                dir: parent.map(Path::to_owned),
+                path,
+                modified: meta.modified()?,
+                size: meta.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_expired() {
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+        let artifacts: Vec<Artifact> = (0..4)
+            .map(|i| Artifact {
+                path: PathBuf::from(format!("script{}.lua", i)),
+                modified: now - hour * i,
+                size: 0,
+                dir: None,
+            })
+            .collect();
+        let names = |retention| -> Vec<String> {
+            expired(&artifacts, retention, now)
+                .iter()
+                .map(|a| a.path.display().to_string())
+                .collect()
+        };
+
+        assert!(names(Retention::default()).is_empty());
+        assert_eq!(
+            vec!["script3.lua"],
+            names(Retention {
+                max_age: Some(hour * 2),
+                keep: None
+            })
+        );
+        assert_eq!(
+            vec!["script1.lua", "script2.lua", "script3.lua"],
+            names(Retention {
+                max_age: None,
+                keep: Some(1)
+            })
+        );
+    }
+
+    #[test]
+    fn test_over_quota() {
+        let now = SystemTime::now();
+        let artifacts: Vec<Artifact> = (0..4)
+            .map(|i| Artifact {
+                path: PathBuf::from(format!("script{}.lua", i)),
+                modified: now - Duration::from_secs(i),
+                size: 100,
+                dir: None,
+            })
+            .collect();
+        let names = |max_bytes| -> Vec<String> {
+            over_quota(&artifacts, max_bytes)
+                .iter()
+                .map(|a| a.path.display().to_string())
+                .collect()
+        };
+
+        assert!(names(400).is_empty());
+        assert_eq!(vec!["script2.lua", "script3.lua"], names(250));
+        assert_eq!(4, names(0).len());
+    }
+
+    #[tokio::test]
+    async fn test_find() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let dir = tmp.path().join("ipv4");
+        fs::create_dir_all(&dir).await.expect("mkdir failed");
+        for name in &[
+            "update_blocklist.0.lua",
+            "update_blocklist.1.lua",
+            "other.lua",
+        ] {
+            fs::write(dir.join(name), "").await.expect("write failed");
+        }
+        let output = format!(
+            "{}/{{proto}}/update_{{kind}}.{{i}}.lua",
+            tmp.path().display()
+        );
+
+        let mut found: Vec<_> = find(&[output.clone(), output])
+            .await
+            .expect("find failed")
+            .into_iter()
+            .map(|a| a.path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        found.sort();
+        assert_eq!(
+            vec!["update_blocklist.0.lua", "update_blocklist.1.lua"],
+            found
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{self, AsyncWriteExt};
+
+use crate::config::DyncfgConfig;
+use crate::dyncfg;
+use crate::error;
+use crate::util::safe_write;
+
+/// A script that could not be sent, persisted along with where it was
+/// headed so that it can be retried later. Entries are retried in the order
+/// they were queued, which preserves the script ordering within a table.
+///
+/// Each entry is stored as a pair of files named after its sequence number:
+/// the script itself, with a `.lua` extension, and this metadata, with a
+/// `.json` extension. The metadata is written last, so its presence marks
+/// the entry as complete.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub socket: PathBuf,
+    pub table: String,
+    pub queued_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct RetryQueue {
+    dir: PathBuf,
+}
+
+impl RetryQueue {
+    pub async fn open(dir: impl AsRef<Path>) -> Result<RetryQueue, Error> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir).await?;
+        Ok(RetryQueue { dir })
+    }
+
+    pub async fn push(&self, entry: &Entry, script: &[u8]) -> Result<(), Error> {
+        let mut seq = match self.paths().await?.last() {
+            Some(path) => parse_seq(path).map(|n| n + 1).unwrap_or(0),
+            None => 0,
+        };
+        // Table jobs may push concurrently, so creating the script reserves
+        // its sequence number, and numbers already taken are skipped.
+        let path = loop {
+            let path = self.dir.join(format!("{:020}.json", seq));
+            let created = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path.with_extension("lua"))
+                .await;
+            match created {
+                Ok(mut file) => {
+                    file.write_all(script).await?;
+                    file.sync_all().await?;
+                    break path;
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => seq += 1,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        safe_write(path, &serde_json::to_vec(entry)?).await?;
+        Ok(())
+    }
+
+    pub async fn len(&self) -> Result<usize, Error> {
+        Ok(self.paths().await?.len())
+    }
+
+    pub async fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len().await? == 0)
+    }
+
+    /// Sends queued scripts in order, removing each one once it is sent.
+    /// Stops at the first failure, leaving the remaining entries queued.
+    pub async fn drain(&self, config: &DyncfgConfig) -> Result<usize, Error> {
+        let mut sent = 0;
+        for path in self.paths().await? {
+            let data = fs::read(&path).await?;
+            let entry: Entry = serde_json::from_slice(&data)?;
+            let script = path.with_extension("lua");
+            if let Err(e) = dyncfg::send_config_script(&entry.socket, &script, config).await {
+                warn!(
+                    "failed to send queued script for table {}: {}",
+                    entry.table, e
+                );
+                return Err(e.into());
+            }
+            fs::remove_file(&path).await?;
+            fs::remove_file(&script).await?;
+            sent += 1;
+        }
+        if sent > 0 {
+            info!("sent {} queued scripts", sent);
+        }
+        Ok(sent)
+    }
+
+    /// Removes every queued entry without sending it, returning how many
+    /// were removed.
+    pub async fn clear(&self) -> Result<usize, Error> {
+        let paths = self.paths().await?;
+        for path in &paths {
+            fs::remove_file(path.with_extension("lua")).await?;
+            fs::remove_file(path).await?;
+        }
+        Ok(paths.len())
+    }
+
+    async fn paths(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut paths = Vec::new();
+        let mut dir = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if parse_seq(&path).is_some() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+// Temporary files left by an interrupted `safe_write`, as well as scripts,
+// don't parse.
+fn parse_seq(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    name.strip_suffix(".json")?.parse().ok()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Dyncfg(error::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Json(e) => write!(f, "json error: {}", e),
+            Error::Dyncfg(e) => write!(f, "dyncfg error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Dyncfg(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<error::Error> for Error {
+    fn from(e: error::Error) -> Error {
+        Error::Dyncfg(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queue_order() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let queue = RetryQueue::open(tmp.path().join("retry"))
+            .await
+            .expect("open failed");
+        assert!(queue.is_empty().await.expect("is_empty failed"));
+
+        for i in 0..3 {
+            let entry = Entry {
+                socket: tmp.path().join("socket"),
+                table: "t".to_owned(),
+                queued_at: Utc::now(),
+            };
+            let script = format!("script {}", i);
+            queue
+                .push(&entry, script.as_bytes())
+                .await
+                .expect("push failed");
+        }
+        assert_eq!(3, queue.len().await.expect("len failed"));
+
+        let paths = queue.paths().await.expect("paths failed");
+        let seqs: Vec<_> = paths.iter().map(|p| parse_seq(p).unwrap()).collect();
+        assert_eq!(vec![0, 1, 2], seqs);
+
+        // Nothing is listening on the socket.
+        assert!(queue.drain(&DyncfgConfig::default()).await.is_err());
+        assert_eq!(3, queue.len().await.expect("len failed"));
+
+        assert_eq!(3, queue.clear().await.expect("clear failed"));
+        assert!(queue.is_empty().await.expect("is_empty failed"));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_push() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let dir = tmp.path().join("retry");
+        let entry = Entry {
+            socket: tmp.path().join("socket"),
+            table: "t".to_owned(),
+            queued_at: Utc::now(),
+        };
+
+        // Each job opens its own queue, as table jobs do.
+        let pushes = (0..8).map(|i| {
+            let (dir, entry) = (&dir, &entry);
+            async move {
+                let queue = RetryQueue::open(dir).await.expect("open failed");
+                let script = format!("script {}", i);
+                queue
+                    .push(entry, script.as_bytes())
+                    .await
+                    .expect("push failed");
+            }
+        });
+        futures::future::join_all(pushes).await;
+
+        let queue = RetryQueue::open(&dir).await.expect("open failed");
+        let mut scripts = Vec::new();
+        for path in queue.paths().await.expect("paths failed") {
+            let script = fs::read_to_string(path.with_extension("lua"))
+                .await
+                .expect("read failed");
+            scripts.push(script);
+        }
+        scripts.sort();
+        let expected: Vec<_> = (0..8).map(|i| format!("script {}", i)).collect();
+        assert_eq!(expected, scripts);
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+
+use regex::Regex;
+use serde::de::DeserializeOwned;
+
+/// A line of a dynamic configuration reply holding an identifier followed
+/// by one or more counters, such as the ID, number of rules and number of
+/// tbl8s of an LPM table.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record {
+    pub id: u64,
+    pub values: Vec<u64>,
+}
+
+/// Parses the records of a reply produced by Lua `print` calls.
+///
+/// The parser is lenient about formatting, as it has changed across
+/// Gatekeeper releases: numbers may be separated by any combination of
+/// whitespace, commas, colons, semicolons, equal signs, brackets and
+/// parentheses, integral floats such as `12.0` or `1e+06` are accepted,
+/// and lines may end in CRLF. A record is formed by the numbers ending a
+/// line, so a line may start with a label, and lines preceding the first
+/// record are ignored. After that, any other non-empty line is an error.
+pub fn parse_records(reply: &str) -> Result<Vec<Record>, ParseError> {
+    let mut records = Vec::new();
+    for line in reply.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match parse_record(line) {
+            Some(record) => records.push(record),
+            None if records.is_empty() => continue,
+            None => return Err(ParseError::Line(line.to_owned())),
+        }
+    }
+    if records.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    Ok(records)
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let tokens: Vec<&str> = line
+        .split(|c: char| c.is_whitespace() || ",:;=[]()".contains(c))
+        .filter(|t| !t.is_empty())
+        .collect();
+    let mut numbers = Vec::new();
+    for token in tokens.iter().rev() {
+        match parse_number(token) {
+            Some(n) => numbers.push(n),
+            None => break,
+        }
+    }
+    if numbers.len() < 2 {
+        return None;
+    }
+    numbers.reverse();
+    Some(Record {
+        id: numbers[0],
+        values: numbers.split_off(1),
+    })
+}
+
+/// Parses the records of a reply with a user-supplied pattern, for output
+/// formats the default parser doesn't understand. Each line is matched
+/// against the pattern, which must have an `id` capture group and one group
+/// for each of the given value names. As with `parse_records`, lines
+/// preceding the first match are ignored, and any other non-empty line
+/// after it is an error.
+pub fn parse_pattern(
+    reply: &str,
+    pattern: &Regex,
+    values: &[&str],
+) -> Result<Vec<Record>, ParseError> {
+    let mut records = Vec::new();
+    for line in reply.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let captures = match pattern.captures(line) {
+            Some(captures) => captures,
+            None if records.is_empty() => continue,
+            None => return Err(ParseError::Line(line.to_owned())),
+        };
+        let number = |name: &str| {
+            captures
+                .name(name)
+                .and_then(|m| parse_number(m.as_str()))
+                .ok_or_else(|| ParseError::Line(line.to_owned()))
+        };
+        let id = number("id")?;
+        let values = values
+            .iter()
+            .map(|name| number(name))
+            .collect::<Result<_, _>>()?;
+        records.push(Record { id, values });
+    }
+    if records.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    Ok(records)
+}
+
+// Lua 5.3 and later print floats with a fractional part or an exponent,
+// even when they hold integral values.
+fn parse_number(token: &str) -> Option<u64> {
+    if let Ok(n) = token.parse() {
+        return Some(n);
+    }
+    if !token.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let f: f64 = token.parse().ok()?;
+    if f.fract() == 0.0 && f >= 0.0 && f < u64::MAX as f64 {
+        Some(f as u64)
+    } else {
+        None
+    }
+}
+
+/// Parses a reply made of JSON values, one per Gatekeeper instance, as
+/// produced by scripts that serialize their results instead of printing
+/// them. The values may be separated by any amount of whitespace, and text
+/// preceding the first value is ignored.
+pub fn parse_json<T: DeserializeOwned>(reply: &str) -> Result<Vec<T>, ParseError> {
+    let start = match reply.find(|c| c == '{' || c == '[') {
+        Some(start) => start,
+        None => return Err(ParseError::Empty),
+    };
+    let mut values = Vec::new();
+    for value in serde_json::Deserializer::from_str(&reply[start..]).into_iter() {
+        values.push(value.map_err(|e| ParseError::Json(e.to_string()))?);
+    }
+    Ok(values)
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    Empty,
+    Line(String),
+    Fields {
+        line: String,
+        expected: &'static str,
+    },
+    Json(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "dyncfg returned an empty response"),
+            ParseError::Line(s) => write!(f, "dyncfg returned an unexpected line: {}", s),
+            ParseError::Fields { line, expected } => write!(
+                f,
+                "dyncfg returned a line with the wrong number of fields (expected {}): {}",
+                expected, line
+            ),
+            ParseError::Json(e) => write!(f, "dyncfg returned invalid json: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: u64, values: &[u64]) -> Record {
+        Record {
+            id,
+            values: values.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_parse_records() {
+        assert_eq!(Err(ParseError::Empty), parse_records(""));
+        assert_eq!(Err(ParseError::Empty), parse_records("foo\n\n"));
+
+        let expected = vec![record(0, &[1, 2]), record(1, &[3, 4])];
+        for reply in &[
+            "0: 1, 2\n1: 3, 4\n",
+            "0: 1, 2\r\n1: 3, 4\r\n",
+            "0:\t1\t2\n1:\t3\t4",
+            "  0 1 2\n  1 3 4  ",
+            "[0] = 1, 2\n[1] = 3, 4",
+            "0: 1.0, 2.0\n1: 3.0, 4",
+            "LPM params\nid: rules, tbl8s\n0: 1, 2\n\n1: 3, 4\n",
+            "table 0: 1, 2\ntable 1: 3, 4\n",
+        ] {
+            assert_eq!(Ok(expected.clone()), parse_records(reply), "{:?}", reply);
+        }
+
+        assert_eq!(
+            Ok(vec![record(0, &[1_000_000, 2])]),
+            parse_records("0: 1e+06, 2")
+        );
+        assert_eq!(
+            Ok(vec![record(2, &[1, 2, 3, 4])]),
+            parse_records("2: 1, 2, 3, 4")
+        );
+        assert_eq!(
+            Err(ParseError::Line("oops".to_owned())),
+            parse_records("0: 1, 2\noops\n1: 3, 4")
+        );
+        assert_eq!(Err(ParseError::Empty), parse_records("0: -1, 2"));
+        assert_eq!(Err(ParseError::Empty), parse_records("0: 1.5, 2"));
+    }
+
+    #[test]
+    fn test_parse_pattern() {
+        let pattern = Regex::new(r"^lcore (?P<id>\d+) has (?P<tbl8s>\d+)/(?P<rules>\d+)$").unwrap();
+        let values = &["rules", "tbl8s"];
+
+        assert_eq!(Err(ParseError::Empty), parse_pattern("", &pattern, values));
+        assert_eq!(
+            Ok(vec![record(0, &[1, 2]), record(1, &[3, 4])]),
+            parse_pattern(
+                "Gatekeeper\r\nlcore 0 has 2/1\r\nlcore 1 has 4/3\r\n",
+                &pattern,
+                values
+            )
+        );
+        assert_eq!(
+            Err(ParseError::Line("0: 1, 2".to_owned())),
+            parse_pattern("lcore 0 has 2/1\n0: 1, 2", &pattern, values)
+        );
+
+        // Values matched by an optional group must be present.
+        let pattern = Regex::new(r"^(?P<id>\d+): (?P<rules>\d+)(, (?P<tbl8s>\d+))?$").unwrap();
+        assert_eq!(
+            Err(ParseError::Line("0: 1".to_owned())),
+            parse_pattern("0: 1", &pattern, values)
+        );
+    }
+
+    #[derive(Debug, Eq, PartialEq, serde::Deserialize)]
+    struct Counter {
+        id: u64,
+        count: u64,
+    }
+
+    #[test]
+    fn test_parse_json() {
+        assert_eq!(Err(ParseError::Empty), parse_json::<Counter>(""));
+        assert_eq!(
+            Err(ParseError::Empty),
+            parse_json::<Counter>("nothing here\n")
+        );
+
+        let expected = vec![Counter { id: 0, count: 1 }, Counter { id: 1, count: 2 }];
+        for reply in &[
+            "{\"id\": 0, \"count\": 1}\n{\"id\": 1, \"count\": 2}\n",
+            "{\"id\":0,\"count\":1}{\"id\":1,\"count\":2}",
+            "header\r\n{\"id\": 0, \"count\": 1}\r\n{\"id\": 1, \"count\": 2}\r\n",
+        ] {
+            assert_eq!(
+                Ok(&expected),
+                parse_json::<Counter>(reply).as_ref(),
+                "{:?}",
+                reply
+            );
+        }
+
+        assert!(parse_json::<Counter>("{\"id\": 0}").is_err());
+        assert!(parse_json::<Counter>("{\"id\": 0, \"count\": 1}\ntrailing").is_err());
+    }
+
+    // Feeds the parser pseudo-random replies built from fragments of valid
+    // and invalid output, checking that it never panics and that whatever
+    // it accepts is well formed.
+    #[test]
+    fn test_parse_records_fuzz() {
+        const FRAGMENTS: &[&str] = &[
+            "0",
+            "1",
+            "42",
+            "18446744073709551615",
+            "18446744073709551616",
+            "1.0",
+            "1e+06",
+            "-1",
+            "nan",
+            "inf",
+            ".",
+            ":",
+            ",",
+            " ",
+            "\t",
+            "\r\n",
+            "\n",
+            "\r",
+            "[",
+            "]",
+            "=",
+            "(",
+            ")",
+            "id",
+            "é",
+            "\u{0}",
+        ];
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..10_000 {
+            let len = next() % 32;
+            let reply: String = (0..len)
+                .map(|_| FRAGMENTS[(next() % FRAGMENTS.len() as u64) as usize])
+                .collect();
+            if let Ok(records) = parse_records(&reply) {
+                assert!(!records.is_empty());
+                assert!(records.iter().all(|r| !r.values.is_empty()));
+            }
+        }
+    }
+}
@@ -0,0 +1,3137 @@
+//! The pipeline applying an aggregate to Gatekeeper: diffing it against the
+//! old aggregate of each target, choosing between updates and replaces,
+//! rendering and sending the scripts, and recording state.
+
+use std::borrow::Cow;
+use std::cmp::{max, min, Ord, Reverse};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Debug, Display};
+use std::iter;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use drib::aggregate::{self, Entry};
+use drib::config::Templates;
+use drib::output::{Bootstrap, Changes};
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::stream::{FuturesUnordered, StreamExt};
+use ipnet::{Ipv4Net, Ipv6Net};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::fs;
+
+use crate::config::{
+    in_replace_window, BpfConfig, CapAction, ChangeOrder, ChunkedTemplates, Config, EstimateConfig,
+    GroupKey, LuaFunctions, MaxRanges, PolicyRule, QuotaAction, RemovePolicy, ReplyFormat,
+    ScriptQuotaConfig, ShrinkConfig, TableMode, ValidationAction,
+};
+use crate::dyncfg;
+use crate::error::{
+    self, AggregateError, ConfigError, GatekeeperError, LimitError, Oversized, StateError,
+    TemplateError,
+};
+use crate::metrics;
+use crate::params::{
+    self, CurrentParams, LimitWarning, Limits, Params, Update, Usage, Utilization,
+};
+use crate::plan::{self, BpfPlan, Plan, TablePlan, TargetPlan};
+use crate::prune;
+use crate::queue::{self, RetryQueue};
+use crate::state::{
+    self, Applied, BpfState, Failure, LastRun, Mode, Outcome, Progress, ReplaceState,
+    SequenceState, TableRun,
+};
+use crate::template::{self, Rendered};
+use crate::util::{self, safe_copy, safe_rename};
+use crate::validate::{self, Filtered, Prefix, Violation};
+
+const CUR_AGGREGATE: &'static str = "aggregate.cur";
+
+/// The name of the copy of the last applied aggregate in a target's state
+/// directory.
+pub const OLD_AGGREGATE: &'static str = "aggregate.old";
+
+/// The name of the queue of scripts to be retried.
+pub const RETRY_QUEUE: &str = "retry";
+
+const MAX_VIOLATIONS_SHOWN: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunOptions<'a> {
+    /// Reprocess an interrupted run with no recorded progress.
+    pub recover: bool,
+    /// Treat a missing or invalid old aggregate as empty.
+    pub bootstrap: bool,
+    /// Ignore the current table parameters, creating every LPM table from
+    /// scratch in replace mode. Hash tables are still updated against the
+    /// old aggregate, if any.
+    pub initial_load: bool,
+    /// Render the scripts of every table without sending them or recording
+    /// any state, hashing them into the plan instead.
+    pub plan: bool,
+    /// The plan of the target being applied. Tables whose scripts differ
+    /// from the planned ones aren't sent.
+    pub target_plan: Option<&'a TargetPlan>,
+}
+
+/// The outcome of applying an aggregate to a target, named unless the
+/// top-level configuration itself was used.
+pub type TargetResult = (Option<String>, Result<Vec<TableRun>, error::Error>);
+
+/// A failing target doesn't prevent the others from being processed. Since
+/// each target keeps its own old aggregate, one that failed is brought up to
+/// date with a diff against the state it actually has when retried.
+pub async fn apply(
+    config: &Config,
+    aggregate: impl AsRef<Path>,
+    targets: &[String],
+    opts: RunOptions<'_>,
+) -> Result<Vec<TargetResult>, error::Error> {
+    let mut results = Vec::new();
+    for (name, config) in select_targets(config, targets)? {
+        if let Some(name) = &name {
+            info!("processing target {}", name);
+        }
+        let res = apply_target(&config, &aggregate, opts).await;
+        if let Err(e) = &res {
+            match &name {
+                Some(name) => error!("failed to apply target {}: {:#}", name, e),
+                None => error!("failed to apply: {:#}", e),
+            }
+        }
+        results.push((name, res));
+    }
+    Ok(results)
+}
+
+async fn apply_target(
+    config: &Config,
+    aggregate: impl AsRef<Path>,
+    opts: RunOptions<'_>,
+) -> Result<Vec<TableRun>, error::Error> {
+    create_dirs(config).await?;
+    // Scripts queued by a previous run must be sent before
+    // anything else, to preserve their ordering.
+    if config.retry_queue.is_some() {
+        let queue = RetryQueue::open(config.state_dir.join(RETRY_QUEUE)).await?;
+        queue.drain(&config.dyncfg_config()).await?;
+    }
+    // Current path already exists: must be
+    // a remain from an interrupted execution.
+    // If its progress was recorded, resume it
+    // from the tables that weren't completed.
+    // Otherwise, reprocessing every table must
+    // be explicitly requested.
+    let cur_path = config.state_dir.join(CUR_AGGREGATE);
+    if Path::new(&cur_path).exists() {
+        let hash = state::hash_file(&cur_path)
+            .await
+            .map_err(|e| AggregateError::Read(cur_path.clone(), e))?;
+        let progress = state::read_progress(&config.state_dir)
+            .await
+            .map_err(|e| StateError::Read("progress", e))?;
+        match progress {
+            Some(progress) if progress.aggregate_hash == hash => {
+                warn!(
+                    "found preexisting current aggregate file; resuming with {} tables already applied",
+                    progress.completed.len()
+                );
+            }
+            _ if opts.recover => {
+                warn!("found preexisting current aggregate file with no recorded progress; reprocessing");
+            }
+            _ => {
+                return Err(StateError::Interrupted(cur_path).into());
+            }
+        }
+        dyn_cfg(&cur_path, config, opts).await?;
+    }
+    dyn_cfg(&aggregate, config, opts).await
+}
+
+/// A plan is computed from the state of each target and the parameters
+/// reported by Gatekeeper. No state is recorded, and scripts are rendered
+/// into a scratch directory instead of the configured output paths.
+pub async fn make_plan(
+    config: &Config,
+    aggregate: &Path,
+    targets: &[String],
+) -> Result<(Plan, Vec<Vec<TableRun>>), error::Error> {
+    let aggregate_hash = state::hash_file(aggregate)
+        .await
+        .map_err(|e| AggregateError::Read(aggregate.to_owned(), e))?;
+    let (target_plans, runs) = plan_targets(config, aggregate, targets).await?;
+    let plan = Plan::new(
+        aggregate,
+        aggregate_hash,
+        config.var_overrides.clone(),
+        target_plans,
+    );
+    Ok((plan, runs))
+}
+
+async fn plan_targets(
+    config: &Config,
+    aggregate: &Path,
+    targets: &[String],
+) -> Result<(Vec<TargetPlan>, Vec<Vec<TableRun>>), error::Error> {
+    let dir = tempfile::Builder::new()
+        .prefix("gtctl-plan.")
+        .tempdir()
+        .map_err(StateError::Scratch)?;
+    let path = dir.path().to_owned();
+    let res = make_plan_in(config, aggregate, targets, &path).await;
+    if let Err(e) = dir.close() {
+        warn!("failed to remove '{}': {}", path.display(), e);
+    }
+    res
+}
+
+async fn make_plan_in(
+    config: &Config,
+    aggregate: &Path,
+    targets: &[String],
+    dir: &Path,
+) -> Result<(Vec<TargetPlan>, Vec<Vec<TableRun>>), error::Error> {
+    let mut target_plans = Vec::new();
+    let mut runs = Vec::new();
+    for (name, mut config) in select_targets(config, targets)? {
+        scratch_outputs(&mut config, dir);
+        let (base_hash, tables, bpf) =
+            plan_target(&config, aggregate)
+                .await
+                .map_err(|e| match &name {
+                    Some(name) => e.in_target(name),
+                    None => e,
+                })?;
+        target_plans.push(TargetPlan {
+            target: name,
+            base_hash,
+            tables: tables.iter().cloned().map(TablePlan::from).collect(),
+            bpf,
+        });
+        runs.push(tables);
+    }
+    Ok((target_plans, runs))
+}
+
+// Returns the hash of the old aggregate the target's changes were computed
+// against, along with the changes to each table, ordered by table name, and
+// the BPF programs to be uploaded.
+async fn plan_target(
+    config: &Config,
+    aggregate: &Path,
+) -> Result<(Option<String>, Vec<TableRun>, Vec<BpfPlan>), error::Error> {
+    create_dirs(config).await?;
+    check_pending(config).await?;
+    let base_hash = old_aggregate_hash(config).await?;
+    let opts = RunOptions {
+        plan: true,
+        ..Default::default()
+    };
+    let bpf = match &config.bpf {
+        Some(bpf) => pending_bpf_programs(config, bpf, opts).await?.1,
+        None => Vec::new(),
+    };
+    let mut tables = Vec::new();
+    run_aggregate(aggregate, config, opts, &mut tables).await?;
+    tables.sort_by(|a, b| a.table.cmp(&b.table));
+    Ok((base_hash, tables, bpf))
+}
+
+// Leftovers of an earlier run would be applied before anything else, so a
+// plan can neither be made nor applied while there are any.
+async fn check_pending(config: &Config) -> Result<(), error::Error> {
+    let cur_path = config.state_dir.join(CUR_AGGREGATE);
+    if cur_path.exists() {
+        return Err(StateError::Pending(cur_path).into());
+    }
+    let queue_path = config.state_dir.join(RETRY_QUEUE);
+    if config.retry_queue.is_some() && queue_path.exists() {
+        let queue = RetryQueue::open(&queue_path).await?;
+        if !queue.is_empty().await? {
+            return Err(StateError::Queued(queue_path).into());
+        }
+    }
+    Ok(())
+}
+
+async fn old_aggregate_hash(config: &Config) -> Result<Option<String>, error::Error> {
+    let old_path = config.state_dir.join(OLD_AGGREGATE);
+    if !old_path.exists() {
+        return Ok(None);
+    }
+    let hash = state::hash_file(&old_path)
+        .await
+        .map_err(|e| AggregateError::Read(old_path.clone(), e))?;
+    Ok(Some(hash))
+}
+
+/// Each target of the plan is applied as with `dyncfg`, from the same old
+/// aggregate the plan was made against. Every target is planned again first,
+/// and nothing is sent unless all of them would make exactly the planned
+/// changes. The scripts of every table are still checked against the planned
+/// ones right before being sent.
+pub async fn apply_plan(config: &Config, plan: &Plan) -> Result<Vec<TargetResult>, error::Error> {
+    let hash = state::hash_file(&plan.aggregate)
+        .await
+        .map_err(|e| AggregateError::Read(plan.aggregate.clone(), e))?;
+    if hash != plan.aggregate_hash {
+        return Err(plan::Error::Changed(plan.aggregate.clone()).into());
+    }
+    let names: Vec<String> = plan
+        .targets
+        .iter()
+        .filter_map(|t| t.target.clone())
+        .collect();
+    let targets = select_targets(config, &names)?;
+    if targets.len() != plan.targets.len() {
+        return Err(plan::Error::Targets.into());
+    }
+    let (replanned, _) = plan_targets(config, &plan.aggregate, &names).await?;
+    for (target, replanned) in plan.targets.iter().zip(&replanned) {
+        target.check(replanned).map_err(|e| match &target.target {
+            Some(name) => error::Error::from(e).in_target(name),
+            None => e.into(),
+        })?;
+    }
+    let mut results = Vec::new();
+    for ((name, config), target) in targets.into_iter().zip(&plan.targets) {
+        if let Some(name) = &name {
+            info!("processing target {}", name);
+        }
+        let res = apply_planned_target(&config, &plan.aggregate, target).await;
+        if let Err(e) = &res {
+            match &name {
+                Some(name) => error!("failed to apply target {}: {:#}", name, e),
+                None => error!("failed to apply: {:#}", e),
+            }
+        }
+        results.push((name, res));
+    }
+    Ok(results)
+}
+
+async fn apply_planned_target(
+    config: &Config,
+    aggregate: &Path,
+    target: &TargetPlan,
+) -> Result<Vec<TableRun>, error::Error> {
+    create_dirs(config).await?;
+    check_pending(config).await?;
+    if old_aggregate_hash(config).await? != target.base_hash {
+        return Err(plan::Error::Base.into());
+    }
+    let opts = RunOptions {
+        target_plan: Some(target),
+        ..Default::default()
+    };
+    dyn_cfg(aggregate, config, opts).await
+}
+
+// When applying a plan, only the planned scripts of a table are sent.
+fn check_planned_scripts(
+    opts: RunOptions<'_>,
+    table: &str,
+    scripts: &[PolicyScript],
+) -> Result<(), error::Error> {
+    if let Some(plan) = opts.target_plan {
+        let hashes = hash_scripts(scripts);
+        plan.check_scripts(table, &hashes)?;
+    }
+    Ok(())
+}
+
+/// Checks the parameters against both the limits of the configured LPM
+/// implementation and the configured caps, if any.
+pub fn limit_warnings<T>(params: &Params<T>, proto: &str, config: &Config) -> Vec<LimitWarning> {
+    let percent = config.limits.warn_percent;
+    let limits = params::lpm_limits(proto, &config.estimate);
+    let mut warnings = params::check_limits(params, &limits, "dpdk", percent);
+    let caps = config.limits.caps(proto);
+    if !caps.is_empty() {
+        let caps = Limits::from(caps);
+        warnings.extend(params::check_limits(params, &caps, "configured", percent));
+    }
+    warnings
+}
+
+/// Points the configuration at a scratch directory and a mock socket, so
+/// that nothing outside the directory is touched.
+pub fn scratch_config(config: &mut Config, dir: &Path) {
+    config.socket = dir.join("socket");
+    config.broker_socket = None;
+    config.state_dir = dir.join("state");
+    config.targets.clear();
+    config.retry_queue = None;
+    config.lease = None;
+    config.remove_rendered_scripts = RemovePolicy::Never;
+    // The mock server replies in the default format, which a custom pattern
+    // can't be expected to match.
+    config.lpm.reply_pattern = None;
+    // Nor can a single reply answer both parameters and usage queries.
+    config.lpm.usage_script = None;
+    scratch_outputs(config, &dir.join("scripts"));
+}
+
+// Scripts are rendered into the given directory, whatever the configured
+// output paths.
+fn scratch_outputs(config: &mut Config, dir: &Path) {
+    config.scripts_dir = Some(dir.to_owned());
+    let mut outputs = vec![
+        &mut config.lpm.parameters_script.output,
+        &mut config.replace.templates.output,
+        &mut config.update.templates.output,
+    ];
+    for kind in config.kinds.values_mut() {
+        outputs.extend(kind.replace.iter_mut().map(|t| &mut t.templates.output));
+        outputs.extend(kind.update.iter_mut().map(|t| &mut t.templates.output));
+    }
+    for output in outputs {
+        if let Some(name) = Path::new(output.as_str()).file_name() {
+            *output = name.to_string_lossy().into_owned();
+        }
+    }
+}
+
+/// Groups the ranges of a template test fixture by kind.
+pub fn fixture_tables<T: Ord>(entries: &[Entry<T>]) -> Tables<'_, T> {
+    let mut tables: Tables<T> = BTreeMap::new();
+    for entry in entries {
+        tables.entry(entry.kind.clone()).or_default().insert(entry);
+    }
+    tables
+}
+
+/// Creates the state and scripts directories of a target.
+pub async fn create_dirs(config: &Config) -> Result<(), error::Error> {
+    fs::create_dir_all(&config.state_dir)
+        .await
+        .map_err(|e| StateError::Dir(config.state_dir.clone(), e))?;
+    // Rendered scripts reveal the policy, so they aren't world-readable.
+    if let Some(dir) = &config.scripts_dir {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o750)
+            .create(dir)
+            .await
+            .map_err(|e| StateError::Dir(dir.clone(), e))?;
+    }
+    Ok(())
+}
+
+/// Relative output paths are taken to be relative to `scripts_dir`, if set.
+/// During a run with per-run scripts, they're placed in a subdirectory named
+/// after the run.
+pub fn script_path(config: &Config, output: &str) -> String {
+    let path = match &config.scripts_dir {
+        Some(dir) if Path::new(output).is_relative() => dir.join(output),
+        _ => PathBuf::from(output),
+    };
+    // The runs of each namespace are kept apart, so that pruning one leaves
+    // the others alone.
+    let run_dir = match (&config.state_namespace, &config.run_id) {
+        (Some(namespace), Some(run_id)) => Some(Path::new(namespace).join(run_id)),
+        (None, Some(run_id)) => Some(PathBuf::from(run_id)),
+        (_, None) => None,
+    };
+    let path = match (run_dir, path.file_name()) {
+        (Some(run_dir), Some(name)) => path.with_file_name(run_dir).join(name),
+        _ => path,
+    };
+    path.to_string_lossy().into_owned()
+}
+
+/// The paths rendered scripts may be found at, with a placeholder for the
+/// run they were rendered by, if any.
+pub fn script_patterns(config: &Config) -> Vec<String> {
+    let mut config = Cow::Borrowed(config);
+    if config.per_run_scripts {
+        config.to_mut().run_id = Some("{run}".to_owned());
+    }
+    config
+        .script_outputs()
+        .into_iter()
+        .map(|output| script_path(&config, output))
+        .collect()
+}
+
+// Run ids sort chronologically and include the run's serial number in the
+// history. The time tells apart runs reusing the serial of one that crashed
+// before being recorded.
+async fn run_id(config: &Config, started_at: DateTime<Utc>) -> Result<String, error::Error> {
+    let previous = state::read_last_run(&config.state_dir)
+        .await
+        .map_err(|e| StateError::Read("last run", e))?;
+    let serial = previous.map(|r| r.serial + 1).unwrap_or(1);
+    Ok(format!(
+        "{}-{}",
+        started_at.format("%Y%m%dT%H%M%S%.3fZ"),
+        serial
+    ))
+}
+
+/// Without explicitly selected targets, every configured target is processed,
+/// or the top-level configuration itself if no targets are defined.
+pub fn select_targets(
+    config: &Config,
+    names: &[String],
+) -> Result<Vec<(Option<String>, Config)>, error::Error> {
+    if names.is_empty() && config.targets.is_empty() {
+        return Ok(vec![(None, config.clone())]);
+    }
+    let names: Vec<&String> = if names.is_empty() {
+        config.targets.keys().collect()
+    } else {
+        names.iter().collect()
+    };
+    names
+        .into_iter()
+        .map(|name| match config.target(name) {
+            Some(target) => Ok((Some(name.clone()), target)),
+            None => Err(ConfigError::Target(name.clone()).into()),
+        })
+        .collect()
+}
+
+/// Applies an aggregate to a single target, returning the runs of its
+/// tables.
+pub async fn dyn_cfg(
+    new_path: impl AsRef<Path>,
+    config: &Config,
+    opts: RunOptions<'_>,
+) -> Result<Vec<TableRun>, error::Error> {
+    let start = Instant::now();
+    let started_at = Utc::now();
+    let mut run_config = Cow::Borrowed(config);
+    if config.per_run_scripts {
+        let run_id = run_id(config, started_at).await?;
+        run_config.to_mut().run_id = Some(run_id);
+    }
+    // Hashed up front, as a recovered run moves the aggregate away.
+    let aggregate_hash = state::hash_file(new_path.as_ref()).await;
+    let mut tables = Vec::new();
+    let res = run_aggregate(&new_path, &run_config, opts, &mut tables).await;
+    match config.remove_rendered_scripts {
+        RemovePolicy::Always if run_config.run_id.is_some() => remove_run_dirs(&run_config).await,
+        RemovePolicy::OnSuccess if res.is_ok() => {
+            if let Err(e) = remove_run_scripts(&run_config).await {
+                warn!("failed to remove rendered scripts: {:#}", e);
+            }
+        }
+        _ => {}
+    }
+    metrics::timing("run_time", start.elapsed(), &[]);
+    metrics::count("runs", 1, &[]);
+    if res.is_err() {
+        metrics::count("failures", 1, &[]);
+    }
+    let recorded = match aggregate_hash {
+        Ok(hash) => {
+            let tables = tables.clone();
+            record_last_run(config, new_path.as_ref(), hash, started_at, tables, &res).await
+        }
+        Err(e) => Err(AggregateError::Read(new_path.as_ref().to_owned(), e).into()),
+    };
+    if let Err(e) = recorded {
+        warn!("failed to record last run: {:#}", e);
+    }
+    let sentinel = match &res {
+        Ok(()) => state::clear_failure(&config.state_dir).await,
+        Err(e) => {
+            let failure = Failure::new(new_path.as_ref(), e);
+            state::write_failure(&config.state_dir, &failure).await
+        }
+    };
+    if let Err(e) = sentinel {
+        warn!("failed to update failure sentinel: {:#}", e);
+    }
+    res.map(|()| tables)
+}
+
+// Run directories are left behind if any script in them was kept, e.g.
+// because the run failed before sending it.
+async fn remove_run_dirs(config: &Config) {
+    let dirs: BTreeSet<PathBuf> = config
+        .script_outputs()
+        .into_iter()
+        .filter_map(|output| {
+            Path::new(&script_path(config, output))
+                .parent()
+                .map(Path::to_owned)
+        })
+        .collect();
+    for dir in dirs {
+        let _ = fs::remove_dir(&dir).await;
+    }
+}
+
+// Without per-run directories, every script found was rendered by this run,
+// as each run overwrites the same paths.
+async fn remove_run_scripts(config: &Config) -> Result<(), error::Error> {
+    let outputs: Vec<String> = config
+        .script_outputs()
+        .into_iter()
+        .map(|output| script_path(config, output))
+        .collect();
+    let scripts = prune::find(&outputs).await.map_err(StateError::Prune)?;
+    prune::remove(&scripts).await.map_err(StateError::Prune)?;
+    if config.run_id.is_some() {
+        remove_run_dirs(config).await;
+    }
+    Ok(())
+}
+
+async fn record_last_run(
+    config: &Config,
+    aggregate: &Path,
+    aggregate_hash: String,
+    started_at: DateTime<Utc>,
+    tables: Vec<TableRun>,
+    res: &Result<(), error::Error>,
+) -> Result<(), error::Error> {
+    let previous = state::read_last_run(&config.state_dir)
+        .await
+        .map_err(|e| StateError::Read("last run", e))?;
+    let last_run = LastRun {
+        serial: previous.map(|r| r.serial + 1).unwrap_or(1),
+        aggregate: aggregate.to_owned(),
+        aggregate_hash,
+        started_at,
+        finished_at: Utc::now(),
+        outcome: if res.is_ok() {
+            Outcome::Success
+        } else {
+            Outcome::Failure
+        },
+        error: res.as_ref().err().map(|e| format!("{:#}", e)),
+        tables,
+    };
+    state::write_last_run(&config.state_dir, &last_run)
+        .await
+        .map_err(|e| StateError::Write("last run", e))?;
+    state::write_history(&config.state_dir, &last_run, config.history_size)
+        .await
+        .map_err(|e| StateError::Write("history", e))?;
+    Ok(())
+}
+
+async fn run_aggregate(
+    new_path: impl AsRef<Path>,
+    config: &Config,
+    opts: RunOptions<'_>,
+    tables: &mut Vec<TableRun>,
+) -> Result<(), error::Error> {
+    if config.parallelism > 1 {
+        check_parallel_outputs(config)?;
+    }
+    check_phase_outputs(config)?;
+    // Plans render into a scratch directory, and must not prune anything.
+    if let Some(quota) = config.script_quota.as_ref().filter(|_| !opts.plan) {
+        check_script_quota(config, quota).await?;
+    }
+    if let Some(bpf) = config.bpf.as_ref().filter(|_| !opts.plan) {
+        deploy_bpf_programs(config, bpf, opts).await?;
+    }
+    let cur_path = config.state_dir.join(CUR_AGGREGATE);
+
+    // When recovering, the new aggregate is the current one, and
+    // copying it onto itself would truncate it.
+    let recovering = new_path.as_ref() == cur_path;
+    if config.skip_unchanged && !recovering && !opts.initial_load {
+        if let Some(applied) = unchanged_aggregate(config, new_path.as_ref()).await? {
+            info!(
+                "aggregate '{}' unchanged since it was applied at {}; nothing to do",
+                new_path.as_ref().display(),
+                applied.applied_at
+            );
+            metrics::count("runs_skipped", 1, &[]);
+            return Ok(());
+        }
+    }
+    if !recovering && !opts.plan {
+        safe_copy(&new_path, &cur_path)
+            .await
+            .map_err(|e| StateError::Copy(new_path.as_ref().to_owned(), cur_path.clone(), e))?;
+    }
+
+    // Plans read the new aggregate in place, leaving the state untouched.
+    let cur_path = if opts.plan {
+        new_path.as_ref().to_owned()
+    } else {
+        cur_path
+    };
+    let hash = state::hash_file(&cur_path)
+        .await
+        .map_err(|e| AggregateError::Read(cur_path.clone(), e))?;
+    let pending = state::read_progress(&config.state_dir)
+        .await
+        .map_err(|e| StateError::Read("progress", e))?;
+    // As with whole aggregates, a pending interrupted run means the tables
+    // may not hold what was last applied, so none are skipped then.
+    let applied_tables = match (&pending, config.skip_unchanged && !opts.initial_load) {
+        (None, true) => state::read_applied(&config.state_dir)
+            .await
+            .map_err(|e| StateError::Read("applied aggregate", e))?
+            .map(|applied| applied.tables)
+            .unwrap_or_default(),
+        _ => BTreeMap::new(),
+    };
+    let mut progress = match pending {
+        Some(progress) if recovering && progress.aggregate_hash == hash => progress,
+        _ => Progress::new(hash),
+    };
+    if !opts.plan {
+        state::write_progress(&config.state_dir, &progress)
+            .await
+            .map_err(|e| StateError::Write("progress", e))?;
+    }
+
+    let (ipv4_aggregate, ipv6_aggregate) = aggregate::deserialize(&cur_path)
+        .await
+        .map_err(|e| AggregateError::Deserialize(cur_path.clone(), e.into()))?;
+    let mut new_bootstrap = Bootstrap::new(&ipv4_aggregate, &ipv6_aggregate);
+    let new_embedded = embedded_entries(config, &new_bootstrap.ipv6);
+    if !new_embedded.is_empty() {
+        info!(
+            "moving {} IPv4-mapped or compatible ranges to the ipv4 tables",
+            new_embedded.len()
+        );
+    }
+    prepare_tables(
+        config,
+        &mut new_bootstrap.ipv4,
+        &mut new_bootstrap.ipv6,
+        &new_embedded,
+        config.target_kinds.as_ref(),
+    );
+
+    // Every table is validated before any of them is changed, so that a
+    // rejected aggregate isn't partially applied.
+    validate_tables(config, &new_bootstrap.ipv4, &new_bootstrap.ipv6)?;
+
+    let mut kinds = Vec::new();
+    for (kind, ranges) in &new_bootstrap.ipv4 {
+        kinds.push(KindSummary::new(config, "ipv4", kind, ranges.len()));
+    }
+    for (kind, ranges) in &new_bootstrap.ipv6 {
+        kinds.push(KindSummary::new(config, "ipv6", kind, ranges.len()));
+    }
+
+    // Without a valid old aggregate, every range would be inserted, which
+    // must be explicitly requested to avoid accidental full loads caused by
+    // a wiped state directory. Initial loads recreate LPM tables from
+    // scratch, but hash tables are never recreated, so the ranges of the old
+    // aggregate, if any, that are gone from the new one are still removed
+    // from them.
+    let old_path = config.state_dir.join(OLD_AGGREGATE);
+    let old_aggregates = match aggregate::deserialize(&old_path).await {
+        Ok(aggregates) => Some(aggregates),
+        Err(_) if opts.initial_load => None,
+        Err(e) if opts.bootstrap => {
+            let e = anyhow::Error::from(e);
+            warn!(
+                "no valid old aggregate in '{}' ({:#}); bootstrapping",
+                old_path.display(),
+                e
+            );
+            None
+        }
+        Err(e) => return Err(AggregateError::Old(old_path, e.into()).into()),
+    };
+    let diffed = |kind: &Option<String>| {
+        !opts.initial_load || config.kind(kind).table_mode == TableMode::Hash
+    };
+
+    let mut old_bootstrap = old_aggregates
+        .as_ref()
+        .map(|(ipv4, ipv6)| Bootstrap::new(ipv4, ipv6));
+    // The old aggregate is prepared the same way, so that ranges are
+    // compared against their previous state in the same table.
+    let old_embedded = match &old_bootstrap {
+        Some(bootstrap) => embedded_entries(config, &bootstrap.ipv6),
+        None => Vec::new(),
+    };
+    if let Some(bootstrap) = &mut old_bootstrap {
+        let routed = applied_routing(config).await?;
+        prepare_tables(
+            config,
+            &mut bootstrap.ipv4,
+            &mut bootstrap.ipv6,
+            &old_embedded,
+            routed.as_ref(),
+        );
+    }
+
+    // The tables of kinds routed away from the target since the old
+    // aggregate was applied are emptied.
+    let (empty4, empty6) = (BTreeSet::new(), BTreeSet::new());
+    let mut unrouted4 = unrouted_tables(
+        config,
+        &new_bootstrap.ipv4,
+        old_bootstrap.as_ref().map(|b| &b.ipv4),
+    );
+    let mut unrouted6 = unrouted_tables(
+        config,
+        &new_bootstrap.ipv6,
+        old_bootstrap.as_ref().map(|b| &b.ipv6),
+    );
+    unrouted4.retain(|kind| diffed(kind));
+    unrouted6.retain(|kind| diffed(kind));
+    for kind in &unrouted4 {
+        info!(
+            "emptying ipv4 table of kind {:?}, no longer routed to the target",
+            kind
+        );
+        kinds.push(KindSummary::new(config, "ipv4", kind, 0));
+    }
+    for kind in &unrouted6 {
+        info!(
+            "emptying ipv6 table of kind {:?}, no longer routed to the target",
+            kind
+        );
+        kinds.push(KindSummary::new(config, "ipv6", kind, 0));
+    }
+    let new_ipv4 = new_bootstrap
+        .ipv4
+        .iter()
+        .chain(unrouted4.iter().map(|kind| (kind, &empty4)));
+    let new_ipv6 = new_bootstrap
+        .ipv6
+        .iter()
+        .chain(unrouted6.iter().map(|kind| (kind, &empty6)));
+
+    // Tables are independent of each other, so up to `parallelism` of them
+    // are applied concurrently. Scripts within a table are still sent in
+    // order, and progress is recorded as each table completes.
+    let mut table_hashes = BTreeMap::new();
+    let mut jobs: Vec<(
+        i64,
+        LocalBoxFuture<'_, Result<(String, TableRun), error::Error>>,
+    )> = Vec::new();
+    for (kind, new_ranges) in new_ipv4 {
+        let key = table_key("ipv4", kind);
+        let hash = ranges_hash(new_ranges);
+        table_hashes.insert(key.clone(), hash.clone());
+        if progress.completed.contains(&key) {
+            info!("skipping {}, applied by the interrupted run", key);
+            continue;
+        }
+        if applied_tables.get(&key) == Some(&hash) {
+            skip_unchanged_table(config, "ipv4", kind);
+            progress.completed.insert(key);
+            continue;
+        }
+        let old_ranges = old_bootstrap
+            .as_ref()
+            .filter(|_| diffed(kind))
+            .and_then(|b| b.ipv4.get(kind))
+            .unwrap_or(&empty4);
+        let kinds = &kinds;
+        jobs.push((
+            config.kind_priority(kind),
+            async move {
+                // Invalid ranges are also left out of the old aggregate, so
+                // that ranges stripped by the previous run aren't removed.
+                let new_valid = valid_ranges(config, kind, new_ranges);
+                let old_valid = valid_ranges(config, kind, old_ranges);
+                let (new_ranges, old_ranges) = (new_valid.ranges(), old_valid.ranges());
+                let run = run_ipv4(config, opts, kinds, kind, &new_ranges, &old_ranges)
+                    .await
+                    .map_err(|e| e.in_table(&table_name(config, "ipv4", kind)))?;
+                Ok((key, run))
+            }
+            .boxed_local(),
+        ));
+    }
+    for (kind, new_ranges) in new_ipv6 {
+        let key = table_key("ipv6", kind);
+        let hash = ranges_hash(new_ranges);
+        table_hashes.insert(key.clone(), hash.clone());
+        if progress.completed.contains(&key) {
+            info!("skipping {}, applied by the interrupted run", key);
+            continue;
+        }
+        if applied_tables.get(&key) == Some(&hash) {
+            skip_unchanged_table(config, "ipv6", kind);
+            progress.completed.insert(key);
+            continue;
+        }
+        let old_ranges = old_bootstrap
+            .as_ref()
+            .filter(|_| diffed(kind))
+            .and_then(|b| b.ipv6.get(kind))
+            .unwrap_or(&empty6);
+        let kinds = &kinds;
+        jobs.push((
+            config.kind_priority(kind),
+            async move {
+                let new_valid = valid_ranges(config, kind, new_ranges);
+                let old_valid = valid_ranges(config, kind, old_ranges);
+                let (new_ranges, old_ranges) = (new_valid.ranges(), old_valid.ranges());
+                let run = run_ipv6(config, opts, kinds, kind, &new_ranges, &old_ranges)
+                    .await
+                    .map_err(|e| e.in_table(&table_name(config, "ipv6", kind)))?;
+                Ok((key, run))
+            }
+            .boxed_local(),
+        ));
+    }
+
+    // Tables of a higher priority kind are all applied before any of a
+    // lower priority is started. After a failure no more tables are
+    // started, but the ones in flight are allowed to complete, so that
+    // their progress is recorded.
+    jobs.sort_by_key(|(priority, _)| Reverse(*priority));
+    let parallelism = max(1, config.parallelism);
+    let mut jobs = jobs.into_iter().peekable();
+    let mut running = FuturesUnordered::new();
+    let mut running_priority = None;
+    let mut failure = None;
+    loop {
+        while failure.is_none() && running.len() < parallelism {
+            let next = jobs
+                .next_if(|(priority, _)| running.is_empty() || running_priority == Some(*priority));
+            match next {
+                Some((priority, job)) => {
+                    running_priority = Some(priority);
+                    running.push(job);
+                }
+                None => break,
+            }
+        }
+        match running.next().await {
+            Some(Ok((key, run))) => {
+                tables.push(run);
+                progress.completed.insert(key);
+                if !opts.plan {
+                    state::write_progress(&config.state_dir, &progress)
+                        .await
+                        .map_err(|e| StateError::Write("progress", e))?;
+                }
+            }
+            Some(Err(e)) if failure.is_none() => failure = Some(e),
+            Some(Err(e)) => error!("{:#}", e),
+            None => break,
+        }
+    }
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    if opts.plan {
+        return Ok(());
+    }
+
+    safe_rename(&cur_path, &old_path)
+        .await
+        .map_err(|e| StateError::Rename(cur_path.clone(), old_path.clone(), e))?;
+    state::clear_progress(&config.state_dir)
+        .await
+        .map_err(|e| StateError::Write("progress", e))?;
+    let applied = Applied {
+        aggregate_hash: progress.aggregate_hash,
+        applied_at: Utc::now(),
+        tables: table_hashes,
+        routed_kinds: config.target_kinds.clone(),
+    };
+    state::write_applied(&config.state_dir, &applied)
+        .await
+        .map_err(|e| StateError::Write("applied aggregate", e))?;
+
+    Ok(())
+}
+
+// Returns the record of the last applied aggregate if it has the same
+// contents as the new one. A pending interrupted run means the tables may
+// hold a different aggregate, so nothing is skipped then.
+async fn unchanged_aggregate(
+    config: &Config,
+    new_path: &Path,
+) -> Result<Option<Applied>, error::Error> {
+    let applied = state::read_applied(&config.state_dir)
+        .await
+        .map_err(|e| StateError::Read("applied aggregate", e))?;
+    let applied = match applied {
+        Some(applied) => applied,
+        None => return Ok(None),
+    };
+    let progress = state::read_progress(&config.state_dir)
+        .await
+        .map_err(|e| StateError::Read("progress", e))?;
+    if progress.is_some() {
+        return Ok(None);
+    }
+    // Routing kinds away from the target changes its tables even if the
+    // aggregate is the same.
+    if applied.routed_kinds != config.target_kinds {
+        return Ok(None);
+    }
+    let hash = state::hash_file(new_path)
+        .await
+        .map_err(|e| AggregateError::Read(new_path.to_owned(), e))?;
+    Ok(Some(applied).filter(|applied| applied.aggregate_hash == hash))
+}
+
+// Returns the kinds of the old tables that are no longer routed to the
+// target.
+fn unrouted_tables<T>(
+    config: &Config,
+    new: &Tables<T>,
+    old: Option<&Tables<T>>,
+) -> Vec<Option<String>> {
+    let routed = match &config.target_kinds {
+        Some(routed) => routed,
+        None => return Vec::new(),
+    };
+    old.iter()
+        .flat_map(|old| old.keys())
+        .filter(|kind| !new.contains_key(*kind) && !is_routed(routed, kind))
+        .cloned()
+        .collect()
+}
+
+// Identifies a table's contents, so that tables left unchanged by a new
+// aggregate can be recognized.
+fn ranges_hash<T: Display + Serialize>(ranges: &BTreeSet<&Entry<T>>) -> String {
+    state::hash_lines(
+        ranges
+            .iter()
+            .map(|e| format!("{} {}", e.range, display_value(&e.class))),
+    )
+}
+
+fn skip_unchanged_table(config: &Config, proto: &str, kind: &Option<String>) {
+    let table = table_name(config, proto, kind);
+    info!(
+        "skipping table {}, unchanged since the last applied aggregate",
+        table
+    );
+    metrics::count(
+        "tables_skipped",
+        1,
+        &[("proto", proto), ("table", table.as_str())],
+    );
+}
+
+// Checked before rendering anything, so that a run doesn't fill the disk
+// halfway through.
+async fn check_script_quota(
+    config: &Config,
+    quota: &ScriptQuotaConfig,
+) -> Result<(), error::Error> {
+    let outputs = script_patterns(config);
+    match quota.action {
+        QuotaAction::Prune => {
+            let pruned = prune::enforce_quota(&outputs, quota.max_bytes)
+                .await
+                .map_err(StateError::Prune)?;
+            if !pruned.is_empty() {
+                let bytes: u64 = pruned.iter().map(|a| a.size).sum();
+                warn!(
+                    "script quota exceeded; removed {} scripts ({} bytes)",
+                    pruned.len(),
+                    bytes
+                );
+                metrics::count("scripts_pruned", pruned.len() as u64, &[]);
+            }
+        }
+        QuotaAction::Fail => {
+            let artifacts = prune::find(&outputs).await.map_err(StateError::Prune)?;
+            let used: u64 = artifacts.iter().map(|a| a.size).sum();
+            if used > quota.max_bytes {
+                return Err(LimitError::Quota(used, quota.max_bytes).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Tables applied concurrently must render their scripts to distinct paths.
+// Templates overridden for a single kind only need to tell protocols apart.
+fn check_parallel_outputs(config: &Config) -> Result<(), error::Error> {
+    let shared = [
+        Some(&config.lpm.parameters_script.output),
+        config.lpm.usage_script.as_ref().map(|t| &t.output),
+        Some(&config.replace.templates.output),
+        Some(&config.update.templates.output),
+    ];
+    let per_kind = config
+        .kinds
+        .values()
+        .flat_map(|kind| kind.replace.iter().chain(&kind.update))
+        .map(|templates| &templates.templates.output);
+    let invalid = shared
+        .iter()
+        .flatten()
+        .copied()
+        .find(|output| !output.contains("{proto}") || !output.contains("{kind}"))
+        .or_else(|| {
+            per_kind
+                .into_iter()
+                .find(|output| !output.contains("{proto}"))
+        });
+    match invalid {
+        Some(output) => Err(ConfigError::Parallel(output.clone()).into()),
+        None => Ok(()),
+    }
+}
+
+// Removals and insertions rendered separately must be written to different
+// scripts. Kinds without overrides use the top-level settings.
+fn check_phase_outputs(config: &Config) -> Result<(), error::Error> {
+    let kinds = iter::once(None).chain(config.kinds.keys().cloned().map(Some));
+    for kind in kinds {
+        let config = config.kind(&kind);
+        let output = &config.update.templates.output;
+        if config.change_order != ChangeOrder::Interleaved && !output.contains("{phase}") {
+            return Err(ConfigError::Phase(output.clone()).into());
+        }
+    }
+    Ok(())
+}
+
+// Policies refer to BPF programs by index, so no two programs may share one.
+fn check_bpf_indices(bpf: &BpfConfig) -> Result<(), error::Error> {
+    let mut indices = BTreeMap::new();
+    for (name, program) in &bpf.programs {
+        if let Some(other) = indices.insert(program.index, name) {
+            return Err(ConfigError::BpfIndex(other.clone(), name.clone(), program.index).into());
+        }
+    }
+    Ok(())
+}
+
+// Uploads the BPF programs whose files changed since they were last
+// uploaded. This happens before any table is changed, so that the policies
+// of new ranges never refer to a program Gatekeeper doesn't have yet, and a
+// failed upload stops the run with the tables untouched.
+async fn deploy_bpf_programs(
+    config: &Config,
+    bpf: &BpfConfig,
+    opts: RunOptions<'_>,
+) -> Result<(), error::Error> {
+    let (mut state, uploads) = pending_bpf_programs(config, bpf, opts).await?;
+    if let Some(plan) = opts.target_plan {
+        plan.check_bpf(&uploads)?;
+    }
+    if uploads.is_empty() {
+        return Ok(());
+    }
+
+    let template = fs::read_to_string(&bpf.input)
+        .await
+        .map_err(|e| TemplateError::Read(bpf.input.clone(), e))?;
+    for upload in uploads {
+        let name = &upload.program;
+        let program = &bpf.programs[name];
+        info!(
+            "uploading bpf program {} from '{}' at index {}",
+            name,
+            program.path.display(),
+            program.index
+        );
+        let vars = BpfVariables {
+            extra: &config.extra_vars.bpf,
+            program: name,
+            path: &program.path,
+            index: program.index,
+            replace: upload.replace,
+            overrides: &config.var_overrides,
+        };
+        let output = PathBuf::from(script_path(config, &bpf.output.replace("{program}", name)));
+        let script = render_template(config, &template, &vars, &output).await?;
+        if let (true, Some(path)) = (config.durable, &script.path) {
+            util::sync_files(&[path])
+                .await
+                .map_err(TemplateError::Sync)?;
+        }
+        let reply = dyncfg::send_config_bytes(
+            config.dyncfg_socket(),
+            &script.data,
+            &config.dyncfg_config(),
+        )
+        .await
+        .map_err(|e| e.in_script(&output, 0))?;
+        if !reply.trim_start().starts_with(&bpf.success_reply) {
+            let reply = reply.trim().to_owned();
+            return Err(GatekeeperError::Bpf(name.clone(), reply).into());
+        }
+        metrics::count("bpf_programs_uploaded", 1, &[("program", name.as_str())]);
+        if let (RemovePolicy::Always, Some(path)) = (config.remove_rendered_scripts, &script.path) {
+            remove_script(path).await?;
+        }
+        state.programs.insert(name.clone(), upload.hash);
+        state.indices.insert(program.index, name.clone());
+        state::write_bpf_state(&config.state_dir, &state)
+            .await
+            .map_err(|e| StateError::Write("bpf state", e))?;
+    }
+    Ok(())
+}
+
+// Returns the BPF programs whose files changed since they were last
+// uploaded, along with the state they were compared against.
+async fn pending_bpf_programs(
+    config: &Config,
+    bpf: &BpfConfig,
+    opts: RunOptions<'_>,
+) -> Result<(BpfState, Vec<BpfPlan>), error::Error> {
+    check_bpf_indices(bpf)?;
+    // On an initial load, Gatekeeper has none of the programs yet.
+    let mut state = if opts.initial_load {
+        BpfState::default()
+    } else {
+        state::read_bpf_state(&config.state_dir)
+            .await
+            .map_err(|e| StateError::Read("bpf state", e))?
+    };
+    // Programs removed from the configuration are uploaded again if they
+    // come back. Their indices are still taken in Gatekeeper, though.
+    state
+        .programs
+        .retain(|name, _| bpf.programs.contains_key(name));
+
+    let mut uploads = Vec::new();
+    for (name, program) in &bpf.programs {
+        let hash = state::hash_file(&program.path)
+            .await
+            .map_err(|e| ConfigError::Read(program.path.clone(), e))?;
+        if state.programs.get(name) == Some(&hash) {
+            continue;
+        }
+        uploads.push(BpfPlan {
+            program: name.clone(),
+            index: program.index,
+            hash,
+            replace: state.indices.contains_key(&program.index),
+        });
+    }
+    Ok((state, uploads))
+}
+
+/// The ranges of an aggregate, grouped by kind.
+pub type Tables<'a, T> = BTreeMap<Option<String>, BTreeSet<&'a Entry<T>>>;
+
+/// Copies the IPv4 ranges embedded in IPv6 ranges, if they are to be
+/// normalized. The copies must outlive the tables passed to `prepare_tables`.
+pub fn embedded_entries(config: &Config, ipv6: &Tables<Ipv6Net>) -> Vec<Entry<Ipv4Net>> {
+    let normalize = config
+        .validation
+        .as_ref()
+        .map_or(false, |v| v.normalize_ipv4_embedded);
+    if normalize {
+        validate::ipv4_embedded_entries(ipv6)
+    } else {
+        Vec::new()
+    }
+}
+
+/// Rearranges the tables of an aggregate into the ones Gatekeeper is
+/// configured with, moving IPv4-embedded ranges to the IPv4 tables,
+/// renaming kinds according to `kind_map`, grouping ranges according to
+/// `group_by` and keeping the tables of the `routed` kinds, if given.
+pub fn prepare_tables<'a>(
+    config: &Config,
+    ipv4: &mut Tables<'a, Ipv4Net>,
+    ipv6: &mut Tables<'a, Ipv6Net>,
+    embedded: &'a [Entry<Ipv4Net>],
+    routed: Option<&BTreeSet<String>>,
+) {
+    if !embedded.is_empty() {
+        validate::normalize_ipv4_embedded(ipv4, ipv6, embedded);
+    }
+    rename_kinds(ipv4, &config.kind_map);
+    rename_kinds(ipv6, &config.kind_map);
+    group_tables(ipv4, &config.group_by);
+    group_tables(ipv6, &config.group_by);
+    if let Some(kinds) = routed {
+        route_tables(ipv4, kinds);
+        route_tables(ipv6, kinds);
+    }
+}
+
+// Keeps only the tables of the kinds routed to the target. Kinds are matched
+// after any renaming or grouping, so the unnamed table is never routed.
+fn route_tables<T>(tables: &mut Tables<T>, kinds: &BTreeSet<String>) {
+    tables.retain(|kind, _| is_routed(kinds, kind));
+}
+
+fn is_routed(kinds: &BTreeSet<String>, kind: &Option<String>) -> bool {
+    kind.as_ref().map_or(false, |kind| kinds.contains(kind))
+}
+
+/// Returns the kinds routed to the target when the old aggregate was applied,
+/// so that the old aggregate is compared against the tables it actually
+/// filled. Without a record of the last applied aggregate, the current
+/// routing is assumed.
+pub async fn applied_routing(config: &Config) -> Result<Option<BTreeSet<String>>, error::Error> {
+    let applied = state::read_applied(&config.state_dir)
+        .await
+        .map_err(|e| StateError::Read("applied aggregate", e))?;
+    let routed = match applied {
+        Some(applied) => applied.routed_kinds,
+        None => config.target_kinds.clone(),
+    };
+    Ok(routed)
+}
+
+// Merges the tables of kinds renamed by `kind_map` into the tables of
+// their new names. A range already present in the merged table is kept
+// only once, with the class from the table that sorts first.
+fn rename_kinds<T: Ord + Copy>(tables: &mut Tables<T>, kind_map: &BTreeMap<String, String>) {
+    if kind_map.is_empty() {
+        return;
+    }
+    for (kind, ranges) in std::mem::take(tables) {
+        let kind = kind.map(|kind| kind_map.get(&kind).cloned().unwrap_or(kind));
+        let table = tables.entry(kind).or_default();
+        let present: BTreeSet<T> = table.iter().map(|e| e.range).collect();
+        table.extend(ranges.into_iter().filter(|e| !present.contains(&e.range)));
+    }
+}
+
+// Regroups the ranges of each kind into tables named after the values of
+// the `group_by` keys, joined by underscores. Ranges without a value for
+// any of the keys go to the unnamed table.
+fn group_tables<T: Ord + Copy>(tables: &mut Tables<T>, group_by: &[GroupKey]) {
+    if group_by == [GroupKey::Kind] {
+        return;
+    }
+    let mut present: BTreeMap<Option<String>, BTreeSet<T>> = BTreeMap::new();
+    for (kind, ranges) in std::mem::take(tables) {
+        for entry in ranges {
+            let values: Vec<String> = group_by
+                .iter()
+                .filter_map(|key| match key {
+                    GroupKey::Kind => kind.clone(),
+                    GroupKey::Class => Some(display_value(&entry.class)),
+                })
+                .collect();
+            let name = Some(values.join("_")).filter(|name| !name.is_empty());
+            if present.entry(name.clone()).or_default().insert(entry.range) {
+                tables.entry(name).or_default().insert(entry);
+            }
+        }
+    }
+}
+
+/// Checks the ranges of every table against the configured validation
+/// rules, failing if the action is to reject the aggregate.
+pub fn validate_tables(
+    config: &Config,
+    ipv4: &Tables<Ipv4Net>,
+    ipv6: &Tables<Ipv6Net>,
+) -> Result<(), error::Error> {
+    let validation = match &config.validation {
+        Some(validation) => validation,
+        None => return Ok(()),
+    };
+    let mut violations = Vec::new();
+    for (kind, ranges) in ipv4 {
+        let filtered = validate::filter(validation, kind, ranges);
+        let table = table_key("ipv4", kind);
+        violations.extend(filtered.violations.into_iter().map(|v| (table.clone(), v)));
+    }
+    for (kind, ranges) in ipv6 {
+        let filtered = validate::filter(validation, kind, ranges);
+        let table = table_key("ipv6", kind);
+        violations.extend(filtered.violations.into_iter().map(|v| (table.clone(), v)));
+    }
+    report_violations(validation.action, &violations)
+}
+
+/// Returns the ranges of a table that pass validation.
+pub fn valid_ranges<'s, 'a, T: Prefix>(
+    config: &Config,
+    kind: &Option<String>,
+    ranges: &'s BTreeSet<&'a Entry<T>>,
+) -> Filtered<'s, 'a, T> {
+    match &config.validation {
+        Some(validation) => validate::filter(validation, kind, ranges),
+        None => Filtered::unfiltered(ranges),
+    }
+}
+
+fn report_violations(
+    action: ValidationAction,
+    violations: &[(String, Violation)],
+) -> Result<(), error::Error> {
+    if violations.is_empty() {
+        return Ok(());
+    }
+    let mut shown: Vec<String> = violations
+        .iter()
+        .take(MAX_VIOLATIONS_SHOWN)
+        .map(|(table, v)| format!("{} in {}", v, table))
+        .collect();
+    if violations.len() > MAX_VIOLATIONS_SHOWN {
+        shown.push(format!(
+            "and {} more",
+            violations.len() - MAX_VIOLATIONS_SHOWN
+        ));
+    }
+    match action {
+        ValidationAction::Reject => {
+            Err(AggregateError::Rejected(violations.len(), shown.join(", ")).into())
+        }
+        ValidationAction::Strip => {
+            warn!(
+                "stripping {} invalid ranges: {}",
+                violations.len(),
+                shown.join(", ")
+            );
+            for (table, _) in violations {
+                metrics::count("ranges_stripped", 1, &[("table", table)]);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn table_key(proto: &str, kind: &Option<String>) -> String {
+    format!("{}/{}", proto, kind.as_deref().unwrap_or(""))
+}
+
+fn table_name(config: &Config, proto: &str, kind: &Option<String>) -> String {
+    replace_vars(&config.kind(kind).lpm.table_format, proto, kind)
+}
+
+/// Applies the changes to the IPv4 table of a kind.
+pub async fn run_ipv4(
+    config: &Config,
+    opts: RunOptions<'_>,
+    kinds: &[KindSummary],
+    kind: &Option<String>,
+    new: &BTreeSet<&Entry<Ipv4Net>>,
+    old: &BTreeSet<&Entry<Ipv4Net>>,
+) -> Result<TableRun, error::Error> {
+    run(
+        config,
+        opts,
+        kinds,
+        config.lpm.lua_functions("ipv4", kind),
+        "ipv4",
+        kind,
+        &new,
+        &old,
+        params::estimate_ipv4,
+        params::tbl8s_ipv4,
+    )
+    .await
+}
+
+/// Applies the changes to the IPv6 table of a kind.
+pub async fn run_ipv6(
+    config: &Config,
+    opts: RunOptions<'_>,
+    kinds: &[KindSummary],
+    kind: &Option<String>,
+    new: &BTreeSet<&Entry<Ipv6Net>>,
+    old: &BTreeSet<&Entry<Ipv6Net>>,
+) -> Result<TableRun, error::Error> {
+    run(
+        config,
+        opts,
+        kinds,
+        config.lpm.lua_functions("ipv6", kind),
+        "ipv6",
+        kind,
+        &new,
+        &old,
+        params::estimate_ipv6,
+        params::tbl8s_ipv6,
+    )
+    .await
+}
+
+#[derive(Debug, Serialize)]
+struct BpfVariables<'a> {
+    #[serde(flatten)]
+    extra: &'a BTreeMap<String, serde_yaml::Value>,
+    program: &'a str,
+    path: &'a Path,
+    index: u8,
+    // Whether a program was uploaded at the index before.
+    replace: bool,
+    #[serde(flatten)]
+    overrides: &'a BTreeMap<String, serde_yaml::Value>,
+}
+
+/// A table present in the aggregate, exposed to the parameters script so
+/// that it can handle every table at once.
+#[derive(Debug, Serialize)]
+pub struct KindSummary {
+    proto: &'static str,
+    kind: Option<String>,
+    lpm_table: String,
+    num_ranges: usize,
+}
+
+impl KindSummary {
+    /// Names the table of a kind with `num_ranges` ranges.
+    pub fn new(
+        config: &Config,
+        proto: &'static str,
+        kind: &Option<String>,
+        num_ranges: usize,
+    ) -> KindSummary {
+        KindSummary {
+            proto,
+            kind: kind.clone(),
+            lpm_table: replace_vars(&config.lpm.table_format, proto, kind),
+            num_ranges,
+        }
+    }
+}
+
+// Extra variables are serialized first so that the ones provided by gtctl
+// take precedence over them, and overrides last so that they take
+// precedence over everything.
+#[derive(Debug, Serialize)]
+struct ParametersScriptVariables<'a> {
+    #[serde(flatten)]
+    extra: &'a BTreeMap<String, serde_yaml::Value>,
+    lpm_table: &'a str,
+    lpm_params_function: &'a str,
+    lpm_usage_function: Option<&'a str>,
+    reply_format: ReplyFormat,
+    kinds: &'a [KindSummary],
+    #[serde(flatten)]
+    overrides: &'a BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplaceModeVariables<'a, T> {
+    #[serde(flatten)]
+    pub extra: &'a BTreeMap<String, serde_yaml::Value>,
+    pub params: &'a Params<T>,
+    pub lpm_table: &'a str,
+    pub lpm_table_constructor: &'a str,
+    pub proto: &'a str,
+    pub kind: &'a Option<String>,
+    pub policies: Policies<'a>,
+    #[serde(flatten)]
+    pub overrides: &'a BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct UpdateModeVariables<'a> {
+    #[serde(flatten)]
+    extra: &'a BTreeMap<String, serde_yaml::Value>,
+    lpm_table: &'a str,
+    proto: &'a str,
+    kind: &'a Option<String>,
+    policies: Policies<'a>,
+    #[serde(flatten)]
+    overrides: &'a BTreeMap<String, serde_yaml::Value>,
+}
+
+/// The policy variables of each kind and class of ranges in a script, keyed
+/// by `<kind>/<class>`, for templates to look up per range.
+pub type Policies<'a> = BTreeMap<String, &'a BTreeMap<String, serde_yaml::Value>>;
+
+/// Returns the policy variables of the kinds and classes of the given ranges.
+pub fn policies<'a, 'e, T: 'e>(
+    rules: &'a [PolicyRule],
+    entries: impl Iterator<Item = &'e Entry<T>>,
+) -> Policies<'a> {
+    let mut policies = BTreeMap::new();
+    for entry in entries {
+        let kind = entry.kind.as_deref().unwrap_or("");
+        let class = display_value(&entry.class);
+        let key = format!("{}/{}", kind, class);
+        if policies.contains_key(&key) {
+            continue;
+        }
+        let rule = rules.iter().find(|rule| {
+            rule.kind.as_ref().map_or(true, |k| k == kind)
+                && rule.class.as_ref().map_or(true, |c| *c == class)
+        });
+        if let Some(rule) = rule {
+            policies.insert(key, &rule.vars);
+        }
+    }
+    policies
+}
+
+async fn run<T>(
+    config: &Config,
+    opts: RunOptions<'_>,
+    kinds: &[KindSummary],
+    lua_functions: &LuaFunctions,
+    proto: &str,
+    kind: &Option<String>,
+    new_ranges: &BTreeSet<&Entry<T>>,
+    old_ranges: &BTreeSet<&Entry<T>>,
+    estimate: impl Fn(&BTreeSet<T>, &EstimateConfig) -> Params<T>,
+    count_tbl8s: impl Fn(&BTreeSet<T>, &EstimateConfig) -> usize,
+) -> Result<TableRun, error::Error>
+where
+    T: Ord + Serialize + Copy + Debug,
+{
+    let config = &*config.kind(kind);
+    let table = replace_vars(&config.lpm.table_format, proto, kind);
+    if config.table_mode == TableMode::Hash {
+        return run_hash(config, opts, proto, kind, table, new_ranges, old_ranges).await;
+    }
+    let vars = ParametersScriptVariables {
+        extra: &config.extra_vars.parameters,
+        lpm_table: &table,
+        lpm_params_function: &lua_functions.lpm_get_params_function,
+        lpm_usage_function: lua_functions.lpm_get_usage_function.as_deref(),
+        reply_format: config.lpm.reply_format,
+        kinds,
+        overrides: &config.var_overrides,
+    };
+
+    let mut parameters_script = config.lpm.parameters_script.clone();
+    parameters_script.output = script_path(config, &parameters_script.output);
+    let script = render_parameters_script(config, &parameters_script, proto, kind, &vars).await?;
+
+    let set = new_ranges.iter().map(|e| e.range).collect();
+    let mut estimated_params = estimate(&set, &config.estimate);
+    debug!("estimated parameters: {:?}", estimated_params);
+    let caps = Limits::from(config.limits.caps(proto));
+    if estimated_params.exceeds(&caps) {
+        match config.limits.action {
+            CapAction::Fail => {
+                let (params, caps) = (estimated_params.to_string(), caps.to_string());
+                return Err(LimitError::Caps(table, params, caps).into());
+            }
+            CapAction::Truncate => {
+                warn!(
+                    "truncating estimated parameters {} for table {} to its caps {}",
+                    estimated_params, table, caps
+                );
+                metrics::count(
+                    "params_truncated",
+                    1,
+                    &[("proto", proto), ("table", table.as_str())],
+                );
+                estimated_params = estimated_params.truncated(&caps);
+            }
+        }
+    }
+    let limit_warnings = limit_warnings(&estimated_params, proto, config);
+    for w in &limit_warnings {
+        warn!("table {} is approaching its limits: {}", table, w);
+        metrics::count(
+            "limit_warnings",
+            1,
+            &[
+                ("proto", proto),
+                ("table", table.as_str()),
+                ("param", w.param),
+            ],
+        );
+    }
+
+    // On an initial load the table may not exist yet, so its
+    // parameters can't be read.
+    let (mode, current, utilization, overflows) = if opts.initial_load {
+        (Mode::Replace, vec![], None, true)
+    } else {
+        let mut current_params = params::read(
+            config.dyncfg_socket(),
+            &script.data,
+            &config.lpm,
+            &config.dyncfg_config(),
+        )
+        .await?;
+        match (&config.lpm.usage_script, vars.lpm_usage_function) {
+            (Some(usage_script), Some(_)) => {
+                let usage = read_usage(config, usage_script, proto, kind, &vars).await?;
+                current_params.set_usage(&usage);
+            }
+            (Some(_), None) => debug!("no usage function configured for table {}", table),
+            _ => {}
+        }
+        debug!("current parameters: {:?}", current_params);
+        if let Some((rules, tbl8s)) = current_params.max_usage() {
+            let tags = [("proto", proto), ("table", table.as_str())];
+            metrics::gauge("rules_used", rules as f64, &tags);
+            metrics::gauge("tbl8s_used", tbl8s as f64, &tags);
+        }
+        let inserted: BTreeSet<T> = new_ranges.difference(old_ranges).map(|e| e.range).collect();
+        let update = Update {
+            removed: old_ranges.difference(new_ranges).count(),
+            inserted: inserted.len(),
+            inserted_tbl8s: count_tbl8s(&inserted, &config.estimate),
+        };
+        let mode = run_mode(
+            &current_params,
+            &estimated_params,
+            &update,
+            config.replace_threshold_percent,
+        );
+        let fits = current_params.fits(&estimated_params);
+        match mode {
+            Mode::Replace if fits => info!(
+                "table {} would exceed {}% of its capacity with the update: {:?}",
+                table, config.replace_threshold_percent, update
+            ),
+            Mode::Update if !fits => info!(
+                "table {} has room for the update despite its estimated parameters {}",
+                table, estimated_params
+            ),
+            _ => {}
+        }
+        let utilization = current_params.utilization(&estimated_params);
+        if let Some(u) = &utilization {
+            info!("table {} utilization: {}", table, u);
+            let tags = [("proto", proto), ("table", table.as_str())];
+            metrics::gauge("rules_utilization", u.rules_percent, &tags);
+            metrics::gauge("tbl8s_utilization", u.tbl8s_percent, &tags);
+        }
+        // Whether the update doesn't fit at all, regardless of the replace
+        // threshold.
+        let overflows = !current_params.fits_update(&estimated_params, &update, 100);
+        (mode, current_params.tables, utilization, overflows)
+    };
+    let (mode, overprovisioned_runs) = match &config.shrink {
+        Some(shrink) if mode == Mode::Update && config.allow_replace => {
+            let runs = overprovisioned_runs(config, shrink, &table, &utilization).await;
+            if runs >= shrink.runs {
+                info!(
+                    "shrinking table {}, below {}% of its capacity for {} runs",
+                    table, shrink.below_percent, runs
+                );
+                metrics::count("shrinks", 1, &[("proto", proto), ("table", table.as_str())]);
+                (Mode::Replace, 0)
+            } else {
+                (mode, runs)
+            }
+        }
+        _ => (mode, 0),
+    };
+    let track_replaces = config.replace_cooldown.is_some() || !config.replace_windows.is_empty();
+    let mode = if track_replaces && !opts.initial_load {
+        let mut replace_state = state::read_replace_state(&config.state_dir, &table)
+            .await
+            .map_err(|e| StateError::Read("replace state", e))?;
+        let pending = replace_state.pending_since;
+        let mode = scheduled_mode(
+            config,
+            &table,
+            mode,
+            overflows,
+            &mut replace_state,
+            Utc::now(),
+        )?;
+        if replace_state.pending_since != pending && !opts.plan {
+            metrics::count(
+                "replaces_deferred",
+                1,
+                &[("proto", proto), ("table", table.as_str())],
+            );
+            state::write_replace_state(&config.state_dir, &table, &replace_state)
+                .await
+                .map_err(|e| StateError::Write("replace state", e))?;
+        }
+        mode
+    } else {
+        mode
+    };
+    if mode == Mode::Replace && !opts.initial_load && !config.allow_replace {
+        return Err(LimitError::Replace(table, estimated_params.to_string()).into());
+    }
+
+    // A replaced table starts over, so its scripts are numbered afresh.
+    let sequence = match mode {
+        Mode::Replace => SequenceState::default(),
+        Mode::Update => state::read_sequence(&config.state_dir, &table)
+            .await
+            .map_err(|e| StateError::Read("script sequence", e))?,
+    };
+    let tags = [("proto", proto), ("table", table.as_str())];
+    let (scripts, inserted, removed, unchanged) = match mode {
+        Mode::Replace => {
+            metrics::count("replaces", 1, &tags);
+            let table_params = replacement_params(config, proto, &estimated_params, &caps);
+            info!("replacing table {} with parameters {}", table, table_params);
+            let changes = Changes {
+                insert: new_ranges.iter().map(Deref::deref).collect(),
+                remove: vec![],
+            };
+            let (inserted, unchanged) = (changes.insert.len(), 0);
+            let vars = ReplaceModeVariables {
+                extra: &config.extra_vars.replace,
+                params: &table_params,
+                lpm_table: &table,
+                lpm_table_constructor: &lua_functions.lpm_table_constructor,
+                proto: proto,
+                kind,
+                policies: policies(&config.policies, new_ranges.iter().copied()),
+                overrides: &config.var_overrides,
+            };
+            let mut replace = config.replace.clone();
+            replace.templates.output = script_path(
+                config,
+                &replace_vars(&config.replace.templates.output, proto, kind),
+            );
+            let scripts = render_ordered(config, &replace, proto, changes, &vars, sequence).await?;
+            (scripts, inserted, 0, unchanged)
+        }
+        Mode::Update => {
+            metrics::count("updates", 1, &tags);
+            info!(
+                "updating table {} with parameters {}",
+                table, estimated_params,
+            );
+            render_update(
+                config, proto, kind, &table, new_ranges, old_ranges, sequence,
+            )
+            .await?
+        }
+    };
+    let num_scripts = scripts.len();
+    let (queued, bytes_sent, script_hashes) = if opts.plan {
+        (0, 0, hash_scripts(&scripts))
+    } else {
+        check_planned_scripts(opts, &table, &scripts)?;
+        let (queued, bytes_sent) = send_scripts(config, proto, &table, &scripts, sequence).await?;
+        (queued, bytes_sent, vec![])
+    };
+    if config.remove_rendered_scripts == RemovePolicy::Always && !opts.plan {
+        if let Some(path) = &script.path {
+            remove_script(path).await?;
+        }
+    }
+    if mode == Mode::Replace && track_replaces && !opts.plan {
+        let replace_state = state::ReplaceState {
+            time: Some(Utc::now()),
+            pending_since: None,
+        };
+        state::write_replace_state(&config.state_dir, &table, &replace_state)
+            .await
+            .map_err(|e| StateError::Write("replace state", e))?;
+    }
+
+    Ok(TableRun {
+        table,
+        proto: proto.to_owned(),
+        kind: kind.clone(),
+        mode,
+        num_rules: estimated_params.num_rules,
+        num_tbl8s: estimated_params.num_tbl8s,
+        current,
+        utilization,
+        limit_warnings: limit_warnings.iter().map(ToString::to_string).collect(),
+        overprovisioned_runs,
+        inserted,
+        removed,
+        scripts: num_scripts,
+        queued,
+        unchanged,
+        bytes_sent,
+        script_hashes,
+    })
+}
+
+// Applies the changes to a hash-based table. Such tables have no parameters
+// to estimate and are never replaced, so only insertions and removals are
+// sent, even on initial loads.
+async fn run_hash<T>(
+    config: &Config,
+    opts: RunOptions<'_>,
+    proto: &str,
+    kind: &Option<String>,
+    table: String,
+    new_ranges: &BTreeSet<&Entry<T>>,
+    old_ranges: &BTreeSet<&Entry<T>>,
+) -> Result<TableRun, error::Error>
+where
+    T: Ord + Serialize + Copy + Debug,
+{
+    // An initial load starts over, so its scripts are numbered afresh.
+    let sequence = if opts.initial_load {
+        SequenceState::default()
+    } else {
+        state::read_sequence(&config.state_dir, &table)
+            .await
+            .map_err(|e| StateError::Read("script sequence", e))?
+    };
+    let tags = [("proto", proto), ("table", table.as_str())];
+    metrics::count("updates", 1, &tags);
+    info!("updating hash table {}", table);
+    let (scripts, inserted, removed, unchanged) = render_update(
+        config, proto, kind, &table, new_ranges, old_ranges, sequence,
+    )
+    .await?;
+    let num_scripts = scripts.len();
+    let (queued, bytes_sent, script_hashes) = if opts.plan {
+        (0, 0, hash_scripts(&scripts))
+    } else {
+        check_planned_scripts(opts, &table, &scripts)?;
+        let (queued, bytes_sent) = send_scripts(config, proto, &table, &scripts, sequence).await?;
+        (queued, bytes_sent, vec![])
+    };
+
+    Ok(TableRun {
+        table,
+        proto: proto.to_owned(),
+        kind: kind.clone(),
+        mode: Mode::Update,
+        num_rules: new_ranges.len(),
+        num_tbl8s: 0,
+        current: vec![],
+        utilization: None,
+        limit_warnings: vec![],
+        overprovisioned_runs: 0,
+        inserted,
+        removed,
+        scripts: num_scripts,
+        queued,
+        unchanged,
+        bytes_sent,
+        script_hashes,
+    })
+}
+
+// Renders the update scripts inserting and removing the differences between
+// the old and new ranges, returning them along with the number of inserted,
+// removed and unchanged ranges.
+async fn render_update<T>(
+    config: &Config,
+    proto: &str,
+    kind: &Option<String>,
+    table: &str,
+    new_ranges: &BTreeSet<&Entry<T>>,
+    old_ranges: &BTreeSet<&Entry<T>>,
+    sequence: SequenceState,
+) -> Result<(Vec<PolicyScript>, usize, usize, usize), error::Error>
+where
+    T: Ord + Serialize + Copy + Debug,
+{
+    let insert = new_ranges - old_ranges;
+    let remove = old_ranges - new_ranges;
+    let changes = Changes {
+        insert: insert.into_iter().collect(),
+        remove: remove.into_iter().collect(),
+    };
+    let (inserted, removed) = (changes.insert.len(), changes.remove.len());
+    let unchanged = new_ranges.len() - inserted;
+    let vars = UpdateModeVariables {
+        extra: &config.extra_vars.update,
+        lpm_table: table,
+        proto: proto,
+        kind,
+        policies: policies(
+            &config.policies,
+            changes.insert.iter().chain(&changes.remove).copied(),
+        ),
+        overrides: &config.var_overrides,
+    };
+    let mut update = config.update.clone();
+    update.templates.output = script_path(
+        config,
+        &replace_vars(&config.update.templates.output, proto, kind),
+    );
+    let scripts = render_ordered(config, &update, proto, changes, &vars, sequence).await?;
+    Ok((scripts, inserted, removed, unchanged))
+}
+
+fn hash_scripts(scripts: &[PolicyScript]) -> Vec<String> {
+    scripts.iter().map(|s| state::hash_data(&s.data)).collect()
+}
+
+// Sends the rendered scripts of a table in order, queueing them from the
+// first one that fails to be sent if a retry queue is configured. Returns the
+// number of queued scripts and the number of bytes sent.
+async fn send_scripts(
+    config: &Config,
+    proto: &str,
+    table: &str,
+    scripts: &[PolicyScript],
+    sequence: SequenceState,
+) -> Result<(usize, u64), error::Error> {
+    let paths: Vec<&PathBuf> = scripts.iter().map(|s| &s.path).collect();
+    debug!("rendered scripts: {:?}", paths);
+    if config.durable && config.write_gtctl_scripts {
+        util::sync_files(&paths)
+            .await
+            .map_err(TemplateError::Sync)?;
+    }
+    let tags = [("proto", proto), ("table", table)];
+    let mut queued = 0;
+    let mut bytes_sent = 0;
+    for (i, script) in scripts.iter().enumerate() {
+        let start = Instant::now();
+        let size = script.data.len() as u64;
+        match dyncfg::send_config_bytes(
+            config.dyncfg_socket(),
+            &script.data,
+            &config.dyncfg_config(),
+        )
+        .await
+        {
+            Ok(_) => {
+                bytes_sent += size;
+                record_sequence(config, table, sequence, i + 1).await?;
+            }
+            // Failures to reach Gatekeeper are likely transient, e.g.
+            // Gatekeeper being restarted, so the script and the ones
+            // following it are kept for later instead of failing the run.
+            // Scripts that were sent may have been applied, so losing
+            // their replies fails the run instead.
+            Err(e) if e.is_retryable() && config.retry_queue.is_some() => {
+                warn!(
+                    "failed to send script '{}': {}; queueing {} scripts for table {}",
+                    script.path.display(),
+                    e,
+                    scripts.len() - i,
+                    table
+                );
+                enqueue(config, table, &scripts[i..]).await?;
+                // Queued scripts keep their sequence numbers, so the next
+                // run continues after them.
+                record_sequence(config, table, sequence, scripts.len()).await?;
+                queued = scripts.len() - i;
+                metrics::count("scripts_queued", queued as u64, &tags);
+                break;
+            }
+            Err(e) => return Err(e.in_script(&script.path, i)),
+        }
+        metrics::timing("script_send_time", start.elapsed(), &tags);
+        metrics::count("scripts_sent", 1, &tags);
+        if config.remove_rendered_scripts == RemovePolicy::Always && script.written {
+            remove_script(&script.path).await?;
+        }
+    }
+    Ok((queued, bytes_sent))
+}
+
+async fn remove_script(path: &Path) -> Result<(), TemplateError> {
+    fs::remove_file(path)
+        .await
+        .map_err(|e| TemplateError::Remove(path.to_owned(), e))
+}
+
+async fn enqueue(
+    config: &Config,
+    table: &str,
+    scripts: &[PolicyScript],
+) -> Result<(), error::Error> {
+    let queue = RetryQueue::open(config.state_dir.join(RETRY_QUEUE)).await?;
+    for script in scripts {
+        let entry = queue::Entry {
+            socket: config.dyncfg_socket().to_owned(),
+            table: table.to_owned(),
+            queued_at: Utc::now(),
+        };
+        queue
+            .push(&entry, &script.data)
+            .await
+            .map_err(|e| error::Error::from(e).in_script(&script.path, 0))?;
+        if config.remove_rendered_scripts == RemovePolicy::Always && script.written {
+            remove_script(&script.path).await?;
+        }
+    }
+    Ok(())
+}
+
+// Advances the sequence of a table past the scripts sent.
+async fn record_sequence(
+    config: &Config,
+    table: &str,
+    sequence: SequenceState,
+    sent: usize,
+) -> Result<(), error::Error> {
+    let sequence = SequenceState {
+        last: sequence.last + sent as u64,
+    };
+    state::write_sequence(&config.state_dir, table, sequence)
+        .await
+        .map_err(|e| StateError::Write("script sequence", e).into())
+}
+
+// Numbers a table's scripts, so that templates can refuse scripts applied
+// out of order or replayed. The script with index `i` has sequence number
+// `sequence_start + i` and follows the one numbered `previous_sequence + i`.
+#[derive(Debug, Serialize)]
+struct SequenceVariables<'a, V> {
+    sequence_start: u64,
+    previous_sequence: u64,
+    #[serde(flatten)]
+    vars: &'a V,
+}
+
+/// A policy script, rendered from the `replace` or `update` templates and
+/// sent from memory. Its output path names it in logs and error reports, but
+/// it's only written there if `write_gtctl_scripts` is set.
+#[derive(Debug)]
+pub struct PolicyScript {
+    pub data: Vec<u8>,
+    pub path: PathBuf,
+    pub written: bool,
+}
+
+// The ranges of a policy script, exposed to templates as the `ipv4` and
+// `ipv6` objects. The one of the protocol not being applied has none.
+#[derive(Debug, Serialize)]
+struct ChangeVariables<'a, T> {
+    insert: &'a [&'a Entry<T>],
+    remove: &'a [&'a Entry<T>],
+}
+
+#[derive(Debug, Serialize)]
+struct PolicyVariables<'a, T, V> {
+    ipv4: ChangeVariables<'a, T>,
+    ipv6: ChangeVariables<'a, T>,
+    script_index: usize,
+    is_first_script: bool,
+    is_last_script: bool,
+    #[serde(flatten)]
+    vars: &'a V,
+}
+
+// Renders changes in the configured order. Unless they are interleaved,
+// removals and insertions are rendered to separate scripts, told apart by
+// the `{phase}` variable of the output path, which is `all` otherwise.
+async fn render_ordered<'a, T: Serialize, V: Serialize>(
+    config: &Config,
+    templates: &ChunkedTemplates,
+    proto: &str,
+    changes: Changes<'a, T>,
+    vars: &V,
+    sequence: SequenceState,
+) -> Result<Vec<PolicyScript>, error::Error> {
+    let split = !changes.insert.is_empty() && !changes.remove.is_empty();
+    let phases = match config.change_order {
+        ChangeOrder::RemoveFirst if split => vec![
+            (
+                "remove",
+                Changes {
+                    insert: vec![],
+                    remove: changes.remove,
+                },
+            ),
+            (
+                "insert",
+                Changes {
+                    insert: changes.insert,
+                    remove: vec![],
+                },
+            ),
+        ],
+        ChangeOrder::InsertFirst if split => vec![
+            (
+                "insert",
+                Changes {
+                    insert: changes.insert,
+                    remove: vec![],
+                },
+            ),
+            (
+                "remove",
+                Changes {
+                    insert: vec![],
+                    remove: changes.remove,
+                },
+            ),
+        ],
+        _ => vec![("all", changes)],
+    };
+    let mut scripts: Vec<PolicyScript> = Vec::new();
+    for (phase, changes) in phases {
+        let mut templates = templates.clone();
+        templates.templates.output = templates.templates.output.replace("{phase}", phase);
+        let previous = sequence.last + scripts.len() as u64;
+        let vars = SequenceVariables {
+            sequence_start: previous + 1,
+            previous_sequence: previous,
+            vars,
+        };
+        scripts.extend(render_chunked(config, &templates, proto, changes, &vars).await?);
+    }
+    Ok(scripts)
+}
+
+/// Renders the changes into scripts of at most `max_ranges_per_file` ranges.
+/// If a size limit applies, the number of ranges per script is reduced until
+/// every script fits, assuming the size of a script grows linearly with its
+/// number of ranges. Scripts are rendered in memory, and only written once
+/// they fit.
+pub async fn render_chunked<'a, T: Serialize>(
+    config: &Config,
+    templates: &ChunkedTemplates,
+    proto: &str,
+    changes: Changes<'a, T>,
+    vars: &impl Serialize,
+) -> Result<Vec<PolicyScript>, error::Error> {
+    let input = &templates.templates.input;
+    let template = fs::read_to_string(input)
+        .await
+        .map_err(|e| TemplateError::Read(input.clone(), e))?;
+    let max_msg_len = usize::from(dyncfg::MAX_MSG_LEN);
+    let limit = match (config.max_script_size, templates.max_ranges_per_file) {
+        (Some(limit), _) => Some(min(limit, max_msg_len)),
+        (None, Some(MaxRanges::Auto)) => Some(max_msg_len),
+        (None, _) => None,
+    };
+    let mut max_ranges = match templates.max_ranges_per_file {
+        Some(MaxRanges::Count(n)) => Some(n),
+        Some(MaxRanges::Auto) => {
+            let limit = limit.unwrap_or(max_msg_len);
+            Some(auto_max_ranges(templates, &template, proto, &changes, vars, limit).await?)
+        }
+        None => None,
+    };
+    let num_ranges = changes.insert.len() + changes.remove.len();
+    let chunks = loop {
+        let chunks = render_policy(&template, proto, &changes, max_ranges, vars).await?;
+        let current = max_ranges.unwrap_or(num_ranges);
+        let limit = match limit {
+            Some(limit) if current > 1 => limit,
+            _ => {
+                check_script_sizes(templates, &chunks, current)?;
+                break chunks;
+            }
+        };
+        let largest = chunks.iter().map(Vec::len).max().unwrap_or(0);
+        if largest <= limit {
+            break chunks;
+        }
+        let next = min(current - 1, max(1, current * limit / largest));
+        debug!(
+            "largest script has {} bytes, over the {} bytes limit; splitting into {} ranges per script",
+            largest, limit, next
+        );
+        max_ranges = Some(next);
+    };
+    let mut scripts = Vec::new();
+    for (i, data) in chunks.into_iter().enumerate() {
+        let path = PathBuf::from(template::indexed_output(&templates.templates.output, i));
+        if config.write_gtctl_scripts {
+            template::write(&path, &data).await?;
+        }
+        scripts.push(PolicyScript {
+            data,
+            path,
+            written: config.write_gtctl_scripts,
+        });
+    }
+    Ok(scripts)
+}
+
+// Renders the changes into scripts of at most `max_ranges` ranges each, or
+// a single script if not given. Removals come before insertions, and a
+// script is rendered even if there are no changes, e.g. for a replace with
+// no ranges.
+async fn render_policy<'a, T: Serialize>(
+    template: &str,
+    proto: &str,
+    changes: &Changes<'a, T>,
+    max_ranges: Option<usize>,
+    vars: &impl Serialize,
+) -> Result<Vec<Vec<u8>>, TemplateError> {
+    let num_removed = changes.remove.len();
+    let num_ranges = num_removed + changes.insert.len();
+    let per_script = max(1, max_ranges.unwrap_or(num_ranges));
+    let num_scripts = max(1, (num_ranges + per_script - 1) / per_script);
+    let mut scripts = Vec::new();
+    for i in 0..num_scripts {
+        let start = i * per_script;
+        let end = min(num_ranges, start + per_script);
+        let ranges = ChangeVariables {
+            insert: &changes.insert
+                [start.saturating_sub(num_removed)..end.saturating_sub(num_removed)],
+            remove: &changes.remove[min(start, num_removed)..min(end, num_removed)],
+        };
+        let none = ChangeVariables {
+            insert: &[],
+            remove: &[],
+        };
+        let (ipv4, ipv6) = match proto {
+            "ipv4" => (ranges, none),
+            _ => (none, ranges),
+        };
+        let vars = PolicyVariables {
+            ipv4,
+            ipv6,
+            script_index: i,
+            is_first_script: i == 0,
+            is_last_script: i == num_scripts - 1,
+            vars,
+        };
+        scripts.push(template::render(template, &vars, None).await?.data);
+    }
+    Ok(scripts)
+}
+
+// Fails before anything is sent if a script doesn't fit in a dynamic
+// configuration message, since a run would otherwise stop halfway through
+// a table.
+fn check_script_sizes(
+    templates: &ChunkedTemplates,
+    scripts: &[Vec<u8>],
+    max_ranges: usize,
+) -> Result<(), error::Error> {
+    let max_msg_len = usize::from(dyncfg::MAX_MSG_LEN);
+    for (i, script) in scripts.iter().enumerate() {
+        let size = script.len();
+        if size <= max_msg_len {
+            continue;
+        }
+        let suggested = max(1, max_ranges * max_msg_len / size);
+        return Err(TemplateError::Oversized(Oversized {
+            script: template::indexed_output(&templates.templates.output, i),
+            input: templates.templates.input.clone(),
+            chunk: i,
+            chunks: scripts.len(),
+            size,
+            limit: max_msg_len,
+            suggested,
+        })
+        .into());
+    }
+    Ok(())
+}
+
+const AUTO_CHUNK_SAMPLE: usize = 100;
+
+// Picks the largest number of ranges per script that stays under the
+// limit, by rendering the template without any ranges and with a sample of
+// them to measure the fixed and per-range sizes.
+async fn auto_max_ranges<'a, T: Serialize>(
+    templates: &ChunkedTemplates,
+    template: &str,
+    proto: &str,
+    changes: &Changes<'a, T>,
+    vars: &impl Serialize,
+    limit: usize,
+) -> Result<usize, error::Error> {
+    let num_insert = min(changes.insert.len(), AUTO_CHUNK_SAMPLE);
+    let num_remove = min(changes.remove.len(), AUTO_CHUNK_SAMPLE - num_insert);
+    let sample_len = num_insert + num_remove;
+    if sample_len == 0 {
+        return Ok(1);
+    }
+
+    let mut sizes = Vec::new();
+    for &(insert, remove) in &[(0, 0), (num_insert, num_remove)] {
+        let sample = Changes {
+            insert: changes.insert[..insert].to_vec(),
+            remove: changes.remove[..remove].to_vec(),
+        };
+        let scripts = render_policy(template, proto, &sample, None, vars).await?;
+        sizes.push(scripts.iter().map(Vec::len).max().unwrap_or(0));
+    }
+
+    let (fixed, sample) = (sizes[0], sizes[1]);
+    let per_range = max(
+        1,
+        (sample.saturating_sub(fixed) + sample_len - 1) / sample_len,
+    );
+    let max_ranges = max(1, limit.saturating_sub(fixed) / per_range);
+    debug!(
+        "template '{}' renders {} fixed bytes and {} bytes per range; using {} ranges per script",
+        templates.templates.input.display(),
+        fixed,
+        per_range,
+        max_ranges
+    );
+    Ok(max_ranges)
+}
+
+/// A table is replaced if the update would take it above the threshold
+/// percentage of its capacity, judging by its reported usage or, if that is
+/// not available, by the estimated parameters.
+pub fn run_mode<T>(
+    cur: &CurrentParams<T>,
+    est: &Params<T>,
+    update: &Update,
+    threshold_percent: usize,
+) -> Mode {
+    if cur.fits_update(est, update, threshold_percent) {
+        Mode::Update
+    } else {
+        Mode::Replace
+    }
+}
+
+// Sizes the table replacing one with the estimated parameters. The replace
+// headroom and rounding only affect the size of the new table, not the
+// decision to replace it, and never go beyond the caps or DPDK's limits. The
+// table is also made large enough for the estimate to be within the replace
+// threshold, so that it isn't replaced again by the next run.
+fn replacement_params<T>(
+    config: &Config,
+    proto: &str,
+    estimated: &Params<T>,
+    caps: &Limits,
+) -> Params<T> {
+    estimated
+        .grown(config.estimate.replace_headroom_percent)
+        .at_least(&estimated.within(config.replace_threshold_percent))
+        .rounded_for(proto, &config.estimate)
+        .truncated(caps)
+}
+
+// Applies the replace windows and cooldown to the mode chosen for a table.
+// A replace outside the windows is deferred, recording the pending intent so
+// that the table is replaced in the next window, and within the cooldown
+// after a replace the table is updated instead. In both cases a table that
+// can't fit the update at all fails the run.
+fn scheduled_mode(
+    config: &Config,
+    table: &str,
+    mode: Mode,
+    overflows: bool,
+    replace_state: &mut ReplaceState,
+    now: DateTime<Utc>,
+) -> Result<Mode, error::Error> {
+    let in_window = in_replace_window(&config.replace_windows, now);
+    let mode = match (mode, replace_state.pending_since) {
+        (Mode::Update, Some(since)) if in_window && config.allow_replace => {
+            info!("replacing table {}, deferred since {}", table, since);
+            Mode::Replace
+        }
+        _ => mode,
+    };
+    if mode == Mode::Update {
+        return Ok(mode);
+    }
+    if !in_window {
+        if overflows {
+            return Err(LimitError::Window(table.to_owned()).into());
+        }
+        let since = *replace_state.pending_since.get_or_insert(now);
+        info!(
+            "deferring the replace of table {} to the next replace window, pending since {}",
+            table, since
+        );
+        return Ok(Mode::Update);
+    }
+    let (cooldown, last_replace) = match (config.replace_cooldown, replace_state.time) {
+        (Some(cooldown), Some(time)) => (Duration::from_secs(cooldown), time),
+        _ => return Ok(mode),
+    };
+    let elapsed = (now - last_replace).to_std().unwrap_or_default();
+    if elapsed >= cooldown {
+        return Ok(mode);
+    }
+    let remaining = (cooldown - elapsed).as_secs();
+    if overflows {
+        return Err(LimitError::Cooldown(table.to_owned(), elapsed.as_secs(), remaining).into());
+    }
+    info!(
+        "table {} was replaced {}s ago, updating it instead for the next {}s",
+        table,
+        elapsed.as_secs(),
+        remaining
+    );
+    Ok(Mode::Update)
+}
+
+// Counts the consecutive runs, this one included, in which the table was
+// below the shrink threshold in every instance, carrying the count over from
+// the previous run.
+async fn overprovisioned_runs(
+    config: &Config,
+    shrink: &ShrinkConfig,
+    table: &str,
+    utilization: &Option<Utilization>,
+) -> usize {
+    let below = shrink.below_percent as f64;
+    match utilization {
+        Some(u) if u.rules_percent < below && u.tbl8s_percent < below => {}
+        _ => return 0,
+    }
+    let previous = match state::read_last_run(&config.state_dir).await {
+        Ok(last_run) => last_run
+            .and_then(|run| run.tables.into_iter().find(|t| t.table == table))
+            .map(|t| t.overprovisioned_runs)
+            .unwrap_or(0),
+        Err(e) => {
+            warn!("failed to read last run, restarting shrink count: {}", e);
+            0
+        }
+    };
+    previous + 1
+}
+
+async fn read_usage<'a>(
+    config: &Config,
+    usage_script: &Templates,
+    proto: &str,
+    kind: &Option<String>,
+    vars: &ParametersScriptVariables<'a>,
+) -> Result<Vec<Usage>, error::Error> {
+    let mut templates = usage_script.clone();
+    templates.output = script_path(config, &templates.output);
+    let script = render_parameters_script(config, &templates, proto, kind, vars).await?;
+    let usage = params::read_usage(
+        config.dyncfg_socket(),
+        &script.data,
+        &config.lpm,
+        &config.dyncfg_config(),
+    )
+    .await?;
+    debug!("current usage: {:?}", usage);
+    Ok(usage)
+}
+
+async fn render_parameters_script<'a>(
+    config: &Config,
+    templates: &Templates,
+    proto: &str,
+    kind: &Option<String>,
+    vars: &ParametersScriptVariables<'a>,
+) -> Result<Rendered, error::Error> {
+    let output = PathBuf::from(replace_vars(&templates.output, proto, kind));
+    let output = Some(output.as_path()).filter(|_| config.write_gtctl_scripts);
+    Ok(template::render_file(&templates.input, vars, output).await?)
+}
+
+/// Renders a template, writing the output only if `write_gtctl_scripts` is
+/// set.
+pub async fn render_template(
+    config: &Config,
+    template: &str,
+    vars: &impl Serialize,
+    output: &Path,
+) -> Result<Rendered, error::Error> {
+    let output = Some(output).filter(|_| config.write_gtctl_scripts);
+    Ok(template::render(template, vars, output).await?)
+}
+
+/// Replaces the `{proto}` and `{kind}` placeholders of a name.
+pub fn replace_vars(s: &str, proto: &str, kind: &Option<String>) -> String {
+    s.replace("{proto}", proto)
+        .replace("{kind}", kind.as_deref().unwrap_or(""))
+}
+
+/// Strings are shown without the quotes of their JSON representation.
+pub fn display_value(value: &impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        Ok(value) => value.to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{BpfProgram, RetryQueueConfig};
+    use crate::mock::{params_reply, MockServer};
+    use crate::params::TableParams;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // A minimal configuration, extended with the given settings.
+    fn test_config(extra: &str) -> Config {
+        let yaml = format!(
+            "{}{}",
+            r#"
+state_dir: /var/lib/gtctl
+replace: { input: replace.tpl, output: replace.lua }
+update: { input: update.tpl, output: update.lua }
+lpm: {
+  table_format: "{kind}_lpm_{proto}",
+  parameters_script: { input: params.tpl, output: params.lua },
+  ipv4: { lpm_table_constructor: new_lpm, lpm_get_params_function: get_paras },
+  ipv6: { lpm_table_constructor: new_lpm6, lpm_get_params_function: get6_paras },
+}
+"#,
+            extra
+        );
+        serde_yaml::from_str(&yaml).expect("invalid config")
+    }
+
+    // A scratch configuration extended with the given settings, rendering
+    // the given replace and update templates, and a mock Gatekeeper
+    // reporting tables large enough for anything.
+    async fn template_config(
+        extra: &str,
+        replace: &str,
+        update: &str,
+    ) -> (TempDir, Config, MockServer) {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let mut config = test_config(extra);
+        scratch_config(&mut config, tmp.path());
+        for (name, text) in &[
+            ("params.tpl", "params {{lpm_table}}"),
+            ("replace.tpl", replace),
+            ("update.tpl", update),
+        ] {
+            fs::write(tmp.path().join(name), text)
+                .await
+                .expect("write failed");
+        }
+        config.lpm.parameters_script.input = tmp.path().join("params.tpl");
+        config.replace.templates.input = tmp.path().join("replace.tpl");
+        config.update.templates.input = tmp.path().join("update.tpl");
+        create_dirs(&config).await.expect("create failed");
+        let large = u32::MAX as usize;
+        let server = MockServer::start(&config.socket, params_reply(&config, large, large))
+            .await
+            .expect("start failed");
+        (tmp, config, server)
+    }
+
+    #[test]
+    fn test_check_bpf_indices() {
+        let mut bpf: BpfConfig = serde_yaml::from_str(
+            r#"{
+              input: /etc/gtctl/bpf.lua.tpl,
+              output: "bpf_{program}.lua",
+              programs: {
+                granted: { path: /etc/gtctl/bpf/granted.bpf, index: 0 },
+                tcp-services: { path: /etc/gtctl/bpf/tcp-services.bpf, index: 1 },
+              },
+            }"#,
+        )
+        .expect("deserialize failed");
+        assert!(check_bpf_indices(&bpf).is_ok());
+
+        bpf.programs.get_mut("tcp-services").unwrap().index = 0;
+        let e = check_bpf_indices(&bpf).unwrap_err();
+        assert_eq!(
+            "bpf programs granted and tcp-services have the same index 0",
+            e.to_string()
+        );
+    }
+
+    #[test]
+    fn test_run_mode() {
+        let current = |tables: &[(usize, usize)]| -> CurrentParams<()> {
+            CurrentParams::new(
+                tables
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (r, t))| TableParams::new(i as u64, *r, *t))
+                    .collect(),
+            )
+        };
+
+        let none = Update::default();
+        let cur = current(&[(10, 10), (20, 10)]);
+        let est = Params::new(15, 15);
+        assert_eq!(Mode::Replace, run_mode(&cur, &est, &none, 100));
+
+        let cur = current(&[(20, 20), (15, 10)]);
+        let est = Params::new(15, 15);
+        assert_eq!(Mode::Replace, run_mode(&cur, &est, &none, 100));
+
+        let cur = current(&[(20, 20), (15, 15)]);
+        let est = Params::new(15, 15);
+        assert_eq!(Mode::Update, run_mode(&cur, &est, &none, 100));
+
+        // Fewer tbl8s than estimated, even with more rules.
+        let cur = current(&[(20, 10)]);
+        let est = Params::new(15, 15);
+        assert_eq!(Mode::Replace, run_mode(&cur, &est, &none, 100));
+
+        // With reported usage, the table is replaced only when the update
+        // doesn't fit, whatever the estimate.
+        let cur: CurrentParams<()> =
+            CurrentParams::new(vec![TableParams::new(0, 20, 20).with_usage(19, 2)]);
+        let est = Params::new(30, 30);
+        let update = |removed, inserted, inserted_tbl8s| Update {
+            removed,
+            inserted,
+            inserted_tbl8s,
+        };
+        assert_eq!(Mode::Update, run_mode(&cur, &est, &update(1, 2, 18), 100));
+        assert_eq!(Mode::Replace, run_mode(&cur, &est, &update(0, 2, 0), 100));
+        assert_eq!(Mode::Replace, run_mode(&cur, &est, &update(1, 2, 19), 100));
+
+        // Replaced before it is full.
+        let cur = current(&[(20, 20)]);
+        let est = Params::new(18, 18);
+        assert_eq!(Mode::Update, run_mode(&cur, &est, &none, 90));
+        let est = Params::new(19, 18);
+        assert_eq!(Mode::Replace, run_mode(&cur, &est, &none, 90));
+    }
+
+    #[test]
+    fn test_replacement_params() {
+        let config = test_config("replace_threshold_percent: 80\n");
+        let caps = Limits::from(config.limits.caps("ipv4"));
+        let est = Params::<()>::new(1000, 100);
+        let table = replacement_params(&config, "ipv4", &est, &caps);
+
+        // The next run finds the estimate within the threshold.
+        let cur = CurrentParams::new(vec![TableParams::new(0, table.num_rules, table.num_tbl8s)]);
+        assert_eq!(Mode::Update, run_mode(&cur, &est, &Update::default(), 80));
+
+        // A larger replace headroom still applies.
+        let config = test_config(
+            "replace_threshold_percent: 80\nestimate: { replace_headroom_percent: 50 }\n",
+        );
+        let table = replacement_params(&config, "ipv4", &est, &caps);
+        assert_eq!(Params::new(1500, 150), table);
+    }
+
+    #[test]
+    fn test_scheduled_mode() {
+        let config = test_config(
+            r#"
+replace_cooldown: 3600
+replace_windows: [{ start: "02:00", end: "04:00" }]
+"#,
+        );
+        let at = |s: &str| -> DateTime<Utc> { s.parse().expect("invalid time") };
+        let outside = at("2021-03-01T12:00:00Z");
+        let inside = at("2021-03-02T03:00:00Z");
+        let mode = |mode, overflows, state: &mut ReplaceState, now| {
+            scheduled_mode(&config, "t", mode, overflows, state, now)
+        };
+
+        // Deferred outside the window, recording the intent.
+        let mut state = ReplaceState::default();
+        let m = mode(Mode::Replace, false, &mut state, outside).expect("mode failed");
+        assert_eq!(Mode::Update, m);
+        assert_eq!(Some(outside), state.pending_since);
+        assert!(mode(Mode::Replace, true, &mut state, outside).is_err());
+
+        // The pending replace happens in the window.
+        let m = mode(Mode::Update, false, &mut state, inside).expect("mode failed");
+        assert_eq!(Mode::Replace, m);
+
+        // Within the cooldown, updated if possible.
+        let mut state = ReplaceState {
+            time: Some(at("2021-03-02T02:30:00Z")),
+            pending_since: None,
+        };
+        let m = mode(Mode::Replace, false, &mut state, inside).expect("mode failed");
+        assert_eq!(Mode::Update, m);
+        assert!(mode(Mode::Replace, true, &mut state, inside).is_err());
+        let later = at("2021-03-02T03:30:00Z");
+        let m = mode(Mode::Replace, false, &mut state, later).expect("mode failed");
+        assert_eq!(Mode::Replace, m);
+        assert_eq!(None, state.pending_since);
+    }
+
+    #[tokio::test]
+    async fn test_deploy_bpf_programs() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let mut config = test_config("");
+        scratch_config(&mut config, tmp.path());
+        config.write_gtctl_scripts = false;
+        fs::create_dir_all(&config.state_dir)
+            .await
+            .expect("create failed");
+        let template = tmp.path().join("bpf.tpl");
+        fs::write(
+            &template,
+            "{% if replace %}unload {{index}}; {% endif %}load {{program}} at {{index}}",
+        )
+        .await
+        .expect("write failed");
+        let program = tmp.path().join("granted.bpf");
+        let bpf = BpfConfig {
+            input: template,
+            output: "bpf_{program}.lua".to_owned(),
+            success_reply: "loaded BPF program".to_owned(),
+            programs: iter::once((
+                "granted".to_owned(),
+                BpfProgram {
+                    path: program.clone(),
+                    index: 1,
+                },
+            ))
+            .collect(),
+        };
+        let deploy = || deploy_bpf_programs(&config, &bpf, RunOptions::default());
+        let read_state = || state::read_bpf_state(&config.state_dir);
+
+        let server = MockServer::start(&config.socket, "loaded BPF program granted\n".to_owned())
+            .await
+            .expect("start failed");
+        fs::write(&program, "v1").await.expect("write failed");
+        deploy().await.expect("deploy failed");
+        assert_eq!(vec!["load granted at 1"], server.received());
+        let state = read_state().await.expect("read failed");
+        let v1 = state::hash_file(&program).await.expect("hash failed");
+        assert_eq!(Some(&v1), state.programs.get("granted"));
+        assert_eq!(Some("granted"), state.indices.get(&1).map(String::as_str));
+
+        // Unchanged programs aren't uploaded again.
+        deploy().await.expect("deploy failed");
+        assert_eq!(1, server.received().len());
+
+        // Changed ones replace the program at their index.
+        fs::write(&program, "v2").await.expect("write failed");
+        deploy().await.expect("deploy failed");
+        assert_eq!(
+            vec!["load granted at 1", "unload 1; load granted at 1"],
+            server.received()
+        );
+        let v2 = state::hash_file(&program).await.expect("hash failed");
+        assert_eq!(
+            Some(&v2),
+            read_state()
+                .await
+                .expect("read failed")
+                .programs
+                .get("granted")
+        );
+
+        // A failed upload isn't recorded, so it's retried by the next run.
+        drop(server);
+        let server = MockServer::start(&config.socket, "failed to load\n".to_owned())
+            .await
+            .expect("start failed");
+        fs::write(&program, "v3").await.expect("write failed");
+        assert!(deploy().await.is_err());
+        assert_eq!(1, server.received().len());
+        assert_eq!(
+            Some(&v2),
+            read_state()
+                .await
+                .expect("read failed")
+                .programs
+                .get("granted")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_policy_scripts_in_memory() {
+        let (tmp, mut config, server) = template_config(
+            "",
+            "replace {{script_index}}:{% for e in ipv4.insert %} {{e.range}}{% endfor %}",
+            "update",
+        )
+        .await;
+        config.replace.max_ranges_per_file = Some(MaxRanges::Count(1));
+        // Nothing can be written under a regular file, even by root.
+        let file = tmp.path().join("file");
+        fs::write(&file, "").await.expect("write failed");
+        config.replace.templates.output = file.join("replace.{i}.lua").display().to_string();
+        config.write_gtctl_scripts = false;
+
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 192.0.2.0/24, kind: blocked, class: drop }
+- { range: 198.51.100.0/24, kind: blocked, class: drop }
+"#,
+        )
+        .expect("deserialize failed");
+        let kind = Some("blocked".to_owned());
+        let new: BTreeSet<_> = entries.iter().collect();
+        let kinds = vec![KindSummary::new(&config, "ipv4", &kind, entries.len())];
+        // Initial loads don't read the parameters, so only policy scripts
+        // are sent.
+        let opts = RunOptions {
+            initial_load: true,
+            ..Default::default()
+        };
+
+        let run = run_ipv4(&config, opts, &kinds, &kind, &new, &BTreeSet::new())
+            .await
+            .expect("run failed");
+        assert_eq!(Mode::Replace, run.mode);
+        assert_eq!(2, run.scripts);
+        assert_eq!(
+            vec!["replace 0: 192.0.2.0/24", "replace 1: 198.51.100.0/24"],
+            server.received()
+        );
+
+        config.write_gtctl_scripts = true;
+        assert!(
+            run_ipv4(&config, opts, &kinds, &kind, &new, &BTreeSet::new())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_entry_metadata() {
+        let script =
+            "{{kind}}:{% for e in ipv4.insert %} {{e.range}}={{e.kind}}/{{e.class}}{% endfor %}";
+        let (_tmp, config, server) = template_config("", script, script).await;
+
+        // A table merging ranges of different kinds and classes.
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 192.0.2.0/24, kind: scanners, class: scan }
+- { range: 198.51.100.0/24, kind: spammers, class: drop }
+"#,
+        )
+        .expect("deserialize failed");
+        let kind = Some("blocked".to_owned());
+        let new: BTreeSet<_> = entries.iter().collect();
+        let kinds = vec![KindSummary::new(&config, "ipv4", &kind, entries.len())];
+
+        let opts = RunOptions::default();
+        run_ipv4(&config, opts, &kinds, &kind, &new, &BTreeSet::new())
+            .await
+            .expect("run failed");
+        assert_eq!(
+            Some(&"blocked: 192.0.2.0/24=scanners/scan 198.51.100.0/24=spammers/drop".to_owned()),
+            server.received().last()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sequence_reset() {
+        let (_tmp, config, server) = template_config(
+            "",
+            "replace {{sequence_start}} {{previous_sequence}}",
+            "update {{sequence_start}} {{previous_sequence}}",
+        )
+        .await;
+
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 192.0.2.0/24, kind: scanners, class: scan }
+- { range: 198.51.100.0/24, kind: scanners, class: scan }
+"#,
+        )
+        .expect("deserialize failed");
+        let kind = Some("scanners".to_owned());
+        let (new, older) = (fixture_tables(&entries), fixture_tables(&entries[..1]));
+        let kinds = vec![KindSummary::new(&config, "ipv4", &kind, entries.len())];
+        let table = "scanners_lpm_ipv4";
+        state::write_sequence(&config.state_dir, table, SequenceState { last: 7 })
+            .await
+            .expect("write failed");
+
+        // A replace, here an initial load, starts the numbering over.
+        let opts = RunOptions {
+            initial_load: true,
+            ..Default::default()
+        };
+        let run = run_ipv4(
+            &config,
+            opts,
+            &kinds,
+            &kind,
+            &older[&kind],
+            &BTreeSet::new(),
+        )
+        .await
+        .expect("run failed");
+        assert_eq!(Mode::Replace, run.mode);
+        assert_eq!(Some(&"replace 1 0".to_owned()), server.received().last());
+        let sequence = state::read_sequence(&config.state_dir, table)
+            .await
+            .expect("read failed");
+        assert_eq!(1, sequence.last);
+
+        // Updates continue from it.
+        let opts = RunOptions::default();
+        let run = run_ipv4(&config, opts, &kinds, &kind, &new[&kind], &older[&kind])
+            .await
+            .expect("run failed");
+        assert_eq!(Mode::Update, run.mode);
+        assert_eq!(Some(&"update 2 1".to_owned()), server.received().last());
+    }
+
+    #[tokio::test]
+    async fn test_send_scripts_queueing() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let mut config = test_config("");
+        scratch_config(&mut config, tmp.path());
+        config.retry_queue = Some(RetryQueueConfig { max_backoff: 300 });
+        create_dirs(&config).await.expect("create failed");
+        let scripts = vec![PolicyScript {
+            data: b"update".to_vec(),
+            path: tmp.path().join("update.lua"),
+            written: false,
+        }];
+        let queue = RetryQueue::open(config.state_dir.join(RETRY_QUEUE))
+            .await
+            .expect("open failed");
+
+        // Gatekeeper can't be reached, so the script is kept for later.
+        let (queued, _) = send_scripts(&config, "ipv4", "t", &scripts, SequenceState::default())
+            .await
+            .expect("send failed");
+        assert_eq!(1, queued);
+        assert_eq!(1, queue.clear().await.expect("clear failed"));
+
+        // The script was sent, but its reply was lost, so it may have been
+        // applied and isn't sent again.
+        let listener = tokio::net::UnixListener::bind(&config.socket).expect("bind failed");
+        tokio::spawn(async move {
+            use tokio::io::AsyncReadExt;
+
+            let (mut stream, _addr) = listener.accept().await.expect("accept failed");
+            let mut buf = [0u8; 64];
+            let _ = stream.read(&mut buf).await;
+        });
+        let res = send_scripts(&config, "ipv4", "t", &scripts, SequenceState::default()).await;
+        assert!(res.is_err());
+        assert!(queue.is_empty().await.expect("is_empty failed"));
+    }
+
+    #[tokio::test]
+    async fn test_hash_table_mode() {
+        let (_tmp, config, server) = template_config(
+            r#"
+kinds: { scanners: { table_mode: hash } }
+"#,
+            "replace {{lpm_table}}",
+            "update {{lpm_table}}",
+        )
+        .await;
+
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 192.0.2.0/24, kind: scanners, class: scan }
+- { range: 198.51.100.0/24, kind: scanners, class: scan }
+"#,
+        )
+        .expect("deserialize failed");
+        let kind = Some("scanners".to_owned());
+        let (new, newer) = (fixture_tables(&entries), fixture_tables(&entries[..1]));
+        let kinds = vec![KindSummary::new(&config, "ipv4", &kind, entries.len())];
+
+        // Even an initial load only inserts the ranges.
+        let opts = RunOptions {
+            initial_load: true,
+            ..Default::default()
+        };
+        let run = run_ipv4(&config, opts, &kinds, &kind, &new[&kind], &BTreeSet::new())
+            .await
+            .expect("run failed");
+        assert_eq!(Mode::Update, run.mode);
+        assert_eq!(2, run.inserted);
+        assert_eq!(vec!["update scanners_lpm_ipv4"], server.received());
+
+        let opts = RunOptions::default();
+        let run = run_ipv4(&config, opts, &kinds, &kind, &newer[&kind], &new[&kind])
+            .await
+            .expect("run failed");
+        assert_eq!(Mode::Update, run.mode);
+        assert_eq!(1, run.removed);
+        assert_eq!(
+            vec!["update scanners_lpm_ipv4", "update scanners_lpm_ipv4"],
+            server.received()
+        );
+    }
+
+    #[test]
+    fn test_script_path() {
+        let mut config = test_config(
+            r#"
+scripts_dir: /var/lib/gtctl/scripts
+per_run_scripts: true
+"#,
+        );
+        config.replace.templates.output = "replace_{proto}_{kind}.lua".to_owned();
+        config.update.templates.output = "/tmp/update_{proto}_{kind}.lua".to_owned();
+
+        assert_eq!(
+            "/var/lib/gtctl/scripts/params.lua",
+            script_path(&config, "params.lua")
+        );
+        assert_eq!(
+            vec![
+                "/var/lib/gtctl/scripts/{run}/params.lua",
+                "/var/lib/gtctl/scripts/{run}/replace_{proto}_{kind}.lua",
+                "/tmp/{run}/update_{proto}_{kind}.lua",
+            ],
+            script_patterns(&config)
+        );
+
+        config.run_id = Some("20210302T141007.000Z-42".to_owned());
+        assert_eq!(
+            "/var/lib/gtctl/scripts/20210302T141007.000Z-42/params.lua",
+            script_path(&config, "params.lua")
+        );
+        assert_eq!(
+            "/tmp/20210302T141007.000Z-42/update.lua",
+            script_path(&config, "/tmp/update.lua")
+        );
+
+        config.state_namespace = Some("feed1".to_owned());
+        assert_eq!(
+            "/var/lib/gtctl/scripts/feed1/20210302T141007.000Z-42/params.lua",
+            script_path(&config, "params.lua")
+        );
+    }
+
+    #[test]
+    fn test_policies() {
+        let rules: Vec<PolicyRule> = serde_yaml::from_str(
+            r#"
+- { kind: blocklist, class: scan, vars: { policy_id: 3, bpf_index: 2 } }
+- { class: drop, vars: { policy_id: 2 } }
+"#,
+        )
+        .expect("deserialize failed");
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 192.0.2.0/24, kind: blocklist, class: scan }
+- { range: 192.0.2.0/25, kind: blocklist, class: drop }
+- { range: 198.51.100.0/24, kind: other, class: drop }
+- { range: 203.0.113.0/24, kind: other, class: scan }
+"#,
+        )
+        .expect("deserialize failed");
+        let policies = policies(&rules, entries.iter());
+        let keys: Vec<_> = policies.keys().map(String::as_str).collect();
+        assert_eq!(vec!["blocklist/drop", "blocklist/scan", "other/drop"], keys);
+        assert_eq!(
+            Some(&serde_yaml::Value::from(2)),
+            policies["blocklist/scan"].get("bpf_index")
+        );
+        assert_eq!(
+            Some(&serde_yaml::Value::from(2)),
+            policies["other/drop"].get("policy_id")
+        );
+    }
+
+    #[test]
+    fn test_route_tables() {
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 192.0.2.0/24, kind: blocklist, class: drop }
+- { range: 198.51.100.0/24, kind: allowlist, class: pass }
+- { range: 203.0.113.0/24, class: pass }
+"#,
+        )
+        .expect("deserialize failed");
+        let mut tables = fixture_tables(&entries);
+        let kinds: BTreeSet<String> = vec!["allowlist".to_owned(), "other".to_owned()]
+            .into_iter()
+            .collect();
+        route_tables(&mut tables, &kinds);
+        let routed: Vec<_> = tables.keys().cloned().collect();
+        assert_eq!(vec![Some("allowlist".to_owned())], routed);
+    }
+
+    #[test]
+    fn test_unrouted_tables() {
+        let entries: Vec<Entry<Ipv4Net>> = serde_yaml::from_str(
+            r#"
+- { range: 192.0.2.0/24, kind: blocklist, class: drop }
+- { range: 198.51.100.0/24, kind: allowlist, class: pass }
+- { range: 203.0.113.0/24, kind: other, class: pass }
+"#,
+        )
+        .expect("deserialize failed");
+        let old = fixture_tables(&entries);
+        let mut new = fixture_tables(&entries[..1]);
+        let mut config = test_config("");
+        assert!(unrouted_tables(&config, &new, Some(&old)).is_empty());
+
+        config.target_kinds = Some(vec!["blocklist".to_owned()].into_iter().collect());
+        route_tables(&mut new, config.target_kinds.as_ref().unwrap());
+        assert!(unrouted_tables(&config, &new, None).is_empty());
+        // Kinds still routed to the target are left alone even if they are
+        // gone from the aggregate.
+        let other = Some("other".to_owned());
+        config
+            .target_kinds
+            .as_mut()
+            .unwrap()
+            .insert("other".to_owned());
+        assert_eq!(
+            vec![Some("allowlist".to_owned())],
+            unrouted_tables(&config, &new, Some(&old))
+        );
+        config.target_kinds.as_mut().unwrap().remove("other");
+        assert_eq!(
+            vec![Some("allowlist".to_owned()), other],
+            unrouted_tables(&config, &new, Some(&old))
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use rustyline::{error::ReadlineError, Editor};
+use tokio::task;
+
+use crate::config::{DyncfgConfig, ReplyDecoding};
+use crate::dyncfg;
+
+const PROMPT: &str = "gt> ";
+const CONTINUATION_PROMPT: &str = "..> ";
+
+/// An interactive shell sending each snippet entered to Gatekeeper's
+/// dynamic configuration socket and printing the replies. A line ending
+/// with a backslash continues the snippet on the next line.
+pub struct Shell {
+    socket: PathBuf,
+    history: Option<PathBuf>,
+    config: DyncfgConfig,
+    editor: Editor<()>,
+}
+
+impl Shell {
+    pub fn new(socket: impl AsRef<Path>, history: Option<PathBuf>, config: DyncfgConfig) -> Shell {
+        let mut editor = Editor::<()>::new();
+        if let Some(path) = &history {
+            // There's no history the first time the shell is run.
+            let _ = editor.load_history(path);
+        }
+        Shell {
+            socket: socket.as_ref().to_owned(),
+            history,
+            config,
+            editor,
+        }
+    }
+
+    /// Runs the shell until the end of its input.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            let editor = &mut self.editor;
+            let snippet = match task::block_in_place(|| read_snippet(editor))? {
+                Some(snippet) => snippet,
+                None => break,
+            };
+            if snippet.trim().is_empty() {
+                continue;
+            }
+            self.editor.add_history_entry(snippet.as_str());
+            match dyncfg::exchange_with(&self.socket, snippet.as_bytes(), &self.config).await {
+                Ok(reply) if self.config.reply_decoding == ReplyDecoding::Raw => {
+                    let mut stdout = std::io::stdout();
+                    if let Err(e) = stdout.write_all(&reply).and_then(|()| stdout.flush()) {
+                        eprintln!("error: {}", e);
+                    }
+                }
+                Ok(reply) => match dyncfg::decode(reply, &self.config) {
+                    Ok(reply) => println!("{}", reply.trim_end()),
+                    Err(e) => eprintln!("error: {}", e),
+                },
+                Err(e) => eprintln!("error: {}", e),
+            }
+        }
+        if let Some(path) = &self.history {
+            if let Err(e) = self.editor.save_history(path) {
+                warn!("failed to save history to '{}': {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads lines up to a complete snippet, returning None at the end of the
+// input. An interrupt discards the snippet being entered.
+fn read_snippet(editor: &mut Editor<()>) -> Result<Option<String>, ReadlineError> {
+    let mut snippet = String::new();
+    loop {
+        let prompt = if snippet.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if push_line(&mut snippet, &line) {
+                    return Ok(Some(snippet));
+                }
+            }
+            Err(ReadlineError::Interrupted) => snippet.clear(),
+            Err(ReadlineError::Eof) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Appends a line to the snippet, returning whether the snippet is complete.
+fn push_line(snippet: &mut String, line: &str) -> bool {
+    match line.strip_suffix('\\') {
+        Some(line) => {
+            snippet.push_str(line);
+            snippet.push('\n');
+            false
+        }
+        None => {
+            snippet.push_str(line);
+            true
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Readline(ReadlineError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Readline(e) => write!(f, "readline error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Readline(e) => Some(e),
+        }
+    }
+}
+
+impl From<ReadlineError> for Error {
+    fn from(e: ReadlineError) -> Error {
+        Error::Readline(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_line() {
+        let mut snippet = String::new();
+        assert!(!push_line(
+            &mut snippet,
+            "local t = lpmlib.new_lpm(10, 2)\\"
+        ));
+        assert!(!push_line(&mut snippet, "\\"));
+        assert!(push_line(&mut snippet, "return 'ok'"));
+        assert_eq!("local t = lpmlib.new_lpm(10, 2)\n\nreturn 'ok'", snippet);
+
+        let mut snippet = String::new();
+        assert!(push_line(&mut snippet, "return 1"));
+        assert_eq!("return 1", snippet);
+    }
+}
@@ -0,0 +1,741 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io;
+
+use crate::params::{TableParams, Utilization};
+use crate::util::safe_write;
+
+pub const LAST_RUN: &str = "last_run.json";
+pub const FAILURE: &str = "failure.json";
+pub const FAILURE_METRICS: &str = "gtctl_failure.prom";
+pub const PROGRESS: &str = "progress.json";
+pub const HISTORY: &str = "history";
+pub const REPLACES: &str = "replaces";
+pub const APPLIED: &str = "applied.json";
+pub const SEQUENCES: &str = "sequences";
+pub const BPF: &str = "bpf.json";
+pub const FIB_PROGRESS: &str = "fib_progress.json";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Replace,
+    Update,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Replace => write!(f, "replace"),
+            Mode::Update => write!(f, "update"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Success => write!(f, "success"),
+            Outcome::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+/// A summary of the last processed aggregate, written to `state_dir` after
+/// every run so that monitoring can check for staleness and failures.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LastRun {
+    pub serial: u64,
+    pub aggregate: PathBuf,
+    pub aggregate_hash: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    pub tables: Vec<TableRun>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableRun {
+    pub table: String,
+    pub proto: String,
+    pub kind: Option<String>,
+    pub mode: Mode,
+    pub num_rules: usize,
+    pub num_tbl8s: usize,
+    // The parameters reported by each Gatekeeper instance before the run,
+    // empty on initial loads.
+    #[serde(default)]
+    pub current: Vec<TableParams>,
+    #[serde(default)]
+    pub utilization: Option<Utilization>,
+    #[serde(default)]
+    pub limit_warnings: Vec<String>,
+    // Consecutive runs, up to this one, in which the table was below the
+    // shrink threshold.
+    #[serde(default)]
+    pub overprovisioned_runs: usize,
+    pub inserted: usize,
+    pub removed: usize,
+    pub scripts: usize,
+    #[serde(default)]
+    pub queued: usize,
+    #[serde(default)]
+    pub unchanged: usize,
+    #[serde(default)]
+    pub bytes_sent: u64,
+    // The SHA-256 digests of the rendered scripts, only recorded by plans.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub script_hashes: Vec<String>,
+}
+
+/// Details of a failed run. The presence of this file in `state_dir` flags
+/// that the last run failed; it is removed once a run succeeds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Failure {
+    pub time: DateTime<Utc>,
+    pub aggregate: PathBuf,
+    pub error: String,
+    pub causes: Vec<String>,
+}
+
+impl Failure {
+    pub fn new(aggregate: &Path, error: &(dyn std::error::Error + 'static)) -> Failure {
+        let mut causes = Vec::new();
+        let mut source = error.source();
+        while let Some(e) = source {
+            causes.push(e.to_string());
+            source = e.source();
+        }
+        Failure {
+            time: Utc::now(),
+            aggregate: aggregate.to_owned(),
+            error: error.to_string(),
+            causes,
+        }
+    }
+
+    /// Formats the failure as metrics in the Prometheus text format, for
+    /// node-exporter's textfile collector.
+    pub fn to_prometheus(&self) -> String {
+        let aggregate = self.aggregate.display().to_string();
+        let aggregate = aggregate
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        format!(
+            "# HELP gtctl_last_run_failed Whether the last run failed.\n\
+             # TYPE gtctl_last_run_failed gauge\n\
+             gtctl_last_run_failed{{aggregate=\"{}\"}} 1\n\
+             # HELP gtctl_last_failure_timestamp_seconds The time of the last failure.\n\
+             # TYPE gtctl_last_failure_timestamp_seconds gauge\n\
+             gtctl_last_failure_timestamp_seconds {}\n",
+            aggregate,
+            self.time.timestamp()
+        )
+    }
+}
+
+/// The tables already applied from the current aggregate, which is
+/// identified by its hash. If a run is interrupted, this allows the next
+/// run to resume from the first table that wasn't completed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Progress {
+    pub aggregate_hash: String,
+    pub completed: BTreeSet<String>,
+}
+
+impl Progress {
+    pub fn new(aggregate_hash: String) -> Progress {
+        Progress {
+            aggregate_hash,
+            completed: BTreeSet::new(),
+        }
+    }
+}
+
+/// The last aggregate applied in full, identified by its hash, so that runs
+/// presenting the same file again can be skipped. The hash of each table's
+/// ranges is also kept, so that tables left unchanged by a new aggregate
+/// can be skipped, along with the kinds routed to the target, if
+/// restricted, so that tables of kinds routed away from it can be emptied.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Applied {
+    pub aggregate_hash: String,
+    pub applied_at: DateTime<Utc>,
+    #[serde(default)]
+    pub tables: BTreeMap<String, String>,
+    #[serde(default)]
+    pub routed_kinds: Option<BTreeSet<String>>,
+}
+
+pub async fn read_applied(state_dir: impl AsRef<Path>) -> Result<Option<Applied>, Error> {
+    read_json(state_dir.as_ref().join(APPLIED)).await
+}
+
+pub async fn write_applied(state_dir: impl AsRef<Path>, applied: &Applied) -> Result<(), Error> {
+    write_json(state_dir.as_ref().join(APPLIED), applied).await
+}
+
+/// Writes the failure sentinel, both as JSON and as Prometheus metrics.
+pub async fn write_failure(state_dir: impl AsRef<Path>, failure: &Failure) -> Result<(), Error> {
+    let state_dir = state_dir.as_ref();
+    write_json(state_dir.join(FAILURE), failure).await?;
+    let metrics = failure.to_prometheus();
+    safe_write(state_dir.join(FAILURE_METRICS), metrics.as_bytes()).await?;
+    Ok(())
+}
+
+pub async fn clear_failure(state_dir: impl AsRef<Path>) -> Result<(), Error> {
+    let state_dir = state_dir.as_ref();
+    remove(state_dir.join(FAILURE)).await?;
+    remove(state_dir.join(FAILURE_METRICS)).await
+}
+
+pub async fn read_last_run(state_dir: impl AsRef<Path>) -> Result<Option<LastRun>, Error> {
+    read_json(state_dir.as_ref().join(LAST_RUN)).await
+}
+
+pub async fn write_last_run(state_dir: impl AsRef<Path>, last_run: &LastRun) -> Result<(), Error> {
+    write_json(state_dir.as_ref().join(LAST_RUN), last_run).await
+}
+
+/// Adds a run to the journal kept in the `history` subdirectory of
+/// `state_dir`, removing the oldest runs so that at most `keep` are kept.
+pub async fn write_history(
+    state_dir: impl AsRef<Path>,
+    last_run: &LastRun,
+    keep: usize,
+) -> Result<(), Error> {
+    let dir = state_dir.as_ref().join(HISTORY);
+    fs::create_dir_all(&dir).await?;
+    write_json(dir.join(history_file(last_run.serial)), last_run).await?;
+    let serials = history_serials(&dir).await?;
+    for serial in &serials[..serials.len().saturating_sub(keep)] {
+        remove(dir.join(history_file(*serial))).await?;
+    }
+    Ok(())
+}
+
+/// Returns the journaled runs, oldest first.
+pub async fn read_history(state_dir: impl AsRef<Path>) -> Result<Vec<LastRun>, Error> {
+    let dir = state_dir.as_ref().join(HISTORY);
+    let mut runs = Vec::new();
+    for serial in history_serials(&dir).await? {
+        if let Some(run) = read_json(dir.join(history_file(serial))).await? {
+            runs.push(run);
+        }
+    }
+    Ok(runs)
+}
+
+pub async fn read_history_entry(
+    state_dir: impl AsRef<Path>,
+    serial: u64,
+) -> Result<Option<LastRun>, Error> {
+    let dir = state_dir.as_ref().join(HISTORY);
+    read_json(dir.join(history_file(serial))).await
+}
+
+fn history_file(serial: u64) -> String {
+    format!("{:020}.json", serial)
+}
+
+async fn history_serials(dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut serials = Vec::new();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(serials),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let serial = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(|serial| serial.parse().ok());
+        if let Some(serial) = serial {
+            serials.push(serial);
+        }
+    }
+    serials.sort_unstable();
+    Ok(serials)
+}
+
+/// Replace state of a table, kept in a per-table file in the `replaces`
+/// subdirectory of the state directory.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplaceState {
+    /// The time of the table's last replace.
+    #[serde(default)]
+    pub time: Option<DateTime<Utc>>,
+    /// When a replace deferred to a maintenance window was first requested.
+    #[serde(default)]
+    pub pending_since: Option<DateTime<Utc>>,
+}
+
+pub async fn read_replace_state(
+    state_dir: impl AsRef<Path>,
+    table: &str,
+) -> Result<ReplaceState, Error> {
+    let path = replace_state_path(state_dir.as_ref(), table);
+    Ok(read_json(path).await?.unwrap_or_default())
+}
+
+pub async fn write_replace_state(
+    state_dir: impl AsRef<Path>,
+    table: &str,
+    replace_state: &ReplaceState,
+) -> Result<(), Error> {
+    fs::create_dir_all(state_dir.as_ref().join(REPLACES)).await?;
+    write_json(replace_state_path(state_dir.as_ref(), table), replace_state).await
+}
+
+fn replace_state_path(state_dir: &Path, table: &str) -> PathBuf {
+    state_dir.join(REPLACES).join(format!("{}.json", table))
+}
+
+/// The sequence number of the last script sent to a table, kept in a
+/// per-table file in the `sequences` subdirectory of the state directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SequenceState {
+    pub last: u64,
+}
+
+pub async fn read_sequence(
+    state_dir: impl AsRef<Path>,
+    table: &str,
+) -> Result<SequenceState, Error> {
+    let path = sequence_path(state_dir.as_ref(), table);
+    Ok(read_json(path).await?.unwrap_or_default())
+}
+
+pub async fn write_sequence(
+    state_dir: impl AsRef<Path>,
+    table: &str,
+    sequence: SequenceState,
+) -> Result<(), Error> {
+    fs::create_dir_all(state_dir.as_ref().join(SEQUENCES)).await?;
+    write_json(sequence_path(state_dir.as_ref(), table), &sequence).await
+}
+
+fn sequence_path(state_dir: &Path, table: &str) -> PathBuf {
+    state_dir.join(SEQUENCES).join(format!("{}.json", table))
+}
+
+/// The SHA-256 digests of the BPF programs as last uploaded, by program name,
+/// and the program last uploaded at each index.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BpfState {
+    pub programs: BTreeMap<String, String>,
+    #[serde(default)]
+    pub indices: BTreeMap<u8, String>,
+}
+
+pub async fn read_bpf_state(state_dir: impl AsRef<Path>) -> Result<BpfState, Error> {
+    Ok(read_json(state_dir.as_ref().join(BPF))
+        .await?
+        .unwrap_or_default())
+}
+
+pub async fn write_bpf_state(state_dir: impl AsRef<Path>, state: &BpfState) -> Result<(), Error> {
+    write_json(state_dir.as_ref().join(BPF), state).await
+}
+
+/// The number of scripts of a FIB update already sent, identified by the
+/// hash of its rendered scripts. If the update is interrupted, this allows
+/// the next one to resume from the first script that wasn't sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FibProgress {
+    pub scripts_hash: String,
+    pub sent: usize,
+}
+
+pub async fn read_fib_progress(state_dir: impl AsRef<Path>) -> Result<Option<FibProgress>, Error> {
+    read_json(state_dir.as_ref().join(FIB_PROGRESS)).await
+}
+
+pub async fn write_fib_progress(
+    state_dir: impl AsRef<Path>,
+    progress: &FibProgress,
+) -> Result<(), Error> {
+    write_json(state_dir.as_ref().join(FIB_PROGRESS), progress).await
+}
+
+pub async fn clear_fib_progress(state_dir: impl AsRef<Path>) -> Result<(), Error> {
+    remove(state_dir.as_ref().join(FIB_PROGRESS)).await
+}
+
+pub async fn read_progress(state_dir: impl AsRef<Path>) -> Result<Option<Progress>, Error> {
+    read_json(state_dir.as_ref().join(PROGRESS)).await
+}
+
+pub async fn write_progress(state_dir: impl AsRef<Path>, progress: &Progress) -> Result<(), Error> {
+    write_json(state_dir.as_ref().join(PROGRESS), progress).await
+}
+
+pub async fn clear_progress(state_dir: impl AsRef<Path>) -> Result<(), Error> {
+    remove(state_dir.as_ref().join(PROGRESS)).await
+}
+
+async fn read_json<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<Option<T>, Error> {
+    let data = match fs::read(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+async fn write_json<T: Serialize>(path: impl AsRef<Path>, value: &T) -> Result<(), Error> {
+    let data = serde_json::to_vec_pretty(value)?;
+    safe_write(path, &data).await?;
+    Ok(())
+}
+
+async fn remove(path: impl AsRef<Path>) -> Result<(), Error> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the hex-encoded SHA-256 digest of the file's contents.
+pub async fn hash_file(path: impl AsRef<Path>) -> Result<String, io::Error> {
+    let data = fs::read(path).await?;
+    Ok(hash_data(&data))
+}
+
+/// Returns the hex-encoded SHA-256 digest of the data.
+pub fn hash_data(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Returns the hex-encoded SHA-256 digest of the lines, in order.
+pub fn hash_lines<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> String {
+    let mut hasher = Sha256::new();
+    for line in lines {
+        hasher.update(line.as_ref());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Json(e) => write!(f, "json error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replace_state() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let table = "blocklist_lpm_ipv4";
+        let state = read_replace_state(tmp.path(), table)
+            .await
+            .expect("read failed");
+        assert_eq!(ReplaceState::default(), state);
+
+        let state = ReplaceState {
+            time: Some(Utc::now()),
+            pending_since: None,
+        };
+        write_replace_state(tmp.path(), table, &state)
+            .await
+            .expect("write failed");
+        let read = read_replace_state(tmp.path(), table)
+            .await
+            .expect("read failed");
+        assert_eq!(state, read);
+        let other = read_replace_state(tmp.path(), "other")
+            .await
+            .expect("read failed");
+        assert_eq!(ReplaceState::default(), other);
+    }
+
+    #[tokio::test]
+    async fn test_sequence() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let table = "blocklist_lpm_ipv4";
+        let sequence = read_sequence(tmp.path(), table).await.expect("read failed");
+        assert_eq!(0, sequence.last);
+
+        write_sequence(tmp.path(), table, SequenceState { last: 7 })
+            .await
+            .expect("write failed");
+        let read = read_sequence(tmp.path(), table).await.expect("read failed");
+        assert_eq!(7, read.last);
+        let other = read_sequence(tmp.path(), "other")
+            .await
+            .expect("read failed");
+        assert_eq!(0, other.last);
+    }
+
+    #[tokio::test]
+    async fn test_bpf_state() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let state = read_bpf_state(tmp.path()).await.expect("read failed");
+        assert!(state.programs.is_empty());
+
+        let mut state = BpfState::default();
+        state
+            .programs
+            .insert("tcp-services".to_owned(), "abc".to_owned());
+        state.indices.insert(2, "tcp-services".to_owned());
+        write_bpf_state(tmp.path(), &state)
+            .await
+            .expect("write failed");
+        let read = read_bpf_state(tmp.path()).await.expect("read failed");
+        assert_eq!(state, read);
+    }
+
+    #[tokio::test]
+    async fn test_fib_progress() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let progress = read_fib_progress(tmp.path()).await.expect("read failed");
+        assert!(progress.is_none());
+
+        let progress = FibProgress {
+            scripts_hash: "abc".to_owned(),
+            sent: 2,
+        };
+        write_fib_progress(tmp.path(), &progress)
+            .await
+            .expect("write failed");
+        let read = read_fib_progress(tmp.path()).await.expect("read failed");
+        assert_eq!(Some(progress), read);
+
+        clear_fib_progress(tmp.path()).await.expect("clear failed");
+        let progress = read_fib_progress(tmp.path()).await.expect("read failed");
+        assert!(progress.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_last_run() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        assert!(read_last_run(tmp.path())
+            .await
+            .expect("read failed")
+            .is_none());
+
+        let now = Utc::now();
+        let last_run = LastRun {
+            serial: 7,
+            aggregate: PathBuf::from("/tmp/aggregate"),
+            aggregate_hash: "abc".to_owned(),
+            started_at: now,
+            finished_at: now,
+            outcome: Outcome::Success,
+            error: None,
+            tables: vec![TableRun {
+                table: "blocklist_lpm_ipv4".to_owned(),
+                proto: "ipv4".to_owned(),
+                kind: Some("blocklist".to_owned()),
+                mode: Mode::Update,
+                num_rules: 10,
+                num_tbl8s: 2,
+                current: vec![TableParams::new(0, 20, 4).with_usage(9, 2)],
+                utilization: Some(Utilization {
+                    rules_percent: 50.0,
+                    tbl8s_percent: 50.0,
+                }),
+                limit_warnings: vec![],
+                overprovisioned_runs: 0,
+                inserted: 3,
+                removed: 1,
+                scripts: 1,
+                queued: 0,
+                unchanged: 6,
+                bytes_sent: 512,
+                script_hashes: vec![],
+            }],
+        };
+        write_last_run(tmp.path(), &last_run)
+            .await
+            .expect("write failed");
+
+        let read = read_last_run(tmp.path())
+            .await
+            .expect("read failed")
+            .expect("last run not found");
+        assert_eq!(7, read.serial);
+        assert_eq!(Outcome::Success, read.outcome);
+        assert_eq!(1, read.tables.len());
+        assert_eq!(Mode::Update, read.tables[0].mode);
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        assert!(read_history(tmp.path())
+            .await
+            .expect("read failed")
+            .is_empty());
+
+        let now = Utc::now();
+        for serial in 1..=5 {
+            let last_run = LastRun {
+                serial,
+                aggregate: PathBuf::from("/tmp/aggregate"),
+                aggregate_hash: "abc".to_owned(),
+                started_at: now,
+                finished_at: now,
+                outcome: Outcome::Success,
+                error: None,
+                tables: vec![],
+            };
+            write_history(tmp.path(), &last_run, 3)
+                .await
+                .expect("write failed");
+        }
+
+        let history = read_history(tmp.path()).await.expect("read failed");
+        let serials: Vec<_> = history.iter().map(|r| r.serial).collect();
+        assert_eq!(vec![3, 4, 5], serials);
+        assert!(read_history_entry(tmp.path(), 2)
+            .await
+            .expect("read failed")
+            .is_none());
+        assert!(read_history_entry(tmp.path(), 4)
+            .await
+            .expect("read failed")
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failure() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let path = tmp.path().join(FAILURE);
+
+        let error = anyhow::anyhow!("connection refused").context("failed to send script");
+        let failure = Failure::new(Path::new("/tmp/aggregate"), error.as_ref());
+        assert_eq!("failed to send script", failure.error);
+        assert_eq!(vec!["connection refused".to_owned()], failure.causes);
+
+        write_failure(tmp.path(), &failure)
+            .await
+            .expect("write failed");
+        assert!(path.exists());
+        let metrics =
+            std::fs::read_to_string(tmp.path().join(FAILURE_METRICS)).expect("read metrics failed");
+        assert!(metrics.contains("gtctl_last_run_failed{aggregate=\"/tmp/aggregate\"} 1\n"));
+        let timestamp = format!(
+            "gtctl_last_failure_timestamp_seconds {}\n",
+            failure.time.timestamp()
+        );
+        assert!(metrics.contains(&timestamp));
+
+        clear_failure(tmp.path()).await.expect("clear failed");
+        assert!(!path.exists());
+        assert!(!tmp.path().join(FAILURE_METRICS).exists());
+        clear_failure(tmp.path()).await.expect("clear failed");
+    }
+
+    #[tokio::test]
+    async fn test_applied() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        assert!(read_applied(tmp.path())
+            .await
+            .expect("read failed")
+            .is_none());
+
+        let applied = Applied {
+            aggregate_hash: "abc".to_owned(),
+            applied_at: Utc::now(),
+            tables: vec![("ipv4/".to_owned(), "def".to_owned())]
+                .into_iter()
+                .collect(),
+            routed_kinds: None,
+        };
+        write_applied(tmp.path(), &applied)
+            .await
+            .expect("write failed");
+        let read = read_applied(tmp.path())
+            .await
+            .expect("read failed")
+            .expect("no applied aggregate");
+        assert_eq!("abc", read.aggregate_hash);
+        assert_eq!(Some("def"), read.tables.get("ipv4/").map(String::as_str));
+
+        // Records written before table hashes were kept are still read.
+        std::fs::write(
+            tmp.path().join(APPLIED),
+            r#"{"aggregate_hash":"abc","applied_at":"2021-01-01T00:00:00Z"}"#,
+        )
+        .expect("write failed");
+        let read = read_applied(tmp.path())
+            .await
+            .expect("read failed")
+            .expect("no applied aggregate");
+        assert!(read.tables.is_empty());
+    }
+
+    #[test]
+    fn test_hash_lines() {
+        assert_eq!(hash_lines(vec!["a", "b"]), hash_lines(vec!["a", "b"]));
+        assert_ne!(hash_lines(vec!["a", "b"]), hash_lines(vec!["b", "a"]));
+        assert_ne!(hash_lines(vec!["ab"]), hash_lines(vec!["a", "b"]));
+    }
+
+    #[tokio::test]
+    async fn test_hash_file() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let path = tmp.path().join("aggregate");
+        std::fs::write(&path, b"abc").expect("write failed");
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            hash_file(&path).await.expect("hash failed")
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::Serialize;
+use tera::{Context, Tera};
+use tokio::fs;
+
+use crate::error::TemplateError;
+use crate::util::safe_write;
+
+/// A rendered script. It's sent from memory, and written to its output path
+/// only for inspection.
+#[derive(Debug)]
+pub struct Rendered {
+    pub data: Vec<u8>,
+    pub path: Option<PathBuf>,
+}
+
+/// Renders a template file, writing the result to `output` if given.
+pub async fn render_file(
+    input: &Path,
+    vars: &impl Serialize,
+    output: Option<&Path>,
+) -> Result<Rendered, TemplateError> {
+    let template = fs::read_to_string(input)
+        .await
+        .map_err(|e| TemplateError::Read(input.to_owned(), e))?;
+    render(&template, vars, output).await
+}
+
+/// Renders a template, writing the result to `output` if given.
+pub async fn render(
+    template: &str,
+    vars: &impl Serialize,
+    output: Option<&Path>,
+) -> Result<Rendered, TemplateError> {
+    let mut tera = Tera::default();
+    let context = Context::from_serialize(vars)?;
+    let data = tera.render_str(template, &context)?.into_bytes();
+    let output = match output {
+        Some(output) => output,
+        None => return Ok(Rendered { data, path: None }),
+    };
+    write(output, &data).await?;
+    Ok(Rendered {
+        data,
+        path: Some(output.to_owned()),
+    })
+}
+
+/// Writes a rendered script to its output path.
+pub async fn write(output: &Path, data: &[u8]) -> Result<(), TemplateError> {
+    // Per-run script directories are created on demand.
+    let write_err = |e| TemplateError::Write(output.to_owned(), e);
+    if let Some(dir) = output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o750)
+            .create(dir)
+            .await
+            .map_err(write_err)?;
+    }
+    safe_write(output, data).await.map_err(write_err)
+}
+
+/// Replaces the `{i}` placeholder of an output path with the index of a
+/// script. Given as `{Ni}`, the index is padded with zeros to `N` digits.
+pub fn indexed_output(output: &str, i: usize) -> String {
+    lazy_static! {
+        static ref INDEX: Regex = Regex::new(r"\{(\d*)i\}").expect("BUG: invalid index regex");
+    }
+    INDEX
+        .replace_all(output, |caps: &Captures| {
+            let width = caps[1].parse().unwrap_or(0);
+            format!("{:0width$}", i, width = width)
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let vars = serde_json::json!({"lpm_table": "blocklist"});
+        let output = tmp.path().join("scripts").join("params.lua");
+        let rendered = render("return {{ lpm_table }}", &vars, Some(&output))
+            .await
+            .expect("render failed");
+        assert_eq!(b"return blocklist".to_vec(), rendered.data);
+        assert_eq!(Some(output.clone()), rendered.path);
+        assert_eq!(
+            b"return blocklist".to_vec(),
+            std::fs::read(&output).unwrap()
+        );
+
+        let rendered = render("{{ lpm_table }}", &vars, None)
+            .await
+            .expect("render failed");
+        assert_eq!(None, rendered.path);
+
+        let e = render("{{ missing }}", &vars, None).await.unwrap_err();
+        assert!(matches!(e, TemplateError::Render(_)));
+        let e = render_file(&tmp.path().join("missing.tpl"), &vars, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, TemplateError::Read(..)));
+    }
+
+    #[test]
+    fn test_indexed_output() {
+        assert_eq!("update.3.lua", indexed_output("update.{i}.lua", 3));
+        assert_eq!("update.03.lua", indexed_output("update.{2i}.lua", 3));
+        assert_eq!("update.123.lua", indexed_output("update.{2i}.lua", 123));
+        assert_eq!(
+            "{proto}/update.lua",
+            indexed_output("{proto}/update.lua", 3)
+        );
+    }
+}
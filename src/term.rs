@@ -0,0 +1,137 @@
+use std::env;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Style {
+    Red,
+    Green,
+    Yellow,
+}
+
+/// Enables colored output if standard output is a terminal, unless the
+/// `NO_COLOR` environment variable is set.
+pub fn init() {
+    let enabled = atty::is(atty::Stream::Stdout) && env::var_os("NO_COLOR").is_none();
+    set_color(enabled);
+}
+
+pub fn set_color(enabled: bool) {
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_color_enabled() -> bool {
+    COLOR.load(Ordering::Relaxed)
+}
+
+/// Wraps the text in the escape sequences for the style, if colors are
+/// enabled. Padding must be applied beforehand, as the escape sequences
+/// would otherwise be counted in the text width.
+pub fn paint(text: &str, style: Style) -> String {
+    paint_if(is_color_enabled(), text, style)
+}
+
+fn paint_if(enabled: bool, text: &str, style: Style) -> String {
+    if !enabled {
+        return text.to_owned();
+    }
+    let code = match style {
+        Style::Red => 31,
+        Style::Green => 32,
+        Style::Yellow => 33,
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+/// The changes to a single table, with ranges and classes already formatted
+/// for display.
+#[derive(Debug, Default)]
+pub struct TableDiff {
+    pub proto: String,
+    pub kind: Option<String>,
+    pub insert: Vec<(String, String)>,
+    pub remove: Vec<(String, String)>,
+    pub unchanged: usize,
+}
+
+impl TableDiff {
+    /// Returns whether any range would be inserted or removed.
+    pub fn has_changes(&self) -> bool {
+        !self.insert.is_empty() || !self.remove.is_empty()
+    }
+}
+
+/// Renders diffs as one section per table, headed by its counts and
+/// followed, unless `summary` is set, by the removed and inserted ranges in
+/// aligned columns.
+pub fn render_diff(diffs: &[TableDiff], summary: bool) -> String {
+    let mut out = String::new();
+    for diff in diffs {
+        let _ = writeln!(
+            out,
+            "{} {}: {} inserted, {} removed, {} unchanged",
+            diff.proto,
+            diff.kind.as_deref().unwrap_or("-"),
+            diff.insert.len(),
+            diff.remove.len(),
+            diff.unchanged
+        );
+        if summary {
+            continue;
+        }
+        let width = diff
+            .insert
+            .iter()
+            .chain(&diff.remove)
+            .map(|(range, _)| range.len())
+            .max()
+            .unwrap_or(0);
+        let rows = diff
+            .remove
+            .iter()
+            .map(|row| ('-', Style::Red, row))
+            .chain(diff.insert.iter().map(|row| ('+', Style::Green, row)));
+        for (marker, style, (range, class)) in rows {
+            let line = format!("{} {:<width$}  {}", marker, range, class, width = width);
+            let _ = writeln!(out, "  {}", paint(&line, style));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_diff() {
+        let diffs = vec![TableDiff {
+            proto: "ipv4".to_owned(),
+            kind: Some("blocklist".to_owned()),
+            insert: vec![("192.0.2.0/24".to_owned(), "1".to_owned())],
+            remove: vec![("10.0.0.0/8".to_owned(), "2".to_owned())],
+            unchanged: 5,
+        }];
+        assert_eq!(
+            "ipv4 blocklist: 1 inserted, 1 removed, 5 unchanged\n\
+             \x20 - 10.0.0.0/8    2\n\
+             \x20 + 192.0.2.0/24  1\n",
+            render_diff(&diffs, false)
+        );
+        assert_eq!(
+            "ipv4 blocklist: 1 inserted, 1 removed, 5 unchanged\n",
+            render_diff(&diffs, true)
+        );
+    }
+
+    #[test]
+    fn test_paint() {
+        assert_eq!("replace", paint_if(false, "replace", Style::Yellow));
+        assert_eq!(
+            "\x1b[33mreplace\x1b[0m",
+            paint_if(true, "replace", Style::Yellow)
+        );
+    }
+}
@@ -1,10 +1,26 @@
-use std::path::Path;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use tokio::fs::{self, File};
 use tokio::io::{self, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+
+static DURABLE: AtomicBool = AtomicBool::new(false);
+
+/// Makes `safe_write` also sync the parent directory of the written file,
+/// so that the file itself, and not only its contents, survives a crash.
+pub fn set_durable(durable: bool) {
+    DURABLE.store(durable, Ordering::Relaxed);
+}
+
+pub fn is_durable() -> bool {
+    DURABLE.load(Ordering::Relaxed)
+}
 
 pub async fn safe_write(path: impl AsRef<Path>, buf: &[u8]) -> Result<(), io::Error> {
-    let tmp = format!("{}.tmp", path.as_ref().display());
+    let tmp = tmp_path(&path);
 
     let mut file = File::create(&tmp).await?;
     file.write_all(buf).await?;
@@ -13,5 +29,129 @@ pub async fn safe_write(path: impl AsRef<Path>, buf: &[u8]) -> Result<(), io::Er
 
     fs::rename(&tmp, &path).await?;
 
+    if is_durable() {
+        sync_parent(&path).await?;
+    }
+
+    Ok(())
+}
+
+/// Syncs files written by other means, along with their parent directories.
+pub async fn sync_files(paths: &[impl AsRef<Path>]) -> Result<(), io::Error> {
+    for path in paths {
+        File::open(path).await?.sync_all().await?;
+        sync_parent(path).await?;
+    }
     Ok(())
 }
+
+/// Copies `from` to `to` through a temporary file that is synced before
+/// being renamed into place, so `to` is never seen partially written, even
+/// after a crash.
+pub async fn safe_copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+    let tmp = tmp_path(&to);
+
+    fs::copy(&from, &tmp).await?;
+    File::open(&tmp).await?.sync_all().await?;
+    fs::rename(&tmp, &to).await?;
+
+    sync_parent(&to).await
+}
+
+/// Renames `from` to `to`, syncing the parent directory of `to` so that the
+/// rename survives a crash.
+pub async fn safe_rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+    fs::rename(&from, &to).await?;
+    sync_parent(&to).await
+}
+
+pub async fn sync_parent(path: impl AsRef<Path>) -> Result<(), io::Error> {
+    let dir = match path.as_ref().parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir).await?.sync_all().await
+}
+
+/// Listens on a Unix socket accessible with the given permissions. A
+/// socket left behind by a previous process is replaced, but one that is
+/// still being served is not.
+pub async fn bind_unix(path: impl AsRef<Path>, mode: u32) -> Result<UnixListener, io::Error> {
+    let path = path.as_ref();
+    match UnixStream::connect(path).await {
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "socket is in use by another process",
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path).await?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(mode)).await?;
+    Ok(listener)
+}
+
+fn tmp_path(path: impl AsRef<Path>) -> PathBuf {
+    PathBuf::from(format!("{}.tmp", path.as_ref().display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_safe_copy() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let from = tmp.path().join("from");
+        let to = tmp.path().join("to");
+
+        fs::write(&from, b"new").await.expect("write failed");
+        fs::write(&to, b"old").await.expect("write failed");
+
+        safe_copy(&from, &to).await.expect("copy failed");
+        assert_eq!(b"new".to_vec(), fs::read(&to).await.expect("read failed"));
+        assert!(!tmp_path(&to).exists());
+
+        let renamed = tmp.path().join("renamed");
+        safe_rename(&to, &renamed).await.expect("rename failed");
+        assert!(!to.exists());
+        assert_eq!(
+            b"new".to_vec(),
+            fs::read(&renamed).await.expect("read failed")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let tmp = TempDir::new().expect("tempdir failed");
+        let path = tmp.path().join("socket");
+
+        let listener = bind_unix(&path, 0o600).await.expect("bind failed");
+        let mode = fs::metadata(&path)
+            .await
+            .expect("metadata failed")
+            .permissions()
+            .mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        // A socket still being served isn't taken over.
+        let err = bind_unix(&path, 0o600).await.expect_err("bind succeeded");
+        assert_eq!(io::ErrorKind::AddrInUse, err.kind());
+
+        // A stale one is replaced.
+        drop(listener);
+        assert!(path.exists());
+        bind_unix(&path, 0o660).await.expect("bind failed");
+        let mode = fs::metadata(&path)
+            .await
+            .expect("metadata failed")
+            .permissions()
+            .mode();
+        assert_eq!(0o660, mode & 0o777);
+    }
+}
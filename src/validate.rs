@@ -0,0 +1,408 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use drib::aggregate::Entry;
+use ipnet::{Ipv4Net, Ipv6Net};
+use lazy_static::lazy_static;
+
+use crate::config::{MaxPrefixLen, ValidationConfig};
+
+lazy_static! {
+    // Special-purpose ranges from the IANA registries that are never
+    // routed on the Internet.
+    static ref IPV4_BOGONS: Vec<Ipv4Net> = parse_all(&[
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.0.2.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "198.51.100.0/24",
+        "203.0.113.0/24",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+    ]);
+    static ref IPV6_BOGONS: Vec<Ipv6Net> = parse_all(&[
+        "::/128",
+        "::1/128",
+        "::ffff:0:0/96",
+        "100::/64",
+        "2001:db8::/32",
+        "3ffe::/16",
+        "fc00::/7",
+        "fe80::/10",
+        "fec0::/10",
+        "ff00::/8",
+    ]);
+}
+
+fn parse_all<T: std::str::FromStr>(prefixes: &[&str]) -> Vec<T>
+where
+    T::Err: fmt::Debug,
+{
+    prefixes
+        .iter()
+        .map(|p| p.parse().expect("BUG: invalid bogon prefix"))
+        .collect()
+}
+
+pub trait Prefix: Ord + Copy + fmt::Display {
+    fn prefix_len(&self) -> u8;
+    fn is_bogon(&self) -> bool;
+    fn is_ipv4_embedded(&self) -> bool;
+    /// The covering prefix of the given length.
+    fn supernet(&self, len: u8) -> Self;
+    /// The configured prefix length limit for this protocol.
+    fn max_prefix_len(config: &MaxPrefixLen) -> Option<u8>;
+}
+
+impl Prefix for Ipv4Net {
+    fn prefix_len(&self) -> u8 {
+        Ipv4Net::prefix_len(self)
+    }
+
+    fn is_bogon(&self) -> bool {
+        IPV4_BOGONS.iter().any(|bogon| bogon.contains(self))
+    }
+
+    fn is_ipv4_embedded(&self) -> bool {
+        false
+    }
+
+    fn supernet(&self, len: u8) -> Ipv4Net {
+        Ipv4Net::new(self.network(), len)
+            .expect("BUG: invalid prefix length")
+            .trunc()
+    }
+
+    fn max_prefix_len(config: &MaxPrefixLen) -> Option<u8> {
+        config.ipv4
+    }
+}
+
+impl Prefix for Ipv6Net {
+    fn prefix_len(&self) -> u8 {
+        Ipv6Net::prefix_len(self)
+    }
+
+    fn is_bogon(&self) -> bool {
+        IPV6_BOGONS.iter().any(|bogon| bogon.contains(self))
+    }
+
+    fn is_ipv4_embedded(&self) -> bool {
+        embedded_ipv4(self).is_some()
+    }
+
+    fn supernet(&self, len: u8) -> Ipv6Net {
+        Ipv6Net::new(self.network(), len)
+            .expect("BUG: invalid prefix length")
+            .trunc()
+    }
+
+    fn max_prefix_len(config: &MaxPrefixLen) -> Option<u8> {
+        config.ipv6
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Reason {
+    DefaultRoute,
+    Bogon,
+    TooSpecific,
+    Ipv4Embedded,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::DefaultRoute => write!(f, "default route"),
+            Reason::Bogon => write!(f, "bogon"),
+            Reason::TooSpecific => write!(f, "too specific"),
+            Reason::Ipv4Embedded => write!(f, "IPv4-mapped or compatible"),
+        }
+    }
+}
+
+/// A range that failed validation.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Violation {
+    pub range: String,
+    pub reason: Reason,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.range, self.reason)
+    }
+}
+
+pub fn check<T: Prefix>(config: &ValidationConfig, prefix: &T) -> Option<Reason> {
+    if config.default_route && prefix.prefix_len() == 0 {
+        return Some(Reason::DefaultRoute);
+    }
+    // These never match IPv6 traffic, so they are invalid regardless of
+    // the configuration, unless normalized into the IPv4 tables.
+    if prefix.is_ipv4_embedded() {
+        return Some(Reason::Ipv4Embedded);
+    }
+    if config.bogons && prefix.is_bogon() {
+        return Some(Reason::Bogon);
+    }
+    None
+}
+
+/// Returns the IPv4 prefix embedded in an IPv4-mapped (`::ffff:0:0/96`) or
+/// IPv4-compatible (`::/96`) IPv6 prefix.
+///
+/// Compatible prefixes overlapping `0.0.0.0/8` aren't considered embedded,
+/// since they include the unspecified and loopback addresses, `::` and
+/// `::1`, which would otherwise become IPv4 ranges such as `0.0.0.0/0`.
+pub fn embedded_ipv4(prefix: &Ipv6Net) -> Option<Ipv4Net> {
+    let len = prefix.prefix_len().checked_sub(96)?;
+    let segments = prefix.network().segments();
+    if segments[..5] != [0; 5] {
+        return None;
+    }
+    match segments[5] {
+        0xffff => {}
+        0 if segments[6] >> 8 != 0 => {}
+        _ => return None,
+    }
+    let addr = [segments[6], segments[7]];
+    let addr = (u32::from(addr[0]) << 16) | u32::from(addr[1]);
+    Some(Ipv4Net::new(addr.into(), len).expect("BUG: invalid prefix length"))
+}
+
+/// Copies the IPv4 ranges embedded in IPv4-mapped or compatible IPv6
+/// ranges, for use with `normalize_ipv4_embedded`.
+pub fn ipv4_embedded_entries(
+    ipv6: &BTreeMap<Option<String>, BTreeSet<&Entry<Ipv6Net>>>,
+) -> Vec<Entry<Ipv4Net>> {
+    ipv6.values()
+        .flatten()
+        .filter_map(|entry| {
+            Some(Entry {
+                range: embedded_ipv4(&entry.range)?,
+                kind: entry.kind.clone(),
+                class: entry.class.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Moves IPv4-mapped and compatible ranges from the IPv6 tables to the IPv4
+/// table of the same kind, where they actually match traffic.
+pub fn normalize_ipv4_embedded<'a>(
+    ipv4: &mut BTreeMap<Option<String>, BTreeSet<&'a Entry<Ipv4Net>>>,
+    ipv6: &mut BTreeMap<Option<String>, BTreeSet<&'a Entry<Ipv6Net>>>,
+    embedded: &'a [Entry<Ipv4Net>],
+) {
+    for ranges in ipv6.values_mut() {
+        ranges.retain(|entry| !entry.range.is_ipv4_embedded());
+    }
+    for entry in embedded {
+        ipv4.entry(entry.kind.clone()).or_default().insert(entry);
+    }
+}
+
+/// The ranges of a kind that passed validation, along with the violations
+/// found.
+#[derive(Debug)]
+pub struct Filtered<'s, 'a, T: Prefix> {
+    kept: Cow<'s, BTreeSet<&'a Entry<T>>>,
+    aggregated: Vec<Entry<T>>,
+    pub violations: Vec<Violation>,
+}
+
+impl<'s, 'a, T: Prefix> Filtered<'s, 'a, T> {
+    pub fn unfiltered(ranges: &'s BTreeSet<&'a Entry<T>>) -> Filtered<'s, 'a, T> {
+        Filtered {
+            kept: Cow::Borrowed(ranges),
+            aggregated: Vec::new(),
+            violations: Vec::new(),
+        }
+    }
+
+    /// The valid ranges, including the covering prefixes that replaced
+    /// aggregated ranges.
+    pub fn ranges(&self) -> Cow<'_, BTreeSet<&Entry<T>>> {
+        if self.aggregated.is_empty() {
+            return Cow::Borrowed(&*self.kept);
+        }
+        let mut ranges: BTreeSet<&Entry<T>> = self.kept.iter().copied().collect();
+        ranges.extend(&self.aggregated);
+        Cow::Owned(ranges)
+    }
+}
+
+/// Validates the ranges of a kind. Kinds listed in `exempt_kinds` are not
+/// validated.
+///
+/// Ranges more specific than the configured limit are only considered
+/// invalid once there are more than `allowed` of them in the table. If
+/// `aggregate` is set, they are then replaced by their covering prefixes
+/// at the limit instead.
+pub fn filter<'s, 'a, T: Prefix>(
+    config: &ValidationConfig,
+    kind: &Option<String>,
+    ranges: &'s BTreeSet<&'a Entry<T>>,
+) -> Filtered<'s, 'a, T> {
+    let exempt = kind
+        .as_ref()
+        .map_or(false, |kind| config.exempt_kinds.contains(kind));
+    if exempt {
+        return Filtered::unfiltered(ranges);
+    }
+
+    let limits = &config.max_prefix_len;
+    let limit = T::max_prefix_len(limits);
+    let too_specific = |prefix: &T| limit.map_or(false, |limit| prefix.prefix_len() > limit);
+    let num_too_specific = ranges.iter().filter(|e| too_specific(&e.range)).count();
+    let enforce_limit = num_too_specific > limits.allowed;
+
+    let mut kept = BTreeSet::new();
+    let mut specific = Vec::new();
+    let mut violations = Vec::new();
+    for entry in ranges {
+        let reason = match check(config, &entry.range) {
+            None if enforce_limit && too_specific(&entry.range) => {
+                if limits.aggregate {
+                    specific.push(*entry);
+                    continue;
+                }
+                Some(Reason::TooSpecific)
+            }
+            reason => reason,
+        };
+        match reason {
+            Some(reason) => violations.push(Violation {
+                range: entry.range.to_string(),
+                reason,
+            }),
+            None => {
+                kept.insert(*entry);
+            }
+        }
+    }
+
+    let aggregated = match limit {
+        Some(limit) => aggregate(&kept, &specific, limit),
+        None => Vec::new(),
+    };
+    Filtered {
+        kept: Cow::Owned(kept),
+        aggregated,
+        violations,
+    }
+}
+
+// Replaces each of the specific ranges by its covering prefix of length
+// `limit`, unless that prefix is already present. When several ranges share
+// a covering prefix, the first one determines its class.
+fn aggregate<T: Prefix>(
+    kept: &BTreeSet<&Entry<T>>,
+    specific: &[&Entry<T>],
+    limit: u8,
+) -> Vec<Entry<T>> {
+    let mut seen: BTreeSet<T> = kept.iter().map(|e| e.range).collect();
+    let mut aggregated = Vec::new();
+    for entry in specific {
+        let supernet = entry.range.supernet(limit);
+        if seen.insert(supernet) {
+            aggregated.push(Entry {
+                range: supernet,
+                ..(*entry).clone()
+            });
+        }
+    }
+    aggregated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ValidationConfig {
+        serde_yaml::from_str("{}").expect("invalid config")
+    }
+
+    #[test]
+    fn test_check() {
+        let config = config();
+        let v4 = |s: &str| check(&config, &s.parse::<Ipv4Net>().unwrap());
+        let v6 = |s: &str| check(&config, &s.parse::<Ipv6Net>().unwrap());
+
+        assert_eq!(Some(Reason::DefaultRoute), v4("0.0.0.0/0"));
+        assert_eq!(Some(Reason::Bogon), v4("10.1.0.0/16"));
+        assert_eq!(Some(Reason::Bogon), v4("192.0.2.1/32"));
+        assert_eq!(None, v4("8.0.0.0/8"));
+        // Covers bogons, but isn't one.
+        assert_eq!(None, v4("192.0.0.0/16"));
+
+        assert_eq!(Some(Reason::DefaultRoute), v6("::/0"));
+        assert_eq!(Some(Reason::Bogon), v6("2001:db8:1::/48"));
+        assert_eq!(Some(Reason::Bogon), v6("fe80::/64"));
+        assert_eq!(None, v6("2600::/12"));
+
+        let config: ValidationConfig =
+            serde_yaml::from_str("{bogons: false}").expect("invalid config");
+        assert_eq!(
+            None,
+            check(&config, &"10.0.0.0/8".parse::<Ipv4Net>().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_embedded_ipv4() {
+        let v6 = |s: &str| embedded_ipv4(&s.parse().unwrap()).map(|p| p.to_string());
+        assert_eq!(Some("192.0.2.0/24".to_owned()), v6("::ffff:192.0.2.0/120"));
+        assert_eq!(Some("198.51.100.1/32".to_owned()), v6("::198.51.100.1/128"));
+        assert_eq!(None, v6("::ffff:0:0/95"));
+        assert_eq!(None, v6("64:ff9b::192.0.2.0/120"));
+        assert_eq!(None, v6("2001:db8::/32"));
+        assert_eq!(Some("0.0.0.0/0".to_owned()), v6("::ffff:0:0/96"));
+
+        // The unspecified and loopback addresses aren't IPv4-compatible.
+        assert_eq!(None, v6("::/96"));
+        assert_eq!(None, v6("::/128"));
+        assert_eq!(None, v6("::1/128"));
+        assert_eq!(None, v6("::0.255.255.255/128"));
+        assert_eq!(None, v6("::/100"));
+        assert_eq!(Some("1.0.0.0/8".to_owned()), v6("::1.0.0.0/104"));
+
+        let config = config();
+        assert_eq!(
+            Some(Reason::Ipv4Embedded),
+            check(&config, &"::ffff:192.0.2.0/120".parse::<Ipv6Net>().unwrap())
+        );
+        assert_eq!(
+            Some(Reason::Bogon),
+            check(&config, &"::1/128".parse::<Ipv6Net>().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_max_prefix_len() {
+        let config: ValidationConfig =
+            serde_yaml::from_str("{max_prefix_len: {ipv4: 24, ipv6: 64}}").expect("invalid config");
+        assert_eq!(
+            Some(24),
+            <Ipv4Net as Prefix>::max_prefix_len(&config.max_prefix_len)
+        );
+        assert_eq!(
+            Some(64),
+            <Ipv6Net as Prefix>::max_prefix_len(&config.max_prefix_len)
+        );
+        assert!(serde_yaml::from_str::<ValidationConfig>("{max_prefix_len: {ipv4: 33}}").is_err());
+
+        let v4: Ipv4Net = "198.51.1.1/32".parse().unwrap();
+        assert_eq!("198.51.1.0/24", Prefix::supernet(&v4, 24).to_string());
+        let v6: Ipv6Net = "2600:1:2:3:4::/80".parse().unwrap();
+        assert_eq!("2600:1:2:3::/64", Prefix::supernet(&v6, 64).to_string());
+    }
+}
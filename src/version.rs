@@ -0,0 +1,61 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::dyncfg;
+
+/// The ways gtctl can reach Gatekeeper's dynamic configuration socket.
+pub const TRANSPORTS: &[&str] = &["unix", "ssh"];
+/// The places aggregates can be read from.
+pub const SOURCES: &[&str] = &["file"];
+
+/// Details about the gtctl build, for inventory tooling.
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Empty when built outside of a git checkout.
+    pub git_commit: &'static str,
+    pub build_date: DateTime<Utc>,
+    pub features: Features,
+    /// The drib version aggregates are read with.
+    pub aggregate_version: &'static str,
+    pub protocol_versions: Vec<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Features {
+    pub transports: Vec<&'static str>,
+    pub sources: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn current() -> BuildInfo {
+        let epoch = env!("GTCTL_BUILD_EPOCH").parse().unwrap_or(0);
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("GTCTL_GIT_COMMIT"),
+            build_date: Utc.timestamp(epoch, 0),
+            features: Features {
+                transports: TRANSPORTS.to_vec(),
+                sources: SOURCES.to_vec(),
+            },
+            aggregate_version: env!("GTCTL_DRIB_VERSION"),
+            protocol_versions: vec![dyncfg::PROTOCOL_VERSION],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let info = BuildInfo::current();
+        assert_eq!(env!("CARGO_PKG_VERSION"), info.version);
+        let json = serde_json::to_value(&info).expect("serialization failed");
+        assert_eq!(json!(["unix", "ssh"]), json["features"]["transports"]);
+        assert_eq!(json!([dyncfg::PROTOCOL_VERSION]), json["protocol_versions"]);
+    }
+}